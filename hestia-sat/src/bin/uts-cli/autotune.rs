@@ -0,0 +1,121 @@
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{info, warn};
+
+use uts_ws1::board::Board;
+use uts_ws1::control::autotune::{AutotuneConfig, AutotuneResult, AutotuneStatus, RelayAutotuner};
+use uts_ws1::control::pid::duty_from_fraction;
+use uts_ws1::guard::HeaterGuard;
+use uts_ws1::heater::HeaterMode;
+use uts_ws1::payload::{Config, Payload};
+use uts_ws1::safety::SupervisorThread;
+use uts_ws1::selector::SensorSelector;
+
+/// Margin kept below the firmware max temp, so the firmware cut-out doesn't end the run
+const MAX_TEMP_MARGIN: f32 = 5.0;
+
+pub struct AutotuneArgs {
+    pub board: Option<u8>,
+    pub sensor: SensorSelector,
+    pub setpoint: f32,
+    pub hysteresis: f32,
+    pub duty: f32,
+    pub cycles: usize,
+    pub timeout_mins: u32,
+    pub output: Option<String>,
+}
+
+pub fn run_autotune(args: AutotuneArgs) {
+    let config = Config::read();
+    let payload = Payload::from_config(&config);
+    let board = &payload[args.board];
+    // switches off the heater when autotune completes, fails or is interrupted
+    let _guard = HeaterGuard::new(&config, &[board.bus.id]).exit_on_signal();
+    let _safety = SupervisorThread::spawn(&config);
+    let max_temp = board.read_max_temp()
+        .map(|t| t.display_value - MAX_TEMP_MARGIN)
+        .unwrap_or_else(|e| panic!("Couldn't read max temp from board {}: {}", board.bus, e));
+    assert!(args.setpoint + args.hysteresis < max_temp,
+            "Setpoint {:.1}°C is too close to board max temp {:.1}°C", args.setpoint, max_temp);
+
+    let config = AutotuneConfig {
+        relay_high: args.duty,
+        hysteresis: args.hysteresis,
+        cycles: args.cycles,
+        timeout: chrono::Duration::minutes(args.timeout_mins as i64),
+        ..AutotuneConfig::new(args.setpoint, max_temp)
+    };
+    info!("Autotuning board {} on {} around {:.1}°C (max {:.1}°C)",
+        board.bus, args.sensor, args.setpoint, max_temp);
+
+    match tune(board, &args.sensor, config) {
+        Some(result) => {
+            report(&result);
+            if let Some(output) = &args.output {
+                save(&result, output);
+            }
+        }
+        None => warn!("Autotune failed, no gains recommended"),
+    }
+}
+
+fn tune(board: &Board, sensor: &SensorSelector, config: AutotuneConfig) -> Option<AutotuneResult> {
+    let mut tuner = RelayAutotuner::new(config);
+    let mut duty = None;
+    loop {
+        let temp = match board.read_temp(sensor) {
+            Ok(temp) => temp,
+            Err(e) => {
+                warn!("Failed to read {}, stopping autotune: {}", sensor, e);
+                return None;
+            }
+        };
+        match tuner.update(temp, Utc::now()) {
+            AutotuneStatus::Running(fraction) => {
+                let new_duty = duty_from_fraction(fraction);
+                if duty != Some(new_duty) {
+                    info!("{}: {:.2}°C, cycle {}, setting duty to {}", sensor, temp, tuner.cycles(), new_duty);
+                    board.write_heater_duty(new_duty);
+                    if duty.is_none() {
+                        board.write_heater_mode(HeaterMode::PWM);
+                    }
+                    duty = Some(new_duty);
+                }
+            }
+            AutotuneStatus::Complete(result) => {
+                board.write_heater_mode(HeaterMode::OFF);
+                return Some(result);
+            }
+            AutotuneStatus::Aborted(reason) => {
+                board.write_heater_mode(HeaterMode::OFF);
+                warn!("Autotune aborted: {}", reason);
+                return None;
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn report(result: &AutotuneResult) {
+    let pid = result.pid_config();
+    let (k_p, k_i_shift) = result.firmware_gains();
+    println!("Autotune complete: {}", result);
+    println!("Host PID gains: kp = {:.4}, ki = {:.6}, kd = {:.4}", pid.kp, pid.ki, pid.kd);
+    println!("Firmware PID gains: K_P = {:.0}, K_I_SHIFT = {}", k_p, k_i_shift);
+}
+
+/// Saves the gains as a `[programs.pid]` table, ready to paste into a program file
+fn save(result: &AutotuneResult, filename: &str) {
+    let pid = result.pid_config();
+    let (k_p, k_i_shift) = result.firmware_gains();
+    let contents = format!(
+        "# autotune {}\n# firmware equivalent: K_P = {:.0}, K_I_SHIFT = {}\n\
+        [programs.pid]\nsetpoint = {:.1}\nkp = {:.4}\nki = {:.6}\nkd = {:.4}\n",
+        result, k_p, k_i_shift, pid.setpoint, pid.kp, pid.ki, pid.kd);
+    fs::write(filename, contents)
+        .unwrap_or_else(|e| panic!("Failed to write gains to {}: {}", filename, e));
+    info!("Saved recommended gains to {}", filename);
+}
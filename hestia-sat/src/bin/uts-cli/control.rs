@@ -0,0 +1,75 @@
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{info, warn};
+
+use uts_ws1::board::Board;
+use uts_ws1::control::pid::{PidConfig, PidController};
+use uts_ws1::guard::HeaterGuard;
+use uts_ws1::heater::HeaterMode;
+use uts_ws1::payload::{Config, Payload};
+use uts_ws1::safety::SupervisorThread;
+use uts_ws1::selector::SensorSelector;
+
+pub struct ControlArgs {
+    pub board: Option<u8>,
+    pub sensor: SensorSelector,
+    pub temp: f32,
+    pub duration_mins: u32,
+}
+
+/// Holds a sensor or aggregate at a temperature, using the firmware thermostat if it can
+/// see the sensor, otherwise a host-side PID with equivalent gains
+pub fn run_control(args: ControlArgs) {
+    let config = Config::read();
+    let payload = Payload::from_config(&config);
+    let board = &payload[args.board];
+    // switches off the heater when control ends or is interrupted
+    let _guard = HeaterGuard::new(&config, &[board.bus.id]).exit_on_signal();
+    let _safety = SupervisorThread::spawn(&config);
+    let end_time = Utc::now() + chrono::Duration::minutes(args.duration_mins as i64);
+
+    if let Some(target_sensor) = args.sensor.target_sensor() {
+        info!("Controlling board {} on {} at {:.1}°C with firmware thermostat",
+            board.bus, args.sensor, args.temp);
+        board.write_target_sensor(target_sensor);
+        board.write_target_temp(args.temp);
+        board.write_heater_mode(HeaterMode::PID);
+        while Utc::now() < end_time {
+            report(board, &args.sensor);
+            thread::sleep(Duration::from_secs(5));
+        }
+    } else {
+        info!("Controlling board {} on {} at {:.1}°C with host-side PID",
+            board.bus, args.sensor, args.temp);
+        let mut pid = PidController::new(PidConfig::firmware_equivalent(args.temp));
+        board.write_heater_duty(pid.duty());
+        board.write_heater_mode(HeaterMode::PWM);
+        while Utc::now() < end_time {
+            match board.read_temp(&args.sensor) {
+                Ok(temp) => {
+                    let now = Utc::now();
+                    if pid.is_due(now) {
+                        pid.update(temp, now);
+                        board.write_heater_duty(pid.duty());
+                        info!("{}: {:.2}°C, duty: {}", args.sensor, temp, pid.duty());
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to read {}, stopping control: {}", args.sensor, e);
+                    return;
+                }
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+    info!("Control time completed, switching off heater");
+}
+
+fn report(board: &Board, sensor: &SensorSelector) {
+    match board.read_temp(sensor) {
+        Ok(temp) => info!("{}: {:.2}°C", sensor, temp),
+        Err(e) => warn!("Failed to read {}: {}", sensor, e),
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::process;
+
+use chrono::Duration;
+use log::info;
+
+use uts_ws1::board::BoardId;
+use uts_ws1::payload::Config;
+use uts_ws1::programs::forecast::{forecast, Forecast, ForecastSettings};
+use uts_ws1::programs::plan::format_duration;
+use uts_ws1::programs::validate::{validate, ValidationLimits};
+use uts_ws1::thermal::{read_log_samples, ThermalModel, ThermalModels};
+
+pub struct ForecastArgs {
+    pub toml_file: String,
+    pub model_file: Option<String>,
+    pub window: Option<Duration>,
+    pub json: bool,
+}
+
+/// Predicts the duration, temperatures and energy of a program file with the thermal models,
+/// without touching the hardware. Exits with status 1 if the file has errors.
+pub fn run_forecast(args: ForecastArgs) {
+    let config = Config::read();
+    let source = fs::read_to_string(&args.toml_file).unwrap_or_else(|err| {
+        eprintln!("{}: failed to read: {}", args.toml_file, err);
+        process::exit(1);
+    });
+    let validation = validate(&source, &ValidationLimits::from(&config));
+    let programs = match (validation.is_valid(), &validation.programs) {
+        (true, Some(programs)) => programs,
+        _ => {
+            eprintln!("{}: has errors, run `uts-cli validate` for details", args.toml_file);
+            process::exit(1);
+        }
+    };
+    let models = match &args.model_file {
+        Some(filename) => ThermalModels::load_from_file(filename),
+        None => ThermalModels::from_config(&config),
+    }.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    let forecast = forecast(programs, &models, &ForecastSettings::from(&config), args.window);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&forecast).unwrap());
+    } else {
+        print_forecast(&forecast);
+    }
+}
+
+fn print_forecast(forecast: &Forecast) {
+    for program in &forecast.programs {
+        let outcome = match (program.failed, program.aborted) {
+            (true, _) => ", fails",
+            (false, true) => ", aborts",
+            _ => "",
+        };
+        let scheduled = if program.scheduled { ", after waiting to start" } else { "" };
+        println!("Program {} \"{}\" on {:?}{}{}", program.id, program.name, program.heat_board, outcome, scheduled);
+        println!("       {:<50} {:>9} {:>7} {:>7} {:>7} {:>8}  outcome",
+                 "step", "time", "start", "end", "peak", "energy");
+        for step in &program.steps {
+            println!("  {:>2}.  {:<50} {:>9} {:>6.1}° {:>6.1}° {:>6.1}° {:>6.2}Wh  {:?}",
+                     step.step, step.description, format_secs(step.duration_secs),
+                     step.start_temp, step.end_temp, step.peak_temp, step.energy_wh, step.outcome);
+        }
+        println!("       {:<50} {:>9}                 {:>6.1}° {:>6.2}Wh",
+                 "total", format_secs(program.duration_secs), program.peak_temp, program.energy_wh);
+        println!();
+    }
+    println!("Total: {}{}, peak {:.1}°C, {:.2} Wh",
+             if forecast.complete { "" } else { "at least " },
+             format_secs(forecast.duration_secs), forecast.peak_temp, forecast.energy_wh);
+    if let Some(window) = forecast.window_secs {
+        match (forecast.run_loop, forecast.iterations) {
+            (true, Some(iterations)) =>
+                println!("Loop runs {} times in {} window", iterations, format_secs(window)),
+            _ => println!("{} {} window", if forecast.fits_window == Some(true) { "Fits" } else { "Doesn't fit" },
+                          format_secs(window)),
+        }
+    } else if let (true, Some(iterations)) = (forecast.run_loop, forecast.iterations) {
+        println!("Programs repeat {} times", iterations);
+    } else if forecast.run_loop {
+        println!("Programs repeat until stopped");
+    }
+}
+
+fn format_secs(secs: i64) -> String {
+    format_duration(Duration::seconds(secs))
+}
+
+/// Fits a thermal model for each board in a uts-log CSV file, printing the models or saving
+/// them as a UTS_THERMAL_MODEL_FILE. Boards without enough heating in the log keep their
+/// current model.
+pub fn run_fit_model(log_file: &str, sensor: &str, output: Option<&str>) {
+    let config = Config::read();
+    let mut models = ThermalModels::from_config(&config).unwrap_or_default();
+    for board in [BoardId::Top, BoardId::Bottom] {
+        let samples = read_log_samples(log_file, board as u8, sensor).unwrap_or_else(|err| {
+            eprintln!("{}: failed to read: {}", log_file, err);
+            process::exit(1);
+        });
+        match ThermalModel::fit(&samples, config.ambient_temp) {
+            Some(model) => {
+                info!("Fitted {:?} board from {} readings: {:?}", board, samples.len(), model);
+                models.set(board, model);
+            }
+            None => info!("Not enough heating in {} readings to fit {:?} board", samples.len(), board),
+        }
+    }
+    match output {
+        Some(filename) => {
+            fs::write(filename, models.to_toml())
+                .unwrap_or_else(|e| panic!("Failed to write thermal models to {}: {}", filename, e));
+            info!("Saved thermal models to {}", filename);
+        }
+        None => print!("{}", models.to_toml()),
+    }
+}
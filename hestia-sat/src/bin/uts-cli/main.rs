@@ -1,3 +1,4 @@
+use std::process;
 use std::thread;
 use std::time::Duration;
 
@@ -6,14 +7,26 @@ use clap::{Parser, Subcommand};
 use log::info;
 
 use uts_ws1::board::{Board, BoardDataProvider};
+use uts_ws1::deadman::Deadman;
+use uts_ws1::diagnostics::{diagnose, HeaterMeasurement};
+use uts_ws1::guard::HeaterGuard;
 use uts_ws1::heater::{HeaterMode, TargetSensor};
 use uts_ws1::logger::LogWriter;
 use uts_ws1::payload::{Config, Payload};
+use uts_ws1::power::BudgetDecision;
 use uts_ws1::programs::{Programs, runner};
+use uts_ws1::programs::active::ActiveProgramFile;
 use uts_ws1::reading::SensorReading;
+use uts_ws1::safety::{SafetyLimits, Supervisor, SupervisorThread};
+use uts_ws1::selector::SensorSelector;
 use uts_ws1::{ReadResult, zipper};
 
+mod autotune;
+mod control;
+mod forecast;
+mod replay;
 mod test;
+mod validate;
 
 #[derive(Parser)]
 #[command(version, about)]
@@ -53,8 +66,17 @@ enum Command {
         #[arg(short, long)]
         board: Option<u8>,
 
-        /// Mode to configure on the heater. Turning on heater on one board will first
-        /// disable it on any other connected boards.
+        /// Switch the heater off after this time, e.g. "30m". Defaults to UTS_DEADMAN_TIMEOUT.
+        #[arg(short, long, value_parser = parse_duration)]
+        timeout: Option<chrono::Duration>,
+
+        /// Exit leaving the heater on until the timeout, which is enforced by the safety
+        /// supervisor in uts-log. Otherwise the heater is held on until the timeout or Ctrl-C.
+        #[arg(long)]
+        leave_running: bool,
+
+        /// Mode to configure on the heater. Turning on heater on one board is limited by
+        /// the combined power budget (UTS_POWER_BUDGET) across all connected boards.
         #[command(subcommand)]
         command: HeaterCommand,
     },
@@ -75,10 +97,31 @@ enum Command {
         #[arg(short, long)]
         board: Option<u8>,
 
-        /// Target sensor: TH1, TH2, TH3, J7 or J8
+        /// Target sensor: TH1, TH2, TH3, J7 or J8. Use `control` for any other sensor.
         target_sensor: TargetSensor,
     },
 
+    /// Hold any sensor or aggregate at a temperature, e.g. U7 or "max(TH1, TH2, TH3)"
+    ///
+    /// Uses the firmware thermostat if it can see the sensor, otherwise runs a host-side
+    /// PID until the duration ends, then switches the heater off.
+    Control {
+        /// Board to control. Required if two boards are connected.
+        #[arg(short, long)]
+        board: Option<u8>,
+
+        /// Sensor or aggregate to control on, e.g. U7 or "max(TH1, TH2)"
+        #[arg(short, long)]
+        sensor: SensorSelector,
+
+        /// temperature in °C
+        temp: f32,
+
+        /// Time to hold the temperature (minutes)
+        #[arg(short, long, default_value_t = 60)]
+        duration: u32,
+    },
+
     /// Set PWM duty cycle
     Duty {
         /// Board to update. Required if two boards are connected.
@@ -99,12 +142,116 @@ enum Command {
         temp: f32,
     },
 
+    /// Find PID gains by oscillating the heater around a setpoint (relay method)
+    Autotune {
+        /// Board to tune. Required if two boards are connected.
+        #[arg(short, long)]
+        board: Option<u8>,
+
+        /// Sensor or aggregate to control on, e.g. TH1, U7 or "max(TH1, TH2)"
+        #[arg(short, long)]
+        sensor: SensorSelector,
+
+        /// Temperature to oscillate around in °C
+        #[arg(long)]
+        setpoint: f32,
+
+        /// Switching band either side of the setpoint in °C
+        #[arg(long, default_value_t = 0.5)]
+        hysteresis: f32,
+
+        /// Duty cycle fraction while heating (0.0-1.0)
+        #[arg(long, default_value_t = 1.0)]
+        duty: f32,
+
+        /// Number of oscillations to measure, at least 1
+        #[arg(long, default_value_t = 4, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        cycles: usize,
+
+        /// Maximum time to run before giving up (minutes)
+        #[arg(long, default_value_t = 120)]
+        timeout: u32,
+
+        /// File to save recommended gains to, as a [programs.pid] table
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
     /// Run a TOML program file by name
     Run {
         /// Relative or absolute path to TOML file
         toml_file: String,
     },
 
+    /// Check a TOML program file for problems, without touching the hardware
+    ///
+    /// Exits with status 1 if there are any errors. Uses UTS_I2C_BUS, UTS_SAFETY_MAX_TEMP
+    /// and UTS_AMBIENT_TEMP to check the programs against.
+    Validate {
+        /// Relative or absolute path to TOML file
+        toml_file: String,
+
+        /// Also print the expected sequence of steps and their durations
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Predict how long a TOML program file will take, its peak temperatures and energy
+    ///
+    /// Simulates the programs with a thermal model of each board, from UTS_THERMAL_MODEL_FILE
+    /// or fitted with `fit-model`. Uses UTS_AMBIENT_TEMP and UTS_HEATER_POWER.
+    Forecast {
+        /// Relative or absolute path to TOML file
+        toml_file: String,
+
+        /// Thermal model file to use instead of UTS_THERMAL_MODEL_FILE
+        #[arg(short, long)]
+        model: Option<String>,
+
+        /// Time available to run the programs, e.g. "6h", to check they fit or count loops
+        #[arg(short, long, value_parser = parse_duration)]
+        window: Option<chrono::Duration>,
+
+        /// Print the forecast as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Fit a thermal model for each board from a uts-log CSV file with heating and cooling
+    FitModel {
+        /// CSV log file, e.g. from UTS_LOG_PATH
+        log_file: String,
+
+        /// Sensor to fit the board temperature to
+        #[arg(short, long, default_value = "TH1")]
+        sensor: String,
+
+        /// File to save the models to, for UTS_THERMAL_MODEL_FILE
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Replay a uts-log CSV file through a TOML program file, printing when each phase starts
+    ///
+    /// Runs the programs on the temperatures in the log, from the time it starts, without
+    /// touching the hardware. Compare with the event log from the flight, or try changes to
+    /// the programs against real data.
+    Replay {
+        /// Relative or absolute path to TOML file
+        toml_file: String,
+
+        /// Display or raw CSV log file, e.g. from UTS_LOG_PATH
+        log_file: String,
+
+        /// Directory to write the predicted event log to, as uts-replay-<date>.jsonl files
+        #[arg(short, long)]
+        events: Option<String>,
+
+        /// Print the timeline as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
     /// Compress all the log files in UTS_LOG_PATH
     Zip,
 
@@ -130,12 +277,43 @@ pub fn main() {
             Command::Log => do_log(),
             Command::Status => do_status(),
             Command::Test { duration } => test::run_test(*duration),
-            Command::Heater { board, command } => do_heater(*board, command),
+            Command::Heater { board, timeout, leave_running, command } =>
+                do_heater(*board, command, *timeout, *leave_running),
             Command::Target { board, temp } => do_target(*board, *temp),
             Command::TargetSensor { board, target_sensor } => do_target_sensor(*board, *target_sensor),
             Command::Duty { board, duty } => do_duty(*board, *duty),
             Command::Max { board, temp } => do_max(*board, *temp),
+            Command::Control { board, sensor, temp, duration } =>
+                control::run_control(control::ControlArgs {
+                    board: *board,
+                    sensor: sensor.clone(),
+                    temp: *temp,
+                    duration_mins: *duration,
+                }),
+            Command::Autotune { board, sensor, setpoint, hysteresis, duty, cycles, timeout, output } =>
+                autotune::run_autotune(autotune::AutotuneArgs {
+                    board: *board,
+                    sensor: sensor.clone(),
+                    setpoint: *setpoint,
+                    hysteresis: *hysteresis,
+                    duty: *duty,
+                    cycles: *cycles,
+                    timeout_mins: *timeout,
+                    output: output.clone(),
+                }),
             Command::Run { toml_file } => do_run(toml_file),
+            Command::Validate { toml_file, dry_run } => validate::run_validate(toml_file, *dry_run),
+            Command::Forecast { toml_file, model, window, json } =>
+                forecast::run_forecast(forecast::ForecastArgs {
+                    toml_file: toml_file.clone(),
+                    model_file: model.clone(),
+                    window: *window,
+                    json: *json,
+                }),
+            Command::FitModel { log_file, sensor, output } =>
+                forecast::run_fit_model(log_file, sensor, output.as_deref()),
+            Command::Replay { toml_file, log_file, events, json } =>
+                replay::run_replay(toml_file, log_file, events.as_deref(), *json),
             Command::Zip => do_zip(),
             Command::Enable => do_enable(),
             Command::Disable => do_disable(),
@@ -145,7 +323,10 @@ pub fn main() {
 }
 
 fn do_run(toml_file: &str) {
-    let payload = Payload::create();
+    let config = Config::read();
+    let payload = Payload::from_config(&config);
+    let _safety = SupervisorThread::spawn(&config);
+    let _guard = HeaterGuard::all(&config).abort_on_signal();
     info!("Configured with {} boards: {:?}", payload.iter().len(), payload.iter());
 
     let programs = Programs::load_from_file(toml_file);
@@ -156,7 +337,7 @@ fn do_run(toml_file: &str) {
 }
 
 fn do_duty(board: Option<u8>, duty: u16) {
-    update_board(board, |_, b| b.write_heater_duty(duty));
+    update_board(board, |_, b| { b.write_heater_duty(duty); });
 }
 
 fn do_target_sensor(board: Option<u8>, target_sensor: TargetSensor) {
@@ -174,9 +355,14 @@ fn do_max(board: Option<u8>, temp: f32) {
 fn update_board<F>(board: Option<u8>, mut op: F)
     where F: FnMut(&Payload, &Board)
 {
-    let payload = Payload::create();
+    let config = Config::read();
+    let payload = Payload::from_config(&config);
     let board = &payload[board];
     op(&payload, board);
+    if config.safety_enable {
+        // heaters aren't supervised after we exit, so at least check they're safe now
+        Supervisor::new(SafetyLimits::from(&config), &payload).check(&payload);
+    }
     show_status(&payload);
 }
 
@@ -195,25 +381,47 @@ fn do_disable() {
     uts_ws1::host::disable_payload();
 }
 
-fn do_heater(board: Option<u8>, command: &HeaterCommand) {
-    update_board(board, |payload, this_board| {
-        // disable heater on other boards before enabling on this one
-        for other in payload {
-            if this_board.id != other.id {
-                match command {
-                    HeaterCommand::Off => {} // do nothing if switching off
-                    _ => other.write_heater_mode(HeaterMode::OFF),
-                }
-            }
-        }
-
-        // set mode on this board
-        match command {
+fn do_heater(board: Option<u8>, command: &HeaterCommand, timeout: Option<chrono::Duration>, leave_running: bool) {
+    let config = Config::read();
+    let payload = Payload::from_config(&config);
+    let this_board = &payload[board];
+    let guard = HeaterGuard::new(&config, &[this_board.bus.id]).exit_on_signal();
+    let timeout = timeout.unwrap_or_else(|| chrono::Duration::minutes(config.deadman_timeout as i64));
+    let deadline = Utc::now() + timeout;
+    if !matches!(command, HeaterCommand::Off) {
+        // armed before switching on, so the heater is switched off even if we're killed
+        Deadman::from_config(&config).arm(this_board.bus.id, deadline);
+    }
+    // other boards are left on, the power budget limits the combined power
+    let mut decision = BudgetDecision::Allowed;
+    update_board(Some(this_board.bus.id), |_, this_board| {
+        decision = match command {
             HeaterCommand::Off => this_board.write_heater_mode(HeaterMode::OFF),
             HeaterCommand::Thermostat => this_board.write_heater_mode(HeaterMode::PID),
             HeaterCommand::On => this_board.write_heater_mode(HeaterMode::PWM),
         }
     });
+    if decision == BudgetDecision::Rejected {
+        eprintln!("Heater not switched on, it would exceed the power budget of {} W", config.power_budget);
+        drop(guard); // clears the deadman deadline
+        process::exit(1);
+    }
+    match (command, leave_running) {
+        (HeaterCommand::Off, _) => {} // the guard also clears any deadman deadline
+        (_, true) => guard.leave_running(deadline),
+        (_, false) => {
+            info!("Holding heater on until {}, press Ctrl-C to switch off", deadline);
+            while Utc::now() < deadline {
+                thread::sleep(Duration::from_secs(config.log_interval as u64));
+                show_board_status(this_board);
+            }
+            info!("Heater timeout reached, switching off");
+        }
+    }
+}
+
+fn parse_duration(value: &str) -> Result<chrono::Duration, String> {
+    duration_str::parse_chrono(value).map_err(|e| e.to_string())
 }
 
 fn do_status() {
@@ -222,8 +430,12 @@ fn do_status() {
 }
 
 fn show_status(payload: &Payload) {
+    let deadlines = Deadman::from_config(&Config::read()).read();
     for board in payload {
         show_board_status(board);
+        if let Some(deadline) = deadlines.get(&board.bus.id) {
+            println!("board:{} heater off at {}", board.bus, deadline);
+        }
     }
 }
 
@@ -234,6 +446,7 @@ fn format_reading(reading: ReadResult<SensorReading<f32>>) -> String {
 
 fn show_board_status(board: &Board) {
     if let Some(data) = board.read_data() {
+        let measurement = HeaterMeasurement::from_data(board.version, &data);
         let heater_mode = data.heater_mode
             .map(|m| m.to_string())
             .unwrap_or(String::from("#err"));
@@ -253,6 +466,11 @@ fn show_board_status(board: &Board) {
                  heater_curr.map_or(String::from("#err"), |c| format!("{:0.2}", c)),
                  data.flags.unwrap(),
         );
+        if let Some(measurement) = measurement {
+            for fault in diagnose(&measurement, None) {
+                println!("board:{} heater fault: {}", board.bus, fault);
+            }
+        }
     } else {
         println!("board:{} #err", board.bus);
     }
@@ -262,11 +480,8 @@ fn do_log() {
     let config = Config::read();
     let payload = Payload::create();
 
-    let mut writer = LogWriter::create_stdout_writer(&payload);
+    let mut writer = LogWriter::create_stdout_writer(&payload)
+        .with_active_program(ActiveProgramFile::from_config(&config));
     writer.write_header_if_new();
-    loop {
-        let timestamp = Utc::now();
-        writer.write_data(timestamp);
-        thread::sleep(Duration::from_secs(config.log_interval as u64));
-    }
+    writer.run(chrono::Duration::seconds(config.log_interval as i64));
 }
@@ -0,0 +1,49 @@
+use std::fs;
+use std::process;
+
+use uts_ws1::payload::Config;
+use uts_ws1::programs::event_log::EventLog;
+use uts_ws1::programs::replay::{replay, LogReplay, Transition};
+use uts_ws1::programs::validate::{validate, ValidationLimits};
+
+/// Replays a CSV log through a program file, printing the phases the runner would have entered.
+/// Exits with status 1 if the file has errors or the log can't be read.
+pub fn run_replay(toml_file: &str, log_file: &str, events: Option<&str>, json: bool) {
+    let config = Config::read();
+    let source = fs::read_to_string(toml_file).unwrap_or_else(|err| {
+        eprintln!("{}: failed to read: {}", toml_file, err);
+        process::exit(1);
+    });
+    let validation = validate(&source, &ValidationLimits::from(&config));
+    let programs = match (validation.is_valid(), &validation.programs) {
+        (true, Some(programs)) => programs,
+        _ => {
+            eprintln!("{}: has errors, run `uts-cli validate` for details", toml_file);
+            process::exit(1);
+        }
+    };
+    let log = LogReplay::read(log_file, config.board_version).unwrap_or_else(|err| {
+        eprintln!("{}: failed to read: {}", log_file, err);
+        process::exit(1);
+    });
+    let event_log = events.map(EventLog::replayed);
+    let timeline = replay(&log, programs, event_log.as_ref());
+    if json {
+        println!("{}", serde_json::to_string_pretty(&timeline).unwrap());
+    } else {
+        print_timeline(&timeline);
+    }
+}
+
+fn print_timeline(timeline: &[Transition]) {
+    for transition in timeline {
+        let track = transition.board.map(|board| format!(" [{}]", board)).unwrap_or_default();
+        let program = match (&transition.program_name, transition.step) {
+            (Some(name), Some(step)) => format!(" {} step {}", name, step),
+            (Some(name), None) => format!(" {}", name),
+            _ => String::new(),
+        };
+        let message = transition.message.as_ref().map(|m| format!(": {}", m)).unwrap_or_default();
+        println!("{}{}{} {}{}", transition.time, track, program, transition.phase, message);
+    }
+}
@@ -6,10 +6,12 @@ use std::time::Duration;
 use colored::{ColoredString, Colorize};
 
 use uts_ws1::board::{Board, BoardDataProvider};
+use uts_ws1::guard::HeaterGuard;
 use uts_ws1::heater::HeaterMode;
-use uts_ws1::payload::Payload;
+use uts_ws1::payload::{Config, Payload};
 use uts_ws1::reading::SensorReading;
 use uts_ws1::ReadResult;
+use uts_ws1::safety::SupervisorThread;
 use uts_ws1::sensors::SensorId;
 
 struct TestData<'a> {
@@ -58,19 +60,18 @@ impl std::fmt::Display for TestData<'_> {
 /// Ensures the heater is turned off if test aborts
 struct BoardTest {
     payload: Payload,
+    _safety: Option<SupervisorThread>,
+    // turns off heaters even if a test fails
+    _guard: HeaterGuard,
 }
 
 impl BoardTest {
     pub fn new() -> Self {
-        BoardTest { payload: Payload::create() }
-    }
-}
-
-impl Drop for BoardTest {
-    fn drop(&mut self) {
-        // turn off heater even if a test fails
-        for board in &self.payload {
-            board.write_heater_mode(HeaterMode::OFF);
+        let config = Config::read();
+        BoardTest {
+            payload: Payload::from_config(&config),
+            _safety: SupervisorThread::spawn(&config),
+            _guard: HeaterGuard::all(&config).exit_on_signal(),
         }
     }
 }
@@ -0,0 +1,103 @@
+use std::fs;
+use std::process;
+
+use uts_ws1::payload::Config;
+use uts_ws1::programs::plan::{format_duration, ProgramPlan};
+use uts_ws1::programs::{Program, Programs};
+use uts_ws1::programs::validate::{Severity, validate, ValidationLimits};
+
+/// Reports every problem in a program file, and optionally the expected sequence of steps,
+/// without touching the hardware. Exits with status 1 if there are any errors.
+pub fn run_validate(toml_file: &str, dry_run: bool) {
+    let config = Config::read();
+    let source = fs::read_to_string(toml_file).unwrap_or_else(|err| {
+        eprintln!("{}: failed to read: {}", toml_file, err);
+        process::exit(1);
+    });
+    let validation = validate(&source, &ValidationLimits::from(&config));
+    for problem in &validation.problems {
+        println!("{}: {}", toml_file, problem);
+    }
+    let count = |severity| validation.problems.iter().filter(|p| p.severity == severity).count();
+    println!("{}: {} programs, {} errors, {} warnings", toml_file,
+             validation.programs.as_ref().map_or(0, |p| p.iter().len()),
+             count(Severity::Error), count(Severity::Warning));
+    if let (true, Some(programs)) = (dry_run, &validation.programs) {
+        print_plan(programs, config.ambient_temp);
+    }
+    if !validation.is_valid() {
+        process::exit(1);
+    }
+}
+
+fn print_plan(programs: &Programs, ambient_temp: f32) {
+    for program in programs.iter() {
+        let plan = ProgramPlan::new(program, ambient_temp);
+        println!();
+        println!("Program {} \"{}\" on {:?}, aborting above {:.1}°C on {}",
+                 plan.id, plan.name, plan.heat_board, program.temp_abort, program.abort_sensor());
+        if program.is_scheduled() {
+            println!("      {}", format_schedule(program));
+        }
+        for (index, step) in plan.steps.iter().enumerate() {
+            println!("  {:>2}. {:<60} {}", index + 1, step.description, format_range(step.min, step.max));
+        }
+        println!("      {:<60} {}", "total", format_range(plan.min(), plan.max()));
+    }
+    println!();
+    if programs.parallel {
+        for (board, track) in programs.tracks() {
+            println!("Runs on {}: {}", board, format_sequence(&track));
+        }
+        println!("Tracks run at the same time, heating within the power budget");
+    } else {
+        println!("Runs: {}", format_sequence(&programs.sequence()));
+    }
+    match (programs.run_loop, programs.max_iterations) {
+        (true, Some(iterations)) => println!("Programs repeat {} times", iterations),
+        (true, None) => println!("Programs repeat until stopped"),
+        _ => {}
+    }
+    if let Some(end_time) = programs.end_time {
+        println!("Programs stop at {}", end_time.to_rfc3339());
+    }
+}
+
+/// When the program waits to start, e.g. `starts after 2023-10-01T12:00:00+00:00, within 22:00-04:00 UTC`
+fn format_schedule(program: &Program) -> String {
+    let mut parts = vec![];
+    if let Some(not_before) = program.start_at.or(program.not_before) {
+        parts.push(format!("starts after {}", not_before.to_rfc3339()));
+    }
+    if let Some(not_after) = program.not_after {
+        parts.push(format!("skipped if not started by {}", not_after.to_rfc3339()));
+    }
+    if let Some(window) = &program.daily_window {
+        parts.push(format!("starts within {} each day", window));
+    }
+    parts.join(", ")
+}
+
+/// Program names in run order, with repeats in a row counted, e.g. `"Top hot" x3, "Bottom hot" x3`
+fn format_sequence(sequence: &[&Program]) -> String {
+    let mut runs: Vec<(&str, usize)> = vec![];
+    for program in sequence {
+        match runs.last_mut() {
+            Some((name, count)) if *name == program.name => *count += 1,
+            _ => runs.push((&program.name, 1)),
+        }
+    }
+    let runs: Vec<String> = runs.iter()
+        .map(|(name, count)| if *count > 1 { format!("\"{}\" x{}", name, count) } else { format!("\"{}\"", name) })
+        .collect();
+    format!("{} programs: {}", sequence.len(), runs.join(", "))
+}
+
+fn format_range(min: chrono::Duration, max: Option<chrono::Duration>) -> String {
+    match max {
+        Some(max) if max == min => format_duration(min),
+        Some(max) => format!("{} to {}", format_duration(min), format_duration(max)),
+        None if min.is_zero() => String::from("until reached"),
+        None => format!("at least {}", format_duration(min)),
+    }
+}
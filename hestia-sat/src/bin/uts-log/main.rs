@@ -1,40 +1,40 @@
-use std::thread;
-use std::time::Duration;
-use chrono::{Datelike, Utc};
+use std::rc::Rc;
+
+use chrono::Duration;
 use log::info;
+use uts_ws1::clock::{Clock, SystemClock};
 use uts_ws1::payload::{Config, Payload};
 use uts_ws1::logger::LogWriter;
+use uts_ws1::programs::active::ActiveProgramFile;
+use uts_ws1::safety::SupervisorThread;
 use uts_ws1::zipper;
 
 pub fn main() {
     let config = Config::read();
+    // uts-log is always running, so it also guards heaters switched on manually
+    let _safety = SupervisorThread::spawn(&config);
+    let clock: Rc<dyn Clock> = Rc::new(SystemClock);
     loop { // restarts each new day
         if config.compress_logs {
             // compress logs when we start and after each day
             zipper::zip_logs(&config);
         }
-        loop_logger_for_day(&config);
+        loop_logger_for_day(&config, &clock);
     }
 }
 
-fn loop_logger_for_day(config: &Config) {
-    let start_date = Utc::now();
+fn loop_logger_for_day(config: &Config, clock: &Rc<dyn Clock>) {
+    // the same clock decides when the day is over
+    let start_date = clock.now();
     let log_path = config.log_path.as_ref().expect("Set UTS_LOG_PATH to store log output");
     let payload = Payload::from_config(config);
     info!("Configured with {} boards: {:?}", payload.iter().len(), payload.iter());
 
-    let mut writer = LogWriter::create_file_writer(log_path, &payload, &start_date);
+    let mut writer = LogWriter::create_file_writer(log_path, &payload, &start_date)
+        .with_active_program(ActiveProgramFile::from_config(config))
+        .with_clock(Rc::clone(clock));
     writer.write_header_if_new();
-
-    loop {
-        let timestamp = Utc::now();
-        writer.write_data(timestamp);
-
-        thread::sleep(Duration::from_secs(config.log_interval as u64));
-        if Utc::now().day() != start_date.day() {
-            // it's a new day, time to restart
-            return;
-        }
-    }
+    // returns when it's a new day, time to restart
+    writer.run(Duration::seconds(config.log_interval as i64));
 }
 
@@ -1,16 +1,21 @@
 use log::info;
 
+use uts_ws1::guard::HeaterGuard;
 use uts_ws1::payload::{Config, Payload};
 use uts_ws1::programs::Programs;
 use uts_ws1::programs::runner;
+use uts_ws1::programs::runner::RunFiles;
+use uts_ws1::safety::SupervisorThread;
 
 pub fn main() {
     let config = Config::read();
     let payload = Payload::from_config(&config);
+    let _safety = SupervisorThread::spawn(&config);
+    let _guard = HeaterGuard::all(&config).abort_on_signal();
     info!("Configured with {} boards: {:?}", payload.iter().len(), payload.iter());
 
     let programs = Programs::load(&config);
     info!("Loaded programs:\n{:#?}", programs);
 
-    runner::run(&payload, &programs);
+    runner::run_resumable(&payload, &programs, &RunFiles::from_config(&config));
 }
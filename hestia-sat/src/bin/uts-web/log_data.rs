@@ -165,8 +165,18 @@ pub struct LogFile {
     url: String,
 }
 
+/// Daily CSV data files, newest first
 pub(crate) fn list_logs(config: &Config) -> Vec<LogFile> {
-    let pattern = format!("{}/uts-data-*.csv", config.log_path.as_ref().unwrap());
+    list_files(config, "uts-data-*.csv")
+}
+
+/// Daily runner event logs from uts-run, newest first, kept apart from the data files
+pub(crate) fn list_event_logs(config: &Config) -> Vec<LogFile> {
+    list_files(config, "uts-events-*.jsonl")
+}
+
+fn list_files(config: &Config, pattern: &str) -> Vec<LogFile> {
+    let pattern = format!("{}/{}", config.log_path.as_ref().unwrap(), pattern);
     let mut files: Vec<PathBuf> = glob::glob(pattern.as_str())
         .expect("pattern error")
         .map(Result::unwrap)
@@ -1,16 +1,27 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::io;
 use actix_cors::Cors;
 use actix_files::NamedFile;
-use actix_web::{App, get, post, HttpResponse, HttpServer, middleware, Responder, web};
+use actix_web::{App, get, post, HttpRequest, HttpResponse, HttpServer, middleware, Responder, web};
 use actix_web::error::JsonPayloadError;
 use actix_web::http::header;
 use actix_web::http::header::ContentDisposition;
 use actix_web::middleware::Condition;
-use actix_web::web::Redirect;
+use chrono::Utc;
 use log::info;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use data::SystemTimeTempData;
+use uts_ws1::board::BoardId;
+use uts_ws1::deadman::{Deadman, DeadmanEvent};
+use uts_ws1::diagnostics::Health;
+use uts_ws1::guard::HeaterGuard;
 use uts_ws1::payload::{Config, Payload};
+use uts_ws1::power::BudgetDecision;
+use uts_ws1::programs::active::{ActiveProgram, ActiveProgramFile};
+use uts_ws1::programs::control::{Command, ControlAudit, ControlChannel};
+use uts_ws1::programs::event_log::EventLog;
+use uts_ws1::safety::SupervisorThread;
 use status::SystemStatus;
 use crate::status::BoardStatusUpdate;
 
@@ -18,9 +29,39 @@ mod data;
 mod log_data;
 mod status;
 
+/// Number of recent deadman events returned by the API
+const DEADMAN_EVENTS: usize = 20;
+/// Number of recent runner events returned by the API
+const RUNNER_EVENTS: usize = 100;
+/// Number of recent runner commands returned by the API
+const CONTROL_AUDIT: usize = 20;
+
+#[derive(Serialize)]
+struct DeadmanStatus {
+    deadlines: BTreeMap<u8, String>,
+    events: Vec<DeadmanEvent>,
+}
+
+#[derive(Serialize)]
+struct RunnerStatus {
+    /// Program running on each board, or idle
+    boards: BTreeMap<String, ActiveProgram>,
+    commands: Vec<ControlAudit>,
+}
+
+#[derive(Deserialize)]
+struct RunnerCommand {
+    command: Command,
+    /// Only the track for this board, when programs run in parallel
+    board: Option<BoardId>,
+}
+
 struct AppState {
     app_name: String,
     config: Config,
+    health: Option<Health>,
+    /// Heaters switched on from the API are switched off when the server shuts down
+    guard: HeaterGuard,
 }
 
 #[get("/")]
@@ -40,8 +81,68 @@ async fn post_status(state: web::Data<AppState>, update: web::Json<BoardStatusUp
     -> impl Responder {
     let update = update.into_inner();
     let payload = Payload::from_config(&state.config);
-    update.apply(&payload);
-    Redirect::to("/api/status").see_other()
+    match update.apply(&state.config, &payload, &state.guard) {
+        BudgetDecision::Rejected =>
+            HttpResponse::Conflict().body("Rejected, the heater would exceed the power budget"),
+        _ => HttpResponse::SeeOther().insert_header((header::LOCATION, "/api/status")).finish(),
+    }
+}
+
+#[get("/health")]
+async fn get_health(state: web::Data<AppState>) -> impl Responder {
+    let report = state.health.as_ref().map(|h| h.report()).unwrap_or_default();
+    pretty_json(&report)
+}
+
+#[get("/flags")]
+async fn get_flags(state: web::Data<AppState>) -> impl Responder {
+    let flags = state.health.as_ref().map(|h| h.flags()).unwrap_or_default();
+    pretty_json(&flags)
+}
+
+#[get("/deadman")]
+async fn get_deadman(state: web::Data<AppState>) -> impl Responder {
+    let deadman = Deadman::from_config(&state.config);
+    let deadlines: BTreeMap<u8, String> = deadman.read().iter()
+        .map(|(bus, deadline)| (*bus, deadline.to_rfc3339()))
+        .collect();
+    pretty_json(&DeadmanStatus { deadlines, events: deadman.events(DEADMAN_EVENTS) })
+}
+
+#[get("/events")]
+async fn get_events(state: web::Data<AppState>) -> impl Responder {
+    let events = EventLog::from_config(&state.config)
+        .map(|log| log.events(RUNNER_EVENTS))
+        .unwrap_or_default();
+    pretty_json(&events)
+}
+
+#[get("/runner")]
+async fn get_runner(state: web::Data<AppState>) -> impl Responder {
+    let now = Utc::now();
+    let active = ActiveProgramFile::from_config(&state.config);
+    let boards = state.config.i2c_bus.iter()
+        .filter_map(|&bus| BoardId::try_from(bus).ok())
+        .map(|board| (board.to_string(), active.current_on(board, now)))
+        .collect();
+    let commands = ControlChannel::from_config(&state.config).audit_log(CONTROL_AUDIT);
+    pretty_json(&RunnerStatus { boards, commands })
+}
+
+/// Sends a pause, resume, skip or abort command to uts-run, e.g. `{"command": "pause"}`
+#[post("/runner")]
+async fn post_runner(state: web::Data<AppState>, request: HttpRequest, command: web::Json<RunnerCommand>)
+    -> impl Responder {
+    let source = match request.peer_addr() {
+        Some(addr) => format!("uts-web {}", addr.ip()),
+        None => String::from("uts-web"),
+    };
+    let command = command.into_inner();
+    let channel = ControlChannel::from_config(&state.config);
+    match channel.send(command.command, command.board, &source, Utc::now()) {
+        Some(request) => pretty_json(&request),
+        None => HttpResponse::InternalServerError().body("Failed to send command to uts-run"),
+    }
 }
 
 #[get("/data")]
@@ -63,6 +164,12 @@ async fn get_log_files(state: web::Data<AppState>) -> impl Responder {
     pretty_json(&log_files)
 }
 
+#[get("/event_log_files")]
+async fn get_event_log_files(state: web::Data<AppState>) -> impl Responder {
+    let log_files = log_data::list_event_logs(&state.config);
+    pretty_json(&log_files)
+}
+
 #[get("/log/{name}")]
 async fn download_log(state: web::Data<AppState>, path: web::Path<String>) -> io::Result<NamedFile> {
     let name = path.into_inner();
@@ -86,9 +193,13 @@ fn pretty_json<T>(result: &T) -> HttpResponse
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let config = Config::read();
+    let safety = SupervisorThread::spawn(&config);
     let app_data = web::Data::new(AppState {
         app_name: String::from("Hestia API"),
         config: config.clone(),
+        health: safety.as_ref().map(|s| s.health()),
+        // actix handles SIGINT and SIGTERM, and the guard is dropped after shutdown
+        guard: HeaterGuard::new(&config, &[]),
     });
     let addr = ("0.0.0.0", config.http_port);
     info!("uts-web listening on {:?}...", addr);
@@ -108,9 +219,16 @@ async fn main() -> std::io::Result<()> {
                 web::scope("/api")
                     .service(get_status)
                     .service(post_status)
+                    .service(get_health)
+                    .service(get_flags)
+                    .service(get_deadman)
+                    .service(get_events)
+                    .service(get_runner)
+                    .service(post_runner)
                     .service(get_data)
                     .service(get_log_data)
                     .service(get_log_files)
+                    .service(get_event_log_files)
                     .service(download_log)
             )
     })
@@ -1,6 +1,8 @@
 use std::fmt;
 use std::iter::zip;
 
+use chrono::Utc;
+use duration_str::deserialize_option_duration_chrono;
 use linked_hash_map::LinkedHashMap;
 use log::error;
 use serde::{Deserialize, Serialize, Serializer};
@@ -9,8 +11,12 @@ use serde::ser::SerializeMap;
 use uts_ws1::{board, ReadResult};
 use uts_ws1::board::{Board, BoardData, BoardDataProvider, BoardId, calc_heater_power};
 use uts_ws1::board::{V_CURR_AVG, V_HIGH_AVG, V_LOW_AVG};
+use uts_ws1::deadman::Deadman;
+use uts_ws1::diagnostics::{diagnose, HeaterFault, HeaterMeasurement};
+use uts_ws1::guard::HeaterGuard;
 use uts_ws1::heater::{HeaterMode, TargetSensor};
 use uts_ws1::payload::{Config, Payload};
+use uts_ws1::power::BudgetDecision;
 use uts_ws1::reading::SensorReading;
 use uts_ws1::sensors::{Sensor, SensorId, SensorInterface};
 
@@ -46,6 +52,15 @@ pub(crate) struct BoardStatus {
 
     #[serde(serialize_with = "serialize_f32")]
     pub target_sensor_temp: Option<f32>,
+
+    #[serde(serialize_with = "serialize_f32")]
+    pub heater_resistance: Option<f32>,
+
+    /// Faults in this reading only, see /api/health for drift and history
+    pub heater_faults: Vec<HeaterFault>,
+
+    /// When the deadman will switch off a heater switched on manually
+    pub heater_deadline: Option<String>,
 }
 
 fn serialize_f32<S>(value: &Option<f32>, serializer: S) -> Result<S::Ok, S::Error>
@@ -81,6 +96,9 @@ fn format_f32_3sd(value: &f32) -> String {
 
 impl BoardStatus {
     pub fn from_data(board: &Board, data: BoardData) -> Self {
+        let measurement = HeaterMeasurement::from_data(board.version, &data);
+        let heater_resistance = measurement.and_then(|m| m.resistance());
+        let heater_faults = measurement.map(|m| diagnose(&m, None)).unwrap_or_default();
         let mut sensor_values = LinkedHashMap::with_capacity(board.sensors.len());
         for (sensor, value) in zip(board::ALL_SENSORS, data.sensors) {
             sensor_values.insert(sensor.id, from_reading(value));
@@ -105,6 +123,9 @@ impl BoardStatus {
             target_sensor_temp,
             heater_duty,
             heater_power,
+            heater_resistance,
+            heater_faults,
+            heater_deadline: None,
         }
     }
 }
@@ -156,9 +177,14 @@ pub(crate) struct SystemStatus(pub LinkedHashMap<BoardId, Option<BoardStatus>>);
 impl SystemStatus {
     pub(crate) fn read(config: &Config) -> Self {
         let payload = Payload::from_config(config);
+        let deadlines = Deadman::from_config(config).read();
         let mut result = SystemStatus::new();
         for board in payload {
             result.read_status(&board);
+            let status = result.0.get_mut(&board.id).and_then(|s| s.as_mut());
+            if let (Some(status), Some(deadline)) = (status, deadlines.get(&board.bus.id)) {
+                status.heater_deadline = Some(deadline.to_rfc3339());
+            }
         }
         result
     }
@@ -181,37 +207,54 @@ pub(crate) struct BoardStatusUpdate {
     pub heater_duty: Option<u16>,
     pub target_temp: Option<f32>,
     pub target_sensor: Option<TargetSensor>,
+    /// Switch the heater off after this time, e.g. "30m". Defaults to UTS_DEADMAN_TIMEOUT.
+    #[serde(default, deserialize_with = "deserialize_option_duration_chrono")]
+    pub timeout: Option<chrono::Duration>,
 }
 
 impl BoardStatusUpdate {
-    pub fn apply(&self, payload: &Payload) {
+    /// Returns the power budget decision for the heater mode or duty
+    pub fn apply(&self, config: &Config, payload: &Payload, guard: &HeaterGuard) -> BudgetDecision {
         let board = payload.iter().find(|b| b.bus.id == self.board as u8);
         if let Some(board) = board {
             if let Some(heater_mode) = self.heater_mode {
-                if heater_mode != HeaterMode::OFF {
-                    for other in payload.iter().filter(|b| b.bus.id != self.board as u8) {
-                        other.write_heater_mode(HeaterMode::OFF);
-                    }
+                let deadman = Deadman::from_config(config);
+                if heater_mode == HeaterMode::OFF {
+                    guard.remove(board.bus.id);
+                    deadman.disarm(board.bus.id);
+                } else {
+                    let timeout = self.timeout
+                        .unwrap_or_else(|| chrono::Duration::minutes(config.deadman_timeout as i64));
+                    deadman.arm(board.bus.id, Utc::now() + timeout);
+                    guard.add(board.bus.id);
+                }
+                // other boards are left on, the power budget limits the combined power
+                let decision = board.write_heater_mode(heater_mode);
+                if decision == BudgetDecision::Rejected {
+                    guard.remove(board.bus.id);
+                    deadman.disarm(board.bus.id);
                 }
-                board.write_heater_mode(heater_mode);
+                decision
             } else {
-                self.update_board(board);
+                self.update_board(board)
             }
         } else {
             error!("Board ID not found or configured: {}", self.board);
+            BudgetDecision::Allowed
         }
     }
 
-    fn update_board(&self, board: &Board) {
-        if let Some(heater_duty) = self.heater_duty {
-            board.write_heater_duty(heater_duty);
-        }
+    fn update_board(&self, board: &Board) -> BudgetDecision {
+        let decision = self.heater_duty
+            .map(|heater_duty| board.write_heater_duty(heater_duty))
+            .unwrap_or(BudgetDecision::Allowed);
         if let Some(target_temp) = self.target_temp {
             board.write_target_temp(target_temp);
         }
         if let Some(target_sensor) = self.target_sensor {
             board.write_target_sensor(target_sensor);
         }
+        decision
     }
 }
 
@@ -4,14 +4,17 @@ use std::rc::Rc;
 use log::{debug, error};
 use serde::{Deserialize, Serialize, Serializer};
 
-use crate::{ReadResult};
+use crate::{ReadError, ReadResult};
 use crate::csv::CSV_RAW_FIELD_COUNT;
 use crate::heater::{Heater, HeaterMode, TargetSensor};
+use crate::control::pid::PWM_DUTY_MAX;
 use crate::device::ads7828::Ads7828Sensor;
 use crate::device::i2c::I2cBus;
 use crate::device::max31725::Max31725Sensor;
 use crate::device::msp430::{Msp430, Msp430CurrentSensor, Msp430TempSensor, Msp430VoltageSensor};
+use crate::power::{BudgetDecision, PowerBudget};
 use crate::reading::{DisabledSensor, ReadableSensor, SensorReading};
+use crate::selector::SensorSelector;
 use crate::sensors::{Sensor, SensorInterface};
 
 
@@ -195,6 +198,7 @@ pub struct Board {
     pub bus: I2cBus,
     pub heater: Rc<dyn Heater>,
     pub sensors: Vec<Box<dyn ReadableSensor>>,
+    pub budget: Option<Rc<PowerBudget>>,
 }
 
 impl Board {
@@ -207,9 +211,15 @@ impl Board {
             bus: id.into(),
             heater: Rc::new(msp430),
             sensors,
+            budget: None,
         }
     }
 
+    /// Enforces the power budget on heater mode and duty writes
+    pub fn with_budget(self, budget: Option<Rc<PowerBudget>>) -> Self {
+        Board { budget, ..self }
+    }
+
     fn get_readable_sensors(version: BoardVersion, bus: I2cBus, sensors: &[Sensor]) -> Vec<Box<dyn ReadableSensor>> {
         sensors.iter()
             .map(|s| Board::create_sensor(version, bus, *s))
@@ -236,8 +246,22 @@ impl Board {
         self.heater.read_mode()
     }
 
-    pub fn write_heater_mode(&self, mode: HeaterMode) {
-        self.heater.write_mode(mode)
+    /// Switches the heater mode, unless it would exceed the power budget. PWM duty is
+    /// derated to fit the budget if needed. Returns the budget decision, which is always
+    /// allowed without a budget or when switching off.
+    pub fn write_heater_mode(&self, mode: HeaterMode) -> BudgetDecision {
+        let mut decision = BudgetDecision::Allowed;
+        if let (Some(budget), true) = (&self.budget, mode != HeaterMode::OFF) {
+            let duty = self.read_heater_duty().map(|d| d.display_value).unwrap_or(PWM_DUTY_MAX);
+            decision = budget.check(self.id, mode, duty);
+            match decision {
+                BudgetDecision::Allowed => {}
+                BudgetDecision::Derated(duty) => self.heater.write_duty(duty),
+                BudgetDecision::Rejected => return decision,
+            }
+        }
+        self.heater.write_mode(mode);
+        decision
     }
 
     pub fn read_target_temp(&self) -> ReadResult<SensorReading<f32>> {
@@ -258,6 +282,37 @@ impl Board {
         sensor.read()
     }
 
+    /// Reads any sensor on the board by ID, not just those the heater can target
+    pub fn read_sensor(&self, sensor_id: &str) -> ReadResult<SensorReading<f32>> {
+        let index = ALL_SENSORS.iter()
+            .position(|s| s.id.eq_ignore_ascii_case(sensor_id))
+            .ok_or(ReadError::UnknownSensor)?;
+        self.sensors[index].read()
+    }
+
+    /// Reads a single sensor or an aggregate of sensors for control, skipping any that fail
+    pub fn read_temp(&self, selector: &SensorSelector) -> ReadResult<f32> {
+        if let SensorSelector::Single(id) = selector {
+            return self.read_sensor(id).map(|r| r.display_value);
+        }
+        selector.combine(&self.read_temps(selector)).ok_or(ReadError::ValueOutOfRange)
+    }
+
+    /// Reads a single sensor or an aggregate of sensors to check against a limit, failing if any
+    /// sensor fails
+    pub fn read_limit_temp(&self, selector: &SensorSelector) -> ReadResult<f32> {
+        if let SensorSelector::Single(id) = selector {
+            return self.read_sensor(id).map(|r| r.display_value);
+        }
+        selector.combine_all(&self.read_temps(selector)).ok_or(ReadError::ValueOutOfRange)
+    }
+
+    fn read_temps(&self, selector: &SensorSelector) -> Vec<Option<f32>> {
+        selector.sensors().iter()
+            .map(|id| self.read_sensor(id).ok().map(|r| r.display_value))
+            .collect()
+    }
+
     pub fn write_target_sensor(&self, target_sensor: TargetSensor) {
         self.heater.write_target_sensor(target_sensor)
     }
@@ -266,8 +321,30 @@ impl Board {
         self.heater.read_duty()
     }
 
-    pub fn write_heater_duty(&self, pwm_duty_cycle: u16) {
-        self.heater.write_duty(pwm_duty_cycle);
+    /// Sets the PWM duty cycle, derated to fit the power budget if the heater is on. The heater
+    /// is switched off if the budget rejects it. Returns the budget decision.
+    pub fn write_heater_duty(&self, pwm_duty_cycle: u16) -> BudgetDecision {
+        let mut duty = pwm_duty_cycle;
+        let mut decision = BudgetDecision::Allowed;
+        if let Some(budget) = &self.budget {
+            if matches!(self.read_heater_mode(), Ok(mode) if mode.display_value == HeaterMode::PWM) {
+                decision = budget.check(self.id, HeaterMode::PWM, duty);
+                match decision {
+                    BudgetDecision::Allowed => {}
+                    BudgetDecision::Derated(derated) => duty = derated,
+                    BudgetDecision::Rejected => {
+                        self.heater.write_mode(HeaterMode::OFF);
+                        return decision;
+                    }
+                }
+            }
+        }
+        self.heater.write_duty(duty);
+        decision
+    }
+
+    pub fn read_max_temp(&self) -> ReadResult<SensorReading<f32>> {
+        self.heater.read_max_temp()
     }
 
     pub fn write_max_temp(&self, temp: f32) {
@@ -280,6 +357,14 @@ impl Board {
             .collect::<Vec<ReadResult<SensorReading<f32>>>>()
     }
 
+    /// Measured heater power in W, from the averaged heater voltage and current readings
+    pub fn read_heater_power(&self) -> ReadResult<f32> {
+        let v_low = self.read_sensor(V_LOW_AVG.id);
+        let voltage = self.calc_heater_voltage(self.read_sensor(V_HIGH_AVG.id), v_low.clone())?;
+        let current = self.calc_heater_current(v_low, self.read_sensor(V_CURR_AVG.id))?;
+        Ok(voltage * current)
+    }
+
     pub fn calc_heater_power(&self,
                              v_high: ReadResult<SensorReading<f32>>,
                              v_low: ReadResult<SensorReading<f32>>,
@@ -351,7 +436,7 @@ impl PartialEq for Board {
 
 impl Clone for Board {
     fn clone(&self) -> Self {
-        Self::new(self.id, self.version)
+        Self::new(self.id, self.version).with_budget(self.budget.clone())
     }
 }
 
@@ -417,35 +502,135 @@ impl BoardData {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct BoardFlags {
-    on: bool,
-    max_temp: bool,
+/// Firmware status register bit: set at power-on, so clear means the register is invalid
+pub const FLAG_ON: u16 = 0x0001;
+
+/// Firmware status register bit: heater switched off for exceeding the max temp,
+/// latched until the heater mode is next written
+pub const FLAG_MAX_TEMP: u16 = 0x0002;
+
+const KNOWN_FLAGS: u16 = FLAG_ON | FLAG_MAX_TEMP;
+
+/// A single bit of the firmware status register
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BoardFlag {
+    On,
+    MaxTemp,
+    /// A bit the firmware doesn't define
+    Unknown(u8),
 }
 
-impl Display for BoardFlags {
+impl BoardFlag {
+    pub fn from_bit(bit: u8) -> Self {
+        match 1 << bit {
+            FLAG_ON => BoardFlag::On,
+            FLAG_MAX_TEMP => BoardFlag::MaxTemp,
+            _ => BoardFlag::Unknown(bit),
+        }
+    }
+
+    pub fn mask(&self) -> u16 {
+        match self {
+            BoardFlag::On => FLAG_ON,
+            BoardFlag::MaxTemp => FLAG_MAX_TEMP,
+            BoardFlag::Unknown(bit) => 1 << bit,
+        }
+    }
+
+    /// True if the flag being set indicates a fault. The ON flag indicates a fault when clear.
+    pub fn is_fault(&self) -> bool {
+        !matches!(self, BoardFlag::On)
+    }
+}
+
+impl Display for BoardFlag {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match (self.on, self.max_temp) {
-            (true, true) => write!(f, "ERR_MAX_TEMP"),
-            (true, false) => write!(f, "OK"),
-            (false, _) => write!(f, "ERR_UNKNOWN"),
+        match self {
+            BoardFlag::On => write!(f, "ON"),
+            BoardFlag::MaxTemp => write!(f, "MAX_TEMP"),
+            BoardFlag::Unknown(bit) => write!(f, "BIT{}", bit),
         }
     }
 }
 
-impl TryFrom<u16> for BoardFlags {
-    type Error = ();
+impl Serialize for BoardFlag {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// The firmware status register
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BoardFlags(u16);
+
+impl BoardFlags {
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.0 & FLAG_ON != 0
+    }
+
+    pub fn is_max_temp(&self) -> bool {
+        self.0 & FLAG_MAX_TEMP != 0
+    }
+
+    /// Bits which the firmware doesn't define
+    pub fn unknown_bits(&self) -> u16 {
+        self.0 & !KNOWN_FLAGS
+    }
+
+    /// True if the firmware reports a fault, e.g. max temp exceeded
+    pub fn is_error(&self) -> bool {
+        !self.is_on() || self.is_max_temp() || self.unknown_bits() != 0
+    }
+
+    /// Flags which are set, lowest bit first
+    pub fn flags(&self) -> impl Iterator<Item=BoardFlag> + '_ {
+        (0..16).filter(move |bit| self.0 & (1 << bit) != 0).map(BoardFlag::from_bit)
+    }
+
+    pub fn contains(&self, flag: BoardFlag) -> bool {
+        self.0 & flag.mask() != 0
+    }
+}
+
+impl Default for BoardFlags {
+    /// Status at power-on
+    fn default() -> Self {
+        BoardFlags(FLAG_ON)
+    }
+}
 
-    fn try_from(value: u16) -> Result<Self, Self::Error> {
-        let valid_bits = 0b0011;
-        if value & (!valid_bits) > 0 {
-            return Err(());
+impl Display for BoardFlags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if !self.is_error() {
+            return write!(f, "OK");
+        }
+        let mut errors = vec![];
+        if !self.is_on() {
+            errors.push(String::from("ERR_NOT_ON"));
+        }
+        if self.is_max_temp() {
+            errors.push(String::from("ERR_MAX_TEMP"));
         }
-        let (on, max_temp) = (
-            value & 0b0001 != 0,
-            value & 0b0010 != 0,
-        );
-        Ok(BoardFlags { on, max_temp })
+        if self.unknown_bits() != 0 {
+            errors.push(format!("ERR_UNKNOWN_{:#06x}", self.unknown_bits()));
+        }
+        write!(f, "{}", errors.join("|"))
+    }
+}
+
+impl Serialize for BoardFlags {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl From<u16> for BoardFlags {
+    fn from(value: u16) -> Self {
+        BoardFlags(value)
     }
 }
 
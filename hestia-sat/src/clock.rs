@@ -0,0 +1,116 @@
+use std::cell::Cell;
+use std::thread;
+
+use chrono::{DateTime, Duration, Utc};
+
+/// Source of the current time, and a way to wait for time to pass, so the runner and logger
+/// can run on simulated time in tests and replays
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The system clock, sleeping in real time
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        if let Ok(duration) = duration.to_std() {
+            thread::sleep(duration);
+        }
+    }
+}
+
+/// Time that only moves on when slept, either straight away or sped up from real time
+#[derive(Debug)]
+pub struct SimulatedClock {
+    time: Cell<DateTime<Utc>>,
+    speedup: Option<i32>,
+}
+
+impl SimulatedClock {
+    /// Sleeping moves the time on without waiting
+    pub fn new(start: DateTime<Utc>) -> Self {
+        SimulatedClock { time: Cell::new(start), speedup: None }
+    }
+
+    /// Sleeping waits in real time for the duration divided by the speedup, e.g. to watch a
+    /// program run with the hardware at 60 times real time
+    pub fn accelerated(start: DateTime<Utc>, speedup: i32) -> Self {
+        SimulatedClock { time: Cell::new(start), speedup: Some(speedup.max(1)) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.time.set(self.time.get() + duration);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.time.get()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        if let Some(speedup) = self.speedup {
+            SystemClock.sleep(duration / speedup);
+        }
+        self.advance(duration);
+    }
+}
+
+/// Follows the timestamps of a log as it's replayed, so sleeping doesn't move the time on
+#[derive(Debug)]
+pub struct ReplayClock {
+    time: Cell<DateTime<Utc>>,
+}
+
+impl ReplayClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        ReplayClock { time: Cell::new(start) }
+    }
+
+    /// Moves to the time of the reading being replayed
+    pub fn set(&self, time: DateTime<Utc>) {
+        self.time.set(time);
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.time.get()
+    }
+
+    fn sleep(&self, _duration: Duration) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::clock::{Clock, ReplayClock, SimulatedClock};
+
+    #[test]
+    fn test_simulated_clocks() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let clock = SimulatedClock::new(start);
+        clock.sleep(Duration::minutes(30));
+        assert_eq!(start + Duration::minutes(30), clock.now());
+
+        let clock = SimulatedClock::accelerated(start, 1000);
+        let started = std::time::Instant::now();
+        clock.sleep(Duration::seconds(10));
+        assert!(started.elapsed() >= std::time::Duration::from_millis(10));
+        assert_eq!(start + Duration::seconds(10), clock.now());
+
+        let clock = ReplayClock::new(start);
+        clock.sleep(Duration::hours(1));
+        assert_eq!(start, clock.now());
+        clock.set(start + Duration::seconds(5));
+        assert_eq!(start + Duration::seconds(5), clock.now());
+    }
+}
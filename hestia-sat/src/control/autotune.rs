@@ -0,0 +1,305 @@
+use std::f32::consts::PI;
+use std::fmt::{Display, Formatter};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::control::pid::{adc_slope, FIRMWARE_PID_MAX_OUT, PidConfig};
+
+/// Oscillation smaller than this in °C is below the sensor resolution, and would give an
+/// unbounded ultimate gain
+const MIN_AMPLITUDE: f32 = 0.01;
+
+/// Settings for a relay (Åström–Hägglund) autotune run
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutotuneConfig {
+    /// Temperature to oscillate around in °C
+    pub setpoint: f32,
+
+    /// Duty cycle fraction while the relay is on
+    pub relay_high: f32,
+
+    /// Duty cycle fraction while the relay is off
+    pub relay_low: f32,
+
+    /// Switching band either side of the setpoint in °C, to avoid chattering on noise
+    pub hysteresis: f32,
+
+    /// Number of full oscillations to measure, after the first one is discarded
+    pub cycles: usize,
+
+    /// Heater is switched off and the run aborted if this temperature is reached
+    pub max_temp: f32,
+
+    /// Run is aborted if it hasn't completed within this time
+    pub timeout: Duration,
+}
+
+impl AutotuneConfig {
+    pub fn new(setpoint: f32, max_temp: f32) -> Self {
+        AutotuneConfig {
+            setpoint,
+            relay_high: 1.0,
+            relay_low: 0.0,
+            hysteresis: 0.5,
+            cycles: 4,
+            max_temp,
+            timeout: Duration::hours(2),
+        }
+    }
+}
+
+/// Ultimate gain and period identified from the relay oscillation
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AutotuneResult {
+    pub setpoint: f32,
+
+    /// Peak-to-peak temperature oscillation in °C
+    pub amplitude: f32,
+
+    /// Ultimate gain Ku in duty/°C
+    pub ultimate_gain: f32,
+
+    /// Ultimate period Pu in seconds
+    pub ultimate_period: f32,
+}
+
+impl AutotuneResult {
+    /// Classic Ziegler–Nichols PID gains for the host-side controller
+    pub fn pid_config(&self) -> PidConfig {
+        let (ku, pu) = (self.ultimate_gain, self.ultimate_period);
+        PidConfig::new(self.setpoint, 0.6 * ku, 1.2 * ku / pu, 0.075 * ku * pu)
+    }
+
+    /// Equivalent firmware gains as (K_P, K_I_SHIFT), using the thermistor slope at the setpoint.
+    ///
+    /// The firmware runs once a second on ADC counts with output 0-8000, and has no derivative term.
+    pub fn firmware_gains(&self) -> (f32, u32) {
+        let pid = self.pid_config();
+        let adc_per_degree = adc_slope(self.setpoint);
+        let k_p = pid.kp * FIRMWARE_PID_MAX_OUT / adc_per_degree;
+        let k_i = pid.ki * FIRMWARE_PID_MAX_OUT / adc_per_degree;
+        let k_i_shift = if k_i > 0.0 { (1.0 / k_i).log2().round().max(0.0) as u32 } else { 0 };
+        (k_p, k_i_shift)
+    }
+}
+
+impl Display for AutotuneResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "setpoint: {:.1}°C, amplitude: {:.2}°C, Ku: {:.4} duty/°C, Pu: {:.1} s",
+               self.setpoint, self.amplitude, self.ultimate_gain, self.ultimate_period)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AutotuneStatus {
+    /// Run in progress, heater should be set to this duty cycle fraction
+    Running(f32),
+    Complete(AutotuneResult),
+    Aborted(String),
+}
+
+/// Relay autotuner which switches the heater fully on or off around the setpoint,
+/// and measures the resulting limit cycle.
+pub struct RelayAutotuner {
+    config: AutotuneConfig,
+    start_time: Option<DateTime<Utc>>,
+    heating: bool,
+    extreme: f32,
+    peaks: Vec<f32>,
+    troughs: Vec<f32>,
+    switch_on_times: Vec<DateTime<Utc>>,
+}
+
+impl RelayAutotuner {
+    pub fn new(config: AutotuneConfig) -> Self {
+        RelayAutotuner {
+            config,
+            start_time: None,
+            heating: true,
+            extreme: f32::MIN,
+            peaks: vec![],
+            troughs: vec![],
+            switch_on_times: vec![],
+        }
+    }
+
+    pub fn config(&self) -> &AutotuneConfig {
+        &self.config
+    }
+
+    /// Number of full oscillations measured so far
+    pub fn cycles(&self) -> usize {
+        self.switch_on_times.len().saturating_sub(1)
+    }
+
+    pub fn update(&mut self, temp: f32, now: DateTime<Utc>) -> AutotuneStatus {
+        let start_time = *self.start_time.get_or_insert(now);
+        if temp >= self.config.max_temp {
+            return AutotuneStatus::Aborted(format!(
+                "Max temp reached ({:.2} >= {:.2})", temp, self.config.max_temp));
+        }
+        if now - start_time > self.config.timeout {
+            return AutotuneStatus::Aborted(format!(
+                "Timed out after {} cycles", self.cycles()));
+        }
+
+        let (upper, lower) = (self.config.setpoint + self.config.hysteresis,
+                              self.config.setpoint - self.config.hysteresis);
+        if self.heating {
+            self.extreme = self.extreme.min(temp);
+            if temp > upper {
+                // the first half-cycle is just the warm-up, so there's no trough yet
+                if !self.switch_on_times.is_empty() {
+                    self.troughs.push(self.extreme);
+                }
+                self.heating = false;
+                self.extreme = temp;
+            }
+        } else {
+            self.extreme = self.extreme.max(temp);
+            if temp < lower {
+                self.peaks.push(self.extreme);
+                self.switch_on_times.push(now);
+                self.heating = true;
+                self.extreme = temp;
+            }
+        }
+
+        // discard the first oscillation, as it includes the heat-up from ambient, and measure at
+        // least one more to have a period
+        if self.cycles() > self.config.cycles.max(1) {
+            let result = self.result();
+            if result.amplitude < MIN_AMPLITUDE {
+                return AutotuneStatus::Aborted(format!(
+                    "Oscillation too small to measure ({:.3}°C peak-to-peak)", result.amplitude));
+            }
+            return AutotuneStatus::Complete(result);
+        }
+        AutotuneStatus::Running(if self.heating { self.config.relay_high } else { self.config.relay_low })
+    }
+
+    fn result(&self) -> AutotuneResult {
+        let peaks = &self.peaks[1..];
+        let troughs = &self.troughs[..peaks.len().min(self.troughs.len())];
+        let amplitude = mean(peaks) - mean(troughs);
+        let relay_amplitude = (self.config.relay_high - self.config.relay_low) / 2.0;
+        let ultimate_gain = 4.0 * relay_amplitude / (PI * amplitude / 2.0);
+        let periods: Vec<f32> = self.switch_on_times[1..].windows(2)
+            .map(|w| (w[1] - w[0]).num_milliseconds() as f32 / 1000.0)
+            .collect();
+        AutotuneResult {
+            setpoint: self.config.setpoint,
+            amplitude,
+            ultimate_gain,
+            ultimate_period: mean(&periods),
+        }
+    }
+}
+
+/// Zero rather than NaN if there are no values
+fn mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<f32>() / values.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use assert_approx_eq::assert_approx_eq;
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::control::autotune::{AutotuneConfig, AutotuneResult, AutotuneStatus, RelayAutotuner};
+
+    /// First-order board model with dead time: 8 W heater, 4 °C/W to ambient,
+    /// 60 s time constant and 5 s sensor delay
+    fn run_simulation(config: AutotuneConfig) -> AutotuneStatus {
+        let (ambient, gain, tau) = (20.0, 32.0, 60.0);
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut tuner = RelayAutotuner::new(config);
+        let mut temp: f32 = ambient;
+        let mut delay = VecDeque::from(vec![ambient; 5]);
+        for i in 0..10_000 {
+            delay.push_back(temp);
+            let measured = delay.pop_front().unwrap();
+            match tuner.update(measured, start + Duration::seconds(i)) {
+                AutotuneStatus::Running(duty) => temp += (ambient + gain * duty - temp) / tau,
+                status => return status,
+            }
+        }
+        panic!("autotune didn't finish");
+    }
+
+    #[test]
+    fn test_relay_autotune() {
+        let status = run_simulation(AutotuneConfig::new(40.0, 80.0));
+        let result = match status {
+            AutotuneStatus::Complete(result) => result,
+            status => panic!("Unexpected status: {:?}", status),
+        };
+        assert!(result.amplitude > 1.0 && result.amplitude < 10.0, "amplitude: {}", result);
+        assert!(result.ultimate_period > 20.0 && result.ultimate_period < 120.0, "period: {}", result);
+
+        let pid = result.pid_config();
+        assert_approx_eq!(40.0, pid.setpoint, 0.001);
+        assert_approx_eq!(0.6 * result.ultimate_gain, pid.kp, 0.0001);
+        assert!(pid.ki > 0.0 && pid.kd > 0.0);
+    }
+
+    #[test]
+    fn test_relay_autotune_measures_at_least_one_cycle() {
+        let status = run_simulation(AutotuneConfig { cycles: 0, ..AutotuneConfig::new(40.0, 80.0) });
+        let result = match status {
+            AutotuneStatus::Complete(result) => result,
+            status => panic!("Unexpected status: {:?}", status),
+        };
+        assert!(result.ultimate_period > 0.0, "period: {}", result);
+        assert!(result.pid_config().ki.is_finite());
+    }
+
+    #[test]
+    fn test_relay_autotune_aborts_at_max_temp() {
+        let status = run_simulation(AutotuneConfig::new(40.0, 41.0));
+        assert!(matches!(status, AutotuneStatus::Aborted(_)), "Unexpected status: {:?}", status);
+    }
+
+    #[test]
+    fn test_relay_autotune_times_out() {
+        let status = run_simulation(AutotuneConfig {
+            timeout: Duration::minutes(1),
+            ..AutotuneConfig::new(40.0, 80.0)
+        });
+        assert!(matches!(status, AutotuneStatus::Aborted(_)), "Unexpected status: {:?}", status);
+    }
+
+    #[test]
+    fn test_relay_autotune_aborts_without_amplitude() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut tuner = RelayAutotuner::new(AutotuneConfig { hysteresis: 0.0, ..AutotuneConfig::new(40.0, 80.0) });
+        for i in 0..100 {
+            let temp = if i % 2 == 0 { 40.001 } else { 39.999 };
+            match tuner.update(temp, start + Duration::seconds(i)) {
+                AutotuneStatus::Running(_) => {}
+                AutotuneStatus::Aborted(_) => return,
+                status => panic!("Unexpected status: {:?}", status),
+            }
+        }
+        panic!("autotune didn't finish");
+    }
+
+    #[test]
+    fn test_firmware_gains() {
+        let result = AutotuneResult {
+            setpoint: 60.0,
+            amplitude: 2.0,
+            ultimate_gain: 0.6,
+            ultimate_period: 60.0,
+        };
+        let (k_p, k_i_shift) = result.firmware_gains();
+        // thermistor is ~22 ADC counts per °C at 60°C
+        assert!(k_p > 100.0 && k_p < 160.0, "k_p: {}", k_p);
+        assert!(k_i_shift <= 8, "k_i_shift: {}", k_i_shift);
+    }
+}
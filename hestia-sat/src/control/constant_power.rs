@@ -0,0 +1,128 @@
+use chrono::{DateTime, Duration, Utc};
+
+use crate::control::pid::duty_from_fraction;
+
+/// Largest change in duty per update, as a ratio, so a noisy reading can't swing the heater
+const MAX_STEP_RATIO: f32 = 2.0;
+
+/// Below this measured power in W, the reading is treated as the heater being off
+const MIN_MEASURABLE_POWER: f32 = 0.05;
+
+/// Closed-loop constant-power control, which corrects the PWM duty cycle for changes in
+/// supply voltage and heater resistance using the measured heater power.
+#[derive(Debug, Clone)]
+pub struct PowerController {
+    target: f32,
+    output: f32,
+    interval: Duration,
+    last_update: Option<DateTime<Utc>>,
+    last_power: Option<f32>,
+}
+
+impl PowerController {
+    /// `initial_output` is the duty cycle fraction to start from, e.g. predicted from the
+    /// nominal heater power
+    pub fn new(target: f32, initial_output: f32, interval: Duration) -> Self {
+        PowerController {
+            target,
+            output: initial_output.clamp(0.0, 1.0),
+            interval,
+            last_update: None,
+            last_power: None,
+        }
+    }
+
+    /// Commanded power in W
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// Latest measured power in W
+    pub fn achieved(&self) -> Option<f32> {
+        self.last_power
+    }
+
+    /// Latest output as a PWM duty cycle (0-255)
+    pub fn duty(&self) -> u16 {
+        duty_from_fraction(self.output)
+    }
+
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        match self.last_update {
+            Some(last) => now - last >= self.interval,
+            None => true,
+        }
+    }
+
+    /// Scales the duty cycle by the ratio of commanded to measured power, returning the
+    /// new duty cycle fraction
+    pub fn update(&mut self, measured: f32, now: DateTime<Utc>) -> f32 {
+        let ratio = if measured > MIN_MEASURABLE_POWER {
+            (self.target / measured).clamp(1.0 / MAX_STEP_RATIO, MAX_STEP_RATIO)
+        } else {
+            MAX_STEP_RATIO
+        };
+        // make sure we can climb out of zero output
+        let output = if self.output > 0.0 { self.output } else { 1.0 / 255.0 };
+        self.output = (output * ratio).clamp(0.0, 1.0);
+        if self.target <= 0.0 {
+            self.output = 0.0;
+        }
+        self.last_power = Some(measured);
+        self.last_update = Some(now);
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::control::constant_power::PowerController;
+
+    /// Heater power at a duty cycle fraction, for a heater with the given full power
+    fn heater(full_power: f32, output: f32) -> f32 {
+        full_power * output
+    }
+
+    #[test]
+    fn test_converges_on_target_power() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut controller = PowerController::new(2.5, 2.5 / 15.0, Duration::seconds(5));
+        // supply has sagged, so the heater only makes 10 W at full duty
+        let mut output = 2.5 / 15.0;
+        for i in 0..10 {
+            output = controller.update(heater(10.0, output), start + Duration::seconds(5 * i));
+        }
+        assert_approx_eq!(0.25, output, 0.001);
+        assert_approx_eq!(2.5, controller.achieved().unwrap(), 0.01);
+        assert_eq!(64, controller.duty());
+    }
+
+    #[test]
+    fn test_step_limited() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut controller = PowerController::new(10.0, 0.1, Duration::seconds(5));
+        assert_approx_eq!(0.2, controller.update(0.1, start), 0.001);
+        assert_approx_eq!(0.4, controller.update(0.0, start), 0.001);
+    }
+
+    #[test]
+    fn test_saturates_at_full_duty() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut controller = PowerController::new(20.0, 1.0, Duration::seconds(5));
+        assert_approx_eq!(1.0, controller.update(15.0, start), 0.001);
+        assert_eq!(255, controller.duty());
+    }
+
+    #[test]
+    fn test_is_due() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut controller = PowerController::new(2.5, 0.2, Duration::seconds(5));
+        assert!(controller.is_due(start));
+        controller.update(2.5, start);
+        assert!(!controller.is_due(start + Duration::seconds(4)));
+        assert!(controller.is_due(start + Duration::seconds(5)));
+    }
+}
@@ -0,0 +1,6 @@
+//! Host-side control loops, which run on the payload computer and drive the heater
+//! via its PWM duty cycle, as an alternative to the firmware PID on the MSP430.
+
+pub mod autotune;
+pub mod constant_power;
+pub mod pid;
@@ -0,0 +1,269 @@
+use chrono::{DateTime, Duration, Utc};
+use duration_str::deserialize_duration_chrono;
+use serde::Deserialize;
+
+use crate::sensors::temp_to_adc_val;
+
+/// Largest PWM duty cycle accepted by the MSP430 firmware
+pub const PWM_DUTY_MAX: u16 = 255;
+
+/// Full-scale output of the firmware PID, which maps to 100% duty
+pub(crate) const FIRMWARE_PID_MAX_OUT: f32 = 8000.0;
+
+/// Proportional gain of the firmware PID, in output per ADC count
+const FIRMWARE_K_P: f32 = 3.0;
+
+/// Integral gain of the firmware PID is 1 / 2^K_I_SHIFT, in output per ADC count per second
+const FIRMWARE_K_I_SHIFT: u32 = 3;
+
+fn default_output_min() -> f32 { 0.0 }
+
+fn default_output_max() -> f32 { 1.0 }
+
+fn default_interval() -> Duration { Duration::seconds(5) }
+
+/// Tuning for the host-side PID controller, configured per program as `[programs.pid]`.
+///
+/// Output is a duty cycle fraction between 0.0 and 1.0, so gains are expressed as duty per °C.
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct PidConfig {
+    /// Target temperature in °C
+    pub setpoint: f32,
+
+    /// Proportional gain (duty/°C)
+    pub kp: f32,
+
+    /// Integral gain (duty/°C·s)
+    #[serde(default)]
+    pub ki: f32,
+
+    /// Derivative gain (duty·s/°C)
+    #[serde(default)]
+    pub kd: f32,
+
+    /// Lowest duty cycle fraction the controller will request
+    #[serde(default = "default_output_min")]
+    pub output_min: f32,
+
+    /// Highest duty cycle fraction the controller will request
+    #[serde(default = "default_output_max")]
+    pub output_max: f32,
+
+    /// Minimum time between duty cycle updates
+    #[serde(default = "default_interval", deserialize_with = "deserialize_duration_chrono")]
+    pub interval: Duration,
+}
+
+impl PidConfig {
+    pub fn new(setpoint: f32, kp: f32, ki: f32, kd: f32) -> Self {
+        PidConfig {
+            setpoint,
+            kp,
+            ki,
+            kd,
+            output_min: default_output_min(),
+            output_max: default_output_max(),
+            interval: default_interval(),
+        }
+    }
+
+    /// Gains equivalent to the firmware thermostat around the setpoint, used when the
+    /// firmware can't control on the program's sensor
+    pub fn firmware_equivalent(setpoint: f32) -> Self {
+        let adc_per_degree = adc_slope(setpoint);
+        let kp = FIRMWARE_K_P * adc_per_degree / FIRMWARE_PID_MAX_OUT;
+        let ki = adc_per_degree / (1 << FIRMWARE_K_I_SHIFT) as f32 / FIRMWARE_PID_MAX_OUT;
+        PidConfig {
+            interval: Duration::seconds(1),
+            ..PidConfig::new(setpoint, kp, ki, 0.0)
+        }
+    }
+}
+
+/// Thermistor ADC counts per °C around the given temperature
+pub(crate) fn adc_slope(temp: f32) -> f32 {
+    let (low, high) = ((temp - 1.0).clamp(-55.0, 149.0), (temp + 1.0).clamp(-54.0, 150.0));
+    (temp_to_adc_val(high) as f32 - temp_to_adc_val(low) as f32) / (high - low)
+}
+
+/// Discrete PID controller with output limits and anti-windup.
+///
+/// The integral term is only accumulated while the output is not saturated in the direction
+/// of the error (conditional integration), and the derivative acts on the measurement rather
+/// than the error so setpoint changes don't kick the output.
+#[derive(Debug, Clone)]
+pub struct PidController {
+    config: PidConfig,
+    integral: f32,
+    output: f32,
+    last_measurement: Option<f32>,
+    last_update: Option<DateTime<Utc>>,
+}
+
+impl PidController {
+    pub fn new(config: PidConfig) -> Self {
+        let output = config.output_min;
+        PidController { config, integral: 0.0, output, last_measurement: None, last_update: None }
+    }
+
+    pub fn setpoint(&self) -> f32 {
+        self.config.setpoint
+    }
+
+    pub fn set_setpoint(&mut self, setpoint: f32) {
+        self.config.setpoint = setpoint;
+    }
+
+    /// Latest output as a duty cycle fraction
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    /// Latest output as a PWM duty cycle (0-255)
+    pub fn duty(&self) -> u16 {
+        duty_from_fraction(self.output)
+    }
+
+    /// Returns true if the loop interval has passed since the last update
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        match self.last_update {
+            Some(last) => now - last >= self.config.interval,
+            None => true,
+        }
+    }
+
+    /// Calculates a new output from the latest measurement, returning the duty cycle fraction
+    pub fn update(&mut self, measurement: f32, now: DateTime<Utc>) -> f32 {
+        let PidConfig { setpoint, kp, ki, kd, output_min, output_max, .. } = self.config;
+        let dt = self.last_update
+            .map(|last| (now - last).num_milliseconds() as f32 / 1000.0)
+            .unwrap_or(0.0);
+        let error = setpoint - measurement;
+
+        let proportional = kp * error;
+        let derivative = match self.last_measurement {
+            Some(last) if dt > 0.0 => -kd * (measurement - last) / dt,
+            _ => 0.0,
+        };
+
+        let integral = self.integral + ki * error * dt;
+        let unclamped = proportional + integral + derivative;
+        let saturated = (unclamped > output_max && error > 0.0) ||
+            (unclamped < output_min && error < 0.0);
+        if !saturated {
+            self.integral = integral.clamp(output_min, output_max);
+        }
+
+        self.output = (proportional + self.integral + derivative).clamp(output_min, output_max);
+        self.last_measurement = Some(measurement);
+        self.last_update = Some(now);
+        self.output
+    }
+}
+
+/// Converts a duty cycle fraction (0.0-1.0) to the 8-bit PWM duty used by the firmware
+pub fn duty_from_fraction(fraction: f32) -> u16 {
+    (fraction.clamp(0.0, 1.0) * PWM_DUTY_MAX as f32).round() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::control::pid::{duty_from_fraction, PidConfig, PidController};
+
+    #[test]
+    fn test_proportional_output() {
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut pid = PidController::new(PidConfig::new(60.0, 0.05, 0.0, 0.0));
+        assert_approx_eq!(0.5, pid.update(50.0, now), 0.001);
+        assert_approx_eq!(0.25, pid.update(55.0, now + Duration::seconds(5)), 0.001);
+        assert_approx_eq!(0.0, pid.update(70.0, now + Duration::seconds(10)), 0.001);
+        assert_approx_eq!(1.0, pid.update(20.0, now + Duration::seconds(15)), 0.001);
+        assert_eq!(255, pid.duty());
+    }
+
+    #[test]
+    fn test_integral_anti_windup() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut pid = PidController::new(PidConfig::new(60.0, 0.1, 0.01, 0.0));
+        // far below setpoint for a long time, output is saturated so integral shouldn't grow
+        for i in 0..100 {
+            pid.update(20.0, start + Duration::seconds(i * 5));
+        }
+        assert_approx_eq!(1.0, pid.output(), 0.001);
+
+        // once past the setpoint, output should drop straight away without unwinding
+        let now = start + Duration::seconds(500);
+        assert!(pid.update(61.0, now) < 0.1, "output {} should respond immediately", pid.output());
+    }
+
+    #[test]
+    fn test_integral_removes_steady_state_error() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut pid = PidController::new(PidConfig::new(60.0, 0.05, 0.002, 0.0));
+        pid.update(59.0, start);
+        let first = pid.update(59.0, start + Duration::seconds(5));
+        let later = pid.update(59.0, start + Duration::seconds(50));
+        assert!(later > first, "integral should increase output: {} <= {}", later, first);
+    }
+
+    #[test]
+    fn test_derivative_on_measurement() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut pid = PidController::new(PidConfig::new(60.0, 0.05, 0.0, 1.0));
+        pid.update(40.0, start);
+        // rising 1°C/s outweighs the proportional term (0.5), so output should be clamped to zero
+        assert_approx_eq!(0.0, pid.update(50.0, start + Duration::seconds(10)), 0.001);
+
+        // a setpoint change shouldn't produce a derivative kick
+        pid.set_setpoint(70.0);
+        assert_approx_eq!(1.0, pid.update(50.0, start + Duration::seconds(20)), 0.001);
+    }
+
+    #[test]
+    fn test_is_due() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut pid = PidController::new(PidConfig::new(60.0, 0.05, 0.0, 0.0));
+        assert!(pid.is_due(start));
+        pid.update(50.0, start);
+        assert!(!pid.is_due(start + Duration::seconds(4)));
+        assert!(pid.is_due(start + Duration::seconds(5)));
+    }
+
+    #[test]
+    fn test_converges_on_simulated_heater() {
+        // first-order board model: 8 W heater, 4 °C/W to ambient, 60 s time constant
+        let (ambient, gain, tau) = (20.0, 32.0, 60.0);
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut pid = PidController::new(PidConfig {
+            interval: Duration::seconds(1),
+            ..PidConfig::new(40.0, 0.1, 0.002, 0.0)
+        });
+        let mut temp: f32 = ambient;
+        for i in 0..1800 {
+            let duty = pid.update(temp, start + Duration::seconds(i));
+            temp += (ambient + gain * duty - temp) / tau;
+        }
+        assert_approx_eq!(40.0, temp, 0.5);
+    }
+
+    #[test]
+    fn test_duty_from_fraction() {
+        assert_eq!(0, duty_from_fraction(-0.5));
+        assert_eq!(191, duty_from_fraction(0.75));
+        assert_eq!(255, duty_from_fraction(1.0));
+        assert_eq!(255, duty_from_fraction(2.0));
+    }
+
+    #[test]
+    fn test_firmware_equivalent_gains() {
+        let config = PidConfig::firmware_equivalent(60.0);
+        // thermistor is ~22 ADC counts per °C at 60°C, and K_P = 3 on a full scale of 8000
+        assert!(config.kp > 0.007 && config.kp < 0.01, "kp: {}", config.kp);
+        assert_approx_eq!(config.kp / 24.0, config.ki, 0.00001);
+        assert_eq!(0.0, config.kd);
+        assert_eq!(Duration::seconds(1), config.interval);
+    }
+}
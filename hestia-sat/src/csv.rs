@@ -1,8 +1,8 @@
 use std::convert::TryInto;
 use std::fmt::Debug;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
 
 use chrono::{DateTime, Utc};
@@ -12,6 +12,7 @@ use log::error;
 
 use crate::board::{Board, BoardData, BoardFlags};
 use crate::heater::HeaterMode;
+use crate::programs::active::ActiveProgram;
 use crate::reading::SensorReading;
 use crate::ReadResult;
 use crate::sensors::Sensor;
@@ -198,11 +199,51 @@ pub const CSV_DISPLAY_HEADERS: [&str; CSV_DISPLAY_FIELD_COUNT] = [
     "flags",
 ];
 
+/// Columns after the sensor data in both files, for the program uts-run is running
+pub const CSV_PROGRAM_HEADERS: [&str; 3] = [
+    "program_id",
+    "program_name",
+    "phase",
+];
+
+fn program_columns(active: &ActiveProgram) -> [String; 3] {
+    [
+        active.program_id.map(|id| id.to_string()).unwrap_or_default(),
+        active.program_name.as_deref().map(escape).unwrap_or_default(),
+        escape(&active.phase),
+    ]
+}
+
+/// Whether the header of an existing file ends with the program columns
+fn has_program_headers(path: &Path) -> bool {
+    let mut header = String::new();
+    let read = File::open(path).and_then(|file| BufReader::new(file).read_line(&mut header));
+    match read {
+        Ok(_) => header.trim_end().ends_with(&CSV_PROGRAM_HEADERS.join(",")),
+        Err(e) => {
+            error!("Failed to read header of log file {:?}: {:?}", path, e);
+            false
+        }
+    }
+}
+
+/// Quotes values with commas or quotes, which are only expected in program names
+fn escape(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        String::from(value)
+    }
+}
+
 pub struct CsvWriter
 {
     open_writer: Box<dyn FnMut() -> io::Result<Box<dyn Write>>>,
     line_ending: LineEnding,
     write_headers: bool,
+    /// Whether rows end with the program columns, which files started before they were added
+    /// don't have in their header
+    program_columns: bool,
 }
 
 impl CsvWriter {
@@ -211,10 +252,12 @@ impl CsvWriter {
             open_writer: Box::new(|| Ok(Box::new(io::stdout()))),
             line_ending: LineEnding::LF,
             write_headers: true,
+            program_columns: true,
         }
     }
 
     pub fn file<P: AsRef<Path> + 'static>(path: P, is_new: bool) -> CsvWriter {
+        let program_columns = is_new || has_program_headers(path.as_ref());
         let options = OpenOptions::new()
             .create(true)
             .append(true)
@@ -226,33 +269,34 @@ impl CsvWriter {
             }),
             line_ending: LineEnding::CRLF,
             write_headers: is_new,
+            program_columns,
         }
     }
 
     pub fn write_raw_headers(&mut self) {
         if self.write_headers {
-            self.write_line(CSV_RAW_HEADERS.map(|s| s.to_string()))
+            self.write_line(CSV_RAW_HEADERS.iter().chain(&CSV_PROGRAM_HEADERS).map(|s| s.to_string()))
                 .expect("Failed to write header to new CSV file");
         }
     }
 
     pub fn write_display_headers(&mut self) {
         if self.write_headers {
-            self.write_line(CSV_DISPLAY_HEADERS.map(|s| s.to_string()))
+            self.write_line(CSV_DISPLAY_HEADERS.iter().chain(&CSV_PROGRAM_HEADERS).map(|s| s.to_string()))
                 .expect("Failed to write header to new CSV file");
         }
     }
 
     pub fn write_raw_data(&mut self, timestamp: DateTime<Utc>, board: &Board,
-                          raw_data: &[ReadResult<u16>; CSV_RAW_FIELD_COUNT - 2]) {
+                          raw_data: &[ReadResult<u16>; CSV_RAW_FIELD_COUNT - 2], active: &ActiveProgram) {
         let mut data: Vec<CsvData> = vec![timestamp.into(), board.into()];
         data.extend(raw_data.iter().map(CsvData::from));
         let data: [CsvData; CSV_RAW_FIELD_COUNT] = data.try_into().expect("Array sizes didn't match");
-        self.write_data(data).unwrap_or_else(|e| error!("Failed to write to log file: {:?}", e));
+        self.write_program_data(data, active).unwrap_or_else(|e| error!("Failed to write to log file: {:?}", e));
     }
 
     pub fn write_display_data(&mut self, timestamp: DateTime<Utc>, board: &Board,
-                              board_data: &BoardData) {
+                              board_data: &BoardData, active: &ActiveProgram) {
         let [.., v_high_avg, v_low_avg, v_curr_avg] = &board_data.sensors;
         let heater_voltage = board.calc_heater_voltage(v_high_avg.clone(), v_low_avg.clone());
         let heater_curr = board.calc_heater_current(v_low_avg.clone(), v_curr_avg.clone());
@@ -287,14 +331,19 @@ impl CsvWriter {
             CsvData::from(&board_data.max_temp),
             CsvData::from(&board_data.flags),
         ];
-        self.write_data(data).unwrap_or_else(|e| error!("Failed to write to log file: {:?}", e));
+        self.write_program_data(data, active).unwrap_or_else(|e| error!("Failed to write to log file: {:?}", e));
     }
 
     pub fn write_data<const LEN: usize>(&mut self, data: [CsvData; LEN]) -> io::Result<()> {
         self.write_line(data.map(|d| d.into()))
     }
 
-    fn write_line<const LEN: usize>(&mut self, line: [String; LEN]) -> io::Result<()>
+    fn write_program_data<const LEN: usize>(&mut self, data: [CsvData; LEN], active: &ActiveProgram) -> io::Result<()> {
+        let program = if self.program_columns { program_columns(active).to_vec() } else { vec![] };
+        self.write_line(IntoIterator::into_iter(data.map(String::from)).chain(program))
+    }
+
+    fn write_line<I: IntoIterator<Item = String>>(&mut self, line: I) -> io::Result<()>
     {
         let mut write_delim = false;
         let mut writer = (self.open_writer)()?;
@@ -309,3 +358,50 @@ impl CsvWriter {
         writer.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::csv::{CsvData, CsvWriter, program_columns};
+    use crate::programs::active::ActiveProgram;
+
+    #[test]
+    fn test_program_columns() {
+        let active = ActiveProgram {
+            program_id: Some(2),
+            program_name: Some(String::from("Top soak, \"slow\"")),
+            phase: String::from("heating"),
+            time: String::from("2023-09-01T00:00:00+00:00"),
+        };
+        assert_eq!(["2", "\"Top soak, \"\"slow\"\"\"", "heating"], program_columns(&active));
+        let idle = ActiveProgram { program_id: None, program_name: None, phase: String::from("idle"), ..active };
+        assert_eq!(["", "", "idle"], program_columns(&idle));
+    }
+
+    #[test]
+    fn test_program_columns_match_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let active = ActiveProgram {
+            program_id: Some(2),
+            program_name: Some(String::from("Top soak")),
+            phase: String::from("heating"),
+            time: String::from("2023-09-01T00:00:00+00:00"),
+        };
+        let data = [CsvData::from(1u16), CsvData::from(25.0)];
+
+        // started before the program columns were added, so rows keep to its header
+        let old = dir.path().join("old.csv");
+        fs::write(&old, "board,TH1\r\n").unwrap();
+        let mut writer = CsvWriter::file(old.clone(), false);
+        writer.write_program_data(data, &active).unwrap();
+        assert_eq!("board,TH1\r\n1,25.00\r\n", fs::read_to_string(&old).unwrap());
+
+        let new = dir.path().join("new.csv");
+        fs::write(&new, "board,TH1,program_id,program_name,phase\r\n").unwrap();
+        let mut writer = CsvWriter::file(new.clone(), false);
+        writer.write_program_data(data, &active).unwrap();
+        assert_eq!("board,TH1,program_id,program_name,phase\r\n1,25.00,2,Top soak,heating\r\n",
+                   fs::read_to_string(&new).unwrap());
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::payload::Config;
+
+const DEADMAN_DIR: &str = "deadman";
+
+/// The event log is moved aside to `deadman.log.1` once it reaches this size
+const MAX_EVENTS_SIZE: u64 = 64 * 1024;
+
+/// A heater being switched off by the deadman, recorded one JSON object per line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadmanEvent {
+    /// I2C bus ID of the board
+    pub bus: u8,
+    pub deadline: String,
+    pub time: String,
+}
+
+/// Deadlines after which heaters left running by manual commands are switched off.
+/// They're persisted so the safety supervisor in another process, e.g. uts-log, can
+/// enforce them after the command exits. Each bus has its own file, so arming and
+/// disarming are single renames and removals that can't lose another process's update.
+#[derive(Debug, Clone)]
+pub struct Deadman {
+    dir: PathBuf,
+}
+
+impl Deadman {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Deadman { dir: dir.as_ref().to_path_buf() }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(Path::new(&config.state_path).join(DEADMAN_DIR))
+    }
+
+    fn bus_path(&self, bus: u8) -> PathBuf {
+        self.dir.join(format!("{}.json", bus))
+    }
+
+    fn read_deadline(path: &Path) -> Option<DateTime<Utc>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read deadman file {:?}: {}", path, e);
+                return None;
+            }
+        };
+        match serde_json::from_str::<i64>(&contents) {
+            Ok(secs) => Utc.timestamp_opt(secs, 0).single(),
+            Err(e) => {
+                warn!("Invalid deadman file {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Deadlines by I2C bus ID. Empty if none are armed or the files can't be read.
+    pub fn read(&self) -> BTreeMap<u8, DateTime<Utc>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return BTreeMap::new(),
+            Err(e) => {
+                warn!("Failed to read deadman directory {:?}: {}", self.dir, e);
+                return BTreeMap::new();
+            }
+        };
+        entries.filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            let bus = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((bus, Self::read_deadline(&path)?))
+        }).collect()
+    }
+
+    /// Switch off the heater on this bus at the deadline, replacing any previous deadline
+    pub fn arm(&self, bus: u8, deadline: DateTime<Utc>) {
+        let path = self.bus_path(bus);
+        let result = fs::create_dir_all(&self.dir)
+            .and_then(|_| {
+                // write then rename, so a reader never sees a partial file
+                let mut file = NamedTempFile::new_in(&self.dir)?;
+                write!(file, "{}", deadline.timestamp())?;
+                file.persist(&path).map_err(|e| e.error)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            warn!("Failed to write deadman file {:?}: {}", path, e);
+        }
+    }
+
+    /// Returns false if no deadline was armed for this bus, or another process disarmed it first
+    pub fn disarm(&self, bus: u8) -> bool {
+        let path = self.bus_path(bus);
+        match fs::remove_file(&path) {
+            Ok(()) => true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => {
+                warn!("Failed to remove deadman file {:?}: {}", path, e);
+                false
+            }
+        }
+    }
+
+    /// Disarms the deadline on this bus if it has passed, returning it. Returns None if another
+    /// process got there first, or the bus was armed again with a later deadline.
+    pub fn claim_expired(&self, bus: u8, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let path = self.bus_path(bus);
+        // moving the file aside can only succeed in one process, and the temporary
+        // file it replaces is removed when dropped
+        let claimed = NamedTempFile::new_in(&self.dir).ok()?.into_temp_path();
+        fs::rename(&path, &claimed).ok()?;
+        match Self::read_deadline(&claimed) {
+            Some(deadline) if deadline <= now => Some(deadline),
+            Some(_) => {
+                // re-armed since it expired, so put it back unless it's been armed again since
+                let _ = fs::hard_link(&claimed, &path);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn events_path(&self) -> PathBuf {
+        self.dir.with_extension("log")
+    }
+
+    fn old_events_path(&self) -> PathBuf {
+        self.dir.with_extension("log.1")
+    }
+
+    /// Records a heater being switched off at its deadline
+    pub fn record(&self, bus: u8, deadline: DateTime<Utc>, now: DateTime<Utc>) {
+        let event = DeadmanEvent { bus, deadline: deadline.to_rfc3339(), time: now.to_rfc3339() };
+        let path = self.events_path();
+        if fs::metadata(&path).map(|m| m.len() >= MAX_EVENTS_SIZE).unwrap_or(false) {
+            if let Err(e) = fs::rename(&path, self.old_events_path()) {
+                warn!("Failed to rotate deadman events {:?}: {}", path, e);
+            }
+        }
+        let result = OpenOptions::new().create(true).append(true).open(&path)
+            .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&event)?));
+        if let Err(e) = result {
+            warn!("Failed to record deadman event in {:?}: {}", path, e);
+        }
+    }
+
+    /// The most recent events, oldest first
+    pub fn events(&self, limit: usize) -> Vec<DeadmanEvent> {
+        let events: Vec<DeadmanEvent> = [self.old_events_path(), self.events_path()].iter()
+            .flat_map(|path| fs::read_to_string(path).unwrap_or_default()
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect::<Vec<_>>())
+            .collect();
+        events[events.len().saturating_sub(limit)..].to_vec()
+    }
+
+    /// Buses whose deadline has passed, with the deadline
+    pub fn expired(&self, now: DateTime<Utc>) -> Vec<(u8, DateTime<Utc>)> {
+        self.read().into_iter()
+            .filter(|(_, deadline)| *deadline <= now)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::deadman::{Deadman, MAX_EVENTS_SIZE};
+
+    #[test]
+    fn test_arm_and_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let deadman = Deadman::new(dir.path().join("state/deadman"));
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        assert!(deadman.read().is_empty());
+
+        deadman.arm(1, now + Duration::minutes(10));
+        deadman.arm(2, now + Duration::minutes(20));
+        assert_eq!(Some(&(now + Duration::minutes(10))), deadman.read().get(&1));
+        assert!(deadman.expired(now).is_empty());
+        assert_eq!(vec![(1, now + Duration::minutes(10))], deadman.expired(now + Duration::minutes(10)));
+        assert_eq!(2, deadman.expired(now + Duration::minutes(30)).len());
+
+        assert!(deadman.disarm(1));
+        assert!(!deadman.disarm(1));
+        assert_eq!(vec![(2, now + Duration::minutes(20))], deadman.expired(now + Duration::minutes(30)));
+
+        // a deadline armed again after it expired is left for later
+        deadman.arm(2, now + Duration::minutes(40));
+        assert_eq!(None, deadman.claim_expired(2, now + Duration::minutes(30)));
+        assert_eq!(Some(&(now + Duration::minutes(40))), deadman.read().get(&2));
+        assert_eq!(Some(now + Duration::minutes(40)), deadman.claim_expired(2, now + Duration::minutes(40)));
+        assert_eq!(None, deadman.claim_expired(2, now + Duration::minutes(40)));
+        assert!(deadman.read().is_empty());
+    }
+
+    #[test]
+    fn test_record_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let deadman = Deadman::new(dir.path().join("deadman"));
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        assert!(deadman.events(10).is_empty());
+        for bus in 1..=3 {
+            deadman.record(bus, now, now + Duration::seconds(2));
+        }
+        let events = deadman.events(2);
+        assert_eq!(vec![2, 3], events.iter().map(|e| e.bus).collect::<Vec<_>>());
+        assert_eq!("2023-09-01T00:00:02+00:00", events[0].time);
+
+        // the log is rotated rather than growing forever
+        for i in 0..2000 {
+            deadman.record((i % 200) as u8, now, now);
+        }
+        let size = |path| std::fs::metadata(dir.path().join(path)).unwrap().len();
+        assert!(size("deadman.log") < MAX_EVENTS_SIZE);
+        assert!(size("deadman.log.1") <= MAX_EVENTS_SIZE + 100);
+        let events = deadman.events(5000);
+        assert!(events.len() < 2000);
+        assert_eq!(1999 % 200, events.last().unwrap().bus as usize);
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("1.json"), "not json").unwrap();
+        std::fs::write(dir.path().join("notes.json"), "1693526400").unwrap();
+        assert!(Deadman::new(dir.path()).read().is_empty());
+    }
+}
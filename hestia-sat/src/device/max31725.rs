@@ -6,6 +6,10 @@ use crate::ReadResult;
 const MAX31725_REG_TEMP: I2cReg = I2cReg(0x00);
 const MAX31725_CF_LSB: f32 = 0.00390625;
 
+pub(crate) fn raw_to_temp(raw_value: u16) -> f32 {
+    f32::from(raw_value as i16) * MAX31725_CF_LSB
+}
+
 /// MAX31725 is a discrete I2C temperature sensor on the Hestia boards. Each
 /// one has its own configured I2C address on the bus.
 #[derive(Debug, Clone)]
@@ -32,8 +36,7 @@ impl Display for Max31725Sensor {
 impl ReadableSensor for Max31725Sensor {
     fn read(&self) -> ReadResult<SensorReading<f32>> {
         let raw_value = self.device.read_register(MAX31725_REG_TEMP, "temp")?;
-        let display_value = f32::from(raw_value as i16) * MAX31725_CF_LSB;
-        Ok(SensorReading::new(raw_value, display_value))
+        Ok(SensorReading::new(raw_value, raw_to_temp(raw_value)))
     }
 }
 
@@ -127,10 +127,10 @@ impl Heater for Msp430 {
 
     fn read_flags(&self) -> ReadResult<SensorReading<BoardFlags>> {
         let raw = self.read_register(MSP430_READ_FLAGS, "flags")?;
-        let display: BoardFlags = raw.try_into().map_err(|_| {
-            warn!("{}: Invalid flags: {:?}", self.device, raw);
-            ValueOutOfRange
-        })?;
+        let display = BoardFlags::from(raw);
+        if display.unknown_bits() != 0 {
+            warn!("{}: Unknown flags: {:#06x}", self.device, display.unknown_bits());
+        }
         Ok(SensorReading::new(raw, display))
     }
 }
@@ -163,6 +163,14 @@ impl ReadableSensor for Msp430TempSensor {
     }
 }
 
+pub(crate) fn adc_val_to_voltage(adc_val: u16) -> f32 {
+    adc_val as f32 / (MSP430_ADC_RESOLUTION as f32) * MSP430_ADC_V_REF * MSP430_V_DIVIDER_FACTOR
+}
+
+pub(crate) fn adc_val_to_current(adc_val: u16) -> f32 {
+    adc_val as f32 / (MSP430_ADC_RESOLUTION as f32) * MSP430_ADC_V_REF
+}
+
 /// Represents a voltage sensor read via the MSP430 ADC
 pub struct Msp430VoltageSensor {
     device: Msp430,
@@ -186,9 +194,7 @@ impl Display for Msp430VoltageSensor {
 impl ReadableSensor for Msp430VoltageSensor {
     fn read(&self) -> ReadResult<SensorReading<f32>> {
         let raw_value = self.device.read_register(self.reg, &self.name)?;
-        let display_value = raw_value as f32 / (MSP430_ADC_RESOLUTION as f32) *
-            MSP430_ADC_V_REF * MSP430_V_DIVIDER_FACTOR;
-        Ok(SensorReading::new(raw_value, display_value))
+        Ok(SensorReading::new(raw_value, adc_val_to_voltage(raw_value)))
     }
 }
 
@@ -215,7 +221,6 @@ impl Display for Msp430CurrentSensor {
 impl ReadableSensor for Msp430CurrentSensor {
     fn read(&self) -> ReadResult<SensorReading<f32>> {
         let raw_value = self.device.read_register(self.reg, &self.name)?;
-        let display_value = raw_value as f32 / (MSP430_ADC_RESOLUTION as f32) * MSP430_ADC_V_REF;
-        Ok(SensorReading::new(raw_value, display_value))
+        Ok(SensorReading::new(raw_value, adc_val_to_current(raw_value)))
     }
 }
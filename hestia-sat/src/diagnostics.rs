@@ -0,0 +1,648 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Serialize, Serializer};
+use tempfile::NamedTempFile;
+
+use crate::board;
+use crate::board::{Board, BoardData, BoardFlag, BoardFlags, BoardDataProvider, BoardId, BoardVersion, V_CURR_AVG, V_HIGH_AVG, V_LOW_AVG};
+use crate::heater::HeaterMode;
+use crate::payload::Config;
+
+/// Heater current below this while off is normal leakage, in A
+const OFF_CURRENT_MAX: f32 = 0.1;
+
+/// Heater voltage below this while off is normal, in V
+const OFF_VOLTAGE_MAX: f32 = 0.5;
+
+/// Current expected at full duty for a heater that is conducting, in A
+const ON_CURRENT_MIN: f32 = 0.5;
+
+/// Voltage expected at full duty across a heater that is switched on, in V
+const ON_VOLTAGE_MIN: f32 = 1.0;
+
+/// Resistance below this is treated as a short across the heater, in Ω
+const SHORT_RESISTANCE_MAX: f32 = 0.5;
+
+/// PWM duty below which readings are too small to diagnose the heater while on
+const MIN_DIAGNOSIS_DUTY: u16 = 64;
+
+/// Fractional change in resistance from the baseline which is reported as drift
+const RESISTANCE_DRIFT_MAX: f32 = 0.2;
+
+/// Number of healthy readings averaged for the resistance baseline
+const BASELINE_SAMPLES: usize = 5;
+
+/// Directory under the state path for the resistance baselines
+const BASELINE_DIR: &str = "heater-baseline";
+
+/// Number of heater events kept for the web API
+const EVENT_HISTORY: usize = 100;
+
+/// Faults in the heater circuit, diagnosed from the heater voltage and current
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "fault")]
+pub enum HeaterFault {
+    /// Voltage across the heater but no current, e.g. a broken track or connector
+    OpenCircuit { voltage: f32 },
+    /// Current through the heater with almost no voltage across it
+    ShortCircuit { resistance: f32 },
+    /// Current or voltage while the heater is off, e.g. a failed MOSFET
+    StuckOn { voltage: f32, current: f32 },
+    /// Neither voltage nor current while the heater is on, e.g. MOSFET not switching
+    NoCurrent { duty: u16 },
+    /// Resistance has changed from the baseline measured for this board
+    ResistanceDrift { resistance: f32, baseline: f32 },
+}
+
+impl HeaterFault {
+    fn is_same_kind(&self, other: &HeaterFault) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl Display for HeaterFault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaterFault::OpenCircuit { voltage } =>
+                write!(f, "open circuit ({:.2} V, no current)", voltage),
+            HeaterFault::ShortCircuit { resistance } =>
+                write!(f, "short circuit ({:.2} Ω)", resistance),
+            HeaterFault::StuckOn { voltage, current } =>
+                write!(f, "stuck on while off ({:.2} V, {:.2} A)", voltage, current),
+            HeaterFault::NoCurrent { duty } =>
+                write!(f, "no current at duty {}", duty),
+            HeaterFault::ResistanceDrift { resistance, baseline } =>
+                write!(f, "resistance drift ({:.2} Ω vs baseline {:.2} Ω)", resistance, baseline),
+        }
+    }
+}
+
+/// Heater state and averaged voltage and current from one reading of a board
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HeaterMeasurement {
+    pub mode: HeaterMode,
+    pub duty: u16,
+    pub voltage: f32,
+    pub current: f32,
+}
+
+impl HeaterMeasurement {
+    /// None for v1.1 boards, which can't measure heater voltage or current
+    pub fn from_data(version: BoardVersion, data: &BoardData) -> Option<Self> {
+        if version == BoardVersion::V1_1 {
+            return None;
+        }
+        let reading = |sensor_id| {
+            let index = board::ALL_SENSORS.iter().position(|s| s.id == sensor_id)?;
+            data.sensors[index].as_ref().ok().map(|r| r.display_value)
+        };
+        let (v_high, v_low, v_curr) = (reading(V_HIGH_AVG.id)?, reading(V_LOW_AVG.id)?, reading(V_CURR_AVG.id)?);
+        Some(HeaterMeasurement {
+            mode: data.heater_mode.as_ref().ok()?.display_value,
+            duty: data.heater_duty.as_ref().ok()?.display_value,
+            voltage: board::calc_heater_voltage(version, v_high, v_low),
+            current: board::calc_heater_current(version, v_low, v_curr),
+        })
+    }
+
+    pub fn read(board: &Board) -> Option<Self> {
+        Self::from_data(board.version, &board.read_data()?)
+    }
+
+    /// Fraction of time the heater is switched on, if known. The firmware thermostat
+    /// doesn't report its output, so the heater may or may not be on.
+    fn on_fraction(&self) -> Option<f32> {
+        match self.mode {
+            HeaterMode::OFF => Some(0.0),
+            HeaterMode::PWM => Some(f32::from(self.duty.min(255)) / 255.0),
+            HeaterMode::PID => None,
+        }
+    }
+
+    /// Heater resistance, if there's enough current to measure it
+    pub fn resistance(&self) -> Option<f32> {
+        let on = self.on_fraction().unwrap_or(1.0);
+        if self.current > ON_CURRENT_MIN * on && self.current > 0.0 {
+            Some(self.voltage / self.current)
+        } else {
+            None
+        }
+    }
+}
+
+/// Checks a measurement for heater faults, including drift if a baseline resistance is known
+pub fn diagnose(measurement: &HeaterMeasurement, baseline: Option<f32>) -> Vec<HeaterFault> {
+    let HeaterMeasurement { mode, duty, voltage, current } = *measurement;
+    let mut faults = vec![];
+    match measurement.on_fraction() {
+        Some(on) if on == 0.0 && (current > OFF_CURRENT_MAX || voltage > OFF_VOLTAGE_MAX) => {
+            faults.push(HeaterFault::StuckOn { voltage, current });
+        }
+        Some(on) if mode == HeaterMode::PWM && duty >= MIN_DIAGNOSIS_DUTY => {
+            let conducting = current >= ON_CURRENT_MIN * on;
+            let powered = voltage >= ON_VOLTAGE_MIN * on;
+            if !conducting && powered {
+                faults.push(HeaterFault::OpenCircuit { voltage });
+            } else if !conducting {
+                faults.push(HeaterFault::NoCurrent { duty });
+            }
+        }
+        _ => {}
+    }
+    if let Some(resistance) = measurement.resistance() {
+        if resistance < SHORT_RESISTANCE_MAX {
+            faults.push(HeaterFault::ShortCircuit { resistance });
+        } else if let Some(baseline) = baseline {
+            if (resistance - baseline).abs() > baseline * RESISTANCE_DRIFT_MAX {
+                faults.push(HeaterFault::ResistanceDrift { resistance, baseline });
+            }
+        }
+    }
+    faults
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event")]
+pub enum HeaterEventKind {
+    Raised(HeaterFault),
+    Cleared(HeaterFault),
+}
+
+/// A heater fault being raised or cleared on a board
+#[derive(Debug, Clone, Serialize)]
+pub struct HeaterEvent {
+    pub board: BoardId,
+    #[serde(serialize_with = "serialize_time")]
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: HeaterEventKind,
+}
+
+impl Display for HeaterEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            HeaterEventKind::Raised(fault) => write!(f, "Heater fault on {} board: {}", self.board, fault),
+            HeaterEventKind::Cleared(fault) => write!(f, "Heater fault cleared on {} board: {}", self.board, fault),
+        }
+    }
+}
+
+/// Times in the web API are RFC 3339, as chrono is built without serde
+pub(crate) fn serialize_time<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339())
+}
+
+/// Heater resistance baselines, saved per board so a restart doesn't learn a new baseline
+/// from a heater that has already drifted
+#[derive(Debug, Clone)]
+pub struct Baselines {
+    dir: PathBuf,
+}
+
+impl Baselines {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Baselines { dir: dir.as_ref().to_path_buf() }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(Path::new(&config.state_path).join(BASELINE_DIR))
+    }
+
+    fn board_path(&self, board: BoardId) -> PathBuf {
+        self.dir.join(format!("{}.json", board))
+    }
+
+    /// The saved baseline in Ω, or None if there isn't one or it can't be read
+    pub fn read(&self, board: BoardId) -> Option<f32> {
+        let path = self.board_path(board);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read heater baseline file {:?}: {}", path, e);
+                return None;
+            }
+        };
+        match serde_json::from_str::<f32>(&contents) {
+            Ok(baseline) => Some(baseline),
+            Err(e) => {
+                warn!("Invalid heater baseline file {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, board: BoardId, baseline: f32) {
+        let path = self.board_path(board);
+        let result = fs::create_dir_all(&self.dir)
+            .and_then(|_| {
+                // write then rename, so a reader never sees a partial file
+                let mut file = NamedTempFile::new_in(&self.dir)?;
+                write!(file, "{}", baseline)?;
+                file.persist(&path).map_err(|e| e.error)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            warn!("Failed to write heater baseline file {:?}: {}", path, e);
+        }
+    }
+}
+
+/// Diagnoses one board's heater over time, learning its resistance baseline from the
+/// first healthy readings and raising events when faults appear or clear
+#[derive(Debug, Clone)]
+pub struct HeaterMonitor {
+    board: BoardId,
+    baseline: Option<f32>,
+    baselines: Option<Baselines>,
+    samples: Vec<f32>,
+    faults: Vec<HeaterFault>,
+    resistance: Option<f32>,
+}
+
+impl HeaterMonitor {
+    pub fn new(board: BoardId) -> Self {
+        HeaterMonitor { board, baseline: None, baselines: None, samples: vec![], faults: vec![], resistance: None }
+    }
+
+    /// Starts from the board's saved baseline, if there is one, and saves the baseline once learned
+    pub fn with_baselines(self, baselines: Baselines) -> Self {
+        let baseline = baselines.read(self.board);
+        if let Some(baseline) = baseline {
+            info!("Saved heater resistance baseline for {} board: {:.2} Ω", self.board, baseline);
+        }
+        HeaterMonitor { baseline: baseline.or(self.baseline), baselines: Some(baselines), ..self }
+    }
+
+    pub fn baseline(&self) -> Option<f32> {
+        self.baseline
+    }
+
+    pub fn faults(&self) -> &[HeaterFault] {
+        &self.faults
+    }
+
+    pub fn update(&mut self, measurement: &HeaterMeasurement, now: DateTime<Utc>) -> Vec<HeaterEvent> {
+        let faults = diagnose(measurement, self.baseline);
+        if let Some(resistance) = measurement.resistance() {
+            self.resistance = Some(resistance);
+            if faults.is_empty() && self.baseline.is_none() {
+                self.samples.push(resistance);
+                if self.samples.len() >= BASELINE_SAMPLES {
+                    let baseline = self.samples.iter().sum::<f32>() / self.samples.len() as f32;
+                    info!("Heater resistance baseline for {} board: {:.2} Ω", self.board, baseline);
+                    self.baseline = Some(baseline);
+                    if let Some(baselines) = &self.baselines {
+                        baselines.save(self.board, baseline);
+                    }
+                }
+            }
+        }
+
+        let mut events = vec![];
+        for fault in &faults {
+            if !self.faults.iter().any(|f| f.is_same_kind(fault)) {
+                events.push(HeaterEvent { board: self.board, time: now, kind: HeaterEventKind::Raised(fault.clone()) });
+            }
+        }
+        for fault in &self.faults {
+            if !faults.iter().any(|f| f.is_same_kind(fault)) {
+                events.push(HeaterEvent { board: self.board, time: now, kind: HeaterEventKind::Cleared(fault.clone()) });
+            }
+        }
+        self.faults = faults;
+        events
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event")]
+pub enum FlagChange {
+    Set,
+    Cleared { seconds: i64 },
+}
+
+/// A firmware status flag changing on a board
+#[derive(Debug, Clone, Serialize)]
+pub struct FlagEvent {
+    pub board: BoardId,
+    #[serde(serialize_with = "serialize_time")]
+    pub time: DateTime<Utc>,
+    pub flag: BoardFlag,
+    #[serde(flatten)]
+    pub change: FlagChange,
+}
+
+impl FlagEvent {
+    /// True if the change is towards a fault, e.g. MAX_TEMP set or ON cleared
+    pub fn is_fault(&self) -> bool {
+        match self.change {
+            FlagChange::Set => self.flag.is_fault(),
+            FlagChange::Cleared { .. } => !self.flag.is_fault(),
+        }
+    }
+}
+
+impl Display for FlagEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.change {
+            FlagChange::Set => write!(f, "{} flag set on {} board", self.flag, self.board),
+            FlagChange::Cleared { seconds } =>
+                write!(f, "{} flag cleared on {} board after {}s", self.flag, self.board, seconds),
+        }
+    }
+}
+
+/// A flag which is currently set, and when it was first seen set
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveFlag {
+    pub flag: BoardFlag,
+    #[serde(serialize_with = "serialize_time")]
+    pub since: DateTime<Utc>,
+}
+
+/// Tracks transitions of one board's status flags, keeping a history of them
+#[derive(Debug, Clone)]
+pub struct FlagMonitor {
+    board: BoardId,
+    flags: Option<BoardFlags>,
+    active: Vec<ActiveFlag>,
+    events: VecDeque<FlagEvent>,
+}
+
+impl FlagMonitor {
+    pub fn new(board: BoardId) -> Self {
+        FlagMonitor { board, flags: None, active: vec![], events: VecDeque::new() }
+    }
+
+    pub fn flags(&self) -> Option<BoardFlags> {
+        self.flags
+    }
+
+    /// Compares with the previous flags, or the power-on flags for the first reading
+    pub fn update(&mut self, flags: BoardFlags, now: DateTime<Utc>) -> Vec<FlagEvent> {
+        let previous = self.flags.unwrap_or_default();
+        if self.flags.is_none() {
+            self.active = previous.flags().map(|flag| ActiveFlag { flag, since: now }).collect();
+        }
+        let mut events = vec![];
+        for flag in flags.flags().filter(|f| !previous.contains(*f)) {
+            self.active.push(ActiveFlag { flag, since: now });
+            events.push(FlagEvent { board: self.board, time: now, flag, change: FlagChange::Set });
+        }
+        for flag in previous.flags().filter(|f| !flags.contains(*f)) {
+            let since = self.active.iter().find(|a| a.flag == flag).map_or(now, |a| a.since);
+            let seconds = (now - since).num_seconds();
+            events.push(FlagEvent { board: self.board, time: now, flag, change: FlagChange::Cleared { seconds } });
+        }
+        self.active.retain(|a| flags.contains(a.flag));
+        self.flags = Some(flags);
+        for event in &events {
+            self.events.push_back(event.clone());
+            if self.events.len() > EVENT_HISTORY {
+                self.events.pop_front();
+            }
+        }
+        events
+    }
+}
+
+/// Current flags and their transitions for a board, as shown in the web API
+#[derive(Debug, Clone, Serialize)]
+pub struct FlagHistory {
+    pub board: BoardId,
+    pub flags: Option<BoardFlags>,
+    pub bits: Option<u16>,
+    pub active: Vec<ActiveFlag>,
+    pub events: VecDeque<FlagEvent>,
+}
+
+/// Latest heater diagnosis for a board, as shown in the web API
+#[derive(Debug, Clone, Serialize)]
+pub struct HeaterHealth {
+    pub board: BoardId,
+    pub resistance: Option<f32>,
+    pub baseline: Option<f32>,
+    pub faults: Vec<HeaterFault>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HealthReport {
+    pub heaters: Vec<HeaterHealth>,
+    pub heater_events: VecDeque<HeaterEvent>,
+    pub flags: Vec<FlagHistory>,
+}
+
+/// Diagnosis shared between the supervisor thread and readers such as the web server
+#[derive(Debug, Clone, Default)]
+pub struct Health(Arc<Mutex<HealthReport>>);
+
+impl Health {
+    pub fn report(&self) -> HealthReport {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn faults(&self, board: BoardId) -> Vec<HeaterFault> {
+        self.0.lock().unwrap().heaters.iter()
+            .find(|h| h.board == board)
+            .map(|h| h.faults.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn flags(&self) -> Vec<FlagHistory> {
+        self.0.lock().unwrap().flags.clone()
+    }
+
+    pub(crate) fn update_flags(&self, monitors: &[FlagMonitor], events: Vec<FlagEvent>) {
+        for event in &events {
+            if event.is_fault() {
+                warn!("{}", event);
+            } else {
+                info!("{}", event);
+            }
+        }
+        self.0.lock().unwrap().flags = monitors.iter()
+            .map(|m| FlagHistory {
+                board: m.board,
+                flags: m.flags,
+                bits: m.flags.map(|f| f.bits()),
+                active: m.active.clone(),
+                events: m.events.clone(),
+            })
+            .collect();
+    }
+
+    pub(crate) fn update(&self, monitors: &[HeaterMonitor], events: Vec<HeaterEvent>) {
+        let mut report = self.0.lock().unwrap();
+        report.heaters = monitors.iter()
+            .map(|m| HeaterHealth {
+                board: m.board,
+                resistance: m.resistance,
+                baseline: m.baseline,
+                faults: m.faults.clone(),
+            })
+            .collect();
+        for event in events {
+            match event.kind {
+                HeaterEventKind::Raised(_) => warn!("{}", event),
+                HeaterEventKind::Cleared(_) => info!("{}", event),
+            }
+            report.heater_events.push_back(event);
+            if report.heater_events.len() > EVENT_HISTORY {
+                report.heater_events.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use chrono::Duration;
+
+    use crate::board::{BoardFlag, BoardFlags, BoardId, FLAG_MAX_TEMP, FLAG_ON};
+    use crate::diagnostics::{Baselines, diagnose, FlagChange, FlagMonitor, HeaterEventKind, HeaterFault, HeaterMeasurement, HeaterMonitor};
+    use crate::heater::HeaterMode;
+
+    fn measurement(mode: HeaterMode, duty: u16, voltage: f32, current: f32) -> HeaterMeasurement {
+        HeaterMeasurement { mode, duty, voltage, current }
+    }
+
+    #[test]
+    fn test_healthy_heater() {
+        assert_eq!(Vec::<HeaterFault>::new(), diagnose(&measurement(HeaterMode::OFF, 255, 0.0, 0.0), None));
+        assert_eq!(Vec::<HeaterFault>::new(), diagnose(&measurement(HeaterMode::PWM, 255, 4.5, 1.5), Some(3.0)));
+        // half duty halves the averaged voltage and current
+        assert_eq!(Vec::<HeaterFault>::new(), diagnose(&measurement(HeaterMode::PWM, 128, 2.25, 0.75), Some(3.0)));
+        // thermostat may have switched the heater off
+        assert_eq!(Vec::<HeaterFault>::new(), diagnose(&measurement(HeaterMode::PID, 255, 0.0, 0.0), None));
+    }
+
+    #[test]
+    fn test_open_circuit() {
+        assert_eq!(vec![HeaterFault::OpenCircuit { voltage: 5.0 }],
+                   diagnose(&measurement(HeaterMode::PWM, 255, 5.0, 0.0), None));
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        assert_eq!(vec![HeaterFault::ShortCircuit { resistance: 0.05 }],
+                   diagnose(&measurement(HeaterMode::PWM, 255, 0.1, 2.0), Some(3.0)));
+    }
+
+    #[test]
+    fn test_stuck_on() {
+        assert_eq!(vec![HeaterFault::StuckOn { voltage: 4.5, current: 1.5 }],
+                   diagnose(&measurement(HeaterMode::OFF, 255, 4.5, 1.5), None));
+    }
+
+    #[test]
+    fn test_no_current() {
+        assert_eq!(vec![HeaterFault::NoCurrent { duty: 255 }],
+                   diagnose(&measurement(HeaterMode::PWM, 255, 0.0, 0.0), None));
+        // too low a duty to tell
+        assert_eq!(Vec::<HeaterFault>::new(), diagnose(&measurement(HeaterMode::PWM, 10, 0.0, 0.0), None));
+    }
+
+    #[test]
+    fn test_resistance_drift() {
+        assert_eq!(vec![HeaterFault::ResistanceDrift { resistance: 4.0, baseline: 3.0 }],
+                   diagnose(&measurement(HeaterMode::PWM, 255, 4.0, 1.0), Some(3.0)));
+        assert_eq!(Vec::<HeaterFault>::new(), diagnose(&measurement(HeaterMode::PWM, 255, 3.3, 1.0), Some(3.0)));
+    }
+
+    #[test]
+    fn test_monitor_learns_baseline_and_raises_events() {
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut monitor = HeaterMonitor::new(BoardId::Top);
+        for _ in 0..5 {
+            assert!(monitor.update(&measurement(HeaterMode::PWM, 255, 4.5, 1.5), now).is_empty());
+        }
+        assert_eq!(Some(3.0), monitor.baseline().map(|b| (b * 100.0).round() / 100.0));
+
+        let events = monitor.update(&measurement(HeaterMode::PWM, 255, 6.0, 1.5), now);
+        assert_eq!(1, events.len());
+        assert!(matches!(&events[0].kind, HeaterEventKind::Raised(HeaterFault::ResistanceDrift { .. })));
+
+        // still drifted, so no new event
+        assert!(monitor.update(&measurement(HeaterMode::PWM, 255, 6.0, 1.5), now).is_empty());
+
+        let events = monitor.update(&measurement(HeaterMode::PWM, 255, 4.5, 1.5), now);
+        assert_eq!(1, events.len());
+        assert!(matches!(&events[0].kind, HeaterEventKind::Cleared(HeaterFault::ResistanceDrift { .. })));
+    }
+
+    #[test]
+    fn test_saved_baseline() {
+        let dir = tempfile::tempdir().unwrap();
+        let baselines = Baselines::new(dir.path().join("heater-baseline"));
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut monitor = HeaterMonitor::new(BoardId::Top).with_baselines(baselines.clone());
+        assert_eq!(None, monitor.baseline());
+        for _ in 0..5 {
+            monitor.update(&measurement(HeaterMode::PWM, 255, 4.5, 1.5), now);
+        }
+        assert_eq!(monitor.baseline(), baselines.read(BoardId::Top));
+        assert_eq!(None, baselines.read(BoardId::Bottom));
+
+        // after a restart, a drifted heater is compared with the saved baseline
+        let mut restarted = HeaterMonitor::new(BoardId::Top).with_baselines(baselines.clone());
+        assert_eq!(monitor.baseline(), restarted.baseline());
+        let events = restarted.update(&measurement(HeaterMode::PWM, 255, 6.0, 1.5), now);
+        assert!(matches!(&events[0].kind, HeaterEventKind::Raised(HeaterFault::ResistanceDrift { .. })));
+
+        std::fs::write(dir.path().join("heater-baseline/bottom.json"), "not a number").unwrap();
+        assert_eq!(None, baselines.read(BoardId::Bottom));
+    }
+
+    #[test]
+    fn test_flags_display() {
+        assert_eq!("OK", BoardFlags::from(FLAG_ON).to_string());
+        assert_eq!("ERR_MAX_TEMP", BoardFlags::from(FLAG_ON | FLAG_MAX_TEMP).to_string());
+        assert_eq!("ERR_NOT_ON", BoardFlags::from(0).to_string());
+        assert_eq!("ERR_NOT_ON|ERR_MAX_TEMP", BoardFlags::from(FLAG_MAX_TEMP).to_string());
+        assert_eq!("ERR_UNKNOWN_0x0010", BoardFlags::from(FLAG_ON | 0x10).to_string());
+        assert_eq!(vec![BoardFlag::On, BoardFlag::Unknown(4)],
+                   BoardFlags::from(FLAG_ON | 0x10).flags().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_flag_transitions() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut monitor = FlagMonitor::new(BoardId::Top);
+        assert!(monitor.update(BoardFlags::from(FLAG_ON), start).is_empty());
+
+        let events = monitor.update(BoardFlags::from(FLAG_ON | FLAG_MAX_TEMP), start + Duration::seconds(10));
+        assert_eq!(1, events.len());
+        assert_eq!(BoardFlag::MaxTemp, events[0].flag);
+        assert!(matches!(events[0].change, FlagChange::Set));
+        assert!(events[0].is_fault());
+
+        assert!(monitor.update(BoardFlags::from(FLAG_ON | FLAG_MAX_TEMP), start + Duration::seconds(20)).is_empty());
+
+        let events = monitor.update(BoardFlags::from(FLAG_ON), start + Duration::seconds(70));
+        assert_eq!(1, events.len());
+        assert!(matches!(events[0].change, FlagChange::Cleared { seconds: 60 }));
+        assert!(!events[0].is_fault());
+        assert_eq!(2, monitor.events.len());
+    }
+
+    #[test]
+    fn test_flags_already_latched_at_start() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut monitor = FlagMonitor::new(BoardId::Bottom);
+        let events = monitor.update(BoardFlags::from(FLAG_MAX_TEMP), start);
+        assert_eq!(2, events.len());
+        assert!(matches!((events[0].flag, &events[0].change), (BoardFlag::MaxTemp, FlagChange::Set)));
+        assert!(matches!((events[1].flag, &events[1].change), (BoardFlag::On, FlagChange::Cleared { seconds: 0 })));
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::{Mutex, MutexGuard, Once};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::Relaxed;
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+
+use crate::deadman::Deadman;
+use crate::heater::HeaterMode;
+use crate::payload::{Config, Payload};
+
+lazy_static! {
+    static ref LEASES: Mutex<Vec<Lease>> = Mutex::new(vec![]);
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static ABORT: AtomicBool = AtomicBool::new(false);
+static EXIT_ON_SIGNAL: AtomicBool = AtomicBool::new(true);
+static SIGNAL_HANDLER: Once = Once::new();
+static PANIC_HOOK: Once = Once::new();
+
+/// What happens to the heaters when a guard is dropped
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Release {
+    /// Switch the heaters off
+    Off,
+    /// Leave the heaters running, and have the safety supervisor switch them off at the deadline
+    Deadman(DateTime<Utc>),
+}
+
+#[derive(Debug, Clone)]
+struct Lease {
+    id: usize,
+    config: Config,
+    boards: Vec<u8>,
+    release: Release,
+}
+
+/// A lease on heaters, which switches them off when dropped, on panic, and optionally on
+/// SIGINT, SIGTERM or SIGHUP. Anything which switches heaters on should hold one.
+#[derive(Debug)]
+pub struct HeaterGuard {
+    id: usize,
+}
+
+impl HeaterGuard {
+    /// Guards the heaters on the given I2C buses
+    pub fn new(config: &Config, boards: &[u8]) -> Self {
+        PANIC_HOOK.call_once(install_panic_hook);
+        let id = NEXT_ID.fetch_add(1, Relaxed);
+        leases().push(Lease { id, config: config.clone(), boards: boards.to_vec(), release: Release::Off });
+        HeaterGuard { id }
+    }
+
+    /// Guards the heaters on every configured board
+    pub fn all(config: &Config) -> Self {
+        Self::new(config, &config.i2c_bus)
+    }
+
+    /// Switch the heaters off and exit on SIGINT, SIGTERM or SIGHUP. Not for use in
+    /// processes which handle signals themselves, e.g. the web server.
+    pub fn exit_on_signal(self) -> Self {
+        SIGNAL_HANDLER.call_once(install_signal_handler);
+        self
+    }
+
+    /// Switch the heaters off on SIGINT, SIGTERM or SIGHUP, but leave the caller to check
+    /// `is_aborted()` and finish up, e.g. the program runner
+    pub fn abort_on_signal(self) -> Self {
+        EXIT_ON_SIGNAL.store(false, Relaxed);
+        self.exit_on_signal()
+    }
+
+    /// Leave the heaters running when dropped, until the safety supervisor in another
+    /// process switches them off at the deadline
+    pub fn leave_running(&self, deadline: DateTime<Utc>) {
+        self.update(|lease| lease.release = Release::Deadman(deadline));
+    }
+
+    /// Adds a board to the lease, e.g. when a heater is switched on
+    pub fn add(&self, bus: u8) {
+        self.update(|lease| if !lease.boards.contains(&bus) { lease.boards.push(bus) });
+    }
+
+    /// Removes a board from the lease, e.g. when a heater is switched off
+    pub fn remove(&self, bus: u8) {
+        self.update(|lease| lease.boards.retain(|b| *b != bus));
+    }
+
+    fn update<F: FnOnce(&mut Lease)>(&self, op: F) {
+        if let Some(lease) = leases().iter_mut().find(|l| l.id == self.id) {
+            op(lease);
+        }
+    }
+}
+
+impl Drop for HeaterGuard {
+    fn drop(&mut self) {
+        let lease = {
+            let mut leases = leases();
+            let index = leases.iter().position(|l| l.id == self.id);
+            index.map(|i| leases.remove(i))
+        };
+        let lease = match lease {
+            Some(lease) => lease,
+            None => return,
+        };
+        match lease.release {
+            Release::Off => switch_off(&lease, "guard released"),
+            Release::Deadman(deadline) => {
+                let deadman = Deadman::from_config(&lease.config);
+                for bus in &lease.boards {
+                    info!("Leaving heater on board {} running until {}", bus, deadline);
+                    deadman.arm(*bus, deadline);
+                }
+            }
+        }
+    }
+}
+
+/// True once a signal has been received by a process using `abort_on_signal()`
+pub fn is_aborted() -> bool {
+    ABORT.load(Relaxed)
+}
+
+/// Leases are still usable after a panic while the lock was held, as they're needed to
+/// switch heaters off
+fn leases() -> MutexGuard<'static, Vec<Lease>> {
+    LEASES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn switch_off(lease: &Lease, reason: &str) {
+    if lease.boards.is_empty() {
+        return;
+    }
+    info!("Disabling heaters on boards {:?}: {}", lease.boards, reason);
+    let config = Config { i2c_bus: lease.boards.clone(), ..lease.config.clone() };
+    let deadman = Deadman::from_config(&config);
+    for board in &Payload::from_config(&config) {
+        board.write_heater_mode(HeaterMode::OFF);
+        deadman.disarm(board.bus.id);
+    }
+}
+
+/// Switches off every leased heater, regardless of release mode
+fn switch_off_all(reason: &str) {
+    let leases = leases().clone(); // don't hold the lock while writing to the boards
+    for lease in &leases {
+        switch_off(lease, reason);
+    }
+}
+
+fn install_signal_handler() {
+    let result = ctrlc::set_handler(|| {
+        ABORT.store(true, Relaxed);
+        switch_off_all("signal received");
+        if EXIT_ON_SIGNAL.load(Relaxed) {
+            std::process::exit(130);
+        }
+    });
+    if let Err(e) = result {
+        warn!("Failed to set signal handler, heaters won't be switched off on exit: {}", e);
+    }
+}
+
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        error!("Panic: {}", info);
+        switch_off_all("panic");
+    }));
+}
@@ -1,4 +1,5 @@
 use std::convert::TryFrom;
+use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 use crate::board::BoardFlags;
 use crate::reading::SensorReading;
@@ -68,15 +69,25 @@ impl TryFrom<u16> for HeaterMode {
     }
 }
 
-impl From<String> for TargetSensor {
-    fn from(value: String) -> Self {
-        match value.to_uppercase().as_str() {
-            "TH1" => TargetSensor::TH1,
-            "TH2" => TargetSensor::TH2,
-            "TH3" => TargetSensor::TH3,
-            "J7" => TargetSensor::J7,
-            "J8" => TargetSensor::J8,
-            _ => panic!("Unsupported target sensor: {}", value),
+impl TargetSensor {
+    /// Sensors the MSP430 thermostat can control on, or None for any other sensor
+    pub fn from_id(id: &str) -> Option<TargetSensor> {
+        match id.to_uppercase().as_str() {
+            "TH1" => Some(TargetSensor::TH1),
+            "TH2" => Some(TargetSensor::TH2),
+            "TH3" => Some(TargetSensor::TH3),
+            "J7" => Some(TargetSensor::J7),
+            "J8" => Some(TargetSensor::J8),
+            _ => None,
         }
     }
+}
+
+impl FromStr for TargetSensor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TargetSensor::from_id(s)
+            .ok_or_else(|| format!("Unsupported target sensor: {}, expected TH1, TH2, TH3, J7 or J8", s))
+    }
 }
\ No newline at end of file
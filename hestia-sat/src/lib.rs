@@ -8,13 +8,22 @@ use crate::csv::CsvData;
 // public modules
 pub mod board;
 pub mod payload;
+pub mod clock;
+pub mod control;
 pub mod csv;
+pub mod deadman;
+pub mod diagnostics;
+pub mod guard;
 pub mod heater;
 pub mod host;
 pub mod logger;
+pub mod power;
 pub mod reading;
+pub mod safety;
+pub mod selector;
 pub mod sensors;
 pub mod programs;
+pub mod thermal;
 pub mod zipper;
 
 // private modules
@@ -38,6 +47,10 @@ pub enum ReadError {
     /// Sensor is disabled
     #[fail(display = "Disabled")]
     Disabled,
+
+    /// No sensor with the requested ID
+    #[fail(display = "Unknown sensor")]
+    UnknownSensor,
 }
 
 /// Convert ReadErrors to cubeos_error::Error::ServiceError(u8)
@@ -65,6 +78,7 @@ impl PartialEq for ReadError {
             (ReadError::ValueOutOfRange, ReadError::ValueOutOfRange) => true,
             (ReadError::I2CError(_), ReadError::I2CError(_)) => true,
             (ReadError::Disabled, ReadError::Disabled) => true,
+            (ReadError::UnknownSensor, ReadError::UnknownSensor) => true,
             (_, _) => false,
         }
     }
@@ -1,21 +1,28 @@
 use std::fs;
 use std::path::Path;
-use chrono::{DateTime, Utc};
+use std::rc::Rc;
+use chrono::{DateTime, Duration, Utc};
 use log::info;
 use crate::board::BoardDataProvider;
+use crate::clock::{Clock, SystemClock};
 use crate::csv::CsvWriter;
 use crate::payload::Payload;
+use crate::programs::active::{ActiveProgram, ActiveProgramFile};
 
 pub struct LogWriter<'a> {
     writer: CsvWriter,
     raw_writer: Option<CsvWriter>,
     payload: &'a Payload,
+    active: Option<ActiveProgramFile>,
+    /// Day of the log files, which are started again each day
+    start_date: Option<DateTime<Utc>>,
+    clock: Rc<dyn Clock>,
 }
 
 impl<'a> LogWriter<'a> {
     pub fn create_stdout_writer(payload: &'a Payload) -> LogWriter<'a> {
         let writer = CsvWriter::stdout();
-        LogWriter { writer, raw_writer: None, payload }
+        LogWriter { writer, raw_writer: None, payload, active: None, start_date: None, clock: Rc::new(SystemClock) }
     }
 
     pub fn create_file_writer(path: &String, payload: &'a Payload, start_date: &DateTime<Utc>) -> LogWriter<'a> {
@@ -25,7 +32,26 @@ impl<'a> LogWriter<'a> {
         let writer = Self::new_csv_writer(start_date, log_path, false);
         let raw_writer = Self::new_csv_writer(start_date, log_path, true);
 
-        LogWriter { writer, raw_writer: Some(raw_writer), payload }
+        LogWriter {
+            writer,
+            raw_writer: Some(raw_writer),
+            payload,
+            active: None,
+            start_date: Some(*start_date),
+            clock: Rc::new(SystemClock),
+        }
+    }
+
+    /// Tags each row with the program uts-run is running, otherwise rows are tagged as idle
+    pub fn with_active_program(mut self, active: ActiveProgramFile) -> Self {
+        self.active = Some(active);
+        self
+    }
+
+    /// Takes timestamps from the clock and sleeps on it between rows, instead of the system clock
+    pub fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
     }
 
     fn new_csv_writer(start_date: &DateTime<Utc>, log_path: &Path, raw_log: bool) -> CsvWriter {
@@ -49,12 +75,54 @@ impl<'a> LogWriter<'a> {
 
     pub fn write_data(&mut self, timestamp: DateTime<Utc>) {
         for board in self.payload {
+            let active = match &self.active {
+                Some(active) => active.current_on(board.id, timestamp),
+                None => ActiveProgram::idle(timestamp),
+            };
             if let Some(data) = board.read_data() {
-                self.writer.write_display_data(timestamp, board, &data);
+                self.writer.write_display_data(timestamp, board, &data, &active);
                 if let Some(raw_writer) = &mut self.raw_writer {
-                    raw_writer.write_raw_data(timestamp, board, &data.get_raw_data());
+                    raw_writer.write_raw_data(timestamp, board, &data.get_raw_data(), &active);
                 }
             }
         }
     }
+
+    /// Writes the readings every interval until the day changes, when the log files should be
+    /// started again for the new day. Logging to stdout carries on indefinitely.
+    pub fn run(&mut self, interval: Duration) {
+        loop {
+            self.write_data(self.clock.now());
+            self.clock.sleep(interval);
+            if matches!(self.start_date, Some(start_date) if self.clock.now().date_naive() != start_date.date_naive()) {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::clock::{Clock, SimulatedClock};
+    use crate::logger::LogWriter;
+    use crate::payload::Payload;
+
+    #[test]
+    fn test_run_until_new_day() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_string_lossy().to_string();
+        let payload = Payload::from_boards(vec![]);
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 23, 59, 0).unwrap();
+        let clock = Rc::new(SimulatedClock::new(start));
+        let mut writer = LogWriter::create_file_writer(&path, &payload, &start)
+            .with_clock(clock.clone());
+        writer.write_header_if_new();
+        writer.run(Duration::seconds(20));
+        assert_eq!(Utc.with_ymd_and_hms(2023, 9, 2, 0, 0, 0).unwrap(), clock.now());
+        assert!(dir.path().join("uts-data-2023-09-01.csv").exists());
+    }
 }
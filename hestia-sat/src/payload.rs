@@ -1,11 +1,14 @@
 use std::convert::TryFrom;
 use std::ops::Index;
+use std::rc::Rc;
 use std::slice::Iter;
 use dotenv::dotenv;
 use log::LevelFilter;
 use serde::Deserialize;
 use syslog::Facility;
 use crate::board::{Board, BoardId, BoardVersion};
+use crate::power::PowerBudget;
+use crate::programs::checkpoint::ResumePolicy;
 
 fn default_i2c_bus() -> Vec<u8> { vec![1, 2] }
 
@@ -15,6 +18,28 @@ fn default_http_port() -> u16 { 5000 }
 
 fn default_board_version() -> BoardVersion { BoardVersion::V2_2 }
 
+fn default_power_budget() -> f32 { 15.0 }
+
+fn default_heater_power() -> f32 { 15.0 }
+
+fn default_safety_enable() -> bool { true }
+
+fn default_safety_max_temp() -> f32 { 105.0 }
+
+fn default_safety_stale_timeout() -> u16 { 30 }
+
+fn default_safety_max_failures() -> u32 { 5 }
+
+fn default_safety_interval() -> u16 { 2 }
+
+fn default_deadman_timeout() -> u32 { 60 }
+
+fn default_state_path() -> String { String::from("/var/tmp/uts") }
+
+fn default_ambient_temp() -> f32 { 25.0 }
+
+fn default_resume_policy() -> ResumePolicy { ResumePolicy::Resume }
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     /// Log file directory
@@ -55,6 +80,60 @@ pub struct Config {
     /// Send error logging to syslog instead of console
     #[serde(default)]
     pub syslog: bool,
+
+    /// Maximum combined heater power in W across all boards, or 0 for no limit. The default
+    /// allows one heater at full power at a time, as before there was a budget.
+    #[serde(default = "default_power_budget")]
+    pub power_budget: f32,
+
+    /// Heater power at 100% duty in W, used to predict power against the budget
+    #[serde(default = "default_heater_power")]
+    pub heater_power: f32,
+
+    /// Run the heater safety supervisor in processes which control the heaters
+    #[serde(default = "default_safety_enable")]
+    pub safety_enable: bool,
+
+    /// Heaters are switched off if any temperature sensor reaches this in °C
+    #[serde(default = "default_safety_max_temp")]
+    pub safety_max_temp: f32,
+
+    /// Heaters are switched off if a board can't be read for this many seconds
+    #[serde(default = "default_safety_stale_timeout")]
+    pub safety_stale_timeout: u16,
+
+    /// Heaters are switched off after this many consecutive failed board reads
+    #[serde(default = "default_safety_max_failures")]
+    pub safety_max_failures: u32,
+
+    /// Duration between safety checks in seconds
+    #[serde(default = "default_safety_interval")]
+    pub safety_interval: u16,
+
+    /// Longest time in minutes a heater switched on manually is left on, unless a
+    /// timeout is given with the command
+    #[serde(default = "default_deadman_timeout")]
+    pub deadman_timeout: u32,
+
+    /// Directory for state kept across processes, e.g. heater deadman deadlines and resistance
+    /// baselines
+    #[serde(default = "default_state_path")]
+    pub state_path: String,
+
+    /// Expected ambient temperature in °C, which programs can't cool below
+    #[serde(default = "default_ambient_temp")]
+    pub ambient_temp: f32,
+
+    /// Thermal models of the boards for forecasting programs, see `uts-cli fit-model`
+    pub thermal_model_file: Option<String>,
+
+    /// What uts-run does after being interrupted part way through the programs
+    #[serde(default = "default_resume_policy")]
+    pub resume_policy: ResumePolicy,
+
+    /// Start from the first program if interrupted more than this many minutes ago, or 0 for no limit
+    #[serde(default)]
+    pub resume_max_age: u32,
 }
 
 impl Config {
@@ -91,10 +170,11 @@ impl Payload {
                 panic!("Configured with unknown board ID: {}", bus);
             }
         }
-        Self::from_boards(boards)
+        let budget = PowerBudget::from_config(config, &boards).map(Rc::new);
+        Self::from_boards(boards.into_iter().map(|board| board.with_budget(budget.clone())).collect())
     }
 
-    fn from_boards(boards: Vec<Board>) -> Payload {
+    pub(crate) fn from_boards(boards: Vec<Board>) -> Payload {
         Self { boards }
     }
 
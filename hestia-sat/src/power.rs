@@ -0,0 +1,167 @@
+use log::{debug, warn};
+
+use crate::board::{Board, BoardId};
+use crate::control::pid::PWM_DUTY_MAX;
+use crate::heater::HeaterMode;
+use crate::payload::Config;
+
+/// Combined heater power allowed across all boards, enforced whenever a heater is
+/// switched on or its duty changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerBudget {
+    /// Maximum total heater power in W
+    pub limit: f32,
+
+    /// Power of one heater at 100% duty in W, used to predict power before it's measured
+    pub heater_power: f32,
+
+    /// Boards whose heaters count towards the budget, read without a budget of their own
+    boards: Vec<Board>,
+}
+
+/// Outcome of checking a heater request against the power budget
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BudgetDecision {
+    Allowed,
+    /// Allowed at a lower PWM duty cycle
+    Derated(u16),
+    Rejected,
+}
+
+impl PowerBudget {
+    /// Keeps copies of the payload's boards, made before they're given the budget, to read the
+    /// other heaters on every write. Returns None if no budget is configured.
+    pub fn from_config(config: &Config, boards: &[Board]) -> Option<PowerBudget> {
+        let limit = config.power_budget;
+        if limit <= 0.0 {
+            return None;
+        }
+        Some(PowerBudget {
+            limit,
+            heater_power: config.heater_power,
+            boards: boards.to_vec(),
+        })
+    }
+
+    /// Worst case power for a heater mode and duty. The firmware thermostat can use full power.
+    pub fn predicted_power(&self, mode: HeaterMode, duty: u16) -> f32 {
+        match mode {
+            HeaterMode::OFF => 0.0,
+            HeaterMode::PID => self.heater_power,
+            HeaterMode::PWM => self.heater_power * f32::from(duty.min(PWM_DUTY_MAX)) / f32::from(PWM_DUTY_MAX),
+        }
+    }
+
+    /// Checks a heater request on one board against the power used by the others
+    pub fn check(&self, board: BoardId, mode: HeaterMode, duty: u16) -> BudgetDecision {
+        let used = self.used_by_others(board);
+        let decision = self.decide(self.limit - used, mode, duty);
+        match decision {
+            BudgetDecision::Allowed => debug!("{} board {} duty {} within power budget ({:.2} W used of {:.2} W)",
+                board, mode, duty, used, self.limit),
+            BudgetDecision::Derated(derated) => warn!(
+                "Power budget exceeded on {} board ({:.2} W used of {:.2} W), derating duty from {} to {}",
+                board, used, self.limit, duty, derated),
+            BudgetDecision::Rejected => warn!(
+                "Power budget exceeded on {} board ({:.2} W used of {:.2} W), rejecting heater {} at duty {}",
+                board, used, self.limit, mode, duty),
+        }
+        decision
+    }
+
+    /// Whether a heater request would be allowed, possibly derated, without logging the decision
+    pub fn allows(&self, board: BoardId, mode: HeaterMode, duty: u16) -> bool {
+        self.decide(self.limit - self.used_by_others(board), mode, duty) != BudgetDecision::Rejected
+    }
+
+    fn used_by_others(&self, board: BoardId) -> f32 {
+        self.boards.iter()
+            .filter(|other| other.id != board)
+            .map(|other| self.read_power(other))
+            .sum()
+    }
+
+    fn decide(&self, available: f32, mode: HeaterMode, duty: u16) -> BudgetDecision {
+        if self.predicted_power(mode, duty) <= available {
+            return BudgetDecision::Allowed;
+        }
+        if mode != HeaterMode::PWM || self.heater_power <= 0.0 {
+            return BudgetDecision::Rejected;
+        }
+        let derated = (available.max(0.0) / self.heater_power * f32::from(PWM_DUTY_MAX)).floor() as u16;
+        if derated == 0 {
+            BudgetDecision::Rejected
+        } else {
+            BudgetDecision::Derated(derated)
+        }
+    }
+
+    /// Power used by a board, the larger of the measured and predicted power
+    fn read_power(&self, board: &Board) -> f32 {
+        if !board.bus.exists() {
+            return 0.0;
+        }
+        let mode = match board.read_heater_mode() {
+            Ok(mode) => mode.display_value,
+            // assume the worst if we can't tell what the heater is doing
+            Err(_) => return self.heater_power,
+        };
+        if mode == HeaterMode::OFF {
+            return 0.0;
+        }
+        let duty = board.read_heater_duty().map(|d| d.display_value).unwrap_or(PWM_DUTY_MAX);
+        let predicted = self.predicted_power(mode, duty);
+        let measured = board.read_heater_power().unwrap_or(0.0);
+        predicted.max(measured)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+
+    use crate::board::{Board, BoardId, BoardVersion};
+    use crate::heater::HeaterMode;
+    use crate::power::{BudgetDecision, PowerBudget};
+
+    fn budget(limit: f32) -> PowerBudget {
+        PowerBudget {
+            limit,
+            heater_power: 15.0,
+            boards: vec![Board::new(BoardId::Top, BoardVersion::V2_2), Board::new(BoardId::Bottom, BoardVersion::V2_2)],
+        }
+    }
+
+    #[test]
+    fn test_predicted_power() {
+        let budget = budget(15.0);
+        assert_eq!(0.0, budget.predicted_power(HeaterMode::OFF, 255));
+        assert_eq!(15.0, budget.predicted_power(HeaterMode::PID, 0));
+        assert_eq!(15.0, budget.predicted_power(HeaterMode::PWM, 255));
+        assert_approx_eq!(7.5, budget.predicted_power(HeaterMode::PWM, 127), 0.05);
+    }
+
+    #[test]
+    fn test_within_budget() {
+        let budget = budget(20.0);
+        assert_eq!(BudgetDecision::Allowed, budget.decide(20.0, HeaterMode::PWM, 255));
+        assert_eq!(BudgetDecision::Allowed, budget.decide(20.0, HeaterMode::PID, 255));
+        assert_eq!(BudgetDecision::Allowed, budget.decide(0.0, HeaterMode::OFF, 255));
+    }
+
+    #[test]
+    fn test_pwm_derated() {
+        let budget = budget(20.0);
+        // another board is already using 15 W
+        assert_eq!(BudgetDecision::Derated(85), budget.decide(5.0, HeaterMode::PWM, 255));
+        assert_eq!(BudgetDecision::Allowed, budget.decide(5.0, HeaterMode::PWM, 85));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let budget = budget(20.0);
+        assert_eq!(BudgetDecision::Rejected, budget.decide(5.0, HeaterMode::PID, 255));
+        assert_eq!(BudgetDecision::Rejected, budget.decide(0.0, HeaterMode::PWM, 255));
+        assert_eq!(BudgetDecision::Rejected, budget.decide(-1.0, HeaterMode::PWM, 10));
+    }
+}
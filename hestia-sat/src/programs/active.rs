@@ -0,0 +1,136 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::board::BoardId;
+use crate::payload::Config;
+
+const ACTIVE_FILE: &str = "active.json";
+/// uts-run updates the file more often than this while it's running, so an older file was left
+/// by a runner that was killed
+const STALE_AFTER: Duration = Duration::minutes(2);
+
+/// The program uts-run is running and its phase, shared with uts-log to tag the CSV rows
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActiveProgram {
+    pub program_id: Option<u8>,
+    pub program_name: Option<String>,
+    /// Runner phase, e.g. heating or cooling, or idle when no program is running
+    pub phase: String,
+    pub time: String,
+}
+
+impl ActiveProgram {
+    pub fn idle(now: DateTime<Utc>) -> Self {
+        ActiveProgram { program_id: None, program_name: None, phase: String::from("idle"), time: now.to_rfc3339() }
+    }
+
+    fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        match DateTime::parse_from_rfc3339(&self.time) {
+            Ok(time) => now - time.with_timezone(&Utc) > STALE_AFTER,
+            Err(_) => true,
+        }
+    }
+}
+
+/// Saves the active program to UTS_STATE_PATH, where other processes can read it
+#[derive(Debug, Clone)]
+pub struct ActiveProgramFile {
+    path: PathBuf,
+}
+
+impl ActiveProgramFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        ActiveProgramFile { path: path.as_ref().to_path_buf() }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(Path::new(&config.state_path).join(ACTIVE_FILE))
+    }
+
+    /// The file for the track of programs on one board, with `parallel = true`
+    pub fn for_board(&self, board: BoardId) -> Self {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        Self::new(self.path.with_file_name(format!("{}-{}.json", stem, board)))
+    }
+
+    /// None if there's no file or it can't be read
+    pub fn read(&self) -> Option<ActiveProgram> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read active program file {:?}: {}", self.path, e);
+                return None;
+            }
+        };
+        serde_json::from_str(&contents)
+            .map_err(|e| warn!("Invalid active program file {:?}: {}", self.path, e))
+            .ok()
+    }
+
+    /// The active program, or idle if uts-run isn't running or hasn't updated the file recently
+    pub fn current(&self, now: DateTime<Utc>) -> ActiveProgram {
+        self.read()
+            .filter(|active| !active.is_stale(now))
+            .unwrap_or_else(|| ActiveProgram::idle(now))
+    }
+
+    /// The active program on the board's track if programs run in parallel, otherwise the
+    /// active program
+    pub fn current_on(&self, board: BoardId, now: DateTime<Utc>) -> ActiveProgram {
+        self.for_board(board).read()
+            .filter(|active| !active.is_stale(now))
+            .unwrap_or_else(|| self.current(now))
+    }
+
+    pub fn write(&self, active: &ActiveProgram) {
+        let result = self.path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                // write then rename, so the logger never reads a partial file
+                let tmp = self.path.with_extension("tmp");
+                fs::write(&tmp, serde_json::to_string(active)?)?;
+                fs::rename(&tmp, &self.path)
+            });
+        if let Err(e) = result {
+            warn!("Failed to write active program file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::board::BoardId;
+    use crate::programs::active::{ActiveProgram, ActiveProgramFile};
+
+    #[test]
+    fn test_current() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = ActiveProgramFile::new(dir.path().join("state/active.json"));
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 1, 0, 0).unwrap();
+        assert_eq!(ActiveProgram::idle(now), file.current(now));
+
+        let active = ActiveProgram {
+            program_id: Some(2),
+            program_name: Some(String::from("Top soak")),
+            phase: String::from("heating"),
+            time: now.to_rfc3339(),
+        };
+        file.write(&active);
+        assert_eq!(active, file.current(now + Duration::seconds(30)));
+        // left by a runner that was killed
+        let later = now + Duration::minutes(10);
+        assert_eq!(ActiveProgram::idle(later), file.current(later));
+
+        let bottom = ActiveProgram { phase: String::from("cooling"), ..active.clone() };
+        file.for_board(BoardId::Bottom).write(&bottom);
+        assert_eq!(bottom, file.current_on(BoardId::Bottom, now));
+        assert_eq!(active, file.current_on(BoardId::Top, now));
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::board::BoardId;
+use crate::payload::Config;
+use crate::programs::Programs;
+
+const CHECKPOINT_FILE: &str = "runner.json";
+
+/// What uts-run does with a checkpoint left by a run that was interrupted, configured with
+/// UTS_RESUME_POLICY
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResumePolicy {
+    /// Continue the interrupted step for the rest of its time
+    Resume,
+    /// Start the interrupted step again with its full time
+    RestartStep,
+    /// Start the interrupted program again from its first step
+    RestartProgram,
+    /// Ignore the checkpoint and start again from the first program
+    Restart,
+}
+
+/// Progress through the programs, saved so a run can be resumed after a reboot or power cycle
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Position of the program in `Programs::sequence()`, or its board's track with `parallel = true`
+    pub program_index: usize,
+    pub program_id: u8,
+    pub program_name: String,
+    /// Index of the step in `Program::steps()`
+    pub step: usize,
+    pub phase: String,
+    /// Time spent on the step while running, e.g. the elapsed heat time
+    pub elapsed_secs: i64,
+    /// Set if the program was aborted and is cooling before it finishes
+    pub aborted: bool,
+    /// Set if paused by a command, so the program stays paused with the heater off
+    #[serde(default)]
+    pub paused: bool,
+    /// Number of completed runs through the programs with `loop = true`
+    pub iteration: u32,
+    pub time: String,
+}
+
+/// Where to start running the programs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resume {
+    /// Start from the first program
+    Start,
+    /// Start the program at this position in the sequence from its first step, paused if
+    /// it was paused by a command
+    Program { index: usize, iteration: u32, paused: bool },
+    /// Continue a step of the program at this position, after the time already spent on it
+    Step { index: usize, step: usize, elapsed: Duration, aborted: bool, iteration: u32, paused: bool },
+}
+
+impl Resume {
+    pub fn iteration(&self) -> u32 {
+        match self {
+            Resume::Start => 0,
+            Resume::Program { iteration, .. } | Resume::Step { iteration, .. } => *iteration,
+        }
+    }
+}
+
+/// Saves the runner's progress to UTS_STATE_PATH, and decides where to start from it
+#[derive(Debug, Clone)]
+pub struct Checkpoints {
+    path: PathBuf,
+    policy: ResumePolicy,
+    /// Checkpoints older than this are ignored
+    max_age: Option<Duration>,
+    /// Set for the checkpoints of one board's track
+    board: Option<BoardId>,
+}
+
+impl Checkpoints {
+    pub fn new<P: AsRef<Path>>(path: P, policy: ResumePolicy, max_age: Option<Duration>) -> Self {
+        Checkpoints { path: path.as_ref().to_path_buf(), policy, max_age, board: None }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let max_age = Some(Duration::minutes(config.resume_max_age as i64)).filter(|age| !age.is_zero());
+        Self::new(Path::new(&config.state_path).join(CHECKPOINT_FILE), config.resume_policy, max_age)
+    }
+
+    /// Checkpoints for the track of programs on one board, saved in a separate file
+    pub fn for_board(&self, board: BoardId) -> Self {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let path = self.path.with_file_name(format!("{}-{}.json", stem, board));
+        Checkpoints { path, board: Some(board), ..self.clone() }
+    }
+
+    /// None if there's no checkpoint or it can't be read
+    pub fn read(&self) -> Option<Checkpoint> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read checkpoint file {:?}: {}", self.path, e);
+                return None;
+            }
+        };
+        serde_json::from_str(&contents)
+            .map_err(|e| warn!("Invalid checkpoint file {:?}: {}", self.path, e))
+            .ok()
+    }
+
+    pub fn write(&self, checkpoint: &Checkpoint) {
+        let result = self.path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                // write then rename, so a power cycle never leaves a partial file
+                let tmp = self.path.with_extension("tmp");
+                fs::write(&tmp, serde_json::to_string(checkpoint)?)?;
+                fs::rename(&tmp, &self.path)
+            });
+        if let Err(e) = result {
+            warn!("Failed to write checkpoint file {:?}: {}", self.path, e);
+        }
+    }
+
+    /// Removes the checkpoint once the programs have completed
+    pub fn clear(&self) {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound =>
+                warn!("Failed to remove checkpoint file {:?}: {}", self.path, e),
+            _ => {}
+        }
+    }
+
+    /// Decides where to start from the checkpoint and the resume policy, logging the decision
+    pub fn resume(&self, programs: &Programs, now: DateTime<Utc>) -> Resume {
+        let checkpoint = match self.read() {
+            Some(checkpoint) => checkpoint,
+            None => {
+                info!("No checkpoint, starting from the first program");
+                return Resume::Start;
+            }
+        };
+        let sequence = match self.board {
+            Some(board) => programs.track(board),
+            None => programs.sequence(),
+        };
+        let program = sequence.get(checkpoint.program_index)
+            .filter(|p| p.name == checkpoint.program_name);
+        let program = match program {
+            Some(program) if checkpoint.step < program.steps().len() => program,
+            _ => {
+                warn!("Checkpoint doesn't match the program file, starting from the first program: {:?}", checkpoint);
+                return Resume::Start;
+            }
+        };
+        let saved = DateTime::parse_from_rfc3339(&checkpoint.time).map(|t| t.with_timezone(&Utc));
+        if let (Some(max_age), Ok(saved)) = (self.max_age, saved) {
+            if now - saved > max_age {
+                info!("Checkpoint from {} is too old, starting from the first program", saved);
+                return Resume::Start;
+            }
+        }
+        let (index, iteration, paused) = (checkpoint.program_index, checkpoint.iteration, checkpoint.paused);
+        let elapsed = Duration::seconds(checkpoint.elapsed_secs);
+        let resume = match self.policy {
+            // the program hadn't started, so it waits for its start time again
+            _ if checkpoint.phase == "scheduled" && self.policy != ResumePolicy::Restart =>
+                Resume::Program { index, iteration, paused },
+            ResumePolicy::Resume =>
+                Resume::Step { index, step: checkpoint.step, elapsed, aborted: checkpoint.aborted, iteration, paused },
+            ResumePolicy::RestartStep => Resume::Step {
+                index, step: checkpoint.step, elapsed: Duration::zero(), aborted: checkpoint.aborted, iteration, paused,
+            },
+            ResumePolicy::RestartProgram => Resume::Program { index, iteration, paused },
+            ResumePolicy::Restart => Resume::Start,
+        };
+        info!("Interrupted at step {} ({}) of {} after {}s, iteration {}, resume policy {:?}: {:?}",
+              checkpoint.step + 1, checkpoint.phase, program, checkpoint.elapsed_secs, iteration,
+              self.policy, resume);
+        resume
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::board::BoardId;
+    use crate::programs::checkpoint::{Checkpoint, Checkpoints, Resume, ResumePolicy};
+    use crate::programs::Programs;
+
+    fn programs() -> Programs {
+        toml::from_str(r#"
+            [[programs]]
+            name = "First"
+            heat_board = "Top"
+            heat_time = "10m"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+            cool_temp = 40.0
+
+            [[programs]]
+            name = "Second"
+            heat_board = "Bottom"
+            heat_time = "10m"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+            cool_temp = 40.0
+        "#).unwrap()
+    }
+
+    fn checkpoint(name: &str, step: usize) -> Checkpoint {
+        Checkpoint {
+            program_index: 1,
+            program_id: 1,
+            program_name: String::from(name),
+            step,
+            phase: String::from("heating"),
+            elapsed_secs: 120,
+            aborted: false,
+            paused: false,
+            iteration: 2,
+            time: Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap().to_rfc3339(),
+        }
+    }
+
+    #[test]
+    fn test_write_and_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoints = Checkpoints::new(dir.path().join("state/runner.json"), ResumePolicy::Resume, None);
+        assert_eq!(None, checkpoints.read());
+        checkpoints.write(&checkpoint("Second", 0));
+        assert_eq!(Some(checkpoint("Second", 0)), checkpoints.read());
+        checkpoints.clear();
+        assert_eq!(None, checkpoints.read());
+        checkpoints.clear();
+    }
+
+    #[test]
+    fn test_resume_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("runner.json");
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 1, 0, 0).unwrap();
+        let resume = |policy, max_age| Checkpoints::new(&path, policy, max_age).resume(&programs(), now);
+        assert_eq!(Resume::Start, resume(ResumePolicy::Resume, None));
+
+        Checkpoints::new(&path, ResumePolicy::Resume, None).write(&checkpoint("Second", 0));
+        assert_eq!(Resume::Step { index: 1, step: 0, elapsed: Duration::minutes(2), aborted: false, iteration: 2, paused: false },
+                   resume(ResumePolicy::Resume, None));
+        assert_eq!(Resume::Step { index: 1, step: 0, elapsed: Duration::zero(), aborted: false, iteration: 2, paused: false },
+                   resume(ResumePolicy::RestartStep, None));
+        assert_eq!(Resume::Program { index: 1, iteration: 2, paused: false }, resume(ResumePolicy::RestartProgram, None));
+        assert_eq!(Resume::Start, resume(ResumePolicy::Restart, None));
+        assert_eq!(Resume::Start, resume(ResumePolicy::Resume, Some(Duration::minutes(30))));
+
+        // the program file has changed since the checkpoint
+        Checkpoints::new(&path, ResumePolicy::Resume, None).write(&checkpoint("Renamed", 0));
+        assert_eq!(Resume::Start, resume(ResumePolicy::Resume, None));
+        Checkpoints::new(&path, ResumePolicy::Resume, None).write(&checkpoint("Second", 2));
+        assert_eq!(Resume::Start, resume(ResumePolicy::Resume, None));
+
+        // waiting for its start time, so it's scheduled again
+        let scheduled = Checkpoint { phase: String::from("scheduled"), ..checkpoint("Second", 0) };
+        Checkpoints::new(&path, ResumePolicy::Resume, None).write(&scheduled);
+        assert_eq!(Resume::Program { index: 1, iteration: 2, paused: false }, resume(ResumePolicy::Resume, None));
+
+        // paused by a command, so it stays paused whether the step or program is started again
+        let paused = Checkpoint { paused: true, ..checkpoint("Second", 0) };
+        Checkpoints::new(&path, ResumePolicy::Resume, None).write(&paused);
+        assert!(matches!(resume(ResumePolicy::Resume, None), Resume::Step { paused: true, .. }));
+        assert_eq!(Resume::Program { index: 1, iteration: 2, paused: true }, resume(ResumePolicy::RestartProgram, None));
+        assert_eq!(Resume::Start, resume(ResumePolicy::Restart, None));
+    }
+
+    #[test]
+    fn test_checkpoint_without_paused() {
+        // written before pauses were saved
+        let saved: Checkpoint = serde_json::from_str(r#"{"program_index": 1, "program_id": 1,
+            "program_name": "Second", "step": 0, "phase": "heating", "elapsed_secs": 120, "aborted": false,
+            "iteration": 2, "time": "2023-09-01T00:00:00+00:00"}"#).unwrap();
+        assert_eq!(checkpoint("Second", 0), saved);
+    }
+
+    #[test]
+    fn test_track_checkpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 1, 0, 0).unwrap();
+        let checkpoints = Checkpoints::new(dir.path().join("runner.json"), ResumePolicy::Resume, None);
+        let bottom = checkpoints.for_board(BoardId::Bottom);
+        bottom.write(&Checkpoint { program_index: 0, ..checkpoint("Second", 0) });
+        assert!(dir.path().join("runner-bottom.json").exists());
+        assert_eq!(None, checkpoints.read());
+        // the index is in the board's track, which only has the second program
+        assert!(matches!(bottom.resume(&programs(), now), Resume::Step { index: 0, .. }));
+        assert_eq!(Resume::Start, checkpoints.for_board(BoardId::Top).resume(&programs(), now));
+    }
+}
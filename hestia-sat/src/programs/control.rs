@@ -0,0 +1,206 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::board::BoardId;
+use crate::payload::Config;
+
+const CONTROL_DIR: &str = "control";
+const AUDIT_FILE: &str = "control.log";
+/// Commands not picked up by uts-run within this time are ignored, so a command sent while
+/// it wasn't running doesn't take effect when it next starts
+const COMMAND_EXPIRY: Duration = Duration::minutes(1);
+
+/// A command for the programs uts-run is running
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    /// Switch the heater off and freeze the step's timers
+    Pause,
+    /// Continue a paused step for the rest of its time
+    Resume,
+    /// Finish the current program and start the next
+    Skip,
+    /// Switch the heaters off and stop running programs
+    Abort,
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Pause => write!(f, "pause"),
+            Command::Resume => write!(f, "resume"),
+            Command::Skip => write!(f, "skip"),
+            Command::Abort => write!(f, "abort"),
+        }
+    }
+}
+
+/// A command waiting for uts-run to apply it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlRequest {
+    pub id: String,
+    pub command: Command,
+    /// Only the track for this board, with `parallel = true`, otherwise every program running
+    pub board: Option<BoardId>,
+    /// Who sent the command, e.g. the address of the uts-web client
+    pub source: String,
+    pub time: String,
+}
+
+/// What happened to a command, recorded one JSON object per line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlAudit {
+    pub id: String,
+    pub command: Command,
+    pub board: Option<BoardId>,
+    pub source: String,
+    /// requested, applied, or ignored with the reason
+    pub outcome: String,
+    pub time: String,
+}
+
+/// Commands sent to uts-run through files in UTS_STATE_PATH, one file per command so none are
+/// lost if several are sent at once. Every command and its outcome is audited in control.log.
+#[derive(Debug, Clone)]
+pub struct ControlChannel {
+    path: PathBuf,
+}
+
+impl ControlChannel {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        ControlChannel { path: path.as_ref().to_path_buf() }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(&config.state_path)
+    }
+
+    fn commands_path(&self) -> PathBuf {
+        self.path.join(CONTROL_DIR)
+    }
+
+    fn audit_path(&self) -> PathBuf {
+        self.path.join(AUDIT_FILE)
+    }
+
+    /// Queues a command for uts-run, returning the request, or None if it couldn't be saved
+    pub fn send(&self, command: Command, board: Option<BoardId>, source: &str, now: DateTime<Utc>) -> Option<ControlRequest> {
+        let id = now.format("%Y%m%dT%H%M%S%.6f").to_string();
+        let request = ControlRequest { id, command, board, source: String::from(source), time: now.to_rfc3339() };
+        let file = self.commands_path().join(format!("{}.json", request.id));
+        let result = fs::create_dir_all(self.commands_path())
+            .and_then(|_| {
+                // write then rename, so uts-run never reads a partial command
+                let tmp = file.with_extension("tmp");
+                fs::write(&tmp, serde_json::to_string(&request)?)?;
+                fs::rename(&tmp, &file)
+            });
+        match result {
+            Ok(()) => {
+                info!("Sent {} command from {}", command, source);
+                self.audit(&request, "requested", now);
+                Some(request)
+            }
+            Err(e) => {
+                warn!("Failed to send {} command to {:?}: {}", command, file, e);
+                None
+            }
+        }
+    }
+
+    /// Removes the queued commands and returns them in the order they were sent, ignoring
+    /// expired ones
+    pub fn take(&self, now: DateTime<Utc>) -> Vec<ControlRequest> {
+        let pattern = self.commands_path().join("*.json");
+        let mut files: Vec<PathBuf> = match glob::glob(&pattern.to_string_lossy()) {
+            Ok(files) => files.flatten().collect(),
+            Err(_) => return vec![],
+        };
+        files.sort();
+        let mut requests = vec![];
+        for file in files {
+            let request = fs::read_to_string(&file).ok()
+                .and_then(|contents| serde_json::from_str::<ControlRequest>(&contents).ok());
+            if let Err(e) = fs::remove_file(&file) {
+                warn!("Failed to remove command file {:?}: {}", file, e);
+            }
+            let request = match request {
+                Some(request) => request,
+                None => {
+                    warn!("Ignoring invalid command file {:?}", file);
+                    continue;
+                }
+            };
+            let sent = DateTime::parse_from_rfc3339(&request.time).map(|t| t.with_timezone(&Utc));
+            if matches!(sent, Ok(sent) if now - sent > COMMAND_EXPIRY) {
+                self.audit(&request, "ignored, expired before uts-run read it", now);
+                continue;
+            }
+            requests.push(request);
+        }
+        requests
+    }
+
+    /// Records the outcome of a command
+    pub fn audit(&self, request: &ControlRequest, outcome: &str, now: DateTime<Utc>) {
+        let audit = ControlAudit {
+            id: request.id.clone(),
+            command: request.command,
+            board: request.board,
+            source: request.source.clone(),
+            outcome: String::from(outcome),
+            time: now.to_rfc3339(),
+        };
+        let result = fs::create_dir_all(&self.path)
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(self.audit_path()))
+            .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&audit)?));
+        if let Err(e) = result {
+            warn!("Failed to audit {} command in {:?}: {}", request.command, self.audit_path(), e);
+        }
+    }
+
+    /// The most recent audit entries, oldest first
+    pub fn audit_log(&self, limit: usize) -> Vec<ControlAudit> {
+        let contents = fs::read_to_string(self.audit_path()).unwrap_or_default();
+        let entries: Vec<ControlAudit> = contents.lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        entries[entries.len().saturating_sub(limit)..].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::board::BoardId;
+    use crate::programs::control::{Command, ControlChannel};
+
+    #[test]
+    fn test_send_and_take() {
+        let dir = tempfile::tempdir().unwrap();
+        let channel = ControlChannel::new(dir.path().join("state"));
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        assert!(channel.take(now).is_empty());
+
+        channel.send(Command::Pause, None, "test", now).unwrap();
+        channel.send(Command::Skip, Some(BoardId::Bottom), "test", now + Duration::milliseconds(1)).unwrap();
+        let requests = channel.take(now);
+        assert_eq!([Command::Pause, Command::Skip], [requests[0].command, requests[1].command]);
+        assert_eq!(Some(BoardId::Bottom), requests[1].board);
+        assert!(channel.take(now).is_empty());
+
+        channel.send(Command::Abort, None, "test", now).unwrap();
+        assert!(channel.take(now + Duration::minutes(5)).is_empty());
+        channel.audit(&requests[0], "applied", now);
+        let outcomes: Vec<String> = channel.audit_log(3).into_iter().map(|a| a.outcome).collect();
+        assert_eq!(["requested", "ignored, expired before uts-run read it", "applied"], outcomes.as_slice());
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::board::BoardId;
+use crate::payload::Config;
+use crate::programs::Program;
+use crate::programs::step::Equilibrium;
+
+/// What the runner did, recorded with an `event` tag
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RunnerEventKind {
+    /// Waiting with the heaters off for the program's start time or window
+    Scheduled { start_time: String },
+    /// Not run, e.g. because its window has passed
+    Skipped { reason: String },
+    ProgramStarted,
+    /// Started after a restart, part way through a step
+    ProgramResumed { elapsed_secs: i64 },
+    /// A step has started, or the runner has moved to a new phase
+    Phase { phase: String },
+    /// Skipping to the next cool step
+    Aborted { reason: String },
+    /// A step failed, handled with the program `on_failure` policy
+    Failed { reason: String, policy: String },
+    SteadyState { sensors: Vec<Equilibrium> },
+    /// A setting written to a heater. Duty corrections from PID or constant power control are
+    /// recorded when the duty changes, at most once a minute.
+    HeaterWrite { board: BoardId, setting: String, value: String },
+    ProgramFinished,
+    /// A command from the control channel, and what it did
+    Command { command: String, source: String, outcome: String },
+    /// The programs have completed, or a failure stopped them
+    Stopped { reason: String },
+}
+
+/// One line of the event log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunnerEvent {
+    pub time: String,
+    pub program_id: Option<u8>,
+    pub program_name: Option<String>,
+    /// Step number, starting from 1
+    pub step: Option<usize>,
+    #[serde(flatten)]
+    pub kind: RunnerEventKind,
+}
+
+/// JSON-lines log of runner events, written to a file for each day next to the uts-log CSV files
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    path: PathBuf,
+    prefix: &'static str,
+}
+
+impl EventLog {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        EventLog { path: path.as_ref().to_path_buf(), prefix: "uts-events" }
+    }
+
+    /// Events predicted by replaying a log, in `uts-replay-*.jsonl` files so they can't be
+    /// mixed up with the events of the programs that actually ran
+    pub fn replayed<P: AsRef<Path>>(path: P) -> Self {
+        EventLog { prefix: "uts-replay", ..Self::new(path) }
+    }
+
+    /// None if UTS_LOG_PATH isn't set
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config.log_path.as_ref().map(Self::new)
+    }
+
+    fn file(&self, time: DateTime<Utc>) -> PathBuf {
+        self.path.join(format!("{}-{}.jsonl", self.prefix, time.format("%Y-%m-%d")))
+    }
+
+    pub fn record(&self, program: Option<&Program>, step: Option<usize>, kind: RunnerEventKind, now: DateTime<Utc>) {
+        let event = RunnerEvent {
+            time: now.to_rfc3339(),
+            program_id: program.map(|p| p.id),
+            program_name: program.map(|p| p.name.clone()),
+            step: step.map(|s| s + 1),
+            kind,
+        };
+        let file = self.file(now);
+        let result = fs::create_dir_all(&self.path)
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(&file))
+            .and_then(|mut f| writeln!(f, "{}", serde_json::to_string(&event)?));
+        if let Err(e) = result {
+            warn!("Failed to record runner event in {:?}: {}", file, e);
+        }
+    }
+
+    /// The most recent events from the latest file, oldest first
+    pub fn events(&self, limit: usize) -> Vec<Value> {
+        let pattern = self.path.join(format!("{}-*.jsonl", self.prefix));
+        let latest = glob::glob(&pattern.to_string_lossy()).ok()
+            .and_then(|files| files.flatten().max());
+        let contents = latest.and_then(|file| fs::read_to_string(file).ok()).unwrap_or_default();
+        let events: Vec<Value> = contents.lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        events[events.len().saturating_sub(limit)..].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::board::BoardId;
+    use crate::programs::event_log::{EventLog, RunnerEventKind};
+
+    #[test]
+    fn test_record_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = EventLog::new(dir.path());
+        let now = Utc::now();
+        assert!(log.events(10).is_empty());
+        log.record(None, None, RunnerEventKind::ProgramStarted, now);
+        log.record(None, Some(0), RunnerEventKind::Phase { phase: String::from("heating") }, now);
+        log.record(None, Some(0), RunnerEventKind::HeaterWrite {
+            board: BoardId::Bottom,
+            setting: String::from("heater_mode"),
+            value: String::from("PWM"),
+        }, now);
+        let events = log.events(2);
+        assert_eq!(2, events.len());
+        assert_eq!("phase", events[0]["event"]);
+        assert_eq!(1, events[0]["step"]);
+        assert_eq!("heating", events[0]["phase"]);
+        assert_eq!("bottom", events[1]["board"]);
+        assert!(events[1]["program_id"].is_null());
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::board::BoardId;
+use crate::payload::Config;
+use crate::programs::{FailurePolicy, Program, Programs};
+use crate::programs::profile::SetpointProfile;
+use crate::programs::step::{Action, HeatStep, StabilityDetector, Step, SteadyStateDetector};
+use crate::thermal::{ThermalModel, ThermalModels};
+
+/// Time step of the simulation
+const SIM_INTERVAL: Duration = Duration::seconds(1);
+
+/// Steps waiting on a temperature are assumed never to finish after this long
+const MAX_STEP_TIME: Duration = Duration::hours(24);
+
+/// Settings for simulating programs, from the config
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForecastSettings {
+    pub ambient_temp: f32,
+    /// Heater power at 100% duty in W
+    pub heater_power: f32,
+}
+
+impl From<&Config> for ForecastSettings {
+    fn from(config: &Config) -> Self {
+        ForecastSettings { ambient_temp: config.ambient_temp, heater_power: config.heater_power }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepOutcome {
+    Completed,
+    /// A hold step reached its timeout before the temperature was stable
+    TimedOut,
+    /// The abort temperature was reached, skipping to the next cool step
+    Aborted,
+    /// The temperature the step waits for would never be reached
+    NeverReached,
+    /// The program `heat_timeout` or `cool_timeout` was reached, and `on_failure` applied
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepForecast {
+    /// Step number, starting from 1
+    pub step: usize,
+    pub description: String,
+    pub duration_secs: i64,
+    pub start_temp: f32,
+    pub end_temp: f32,
+    pub peak_temp: f32,
+    pub energy_wh: f32,
+    pub outcome: StepOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProgramForecast {
+    pub id: u8,
+    pub name: String,
+    pub heat_board: BoardId,
+    pub duration_secs: i64,
+    pub peak_temp: f32,
+    pub energy_wh: f32,
+    pub aborted: bool,
+    /// True if a step would fail, even after any retries
+    pub failed: bool,
+    /// False if a step would never finish, so the program would run until stopped
+    pub complete: bool,
+    /// True if the program waits for a start time or window, which isn't included in the duration
+    pub scheduled: bool,
+    /// Includes the steps of each retry
+    pub steps: Vec<StepForecast>,
+}
+
+/// Predicted timing, temperatures and energy of a program file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Forecast {
+    pub programs: Vec<ProgramForecast>,
+    /// Time for one run through the programs
+    pub duration_secs: i64,
+    pub energy_wh: f32,
+    pub peak_temp: f32,
+    /// False if a step would never finish, so the duration is only up to that step
+    pub complete: bool,
+    pub run_loop: bool,
+    pub window_secs: Option<i64>,
+    /// Whether the programs fit in the window, or at least once if they loop
+    pub fits_window: Option<bool>,
+    /// Number of complete runs through the programs that fit in the window, if they loop
+    pub iterations: Option<i64>,
+}
+
+/// Predicts how a program file will run with a first-order thermal model of each board,
+/// assuming ideal thermostat and PID control, and that every sensor reads the board temperature.
+/// Each board starts at ambient, and loops are assumed to take as long as the first run.
+/// Programs are assumed to start straight away, without waiting for their start times or windows.
+/// With `parallel = true` each board's track runs from the start, taking as long as the longest
+/// track, and tracks are assumed not to wait for the power budget.
+pub fn forecast(programs: &Programs, models: &ThermalModels, settings: &ForecastSettings,
+                window: Option<Duration>) -> Forecast {
+    let start = Utc.timestamp_opt(0, 0).unwrap();
+    let tracks = if programs.parallel {
+        programs.tracks().into_iter().map(|(_, track)| track).collect()
+    } else {
+        vec![programs.sequence()]
+    };
+    let mut forecasts: Vec<ProgramForecast> = vec![];
+    let mut duration = Duration::zero();
+    for track in tracks {
+        let mut simulation = Simulation { models, settings, now: start, boards: HashMap::new() };
+        for program in track {
+            let forecast = simulation.run_program(program);
+            let stop = forecast.failed && program.on_failure == FailurePolicy::Abort;
+            forecasts.push(forecast);
+            if stop {
+                break;
+            }
+        }
+        duration = duration.max(simulation.now - start);
+    }
+    let complete = forecasts.iter().all(|p| p.complete);
+    let max_iterations = programs.max_iterations.filter(|_| programs.run_loop).map(i64::from);
+    let iterations = match window {
+        Some(window) if programs.run_loop && complete && duration > Duration::zero() => {
+            let iterations = window.num_seconds() / duration.num_seconds().max(1);
+            Some(max_iterations.map_or(iterations, |max| iterations.min(max)))
+        }
+        Some(_) if programs.run_loop => Some(0),
+        _ => max_iterations,
+    };
+    // a loop with an iteration cap only fits if every iteration does
+    let fits_window = window.map(|window| complete && duration * max_iterations.unwrap_or(1) as i32 <= window);
+    Forecast {
+        duration_secs: duration.num_seconds(),
+        energy_wh: forecasts.iter().map(|p| p.energy_wh).sum(),
+        peak_temp: forecasts.iter().map(|p| p.peak_temp).fold(settings.ambient_temp, f32::max),
+        complete,
+        run_loop: programs.run_loop,
+        window_secs: window.map(|w| w.num_seconds()),
+        fits_window,
+        iterations,
+        programs: forecasts,
+    }
+}
+
+/// Heater control as the runner would leave it
+#[derive(Debug, Clone)]
+enum Heater {
+    Off,
+    /// Constant power from the duty or a power setting
+    Power(f32),
+    /// Thermostat or PID control, with the setpoint following the profile if there is one
+    Setpoint { setpoint: f32, max_power: f32, profile: Option<SetpointProfile> },
+}
+
+impl Heater {
+    fn new(heat: &HeatStep, heater_power: f32, temp: f32, now: DateTime<Utc>) -> Self {
+        let profile = heat.setpoint_profile()
+            .map(|segments| SetpointProfile::new(segments, Some(temp), now));
+        let setpoint = profile.as_ref().map(|p| p.setpoint())
+            .or_else(|| heat.pid.as_ref().map(|pid| pid.setpoint))
+            .or(heat.thermostat);
+        match setpoint {
+            Some(setpoint) => {
+                // host-side PID sets the full duty range, the firmware thermostat is limited by the duty
+                let max_power = if heat.pid.is_some() { heater_power } else { heat.duty * heater_power };
+                Heater::Setpoint { setpoint, max_power, profile }
+            }
+            None => match heat.power {
+                Some(power) => Heater::Power(power.min(heater_power)),
+                None => Heater::Power(heat.duty * heater_power),
+            },
+        }
+    }
+
+    fn power(&self, model: &ThermalModel, temp: f32, ambient: f32) -> f32 {
+        match self {
+            Heater::Off => 0.0,
+            Heater::Power(power) => *power,
+            Heater::Setpoint { setpoint, max_power, .. } =>
+                model.power_to_reach(temp, *setpoint, ambient, SIM_INTERVAL, *max_power),
+        }
+    }
+
+    fn update(&mut self, temp: f32, now: DateTime<Utc>) {
+        if let Heater::Setpoint { setpoint, profile: Some(profile), .. } = self {
+            if let Some(new_setpoint) = profile.update(temp, now) {
+                *setpoint = new_setpoint;
+            }
+        }
+    }
+
+    fn has_profile(&self) -> bool {
+        matches!(self, Heater::Setpoint { profile: Some(_), .. })
+    }
+
+    fn is_profile_complete(&self) -> bool {
+        matches!(self, Heater::Setpoint { profile: Some(profile), .. } if profile.is_complete())
+    }
+}
+
+struct Simulation<'a> {
+    models: &'a ThermalModels,
+    settings: &'a ForecastSettings,
+    now: DateTime<Utc>,
+    /// Last known temperature of each board, and when
+    boards: HashMap<BoardId, (f32, DateTime<Utc>)>,
+}
+
+impl<'a> Simulation<'a> {
+    /// Temperature of a board now, which has been cooling with the heater off since it was last used
+    fn board_temp(&self, board: BoardId) -> f32 {
+        let ambient = self.settings.ambient_temp;
+        match self.boards.get(&board) {
+            Some((temp, time)) => self.models.get(board).temp_after(*temp, 0.0, ambient, self.now - *time),
+            None => ambient,
+        }
+    }
+
+    fn run_program(&mut self, program: &Program) -> ProgramForecast {
+        let start = self.now;
+        let steps = program.steps();
+        let mut temp = self.board_temp(program.heat_board);
+        let mut heater = Heater::Off;
+        let mut forecasts: Vec<StepForecast> = vec![];
+        let mut index = 0;
+        let mut aborted = false;
+        let mut failed = false;
+        let mut retries = 0;
+        let mut complete = true;
+        while let Some(step) = steps.get(index) {
+            // cooling after failing with the wait policy has no timeout
+            let timeout = step.timeout(program).filter(|_| !(failed && aborted));
+            let forecast = self.run_step(program, index, step, timeout, &mut heater, temp);
+            temp = forecast.end_temp;
+            let outcome = forecast.outcome;
+            forecasts.push(forecast);
+            match outcome {
+                StepOutcome::Failed => match program.on_failure {
+                    FailurePolicy::Retry(max) if retries < max => {
+                        retries += 1;
+                        heater = Heater::Off;
+                        index = 0;
+                        aborted = false;
+                    }
+                    FailurePolicy::Wait => {
+                        failed = true;
+                        aborted = true;
+                        if !step.is_cool() {
+                            match steps.iter().skip(index + 1).position(|s| s.is_cool()) {
+                                Some(offset) => index += offset + 1,
+                                None => break,
+                            }
+                        }
+                    }
+                    _ => {
+                        failed = true;
+                        break;
+                    }
+                },
+                _ if aborted => break, // cooled after an abort
+                StepOutcome::Aborted => {
+                    aborted = true;
+                    match steps.iter().skip(index + 1).position(|s| s.is_cool()) {
+                        Some(offset) => index += offset + 1,
+                        None => break,
+                    }
+                }
+                StepOutcome::NeverReached => {
+                    complete = false;
+                    break;
+                }
+                StepOutcome::Completed | StepOutcome::TimedOut => index += 1,
+            }
+        }
+
+        self.boards.insert(program.heat_board, (temp, self.now));
+        ProgramForecast {
+            id: program.id,
+            name: program.name.clone(),
+            heat_board: program.heat_board,
+            duration_secs: (self.now - start).num_seconds(),
+            peak_temp: forecasts.iter().map(|s| s.peak_temp).fold(temp, f32::max),
+            energy_wh: forecasts.iter().map(|s| s.energy_wh).sum(),
+            aborted,
+            failed,
+            complete,
+            scheduled: program.is_scheduled(),
+            steps: forecasts,
+        }
+    }
+
+    fn run_step(&mut self, program: &Program, index: usize, step: &Step, timeout: Option<Duration>,
+                heater: &mut Heater, start_temp: f32) -> StepForecast {
+        let model = *self.models.get(program.heat_board);
+        let ambient = self.settings.ambient_temp;
+        let start = self.now;
+        let deadline = timeout.map(|t| start + t);
+        let temp_abort = step.temp_abort.map_or(program.temp_abort, |t| t.min(program.temp_abort));
+        let mut temp = start_temp;
+        let mut peak_temp = start_temp;
+        let mut energy = 0.0;
+        let mut stability = None;
+        // every sensor is assumed to read the board temperature
+        let steady_sensors = step.steady_state_sensors(program);
+        let mut steady_state = step.steady_state.as_ref()
+            .map(|steady| SteadyStateDetector::new(steady, &steady_sensors));
+        let end_time = match &step.action {
+            Action::Heat(heat) => {
+                *heater = Heater::new(heat, self.settings.heater_power, temp, self.now);
+                heat.duration.map(|d| start + d)
+            }
+            Action::Hold { tolerance, window, timeout } => {
+                stability = Some(StabilityDetector::new(*tolerance, *window));
+                timeout.map(|t| start + t)
+            }
+            Action::Wait { duration } => Some(start + *duration),
+            Action::WaitTemp { .. } => None,
+            Action::Cool { .. } => {
+                *heater = Heater::Off;
+                None
+            }
+        };
+        let outcome = loop {
+            let done = match &step.action {
+                Action::Heat(_) => heater.is_profile_complete()
+                    || (end_time.is_none() && !heater.has_profile() && steady_state.is_none()),
+                Action::Hold { .. } => match stability.as_mut() {
+                    Some(stability) => stability.update(temp, self.now),
+                    None => false,
+                },
+                Action::Wait { .. } => false,
+                Action::WaitTemp { above, below } =>
+                    matches!(above, Some(t) if temp >= *t) || matches!(below, Some(t) if temp <= *t),
+                Action::Cool { temp: cool_temp } => temp <= *cool_temp,
+            };
+            let steady = match steady_state.as_mut() {
+                Some(detector) => steady_sensors.iter()
+                    .fold(None, |_, sensor| detector.update(sensor, temp, self.now))
+                    .is_some(),
+                None => false,
+            };
+            if done || steady {
+                break StepOutcome::Completed;
+            }
+            if matches!(deadline, Some(deadline) if self.now >= deadline) {
+                break StepOutcome::Failed;
+            }
+            if let Some(end_time) = end_time {
+                if self.now >= end_time {
+                    break match step.action {
+                        Action::Hold { .. } => StepOutcome::TimedOut,
+                        _ => StepOutcome::Completed,
+                    };
+                }
+            }
+            if !step.is_cool() && temp > temp_abort {
+                break StepOutcome::Aborted;
+            }
+            // steps with a fixed end can't wait forever
+            if end_time.is_none() && self.now - start >= MAX_STEP_TIME {
+                break StepOutcome::NeverReached;
+            }
+            let power = heater.power(&model, temp, ambient);
+            temp = model.temp_after(temp, power, ambient, SIM_INTERVAL);
+            energy += power * SIM_INTERVAL.num_seconds() as f32;
+            self.now += SIM_INTERVAL;
+            heater.update(temp, self.now);
+            peak_temp = peak_temp.max(temp);
+        };
+        if matches!(outcome, StepOutcome::Aborted | StepOutcome::Failed) {
+            *heater = Heater::Off;
+        }
+        StepForecast {
+            step: index + 1,
+            description: step.to_string(),
+            duration_secs: (self.now - start).num_seconds(),
+            start_temp,
+            end_temp: temp,
+            peak_temp,
+            energy_wh: energy / 3600.0,
+            outcome,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+    use chrono::Duration;
+
+    use crate::programs::forecast::{forecast, ForecastSettings, StepOutcome};
+    use crate::programs::Programs;
+    use crate::thermal::{ThermalModel, ThermalModels};
+
+    fn settings() -> ForecastSettings {
+        ForecastSettings { ambient_temp: 25.0, heater_power: 15.0 }
+    }
+
+    fn models() -> ThermalModels {
+        let model = ThermalModel { gain: 5.0, time_constant: Duration::minutes(10) };
+        ThermalModels { top: model, bottom: model }
+    }
+
+    #[test]
+    fn test_forecast_thermostat() {
+        let programs: Programs = toml::from_str(r#"
+            loop = true
+
+            [[programs]]
+            name = "Thermostat"
+            heat_board = "Top"
+            heat_time = "10m"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+            thermostat = 60.0
+            cool_temp = 40.0
+        "#).unwrap();
+        let forecast = forecast(&programs, &models(), &settings(), Some(Duration::hours(1)));
+        let program = &forecast.programs[0];
+        assert!(!program.aborted);
+        assert_eq!(2, program.steps.len());
+        assert_eq!(600, program.steps[0].duration_secs);
+        assert_approx_eq!(60.0, program.steps[0].peak_temp, 0.01);
+        // 377s at 15 W to reach 60°C, then 7 W to hold it
+        assert_approx_eq!(2.0, program.steps[0].energy_wh, 0.02);
+        // from 60°C to 40°C takes ln(35/15) time constants
+        assert_approx_eq!(508.0, program.steps[1].duration_secs as f32, 2.0);
+        assert_eq!(0.0, program.steps[1].energy_wh);
+        assert!(forecast.complete);
+        assert_eq!(Some(true), forecast.fits_window);
+        assert_eq!(Some(3), forecast.iterations);
+
+        let programs: Programs = toml::from_str(r#"
+            loop = true
+            max_iterations = 2
+
+            [[programs]]
+            name = "Thermostat"
+            heat_board = "Top"
+            heat_time = "10m"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+            thermostat = 60.0
+            cool_temp = 40.0
+            repeat = 2
+        "#).unwrap();
+        let capped = super::forecast(&programs, &models(), &settings(), Some(Duration::hours(1)));
+        assert_eq!(2, capped.programs.len());
+        assert_eq!(Some(false), capped.fits_window);
+        assert_eq!(Some(1), capped.iterations);
+    }
+
+    #[test]
+    fn test_forecast_abort_and_unreachable() {
+        let programs: Programs = toml::from_str(r#"
+            [[programs]]
+            name = "Abort"
+            heat_board = "Top"
+            heat_time = "1h"
+            temp_sensor = "TH1"
+            temp_abort = 50.0
+            cool_temp = 40.0
+
+            [[programs]]
+            name = "Too cold"
+            heat_board = "Bottom"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+
+            [[programs.steps]]
+            type = "cool"
+            temp = 20.0
+        "#).unwrap();
+        let forecast = forecast(&programs, &models(), &settings(), Some(Duration::hours(1)));
+        let program = &forecast.programs[0];
+        assert!(program.aborted);
+        assert_eq!(StepOutcome::Aborted, program.steps[0].outcome);
+        assert!(program.steps[0].duration_secs < 3600);
+        assert_eq!(StepOutcome::Completed, program.steps[1].outcome);
+
+        assert_eq!(StepOutcome::NeverReached, forecast.programs[1].steps[0].outcome);
+        assert!(!forecast.complete);
+        assert_eq!(Some(false), forecast.fits_window);
+        assert_eq!(None, forecast.iterations);
+    }
+
+    #[test]
+    fn test_forecast_long_and_immediate_steps() {
+        let programs: Programs = toml::from_str(r#"
+            [[programs]]
+            name = "Long"
+            heat_board = "Top"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+
+            [[programs.steps]]
+            type = "wait"
+            duration = "30h"
+
+            # already at ambient, like the runner's wait_temp
+            [[programs.steps]]
+            type = "wait_temp"
+            below = 25.0
+        "#).unwrap();
+        let forecast = forecast(&programs, &models(), &settings(), None);
+        let steps = &forecast.programs[0].steps;
+        assert_eq!(StepOutcome::Completed, steps[0].outcome);
+        assert_eq!(30 * 3600, steps[0].duration_secs);
+        assert_eq!(StepOutcome::Completed, steps[1].outcome);
+        assert_eq!(0, steps[1].duration_secs);
+        assert!(forecast.complete);
+    }
+
+    #[test]
+    fn test_forecast_steady_state() {
+        let programs: Programs = toml::from_str(r#"
+            [[programs]]
+            name = "Equilibrium"
+            heat_board = "Top"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+
+            [[programs.steps]]
+            type = "heat"
+            duty = 0.4
+
+            [programs.steps.steady_state]
+            max_rate = 0.05
+            window = "5m"
+
+            # below ambient, so only ends when steady
+            [[programs.steps]]
+            type = "cool"
+            temp = 20.0
+            steady_state = { max_rate = 0.05, sensors = ["TH1", "TH2"] }
+        "#).unwrap();
+        let forecast = forecast(&programs, &models(), &settings(), None);
+        let steps = &forecast.programs[0].steps;
+        // 6 W settles at 55°C, and is within 0.05°C/min after ln(30/0.5) time constants
+        assert_eq!(StepOutcome::Completed, steps[0].outcome);
+        assert!(steps[0].end_temp > 54.0);
+        assert!((2460..3000).contains(&steps[0].duration_secs), "{}", steps[0].duration_secs);
+        assert_eq!(StepOutcome::Completed, steps[1].outcome);
+        assert!(steps[1].end_temp < 26.0);
+        assert!(forecast.complete);
+    }
+
+    #[test]
+    fn test_forecast_failures() {
+        let programs: Programs = toml::from_str(r#"
+            [[programs]]
+            name = "Retry"
+            heat_board = "Top"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+            cool_timeout = "10m"
+            on_failure = { retry = 1 }
+
+            [[programs.steps]]
+            type = "cool"
+            temp = 20.0
+
+            [[programs]]
+            name = "Abort"
+            heat_board = "Bottom"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+            heat_timeout = "5m"
+            on_failure = "abort"
+
+            [[programs.steps]]
+            type = "wait_temp"
+            above = 100.0
+
+            [[programs]]
+            name = "Never run"
+            heat_board = "Top"
+            heat_time = "10m"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+            cool_temp = 40.0
+        "#).unwrap();
+        let forecast = forecast(&programs, &models(), &settings(), None);
+        assert_eq!(2, forecast.programs.len());
+        let program = &forecast.programs[0];
+        assert!(program.failed && program.complete);
+        assert_eq!(vec![StepOutcome::Failed, StepOutcome::Failed],
+                   program.steps.iter().map(|s| s.outcome).collect::<Vec<_>>());
+        assert_eq!(1200, program.duration_secs);
+        assert!(forecast.programs[1].failed);
+        assert_eq!(1500, forecast.duration_secs);
+    }
+
+    #[test]
+    fn test_forecast_parallel() {
+        let programs = |parallel: bool| -> Programs {
+            toml::from_str(&format!(r#"
+                parallel = {}
+
+                [[programs]]
+                name = "Top"
+                heat_board = "Top"
+                heat_time = "10m"
+                temp_sensor = "TH1"
+                temp_abort = 90.0
+
+                [[programs]]
+                name = "Bottom"
+                heat_board = "Bottom"
+                heat_time = "20m"
+                temp_sensor = "TH1"
+                temp_abort = 90.0
+            "#, parallel)).unwrap()
+        };
+        let sequential = forecast(&programs(false), &models(), &settings(), None);
+        assert_eq!(1800, sequential.duration_secs);
+        let parallel = forecast(&programs(true), &models(), &settings(), None);
+        assert_eq!(1200, parallel.duration_secs);
+        assert_eq!(2, parallel.programs.len());
+        assert_approx_eq!(sequential.energy_wh, parallel.energy_wh);
+    }
+}
@@ -3,16 +3,39 @@ use std::fs;
 use std::slice::Iter;
 use std::sync::Mutex;
 
-use chrono::Duration;
-use duration_str::deserialize_duration_chrono;
+use chrono::{DateTime, Duration, Utc};
+use duration_str::deserialize_option_duration_chrono;
 use lazy_static::lazy_static;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
 use serial_int::SerialGenerator;
 
 use crate::board::BoardId;
+use crate::control::pid::PidConfig;
 use crate::payload::Config;
+use crate::programs::profile::Segment;
+use crate::programs::schedule::DailyWindow;
+use crate::programs::step::{Action, HeatStep, Step};
+use crate::selector::SensorSelector;
 
+pub mod active;
+pub mod checkpoint;
+pub mod control;
+pub mod event_log;
+pub mod forecast;
+pub mod plan;
+pub mod profile;
+pub mod replay;
 pub mod runner;
+pub mod schedule;
+pub mod step;
+pub mod summary;
+pub mod validate;
+
+/// Nesting limit for groups, which also stops groups containing themselves
+const MAX_GROUP_DEPTH: usize = 8;
+
+fn default_repeat() -> u32 { 1 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 pub struct Programs {
@@ -20,6 +43,45 @@ pub struct Programs {
 
     #[serde(default, alias = "loop")]
     pub run_loop: bool,
+
+    /// Named groups of programs and other groups, which can be repeated
+    #[serde(default)]
+    pub groups: Vec<Group>,
+
+    /// Names of programs and groups to run in order, instead of every program in file order
+    #[serde(default)]
+    pub sequence: Vec<String>,
+
+    /// With `loop = true`, stop after this many runs through the sequence
+    pub max_iterations: Option<u32>,
+
+    /// Stop running programs at this time, e.g. "2023-10-01T12:00:00Z", cooling the current
+    /// program first
+    #[serde(default, deserialize_with = "deserialize_option_time")]
+    pub end_time: Option<DateTime<Utc>>,
+
+    /// Run the programs for each heat board in a separate track at the same time, each in
+    /// sequence order, with the heaters sharing UTS_POWER_BUDGET
+    #[serde(default)]
+    pub parallel: bool,
+}
+
+/// Programs and groups run in order as part of a sequence, `repeat` times
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct Group {
+    pub name: String,
+    pub programs: Vec<String>,
+    #[serde(default = "default_repeat")]
+    pub repeat: u32,
+}
+
+fn deserialize_option_time<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+    where D: Deserializer<'de> {
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value.map(|v| DateTime::parse_from_rfc3339(&v)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|err| D::Error::custom(format!("invalid time {}: {}", v, err))))
+        .transpose()
 }
 
 impl Programs {
@@ -32,13 +94,106 @@ impl Programs {
     pub fn load_from_file(filename: &str) -> Self {
         let str = fs::read_to_string(filename)
             .unwrap_or_else(|err| panic!("Program file should be readable {}: {}", filename, err));
-        toml::from_str(&str)
-            .unwrap_or_else(|err| panic!("Program file should contain valid TOML {}: {}", filename, err))
+        let programs: Programs = toml::from_str(&str)
+            .unwrap_or_else(|err| panic!("Program file should contain valid TOML {}: {}", filename, err));
+        for program in &programs.programs {
+            if let Err(err) = program.validate() {
+                panic!("Program file should only contain valid programs {}: {}: {}", filename, program, err);
+            }
+        }
+        if let Err(err) = programs.validate_sequence() {
+            panic!("Program file should have a valid sequence {}: {}", filename, err);
+        }
+        programs
     }
 
+    /// Every program in the file, once each
     pub fn iter(&self) -> Iter<Program> {
         self.programs.iter()
     }
+
+    /// The programs in the order they run, with each program and group repeated
+    pub fn sequence(&self) -> Vec<&Program> {
+        let mut sequence = vec![];
+        if self.sequence.is_empty() {
+            for program in &self.programs {
+                sequence.extend((0..program.repeat).map(|_| program));
+            }
+        } else {
+            for name in &self.sequence {
+                self.add_to_sequence(name, &mut sequence, 0);
+            }
+        }
+        sequence
+    }
+
+    /// The programs in the sequence which heat a board, in the order they run on its track
+    pub fn track(&self, board: BoardId) -> Vec<&Program> {
+        self.sequence().into_iter().filter(|p| p.heat_board == board).collect()
+    }
+
+    /// Tracks for each board with programs, in the order the boards first appear in the sequence
+    pub fn tracks(&self) -> Vec<(BoardId, Vec<&Program>)> {
+        let mut boards: Vec<BoardId> = vec![];
+        for program in self.sequence() {
+            if !boards.contains(&program.heat_board) {
+                boards.push(program.heat_board);
+            }
+        }
+        boards.into_iter().map(|board| (board, self.track(board))).collect()
+    }
+
+    fn add_to_sequence<'a>(&'a self, name: &str, sequence: &mut Vec<&'a Program>, depth: usize) {
+        if let Some(group) = self.groups.iter().find(|g| g.name == name) {
+            if depth < MAX_GROUP_DEPTH {
+                for _ in 0..group.repeat {
+                    for name in &group.programs {
+                        self.add_to_sequence(name, sequence, depth + 1);
+                    }
+                }
+            }
+        } else if let Some(program) = self.programs.iter().find(|p| p.name == name) {
+            sequence.extend((0..program.repeat).map(|_| program));
+        }
+    }
+
+    /// Checks names in the sequence and groups refer to exactly one program or group,
+    /// and groups don't contain themselves
+    pub fn validate_sequence(&self) -> Result<(), String> {
+        let count = |name: &str| self.programs.iter().filter(|p| p.name == name).count()
+            + self.groups.iter().filter(|g| g.name == name).count();
+        for group in &self.groups {
+            if count(&group.name) > 1 {
+                return Err(format!("group name \"{}\" is also used by another program or group", group.name));
+            }
+        }
+        let names = self.sequence.iter().map(|name| (None, name))
+            .chain(self.groups.iter().flat_map(|g| g.programs.iter().map(move |name| (Some(g), name))));
+        for (group, name) in names {
+            let within = group.map(|g| format!("group \"{}\"", g.name)).unwrap_or(String::from("sequence"));
+            match count(name) {
+                0 => return Err(format!("{} has unknown program or group \"{}\"", within, name)),
+                1 => {}
+                _ => return Err(format!("{} has \"{}\", which is the name of more than one program", within, name)),
+            }
+        }
+        for group in &self.groups {
+            if self.group_depth(&group.name, 0) > MAX_GROUP_DEPTH {
+                return Err(format!("group \"{}\" contains itself or is nested too deeply", group.name));
+            }
+        }
+        Ok(())
+    }
+
+    fn group_depth(&self, name: &str, depth: usize) -> usize {
+        match self.groups.iter().find(|g| g.name == name) {
+            Some(group) if depth <= MAX_GROUP_DEPTH => group.programs.iter()
+                .map(|name| self.group_depth(name, depth + 1))
+                .max()
+                .unwrap_or(depth + 1),
+            _ => depth,
+        }
+    }
 }
 
 lazy_static! {
@@ -51,20 +206,213 @@ fn generate_program_id() -> u8 {
 
 fn default_heat_duty() -> f32 { 1.0 }
 
+/// What the runner does when a step of a program fails by reaching `heat_timeout` or `cool_timeout`,
+/// configured as `on_failure = "skip"` or `on_failure = { retry = 2 }`
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Switch the heater off and continue with the next program
+    #[default]
+    Skip,
+    /// Start the program again up to this many times, then skip it
+    Retry(u32),
+    /// Switch the heaters off and stop running programs
+    Abort,
+    /// Switch the heater off and wait without a timeout for the next cool step, then continue
+    /// with the next program
+    Wait,
+}
+
+/// A program heats a board and cools it, either with the `heat_*` settings and `cool_temp`,
+/// or a sequence of `[[programs.steps]]`
 #[derive(Debug, PartialEq, Clone, Deserialize)]
 pub struct Program {
     #[serde(default = "generate_program_id")]
     pub id: u8,
     pub name: String,
     pub heat_board: BoardId,
-    #[serde(deserialize_with = "deserialize_duration_chrono")]
-    pub heat_time: Duration,
+    #[serde(default, deserialize_with = "deserialize_option_duration_chrono")]
+    pub heat_time: Option<Duration>,
     #[serde(default = "default_heat_duty")]
     pub heat_duty: f32,
+    /// Constant heater power in W, adjusting duty for the measured power instead of `heat_duty`
+    pub heat_power: Option<f32>,
+    /// Sensor or aggregate such as `max(TH1, TH2)` to control on, and check against `cool_temp`.
+    /// The default for steps without their own `temp_sensor`.
     pub temp_sensor: String,
+    /// Skips to cooling if exceeded at any time before cooling
     pub temp_abort: f32,
+    /// Sensor or aggregate checked against `temp_abort`, if different to `temp_sensor`
+    pub abort_sensor: Option<String>,
     pub thermostat: Option<f32>,
-    pub cool_temp: f32,
+    /// Host-side PID control on `temp_sensor`, used instead of the firmware `thermostat`
+    pub pid: Option<PidConfig>,
+    /// Limits the rate of change of the thermostat or PID setpoint, in °C/min
+    pub ramp_rate: Option<f32>,
+    /// Ramp/soak segments for the setpoint, heating ends when the last one completes
+    #[serde(default)]
+    pub profile: Vec<Segment>,
+    pub cool_temp: Option<f32>,
+    /// Steps run in order instead of heating for `heat_time` then cooling to `cool_temp`
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    /// Fails heat steps without a duration, hold steps without a timeout and wait_temp steps
+    /// which take longer than this
+    #[serde(default, deserialize_with = "deserialize_option_duration_chrono")]
+    pub heat_timeout: Option<Duration>,
+    /// Fails cool steps which take longer than this, e.g. if the sensor fails or the board can't cool
+    #[serde(default, deserialize_with = "deserialize_option_duration_chrono")]
+    pub cool_timeout: Option<Duration>,
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+    /// Runs the program this many times in a row, or not at all if 0
+    #[serde(default = "default_repeat")]
+    pub repeat: u32,
+    /// Waits with the heaters off until this time to start, the same as `not_before`
+    #[serde(default, deserialize_with = "deserialize_option_time")]
+    pub start_at: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "deserialize_option_time")]
+    pub not_before: Option<DateTime<Utc>>,
+    /// Skips the program if it can't start before this time
+    #[serde(default, deserialize_with = "deserialize_option_time")]
+    pub not_after: Option<DateTime<Utc>>,
+    /// Waits with the heaters off for this time of day to start. Only the start is controlled,
+    /// so the heat steps must fit in the window.
+    pub daily_window: Option<DailyWindow>,
+}
+
+impl Program {
+    pub fn sensor_selector(&self) -> SensorSelector {
+        self.temp_sensor.parse()
+            .unwrap_or_else(|err| panic!("Invalid temp_sensor for {}: {}", self, err))
+    }
+
+    pub fn abort_sensor(&self) -> &str {
+        self.abort_sensor.as_deref().unwrap_or(&self.temp_sensor)
+    }
+
+    /// Sensors or aggregates checked against `temp_abort` by the program or its steps
+    pub fn abort_sensors(&self) -> Vec<&str> {
+        let mut sensors = vec![self.abort_sensor()];
+        for step in self.steps.iter().filter(|step| step.temp_abort.is_some()) {
+            sensors.push(step.abort_sensor(self));
+        }
+        sensors
+    }
+
+    /// The steps to run, converting the `heat_*` settings and `cool_temp` to a heat and a cool
+    /// step for programs without `steps`
+    pub fn steps(&self) -> Vec<Step> {
+        if !self.steps.is_empty() {
+            return self.steps.clone();
+        }
+        let heat = HeatStep {
+            duration: self.heat_time,
+            duty: self.heat_duty,
+            power: self.heat_power,
+            thermostat: self.thermostat,
+            pid: self.pid.clone(),
+            ramp_rate: self.ramp_rate,
+            profile: self.profile.clone(),
+        };
+        let mut steps = vec![Step::new(Action::Heat(heat))];
+        if let Some(temp) = self.cool_temp {
+            steps.push(Step::new(Action::Cool { temp }));
+        }
+        steps
+    }
+
+    /// Total time of the heat steps with a `duration`, per repeat
+    fn heat_duration(&self) -> Duration {
+        self.steps().iter()
+            .filter_map(|step| match &step.action {
+                Action::Heat(heat) => heat.duration,
+                _ => None,
+            })
+            .fold(Duration::zero(), |total, duration| total + duration)
+    }
+
+    /// Every sensor or aggregate read by the program, including those of its steps
+    pub fn sensors(&self) -> Vec<&str> {
+        let mut sensors = vec![self.temp_sensor.as_str(), self.abort_sensor()];
+        for step in &self.steps {
+            sensors.extend([step.sensor(self), step.abort_sensor(self)]);
+            sensors.extend(step.steady_state_sensors(self));
+        }
+        sensors
+    }
+
+    /// When the program can start at or after `now`, from `start_at`, `not_before` and
+    /// `daily_window`, or None if it can't start before `not_after`
+    pub fn next_start(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut start = match self.start_at.or(self.not_before) {
+            Some(not_before) if not_before > now => not_before,
+            _ => now,
+        };
+        if let Some(window) = &self.daily_window {
+            start = window.next_open(start);
+        }
+        match self.not_after {
+            Some(not_after) if start > not_after => None,
+            _ => Some(start),
+        }
+    }
+
+    /// True if the program waits for a time or window to start
+    pub fn is_scheduled(&self) -> bool {
+        self.start_at.is_some() || self.not_before.is_some() || self.not_after.is_some()
+            || self.daily_window.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_settings()?;
+        for (index, step) in self.steps.iter().enumerate() {
+            step.validate().map_err(|err| format!("step {}: {}", index + 1, err))?;
+        }
+        for sensor in self.sensors() {
+            sensor.parse::<SensorSelector>().map_err(|err| format!("unknown sensor {}: {}", sensor, err))?;
+        }
+        Ok(())
+    }
+
+    /// Checks heat and cool settings are set on the program or in steps, but not both
+    pub fn validate_settings(&self) -> Result<(), String> {
+        if self.steps.is_empty() {
+            if self.heat_time.is_none() || self.cool_temp.is_none() {
+                return Err(String::from("should set heat_time and cool_temp, or steps"));
+            }
+            if let Some(Step { action: Action::Heat(heat), .. }) = self.steps().first() {
+                heat.validate()?;
+            }
+        } else if self.heat_time.is_some() || self.cool_temp.is_some() || self.heat_power.is_some()
+            || self.thermostat.is_some() || self.pid.is_some() || self.ramp_rate.is_some()
+            || !self.profile.is_empty() {
+            return Err(String::from("should set heat and cool settings in steps, not the program"));
+        }
+        for (name, timeout) in [("heat_timeout", self.heat_timeout), ("cool_timeout", self.cool_timeout)] {
+            if matches!(timeout, Some(timeout) if timeout <= Duration::zero()) {
+                return Err(format!("{} should be positive", name));
+            }
+        }
+        if self.start_at.is_some() && self.not_before.is_some() {
+            return Err(String::from("should set start_at or not_before, not both"));
+        }
+        if let (Some(not_before), Some(not_after)) = (self.start_at.or(self.not_before), self.not_after) {
+            if not_before >= not_after {
+                return Err(String::from("not_after should be after start_at or not_before"));
+            }
+        }
+        if let Some(window) = self.daily_window {
+            if window.start == window.end {
+                return Err(String::from("daily_window start and end should be different"));
+            }
+            if self.heat_duration() > window.length() {
+                return Err(format!("heat steps take {}m, longer than daily_window {}, which only controls the start",
+                                   self.heat_duration().num_minutes(), window));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl std::fmt::Display for Program {
@@ -72,3 +420,103 @@ impl std::fmt::Display for Program {
         write!(f, "Program {{ id: {}, name: \"{}\" }}", self.id, self.name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::board::BoardId;
+    use crate::programs::{Program, Programs};
+
+    fn names(programs: &Programs) -> Vec<&str> {
+        programs.sequence().iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sequence() {
+        let programs = Programs::load_from_file("programs/sequence.toml");
+        let sequence = names(&programs);
+        assert_eq!(26, sequence.len());
+        assert_eq!(["Top hot", "Top hot", "Top hot", "Bottom hot", "Bottom hot", "Bottom hot", "Top warm", "Bottom warm"],
+                   sequence[..8]);
+        assert_eq!(["Top warm", "Bottom warm"], sequence[24..]);
+        let tracks = programs.tracks();
+        assert_eq!([BoardId::Top, BoardId::Bottom], [tracks[0].0, tracks[1].0]);
+        assert_eq!(13, tracks[0].1.len());
+        assert!(tracks[1].1.iter().all(|p| p.heat_board == BoardId::Bottom));
+
+        // without a sequence, every program runs in file order
+        let programs = Programs::load_from_file("programs/example.toml");
+        assert_eq!(programs.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), names(&programs));
+    }
+
+    #[test]
+    fn test_invalid_sequence() {
+        let programs = |groups: &str| -> Programs {
+            toml::from_str(&format!(r#"
+                sequence = ["Loop"]
+                {}
+
+                [[programs]]
+                name = "Hot"
+                heat_board = "Top"
+                heat_time = "3m"
+                temp_sensor = "TH1"
+                temp_abort = 90.0
+                cool_temp = 40.0
+                repeat = 0
+            "#, groups)).unwrap()
+        };
+        let valid = programs(r#"groups = [{ name = "Loop", programs = ["Hot"], repeat = 2 }]"#);
+        assert_eq!(Ok(()), valid.validate_sequence());
+        assert!(names(&valid).is_empty());
+
+        let cycle = programs(r#"groups = [{ name = "Loop", programs = ["Hot", "Inner"] },
+                                          { name = "Inner", programs = ["Loop"] }]"#);
+        assert_eq!(Err(String::from("group \"Loop\" contains itself or is nested too deeply")),
+                   cycle.validate_sequence());
+        assert!(names(&cycle).is_empty());
+
+        let unknown = programs(r#"groups = [{ name = "Loop", programs = ["Cold"] }]"#);
+        assert_eq!(Err(String::from("group \"Loop\" has unknown program or group \"Cold\"")),
+                   unknown.validate_sequence());
+        let duplicate = programs(r#"groups = [{ name = "Hot", programs = [] }]"#);
+        assert!(duplicate.validate_sequence().is_err());
+    }
+
+    #[test]
+    fn test_next_start() {
+        let program = |schedule: &str| -> Program {
+            toml::from_str(&format!(r#"
+                name = "Night"
+                heat_board = "Top"
+                heat_time = "3m"
+                temp_sensor = "TH1"
+                temp_abort = 90.0
+                cool_temp = 40.0
+                {}
+            "#, schedule)).unwrap()
+        };
+        let at = |d, h| Utc.with_ymd_and_hms(2023, 9, d, h, 0, 0).unwrap();
+        let now = at(1, 12);
+        assert_eq!(Some(now), program("").next_start(now));
+        assert_eq!(Some(at(1, 14)), program(r#"start_at = "2023-09-01T14:00:00Z""#).next_start(now));
+        assert_eq!(Some(now), program(r#"not_before = "2023-09-01T10:00:00Z""#).next_start(now));
+        assert_eq!(None, program(r#"not_after = "2023-09-01T10:00:00Z""#).next_start(now));
+
+        let night = r#"daily_window = { start = "22:00", end = "04:00" }"#;
+        assert_eq!(Some(at(1, 22)), program(night).next_start(now));
+        assert_eq!(Some(at(2, 22)), program(&format!("{}\nnot_before = \"2023-09-02T05:00:00Z\"", night)).next_start(now));
+        assert_eq!(None, program(&format!("{}\nnot_after = \"2023-09-01T20:00:00Z\"", night)).next_start(now));
+
+        assert!(program(r#"daily_window = { start = "04:00", end = "04:00" }"#).validate_settings().is_err());
+        assert!(program(night).validate_settings().is_ok());
+        assert_eq!(Err(String::from("heat steps take 420m, longer than daily_window 22:00-04:00 UTC, which only controls the start")),
+                   Program { heat_time: Some(Duration::hours(7)), ..program(night) }.validate_settings());
+        assert_eq!(Err(String::from("should set only one of thermostat or pid")),
+                   program("thermostat = 60.0\npid = { setpoint = 60.0, kp = 0.05, ki = 0.0005, kd = 0.0 }")
+                       .validate_settings());
+        assert!(program("start_at = \"2023-09-01T14:00:00Z\"\nnot_after = \"2023-09-01T14:00:00Z\"")
+            .validate_settings().is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use chrono::Duration;
+
+use crate::board::BoardId;
+use crate::programs::Program;
+use crate::programs::profile::Segment;
+use crate::programs::step::{Action, Step};
+
+/// Expected timing of a step, from its settings alone
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepPlan {
+    pub description: String,
+    /// Shortest time the step can take
+    pub min: Duration,
+    /// Longest time the step can take, None if it waits on a temperature without a timeout
+    pub max: Option<Duration>,
+}
+
+/// Expected sequence of steps for a program, for a dry run
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramPlan {
+    pub id: u8,
+    pub name: String,
+    pub heat_board: BoardId,
+    pub steps: Vec<StepPlan>,
+}
+
+impl ProgramPlan {
+    pub fn new(program: &Program, ambient_temp: f32) -> Self {
+        let steps = program.steps().iter()
+            .map(|step| plan_step(program, step, ambient_temp))
+            .collect();
+        ProgramPlan { id: program.id, name: program.name.clone(), heat_board: program.heat_board, steps }
+    }
+
+    pub fn min(&self) -> Duration {
+        self.steps.iter().fold(Duration::zero(), |total, step| total + step.min)
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.steps.iter().try_fold(Duration::zero(), |total, step| Some(total + step.max?))
+    }
+}
+
+fn plan_step(program: &Program, step: &Step, ambient_temp: f32) -> StepPlan {
+    let sensor = step.sensor(program);
+    let (description, min, max) = match &step.action {
+        Action::Heat(heat) => {
+            let profile = heat.setpoint_profile();
+            let estimate = profile.as_ref().map(|segments| profile_time(segments, ambient_temp));
+            // segments with a tolerance wait for the temperature before soaking
+            let waits = profile.iter().flatten().any(|s| s.tolerance.is_some());
+            let (min, max) = match (heat.duration, estimate) {
+                (Some(duration), Some(estimate)) => (duration.min(estimate), Some(duration)),
+                (Some(duration), None) => (duration, Some(duration)),
+                (None, Some(estimate)) => (estimate, Some(estimate).filter(|_| !waits)),
+                // waits for the steady state with the heater on
+                (None, None) if step.steady_state.is_some() => (Duration::zero(), None),
+                (None, None) => (Duration::zero(), Some(Duration::zero())),
+            };
+            (format!("{} on {}", step, sensor), min, max)
+        }
+        Action::Hold { window, timeout, .. } => (format!("{} on {}", step, sensor), *window, *timeout),
+        Action::Wait { duration } => (step.to_string(), *duration, Some(*duration)),
+        Action::WaitTemp { .. } | Action::Cool { .. } =>
+            (format!("{} on {}", step, sensor), Duration::zero(), None),
+    };
+    // steps waiting on a temperature fail after the program heat_timeout or cool_timeout
+    let max = match (max, step.timeout(program)) {
+        (Some(max), Some(timeout)) => Some(max.min(timeout)),
+        (max, timeout) => max.or(timeout),
+    };
+    // a steady state can end the step once its window has passed
+    let min = match &step.steady_state {
+        Some(steady) => min.min(steady.window),
+        None => min,
+    };
+    let min = max.map_or(min, |max| min.min(max));
+    StepPlan { description, min, max }
+}
+
+/// Time to ramp through the segments from the start temperature and soak at each, ignoring
+/// time spent waiting to get within tolerance
+pub(crate) fn profile_time(segments: &[Segment], start_temp: f32) -> Duration {
+    let mut setpoint = start_temp;
+    let mut total = Duration::zero();
+    for segment in segments {
+        if let Some(rate) = segment.ramp_rate.filter(|r| *r > 0.0) {
+            let minutes = (segment.target - setpoint).abs() / rate;
+            total += Duration::milliseconds((minutes * 60_000.0) as i64);
+        }
+        total += segment.soak;
+        setpoint = segment.target;
+    }
+    total
+}
+
+/// Formats a duration to the second, e.g. 1h05m00s
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.num_seconds();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use crate::programs::plan::{format_duration, ProgramPlan};
+    use crate::programs::Programs;
+
+    #[test]
+    fn test_plan() {
+        let programs: Programs = toml::from_str(r#"
+            [[programs]]
+            name = "Ramp"
+            heat_board = "Top"
+            heat_time = "45m"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+            cool_temp = 40.0
+
+            [[programs.profile]]
+            target = 65.0
+            ramp_rate = 2.0
+            soak = "10m"
+
+            [[programs]]
+            name = "Steps"
+            heat_board = "Bottom"
+            temp_sensor = "TH1"
+            temp_abort = 90.0
+
+            [[programs.steps]]
+            type = "heat"
+            thermostat = 60.0
+
+            [[programs.steps]]
+            type = "hold"
+            window = "2m"
+            timeout = "20m"
+
+            [[programs.steps]]
+            type = "wait"
+            duration = "5m"
+        "#).unwrap();
+        let plans: Vec<ProgramPlan> = programs.iter().map(|p| ProgramPlan::new(p, 25.0)).collect();
+
+        // 20 minute ramp from ambient, then soak, before the heat time
+        assert_eq!(Duration::minutes(30), plans[0].steps[0].min);
+        assert_eq!(Some(Duration::minutes(45)), plans[0].steps[0].max);
+        assert_eq!("cool to 40.0°C on TH1", plans[0].steps[1].description);
+        assert_eq!(None, plans[0].max());
+
+        assert_eq!(Duration::minutes(7), plans[1].min());
+        assert_eq!(Some(Duration::minutes(25)), plans[1].max());
+
+        let mut program = programs.iter().next().unwrap().clone();
+        program.cool_timeout = Some(Duration::minutes(15));
+        assert_eq!(Some(Duration::minutes(60)), ProgramPlan::new(&program, 25.0).max());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!("45s", format_duration(Duration::seconds(45)));
+        assert_eq!("3m00s", format_duration(Duration::minutes(3)));
+        assert_eq!("1h05m09s", format_duration(Duration::seconds(3909)));
+    }
+}
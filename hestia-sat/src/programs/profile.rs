@@ -0,0 +1,212 @@
+use std::fmt::{Display, Formatter};
+
+use chrono::{DateTime, Duration, Utc};
+use duration_str::deserialize_duration_chrono;
+use serde::Deserialize;
+
+fn default_soak() -> Duration { Duration::zero() }
+
+/// One ramp/soak step of a setpoint profile, configured as `[[programs.profile]]`
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct Segment {
+    /// Setpoint to ramp to in °C
+    pub target: f32,
+
+    /// Maximum rate of setpoint change in °C/min, or a step change if not set
+    pub ramp_rate: Option<f32>,
+
+    /// Time to hold at the target before moving to the next segment
+    #[serde(default = "default_soak", deserialize_with = "deserialize_duration_chrono")]
+    pub soak: Duration,
+
+    /// If set, the soak timer only starts once the temperature is within this many °C of the target
+    pub tolerance: Option<f32>,
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.1}", self.target)?;
+        if let Some(rate) = self.ramp_rate {
+            write!(f, "@{:.1}/min", rate)?;
+        }
+        if self.soak > Duration::zero() {
+            write!(f, " soak {}s", self.soak.num_seconds())?;
+        }
+        if let Some(tolerance) = self.tolerance {
+            write!(f, " ±{:.1}", tolerance)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Phase {
+    Ramping,
+    /// Ramp is finished, but temperature isn't yet within tolerance
+    Settling,
+    Soaking {
+        start: DateTime<Utc>,
+    },
+}
+
+/// Tracks the setpoint through a sequence of ramp/soak segments
+#[derive(Debug, Clone)]
+pub struct SetpointProfile {
+    segments: Vec<Segment>,
+    index: usize,
+    phase: Phase,
+    setpoint: f32,
+    last_update: DateTime<Utc>,
+}
+
+impl SetpointProfile {
+    /// Ramps start from `start_temp` if known, otherwise the first setpoint is the first target
+    pub fn new(segments: Vec<Segment>, start_temp: Option<f32>, now: DateTime<Utc>) -> Self {
+        assert!(!segments.is_empty(), "Setpoint profile should have at least one segment");
+        let first = &segments[0];
+        let setpoint = match (first.ramp_rate, start_temp) {
+            (Some(_), Some(temp)) => temp,
+            _ => first.target,
+        };
+        SetpointProfile { segments, index: 0, phase: Phase::Ramping, setpoint, last_update: now }
+    }
+
+    pub fn setpoint(&self) -> f32 {
+        self.setpoint
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.index >= self.segments.len()
+    }
+
+    /// Advances the profile with the latest reading, returning the new setpoint,
+    /// or None once all segments have completed
+    pub fn update(&mut self, temp: f32, now: DateTime<Utc>) -> Option<f32> {
+        let mut elapsed_mins = (now - self.last_update).num_milliseconds() as f32 / 60_000.0;
+        self.last_update = now;
+        loop {
+            let segment = self.segments.get(self.index)?;
+            match self.phase {
+                Phase::Ramping => {
+                    self.setpoint = match segment.ramp_rate {
+                        Some(rate) => ramp_towards(self.setpoint, segment.target, rate * elapsed_mins),
+                        None => segment.target,
+                    };
+                    elapsed_mins = 0.0; // ramp time is only used once per update
+                    if self.setpoint != segment.target {
+                        return Some(self.setpoint);
+                    }
+                    self.phase = Phase::Settling;
+                }
+                Phase::Settling => {
+                    match segment.tolerance {
+                        Some(tolerance) if (temp - segment.target).abs() > tolerance => {
+                            return Some(self.setpoint);
+                        }
+                        _ => self.phase = Phase::Soaking { start: now },
+                    }
+                }
+                Phase::Soaking { start } => {
+                    if now - start < segment.soak {
+                        return Some(self.setpoint);
+                    }
+                    self.index += 1;
+                    self.phase = Phase::Ramping;
+                    elapsed_mins = 0.0; // next ramp starts now, not at the last update
+                }
+            }
+        }
+    }
+}
+
+impl Display for SetpointProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.segments.get(self.index) {
+            Some(segment) => write!(f, "segment {}/{} ({}), {:?}, setpoint: {:.2}",
+                                    self.index + 1, self.segments.len(), segment, self.phase, self.setpoint),
+            None => write!(f, "complete"),
+        }
+    }
+}
+
+fn ramp_towards(current: f32, target: f32, max_step: f32) -> f32 {
+    if (target - current).abs() <= max_step {
+        target
+    } else if target > current {
+        current + max_step
+    } else {
+        current - max_step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::programs::profile::{Segment, SetpointProfile};
+
+    fn segment(target: f32, ramp_rate: Option<f32>, soak_mins: i64, tolerance: Option<f32>) -> Segment {
+        Segment { target, ramp_rate, soak: Duration::minutes(soak_mins), tolerance }
+    }
+
+    #[test]
+    fn test_ramp_rate_limit() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut profile = SetpointProfile::new(vec![segment(60.0, Some(2.0), 10, None)], Some(20.0), start);
+        assert_approx_eq!(20.0, profile.setpoint(), 0.001);
+        assert_approx_eq!(21.0, profile.update(20.0, start + Duration::seconds(30)).unwrap(), 0.001);
+        assert_approx_eq!(40.0, profile.update(30.0, start + Duration::minutes(10)).unwrap(), 0.001);
+        assert_approx_eq!(60.0, profile.update(50.0, start + Duration::minutes(25)).unwrap(), 0.001);
+
+        // setpoint is reached at the 25 minute update, so soak ends at 35 minutes
+        assert_eq!(Some(60.0), profile.update(60.0, start + Duration::minutes(34)));
+        assert_eq!(None, profile.update(60.0, start + Duration::minutes(35)));
+        assert!(profile.is_complete());
+    }
+
+    #[test]
+    fn test_ramp_down() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut profile = SetpointProfile::new(vec![segment(40.0, Some(1.0), 0, None)], Some(50.0), start);
+        assert_approx_eq!(45.0, profile.update(50.0, start + Duration::minutes(5)).unwrap(), 0.001);
+    }
+
+    #[test]
+    fn test_step_without_ramp_rate() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let profile = SetpointProfile::new(vec![segment(60.0, None, 10, None)], Some(20.0), start);
+        assert_approx_eq!(60.0, profile.setpoint(), 0.001);
+    }
+
+    #[test]
+    fn test_guaranteed_soak() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut profile = SetpointProfile::new(vec![segment(60.0, None, 10, Some(1.0))], Some(20.0), start);
+        // soak timer doesn't start until within tolerance
+        assert_eq!(Some(60.0), profile.update(50.0, start + Duration::minutes(5)));
+        assert_eq!(Some(60.0), profile.update(58.0, start + Duration::minutes(15)));
+        assert_eq!(Some(60.0), profile.update(59.5, start + Duration::minutes(20)));
+        assert_eq!(Some(60.0), profile.update(58.0, start + Duration::minutes(29)));
+        assert_eq!(None, profile.update(59.5, start + Duration::minutes(30)));
+    }
+
+    #[test]
+    fn test_multiple_segments() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut profile = SetpointProfile::new(vec![
+            segment(40.0, None, 5, None),
+            segment(60.0, Some(10.0), 5, None),
+            segment(30.0, None, 1, None),
+        ], Some(20.0), start);
+        assert_eq!(Some(40.0), profile.update(25.0, start + Duration::minutes(1)));
+        assert_eq!(Some(40.0), profile.update(40.0, start + Duration::minutes(5)));
+        // first soak started at 1 minute, so ramp starts at 6 minutes
+        assert_eq!(Some(40.0), profile.update(40.0, start + Duration::minutes(6)));
+        assert_eq!(Some(50.0), profile.update(45.0, start + Duration::minutes(7)));
+        assert_eq!(Some(60.0), profile.update(55.0, start + Duration::minutes(8)));
+        assert_eq!(Some(60.0), profile.update(60.0, start + Duration::minutes(12)));
+        assert_eq!(Some(30.0), profile.update(60.0, start + Duration::minutes(13)));
+        assert_eq!(None, profile.update(30.0, start + Duration::minutes(14)));
+    }
+}
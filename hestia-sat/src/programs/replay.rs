@@ -0,0 +1,487 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::rc::Rc;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use crate::board::{Board, BoardFlags, BoardId, BoardVersion, ALL_SENSORS};
+use crate::clock::{Clock, ReplayClock};
+use crate::csv::TIMESTAMP_FORMAT;
+use crate::heater::{Heater, HeaterMode, TargetSensor};
+use crate::payload::Payload;
+use crate::programs::{Program, Programs};
+use crate::programs::event_log::EventLog;
+use crate::programs::runner::{Event, PayloadController, read_watched, State};
+use crate::reading::{ReadableSensor, SensorReading};
+use crate::sensors::{Sensor, SensorId};
+use crate::{ReadError, ReadResult};
+
+/// Latest reading of each sensor on each board, from the row of the log being replayed
+type Readings = Rc<RefCell<HashMap<(BoardId, SensorId), SensorReading<f32>>>>;
+
+/// One row of a uts-log CSV file, with the readings of the sensors it has columns for
+struct LogRow {
+    time: DateTime<Utc>,
+    board: BoardId,
+    readings: Vec<(SensorId, Option<SensorReading<f32>>)>,
+}
+
+/// A sensor that reads its value from the log being replayed
+struct LogSensor {
+    board: BoardId,
+    sensor: Sensor,
+    readings: Readings,
+}
+
+impl Display for LogSensor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.sensor)
+    }
+}
+
+impl ReadableSensor for LogSensor {
+    fn read(&self) -> ReadResult<SensorReading<f32>> {
+        self.readings.borrow().get(&(self.board, self.sensor.id)).copied()
+            .ok_or(ReadError::ValueOutOfRange)
+    }
+}
+
+/// A heater that ignores writes, so replaying a log never touches the hardware
+struct LogHeater;
+
+impl Heater for LogHeater {
+    fn read_mode(&self) -> ReadResult<SensorReading<HeaterMode>> { Err(ReadError::Disabled) }
+    fn write_mode(&self, mode: HeaterMode) { debug!("Replay heater mode: {}", mode) }
+
+    fn read_duty(&self) -> ReadResult<SensorReading<u16>> { Err(ReadError::Disabled) }
+    fn write_duty(&self, duty: u16) { debug!("Replay heater duty: {}", duty) }
+
+    fn read_target_temp(&self) -> ReadResult<SensorReading<f32>> { Err(ReadError::Disabled) }
+    fn write_target_temp(&self, temp: f32) { debug!("Replay target temp: {}", temp) }
+
+    fn read_target_sensor(&self) -> ReadResult<SensorReading<Sensor>> { Err(ReadError::Disabled) }
+    fn write_target_sensor(&self, target_sensor: TargetSensor) { debug!("Replay target sensor: {:?}", target_sensor) }
+
+    fn read_max_temp(&self) -> ReadResult<SensorReading<f32>> { Err(ReadError::Disabled) }
+    fn write_max_temp(&self, temp: f32) { debug!("Replay max temp: {}", temp) }
+
+    fn read_version(&self) -> ReadResult<SensorReading<String>> { Err(ReadError::Disabled) }
+    fn read_flags(&self) -> ReadResult<SensorReading<BoardFlags>> { Err(ReadError::Disabled) }
+}
+
+/// A phase the runner entered while replaying a log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Transition {
+    pub time: String,
+    /// The board's track with `parallel = true`, otherwise None
+    pub board: Option<BoardId>,
+    pub program_id: Option<u8>,
+    pub program_name: Option<String>,
+    /// Step number, starting from 1
+    pub step: Option<usize>,
+    pub phase: String,
+    /// Why the programs stopped, when they failed
+    pub message: Option<String>,
+}
+
+impl Transition {
+    fn new(time: DateTime<Utc>, board: Option<BoardId>, state: &State) -> Self {
+        let program = match *state {
+            State::Scheduled { program, .. } => Some(program),
+            _ => state.program_step().map(|(program, _)| program),
+        };
+        Transition {
+            time: time.to_rfc3339(),
+            board,
+            program_id: program.map(|p| p.id),
+            program_name: program.map(|p| p.name.clone()),
+            step: state.program_step().map(|(_, step)| step + 1),
+            phase: String::from(state.phase()),
+            message: match state {
+                State::Failed { message } => Some(message.clone()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The readings in a uts-log CSV file, replayed through the runner on boards which read their
+/// sensors from the log. Display and raw files can be replayed, with the raw values converted
+/// as the sensors do. Readings from raw files include the heater voltage and current, so
+/// constant-power programs can correct their duty.
+pub struct LogReplay {
+    payload: Payload,
+    readings: Readings,
+    rows: Vec<LogRow>,
+    clock: Rc<ReplayClock>,
+}
+
+impl LogReplay {
+    pub fn read<P: AsRef<Path>>(path: P, version: BoardVersion) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let reader = BufReader::new(fs::File::open(path)?);
+        let mut lines = reader.lines();
+        let headers: Vec<String> = match lines.next() {
+            Some(line) => line?.trim_end().split(',').map(String::from).collect(),
+            None => vec![],
+        };
+        let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+        let (time_col, board_col) = match (column("UTC"), column("board")) {
+            (Some(time_col), Some(board_col)) => (time_col, board_col),
+            _ => return Err(invalid(String::from("Log file should have UTC and board columns"))),
+        };
+        // raw files have the voltages the display files convert to heater power
+        let raw = column("v_high").is_some();
+        let sensor_cols: Vec<(Sensor, usize)> = ALL_SENSORS.iter()
+            .filter_map(|sensor| column(sensor.id).map(|col| (*sensor, col)))
+            .collect();
+        let mut rows = vec![];
+        for line in lines {
+            let line = line?;
+            let values: Vec<&str> = line.trim_end().split(',').collect();
+            let time = values.get(time_col)
+                .and_then(|v| NaiveDateTime::parse_from_str(v, TIMESTAMP_FORMAT).ok());
+            let board = values.get(board_col).and_then(|v| BoardId::try_from(*v).ok());
+            let (time, board) = match (time, board) {
+                (Some(time), Some(board)) => (Utc.from_utc_datetime(&time), board),
+                _ => continue,
+            };
+            let readings = sensor_cols.iter()
+                .map(|(sensor, col)| {
+                    let value = values.get(*col).copied().unwrap_or_default();
+                    let reading = if raw {
+                        value.parse().ok().and_then(|raw_value| sensor.convert_raw(raw_value).ok()
+                            .map(|display_value| SensorReading::new(raw_value, display_value)))
+                    } else {
+                        value.parse().ok().map(|display_value| SensorReading::new(0, display_value))
+                    };
+                    (sensor.id, reading)
+                })
+                .collect();
+            rows.push(LogRow { time, board, readings });
+        }
+        let readings = Readings::default();
+        let boards = [BoardId::Top, BoardId::Bottom].iter()
+            .map(|&id| Board {
+                heater: Rc::new(LogHeater),
+                sensors: ALL_SENSORS.iter()
+                    .map(|&sensor| Box::new(LogSensor { board: id, sensor, readings: Rc::clone(&readings) }) as Box<dyn ReadableSensor>)
+                    .collect(),
+                ..Board::new(id, version)
+            })
+            .collect();
+        let clock = Rc::new(ReplayClock::new(rows.first().map_or_else(Utc::now, |row| row.time)));
+        Ok(LogReplay { payload: Payload::from_boards(boards), readings, rows, clock })
+    }
+
+    /// Boards with the readings of the row being replayed, and heaters that ignore writes
+    pub fn payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    /// Set to the time of each row as its events are read
+    pub fn clock(&self) -> Rc<ReplayClock> {
+        Rc::clone(&self.clock)
+    }
+
+    /// Events for each row of the log, reading the sensors like `PayloadEvents`
+    pub fn events<'a, I>(&'a self, sensors: I) -> LogEvents<'a>
+        where I: IntoIterator<Item=&'a str> {
+        let mut watching: Vec<&'a str> = vec![];
+        for sensor in sensors {
+            if !watching.contains(&sensor) {
+                watching.push(sensor);
+            }
+        }
+        LogEvents { replay: self, rows: self.rows.iter(), sensors: watching, limits: vec![], buffer: vec![] }
+    }
+
+    fn update(&self, row: &LogRow) {
+        let mut readings = self.readings.borrow_mut();
+        for &(sensor, reading) in &row.readings {
+            match reading {
+                Some(reading) => readings.insert((row.board, sensor), reading),
+                None => readings.remove(&(row.board, sensor)),
+            };
+        }
+    }
+}
+
+/// Temperature readings from each row of a log followed by `Event::Time`, moving the replay
+/// clock to the time of the row
+pub struct LogEvents<'a> {
+    replay: &'a LogReplay,
+    rows: std::slice::Iter<'a, LogRow>,
+    sensors: Vec<&'a str>,
+    limits: Vec<&'a str>,
+    buffer: Vec<Event<'a>>,
+}
+
+impl<'a> LogEvents<'a> {
+    /// Sensors read to check a limit, like `PayloadEvents::limiting`
+    pub fn limiting<I>(mut self, sensors: I) -> Self
+        where I: IntoIterator<Item=&'a str> {
+        self.limits.extend(sensors);
+        self
+    }
+}
+
+impl<'a> Iterator for LogEvents<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            let row = self.rows.next()?;
+            self.replay.clock.set(row.time);
+            self.replay.update(row);
+            self.buffer.push(Event::Time);
+            let board = &self.replay.payload[row.board as u8];
+            for &sensor_id in &self.sensors {
+                if let Some(temp) = read_watched(board, sensor_id, &self.limits) {
+                    self.buffer.push(Event::TemperatureReading { board: row.board, temp_sensor: sensor_id, temp });
+                }
+            }
+        }
+        self.buffer.pop()
+    }
+}
+
+/// Runs the programs against the readings in the log on the replay clock, from the start of the
+/// log, returning the phases the runner would have entered and when. Each board's track runs
+/// separately with `parallel = true`, without the power budget, and a track that fails stops
+/// while the others carry on, as in `run_tracks`. Commands, checkpoints and
+/// loops aren't replayed. Events are recorded in the event log, if there is one, to compare
+/// with the log from the flight.
+pub fn replay(log: &LogReplay, programs: &Programs, event_log: Option<&EventLog>) -> Vec<Transition> {
+    if log.rows.is_empty() {
+        warn!("No readings to replay");
+        return vec![];
+    }
+    let clock = log.clock();
+    clock.set(log.rows[0].time);
+    let tracks: Vec<(Option<BoardId>, Vec<&Program>)> = if programs.parallel {
+        programs.tracks().into_iter().map(|(board, track)| (Some(board), track)).collect()
+    } else {
+        vec![(None, programs.sequence())]
+    };
+    let mut program_lists: Vec<_> = tracks.iter().map(|(_, track)| track.iter().copied()).collect();
+    let mut controllers = vec![];
+    for (program_list, (board, _)) in program_lists.iter_mut().zip(&tracks) {
+        let mut controller = PayloadController::new(log.payload(), program_list)
+            .until(programs.end_time)
+            .with_clock(clock.clone());
+        if let Some(board) = board {
+            controller = controller.for_board(*board);
+        }
+        if let Some(event_log) = event_log {
+            controller = controller.with_event_log(event_log.clone());
+        }
+        controllers.push(controller);
+    }
+    let mut timeline = vec![];
+    let mut states: Vec<State> = vec![];
+    for (controller, (board, _)) in controllers.iter_mut().zip(&tracks) {
+        let state = controller.start();
+        controller.save(&state);
+        if matches!(state, State::Failed { .. }) {
+            controller.stop(&state);
+        }
+        timeline.push(Transition::new(clock.now(), *board, &state));
+        states.push(state);
+    }
+    let events = log.events(programs.iter().flat_map(|p| p.sensors()))
+        .limiting(programs.iter().flat_map(|p| p.abort_sensors()));
+    for event in events {
+        if states.iter().all(State::is_finished) { break }
+        for ((controller, state), (board, _)) in controllers.iter_mut().zip(states.iter_mut()).zip(&tracks) {
+            if state.is_finished() || !controller.accepts(&event) {
+                continue;
+            }
+            if let Some(new_state) = state.next(controller, event.clone()) {
+                // the next program is scheduled on the next event
+                if new_state != State::FinishedProgram {
+                    timeline.push(Transition::new(clock.now(), *board, &new_state));
+                }
+                *state = new_state;
+            }
+            controller.save(state);
+            if matches!(state, State::Failed { .. }) {
+                // only this track's heater is off, the others carry on
+                controller.stop(state);
+            }
+        }
+    }
+    for (controller, state) in controllers.iter_mut().zip(&states) {
+        if !matches!(state, State::Failed { .. }) {
+            controller.stop(state);
+        }
+    }
+    info!("Replayed {} transitions from {} readings", timeline.len(), log.rows.len());
+    timeline
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use assert_approx_eq::assert_approx_eq;
+
+    use crate::board::{BoardId, BoardVersion, TH1};
+    use crate::programs::Programs;
+    use crate::programs::event_log::EventLog;
+    use crate::programs::replay::{LogReplay, replay};
+    use crate::programs::runner::Event;
+
+    #[test]
+    fn test_replay() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "UTC,board,TH1,TH2,heater_power,program_id,program_name,phase\r\n").unwrap();
+        let temps = [25.0, 35.0, 45.0, 50.0, 40.0, 32.0, 28.0, 26.0];
+        for (index, temp) in temps.iter().enumerate() {
+            write!(file, "2023-09-01 00:{:02}:00.000000,1,20.00,,0.00,,,idle\r\n\
+                2023-09-01 00:{:02}:00.500000,2,{:.2},,1.00,,,idle\r\n", index, index, temp).unwrap();
+        }
+        let log = LogReplay::read(file.path(), BoardVersion::V2_2).unwrap();
+        let events: Vec<Event> = log.events(vec!["TH1", "TH2"]).take(3).collect();
+        assert_eq!(Event::TemperatureReading { board: BoardId::Top, temp_sensor: "TH1", temp: 20.0 }, events[0]);
+        assert_eq!(Event::Time, events[1]);
+
+        let programs: Programs = toml::from_str(r#"
+            [[programs]]
+            name = "Soak"
+            heat_board = "Bottom"
+            heat_time = "1m"
+            temp_sensor = "TH1"
+            temp_abort = 60.0
+            cool_temp = 30.0
+        "#).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let event_log = EventLog::replayed(dir.path());
+        let timeline = replay(&log, &programs, Some(&event_log));
+        let phases: Vec<(&str, &str)> = timeline.iter()
+            .map(|t| (t.time.as_str(), t.phase.as_str()))
+            .collect();
+        assert_eq!(vec![
+            ("2023-09-01T00:00:00+00:00", "heating"),
+            // the heat time is up at the first reading after a minute
+            ("2023-09-01T00:01:00+00:00", "cooling"),
+            ("2023-09-01T00:06:00.500+00:00", "done"),
+        ], phases);
+        assert_eq!(Some(2), timeline[1].step);
+        let recorded = event_log.events(100);
+        assert!(recorded.iter().any(|e| e["event"] == "phase" && e["phase"] == "cooling"
+            && e["time"] == "2023-09-01T00:01:00+00:00"));
+        assert!(dir.path().join("uts-replay-2023-09-01.jsonl").exists());
+        assert!(EventLog::new(dir.path()).events(100).is_empty());
+    }
+
+    #[test]
+    fn test_replay_failed_track() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "UTC,board,TH1\r\n").unwrap();
+        let temps = [25.0, 35.0, 45.0, 50.0, 50.0, 50.0, 40.0, 32.0, 28.0, 26.0];
+        for (index, temp) in temps.iter().enumerate() {
+            write!(file, "2023-09-01 00:{:02}:00.000000,1,30.00\r\n\
+                2023-09-01 00:{:02}:00.500000,2,{:.2}\r\n", index, index, temp).unwrap();
+        }
+        let log = LogReplay::read(file.path(), BoardVersion::V2_2).unwrap();
+        let programs: Programs = toml::from_str(r#"
+            parallel = true
+
+            [[programs]]
+            name = "Top never cools"
+            heat_board = "Top"
+            heat_time = "1m"
+            temp_sensor = "TH1"
+            temp_abort = 60.0
+            cool_temp = 20.0
+            cool_timeout = "2m"
+            on_failure = "abort"
+
+            [[programs]]
+            name = "Bottom soak"
+            heat_board = "Bottom"
+            heat_time = "5m"
+            temp_sensor = "TH1"
+            temp_abort = 60.0
+            cool_temp = 30.0
+        "#).unwrap();
+        let timeline = replay(&log, &programs, None);
+        let phases: Vec<(Option<BoardId>, &str, &str)> = timeline.iter()
+            .map(|t| (t.board, t.time.as_str(), t.phase.as_str()))
+            .collect();
+        assert_eq!(vec![
+            (Some(BoardId::Top), "2023-09-01T00:00:00+00:00", "heating"),
+            (Some(BoardId::Bottom), "2023-09-01T00:00:00+00:00", "heating"),
+            (Some(BoardId::Top), "2023-09-01T00:01:00+00:00", "cooling"),
+            (Some(BoardId::Top), "2023-09-01T00:03:00+00:00", "failed"),
+            // the bottom track carries on after the top fails
+            (Some(BoardId::Bottom), "2023-09-01T00:05:00+00:00", "cooling"),
+            (Some(BoardId::Bottom), "2023-09-01T00:08:00.500+00:00", "done"),
+        ], phases);
+    }
+
+    #[test]
+    fn test_replay_raw() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "UTC,board,TH1,v_high,v_low,v_curr,v_high_avg,v_low_avg,v_curr_avg\r\n").unwrap();
+        // the heater makes 5.35 W at the starting duty, then 2.68 W after the first correction,
+        // as the log doesn't follow the replayed duty
+        let rows = [(2000, 1000, 1800), (2000, 1000, 1900), (2000, 1000, 1900)];
+        for (index, (v_high, v_low, v_curr)) in rows.iter().enumerate() {
+            write!(file, "2023-09-01 00:{:02}:00.000000,2,2000,{},{},{},{},{},{}\r\n",
+                   index, v_high, v_low, v_curr, v_high, v_low, v_curr).unwrap();
+        }
+        let log = LogReplay::read(file.path(), BoardVersion::V2_2).unwrap();
+
+        let programs: Programs = toml::from_str(r#"
+            [[programs]]
+            name = "Constant power"
+            heat_board = "Bottom"
+            heat_time = "5m"
+            heat_duty = 0.5
+            heat_power = 2.5
+            temp_sensor = "TH1"
+            temp_abort = 200.0
+            cool_temp = 30.0
+        "#).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let event_log = EventLog::replayed(dir.path());
+        replay(&log, &programs, Some(&event_log));
+        let duties: Vec<String> = event_log.events(100).iter()
+            .filter(|e| e["setting"] == "heater_duty")
+            .filter_map(|e| e["value"].as_str().map(String::from))
+            .collect();
+        // the duty starts at heat_duty and is corrected for the measured power, recorded at
+        // most once a minute so the first correction to 64 isn't
+        assert_eq!(vec!["127", "60", "56"], duties);
+
+        // raw values are converted as the sensors do
+        let th1 = log.payload()[BoardId::Bottom as u8].read_sensor("TH1").unwrap();
+        assert_eq!(2000, th1.raw_value);
+        assert_approx_eq!(TH1.convert_raw(2000).unwrap(), th1.display_value);
+    }
+
+    #[test]
+    fn test_limit_with_failed_sensor() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "UTC,board,TH1,TH2\r\n2023-09-01 00:00:00.000000,2,50.00,\r\n").unwrap();
+        let log = LogReplay::read(file.path(), BoardVersion::V2_2).unwrap();
+        let max = "max(TH1, TH2)";
+        // control carries on with the sensors that can be read
+        let events: Vec<Event> = log.events(vec![max]).collect();
+        assert_eq!(vec![Event::TemperatureReading { board: BoardId::Bottom, temp_sensor: max, temp: 50.0 }, Event::Time],
+                   events);
+        // but a limit isn't checked against the cooler sensor when the hottest fails
+        let events: Vec<Event> = log.events(vec![max]).limiting(vec![max]).collect();
+        assert_eq!(vec![Event::Time], events);
+    }
+}
@@ -1,28 +1,81 @@
-use std::fmt::Formatter;
+use std::fmt::{Display, Formatter};
 use std::marker::PhantomData;
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::Relaxed;
-use std::thread::sleep;
+use std::rc::Rc;
 
 use chrono::{DateTime, Duration, Utc};
-use lazy_static::lazy_static;
-use log::{debug, info};
+use log::{debug, info, warn};
 
 use crate::board::{Board, BoardId};
-use crate::heater::{HeaterMode, TargetSensor};
-use crate::payload::Payload;
+use crate::clock::{Clock, SystemClock};
+use crate::control::constant_power::PowerController;
+use crate::control::pid::{PidConfig, PidController, PWM_DUTY_MAX};
+use crate::deadman::Deadman;
+use crate::guard;
+use crate::heater::HeaterMode;
+use crate::payload::{Config, Payload};
+use crate::power::BudgetDecision;
 
-use crate::programs::{Program, Programs};
+use crate::programs::{FailurePolicy, Program, Programs};
+use crate::programs::active::{ActiveProgram, ActiveProgramFile};
+use crate::programs::checkpoint::{Checkpoint, Checkpoints, Resume};
+use crate::programs::control::{Command, ControlChannel, ControlRequest};
+use crate::programs::event_log::{EventLog, RunnerEventKind};
+use crate::programs::profile::SetpointProfile;
+use crate::programs::step::{Action, HeatStep, StabilityDetector, Step, SteadyStateDetector};
+use crate::programs::summary::{SteadyStateRecord, SummaryFile};
+use crate::selector::SensorSelector;
 
+/// Smallest setpoint change written to the firmware while ramping, in °C
+const SETPOINT_RESOLUTION: f32 = 0.1;
+
+/// Time between duty corrections for constant-power programs, allowing the averaged
+/// voltage and current readings to settle
+const POWER_INTERVAL: Duration = Duration::seconds(5);
+
+/// Time between checkpoints while a step is running, so the elapsed time is kept if interrupted
+const CHECKPOINT_INTERVAL: Duration = Duration::seconds(30);
+
+/// Time between recording duty corrections in the event log, as PID control changes the duty
+/// on most readings
+const DUTY_RECORD_INTERVAL: Duration = Duration::seconds(60);
+
+/// States other than `Scheduled`, `FinishedProgram`, `Done` and `Failed` are running a step of
+/// a program, given by its index in `Program::steps()`
 #[derive(Debug)]
 pub enum State<'a> {
+    /// Waiting with the heaters off for the program's start time or window
+    Scheduled {
+        program: &'a Program,
+        start_time: DateTime<Utc>,
+    },
     Heating {
         program: &'a Program,
+        step: usize,
+        end_time: Option<DateTime<Utc>>,
+    },
+    Holding {
+        program: &'a Program,
+        step: usize,
+        end_time: Option<DateTime<Utc>>,
+    },
+    Waiting {
+        program: &'a Program,
+        step: usize,
         end_time: DateTime<Utc>,
     },
+    WaitingForTemp {
+        program: &'a Program,
+        step: usize,
+    },
+    /// Waiting to start a heat step until the other heaters leave enough of the power budget,
+    /// when running in a track
+    WaitingForPower {
+        program: &'a Program,
+        step: usize,
+    },
     Cooling {
         program: &'a Program,
+        step: usize,
     },
     FinishedProgram,
     Done,
@@ -34,8 +87,14 @@ pub enum State<'a> {
 impl<'a> PartialEq for State<'a> {
     fn eq(&self, other: &Self) -> bool {
         matches!((self, other),
+            (State::Scheduled { .. }, State::Scheduled { .. }) |
             (State::Heating { .. }, State::Heating { .. }) |
+            (State::Holding { .. }, State::Holding { .. }) |
+            (State::Waiting { .. }, State::Waiting { .. }) |
+            (State::WaitingForTemp { .. }, State::WaitingForTemp { .. }) |
+            (State::WaitingForPower { .. }, State::WaitingForPower { .. }) |
             (State::Cooling { .. }, State::Cooling { .. }) |
+            (State::FinishedProgram, State::FinishedProgram) |
             (State::Done, State::Done) |
             (State::Failed { .. }, State::Failed { .. }))
     }
@@ -52,23 +111,110 @@ pub enum Event<'a> {
 }
 
 impl<'a> State<'a> {
+    /// Whether the programs have completed, or stopped on a failure
+    pub(crate) fn is_finished(&self) -> bool {
+        matches!(self, State::Done | State::Failed { .. })
+    }
+
     /// Returns a new State if one is entered, otherwise None indicates current state continues
     pub fn next(&self, controller: &mut PayloadController<'a>, event: Event) -> Option<State<'a>> {
-        let current_time = Utc::now();
+        let current_time = controller.now();
+        let steps = Rc::clone(&controller.steps);
+        if let Some((program, step)) = self.program_step() {
+            if matches!(controller.step_deadline, Some(deadline) if current_time >= deadline) {
+                return Some(controller.fail(program, step, &format!("{} timed out", self.phase())));
+            }
+            if let Some(state) = controller.check_end_time(program, step, current_time) {
+                return Some(state);
+            }
+        }
         match self {
-            &State::Heating { program, end_time } => {
-                if current_time >= end_time {
+            &State::Scheduled { program, start_time } => {
+                if controller.is_ended(current_time) {
+                    info!("End time reached before program started: {}", program);
+                    return Some(State::Done);
+                }
+                if current_time >= start_time {
+                    return Some(controller.schedule_program(program));
+                }
+                None
+            }
+            &State::Heating { program, step, end_time } => {
+                if matches!(end_time, Some(end_time) if current_time >= end_time) {
                     info!("Heating time completed: {}", program);
-                    return Some(controller.start_cool(program));
+                    return Some(controller.next_step(program, step));
+                }
+                if let Some(state) = controller.update_power(program, step) {
+                    return Some(state);
+                }
+                if let Some(state) = controller.check_abort(program, step, &event) {
+                    return Some(state);
+                }
+                if let Some(state) = controller.check_steady_state(program, step, self.phase(), &event) {
+                    return Some(state);
                 }
                 match event {
                     Event::TemperatureReading { board, temp_sensor, temp }
-                    if board == program.heat_board && temp_sensor == program.temp_sensor => {
+                    if board == program.heat_board && temp_sensor == steps[step].sensor(program) => {
                         debug!("Checking {}, {}, temp {}°C vs abort temp: {}°C",
                             board, temp_sensor, temp, program.temp_abort);
-                        if temp > program.temp_abort {
-                            info!("Abort temp reached ({} > {}): {}", temp, program.temp_abort, program);
-                            Some(controller.start_cool(program))
+                        if controller.update_profile(program, temp) {
+                            info!("Setpoint profile completed: {}", program);
+                            Some(controller.next_step(program, step))
+                        } else {
+                            controller.update_pid(program, step, temp)
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            &State::Holding { program, step, end_time } => {
+                if matches!(end_time, Some(end_time) if current_time >= end_time) {
+                    info!("Hold timed out before temperature was stable: {}", program);
+                    return Some(controller.next_step(program, step));
+                }
+                if let Some(state) = controller.keep_heating(program, step, &event) {
+                    return Some(state);
+                }
+                match event {
+                    Event::TemperatureReading { board, temp_sensor, temp }
+                    if board == program.heat_board && temp_sensor == steps[step].sensor(program) => {
+                        let stable = match controller.stability.as_mut() {
+                            Some(stability) => stability.update(temp, current_time),
+                            None => true,
+                        };
+                        if stable {
+                            info!("Temperature stable at {}°C on {}: {}", temp, temp_sensor, program);
+                            Some(controller.next_step(program, step))
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            &State::Waiting { program, step, end_time } => {
+                if current_time >= end_time {
+                    info!("Wait completed: {}", program);
+                    return Some(controller.next_step(program, step));
+                }
+                controller.keep_heating(program, step, &event)
+            }
+            &State::WaitingForTemp { program, step } => {
+                if let Some(state) = controller.keep_heating(program, step, &event) {
+                    return Some(state);
+                }
+                let (above, below) = match steps[step].action {
+                    Action::WaitTemp { above, below } => (above, below),
+                    _ => (None, None),
+                };
+                match event {
+                    Event::TemperatureReading { board, temp_sensor, temp }
+                    if board == program.heat_board && temp_sensor == steps[step].sensor(program) => {
+                        if matches!(above, Some(above) if temp >= above)
+                            || matches!(below, Some(below) if temp <= below) {
+                            info!("Wait temp reached on {} ({}°C): {}", temp_sensor, temp, program);
+                            Some(controller.next_step(program, step))
                         } else {
                             None
                         }
@@ -76,15 +222,31 @@ impl<'a> State<'a> {
                     _ => None,
                 }
             }
-            &State::Cooling { program } => {
+            &State::WaitingForPower { program, step } => {
+                match &steps[step].action {
+                    Action::Heat(heat) if controller.has_power(program, heat) => {
+                        info!("Power available, starting heat: {}", program);
+                        Some(controller.start_heat(program, step, heat, steps[step].sensor(program)))
+                    }
+                    _ => None,
+                }
+            }
+            &State::Cooling { program, step } => {
+                if let Some(state) = controller.check_steady_state(program, step, self.phase(), &event) {
+                    return Some(state);
+                }
+                let cool_temp = match steps[step].action {
+                    Action::Cool { temp } => temp,
+                    _ => f32::MAX,
+                };
                 match event {
                     Event::TemperatureReading { board, temp_sensor, temp }
-                    if board == program.heat_board && temp_sensor == program.temp_sensor => {
+                    if board == program.heat_board && temp_sensor == steps[step].sensor(program) => {
                         debug!("Checking {}, {}, temp {}°C vs cool temp: {}°C",
-                            board, temp_sensor, temp, program.cool_temp);
-                        if temp <= program.cool_temp {
-                            info!("Cool temp reached ({} <= {}): {}", temp, program.cool_temp, program);
-                            Some(State::FinishedProgram)
+                            board, temp_sensor, temp, cool_temp);
+                        if temp <= cool_temp {
+                            info!("Cool temp reached ({} <= {}): {}", temp, cool_temp, program);
+                            Some(controller.next_step(program, step))
                         } else {
                             None
                         }
@@ -101,18 +263,101 @@ impl<'a> State<'a> {
             })
         }
     }
+
+    /// The program and step being run, if any
+    pub fn program_step(&self) -> Option<(&'a Program, usize)> {
+        match *self {
+            State::Heating { program, step, .. } |
+            State::Holding { program, step, .. } |
+            State::Waiting { program, step, .. } |
+            State::WaitingForTemp { program, step } |
+            State::WaitingForPower { program, step } |
+            State::Cooling { program, step } => Some((program, step)),
+            _ => None,
+        }
+    }
+
+    /// The running program, including one waiting for its start time
+    fn program(&self) -> Option<&'a Program> {
+        match *self {
+            State::Scheduled { program, .. } => Some(program),
+            _ => self.program_step().map(|(program, _)| program),
+        }
+    }
+
+    pub fn phase(&self) -> &'static str {
+        match self {
+            State::Scheduled { .. } => "scheduled",
+            State::Heating { .. } => "heating",
+            State::Holding { .. } => "holding",
+            State::Waiting { .. } => "waiting",
+            State::WaitingForTemp { .. } => "waiting_for_temp",
+            State::WaitingForPower { .. } => "waiting_for_power",
+            State::Cooling { .. } => "cooling",
+            State::FinishedProgram => "finished_program",
+            State::Done => "done",
+            State::Failed { .. } => "failed",
+        }
+    }
+
+    /// Brings the end time forward by time already spent on the step
+    fn after(self, elapsed: Duration) -> Self {
+        match self {
+            State::Heating { program, step, end_time } =>
+                State::Heating { program, step, end_time: end_time.map(|t| t - elapsed) },
+            State::Holding { program, step, end_time } =>
+                State::Holding { program, step, end_time: end_time.map(|t| t - elapsed) },
+            State::Waiting { program, step, end_time } =>
+                State::Waiting { program, step, end_time: end_time - elapsed },
+            state => state,
+        }
+    }
+}
+
+/// Describes a step for display, numbered from 1
+fn describe_step(program: &Program, step: usize) -> String {
+    match program.steps().get(step) {
+        Some(s) => format!("step {}: {}", step + 1, s),
+        None => format!("step {}", step + 1),
+    }
 }
 
 impl std::fmt::Display for State<'_> {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         match self {
-            State::Heating { program, end_time } => {
-                write!(f, "State::Heating(end_time: {}, temp_abort: {:.2}, thermostat: {})",
-                       end_time.format("%T.%3f"), program.temp_abort,
-                       program.thermostat.map(|v| format!("{:.2}", v))
-                           .unwrap_or(String::from("#empty")))
+            State::Scheduled { program, start_time } =>
+                write!(f, "State::Scheduled({}, start_time: {})", program, start_time.to_rfc3339()),
+            State::Heating { program, step, end_time } => {
+                let steps = program.steps();
+                let heat = match steps.get(*step).map(|s| &s.action) {
+                    Some(Action::Heat(heat)) => heat,
+                    _ => return write!(f, "State::Heating({})", describe_step(program, *step)),
+                };
+                write!(f, "State::Heating(step: {}, end_time: {}, temp_abort: {:.2}, thermostat: {}, pid: {})",
+                       step + 1,
+                       end_time.map(|t| t.format("%T.%3f").to_string())
+                           .unwrap_or(String::from("#empty")),
+                       program.temp_abort,
+                       heat.thermostat.map(|v| format!("{:.2}", v))
+                           .unwrap_or(String::from("#empty")),
+                       heat.pid.as_ref().map(|v| format!("{:.2}", v.setpoint))
+                           .unwrap_or(String::from("#empty")))?;
+                if let Some(profile) = heat.setpoint_profile() {
+                    let segments: Vec<String> = profile.iter().map(|s| s.to_string()).collect();
+                    write!(f, " profile: [{}]", segments.join(", "))?;
+                }
+                Ok(())
             }
-            State::Cooling { program } => write!(f, "State::Cooling({})", program.cool_temp),
+            State::Holding { program, step, .. } =>
+                write!(f, "State::Holding({})", describe_step(program, *step)),
+            State::Waiting { program, step, end_time } =>
+                write!(f, "State::Waiting({}, end_time: {})", describe_step(program, *step), end_time.format("%T.%3f")),
+            State::WaitingForTemp { program, step } =>
+                write!(f, "State::WaitingForTemp({})", describe_step(program, *step)),
+            State::WaitingForPower { program, step } =>
+                write!(f, "State::WaitingForPower({})", describe_step(program, *step)),
+            State::Cooling { program, step } =>
+                write!(f, "State::Cooling({})", describe_step(program, *step)),
             State::FinishedProgram => write!(f, "State::FinishedProgram"),
             State::Done => write!(f, "State::Done"),
             State::Failed { message } => write!(f, "State::Failed(\"{}\")", message),
@@ -120,199 +365,1328 @@ impl std::fmt::Display for State<'_> {
     }
 }
 
-lazy_static! {
-    static ref ABORT: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
-}
-
 pub struct PayloadController<'a> {
     payload: &'a Payload,
     programs: &'a mut dyn Iterator<Item=&'a Program>,
+    /// Steps of the current program
+    steps: Rc<Vec<Step>>,
+    /// Set when the current program is aborted, so it finishes after cooling
+    aborted: bool,
+    /// Sensor the heater is controlled on, kept from the last heat step until the heater is off
+    control_sensor: Option<String>,
+    pid: Option<PidController>,
+    power: Option<PowerController>,
+    profile: Option<SetpointProfile>,
+    target_temp: Option<f32>,
+    /// Heater duty last recorded in the event log, and when
+    duty_recorded: Option<(u16, DateTime<Utc>)>,
+    stability: Option<StabilityDetector>,
+    steady_state: Option<SteadyStateDetector>,
+    /// Number of programs started, so the current program is at `program_count - 1` in the sequence
+    program_count: usize,
+    /// When the current step started, less any time spent on it before a resume
+    step_start: DateTime<Utc>,
+    checkpoints: Option<Checkpoints>,
+    /// Where `start()` starts from
+    resume: Resume,
+    /// Completed runs through the programs with `loop = true`, for checkpoints and the summary
+    iteration: u32,
+    /// Program index, step, whether paused, and time of the last checkpoint
+    checkpointed: Option<(usize, usize, bool, DateTime<Utc>)>,
+    /// When the current step fails, from the program `heat_timeout` or `cool_timeout`
+    step_deadline: Option<DateTime<Utc>>,
+    /// Times the current program has been retried after failing
+    retries: u32,
+    /// Set when the current program has failed with the `wait` policy, so it cools without a timeout
+    failed: bool,
+    /// No programs are started after this, and the current one skips to cooling
+    end_time: Option<DateTime<Utc>>,
+    summary: Option<SummaryFile>,
+    event_log: Option<EventLog>,
+    /// Program index, step and phase last recorded in the event log
+    recorded: Option<(usize, usize, &'static str)>,
+    active: Option<ActiveProgramFile>,
+    /// Program index and phase last saved as the active program, and when
+    published: Option<(usize, &'static str, DateTime<Utc>)>,
+    /// Set when running the track of programs for one board, alongside tracks for the others
+    board: Option<BoardId>,
+    control: Option<ControlChannel>,
+    /// When the current step was paused by a command, with the heater off and its timers frozen
+    paused: Option<DateTime<Utc>>,
+    /// Time for the steps, schedules and records, and sleeping between events
+    clock: Rc<dyn Clock>,
+    deadman: Option<Deadman>,
 }
 
 impl<'a> PayloadController<'a> {
+    /// Callers should hold a HeaterGuard with `abort_on_signal()`, so the programs stop
+    /// and heaters are switched off on Ctrl-C
     pub fn new(payload: &'a Payload, programs: &'a mut dyn Iterator<Item=&'a Program>) -> Self {
-        // switch off heater if program is stopped with Ctrl-C
-        ctrlc::set_handler(|| {
-            ABORT.store(true, Relaxed);
-        }).expect("Error setting Ctrl-C handler");
+        let clock: Rc<dyn Clock> = Rc::new(SystemClock);
+        PayloadController {
+            payload,
+            programs,
+            steps: Rc::new(vec![]),
+            aborted: false,
+            control_sensor: None,
+            pid: None,
+            power: None,
+            profile: None,
+            target_temp: None,
+            duty_recorded: None,
+            stability: None,
+            steady_state: None,
+            program_count: 0,
+            step_start: clock.now(),
+            checkpoints: None,
+            resume: Resume::Start,
+            iteration: 0,
+            checkpointed: None,
+            step_deadline: None,
+            retries: 0,
+            failed: false,
+            end_time: None,
+            summary: None,
+            event_log: None,
+            recorded: None,
+            active: None,
+            published: None,
+            board: None,
+            control: None,
+            paused: None,
+            clock,
+            deadman: None,
+        }
+    }
+
+    /// Saves checkpoints while running, and starts from `resume` instead of the first program
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints, resume: Resume) -> Self {
+        self.iteration = resume.iteration();
+        self.checkpoints = Some(checkpoints);
+        self.resume = resume;
+        self
+    }
+
+    /// Records steady states detected at the end of steps in the run summary
+    pub fn with_summary(mut self, summary: SummaryFile) -> Self {
+        self.summary = Some(summary);
+        self
+    }
+
+    /// Records program starts, phase changes, heater writes and failures in the event log
+    pub fn with_event_log(mut self, event_log: EventLog) -> Self {
+        self.event_log = Some(event_log);
+        self
+    }
+
+    /// Shares the running program and phase with other processes, such as uts-log
+    pub fn with_active_program(mut self, active: ActiveProgramFile) -> Self {
+        self.active = Some(active);
+        self
+    }
+
+    /// Runs the programs as the track for one board, leaving the other heaters to their own
+    /// tracks and waiting for the power budget before heating
+    pub fn for_board(mut self, board: BoardId) -> Self {
+        self.board = Some(board);
+        self
+    }
+
+    /// Takes pause, resume, skip and abort commands from the control channel while running
+    pub fn with_control(mut self, control: ControlChannel) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Stops running programs at the end time, cooling the current program first
+    pub fn until(mut self, end_time: Option<DateTime<Utc>>) -> Self {
+        self.end_time = end_time;
+        self
+    }
+
+    /// Disarms deadlines left by manual heater commands when the programs take over a heater,
+    /// so the safety supervisor doesn't switch it off mid-step
+    pub fn with_deadman(mut self, deadman: Deadman) -> Self {
+        self.deadman = Some(deadman);
+        self
+    }
+
+    /// Runs on the clock instead of the system clock, e.g. simulated time in tests or the
+    /// timestamps of a replayed log
+    pub fn with_clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.step_start = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
 
-        PayloadController { payload, programs }
+    fn is_ended(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.end_time, Some(end_time) if now >= end_time)
     }
 
     pub fn run(&mut self, events: &mut dyn Iterator<Item = Event<'a>>, duration: Duration) -> State<'a>
     {
-        let duration = duration.to_std().unwrap();
         let mut state = self.start();
+        self.save(&state);
         for event in events {
             debug!("{} <- {:?}", &state, &event);
             if state == State::Done { break }
-            if let Some(new_state) = state.next(self, event) {
-                state = new_state
+            for request in self.take_commands() {
+                if let Some(new_state) = self.command(&state, &request) {
+                    state = new_state
+                }
+            }
+            if self.paused.is_none() {
+                if let Some(new_state) = state.next(self, event) {
+                    state = new_state
+                }
             }
-            sleep(duration);
-            if self.is_aborted() { break }
+            self.save(&state);
+            self.clock.sleep(duration);
+            if self.is_aborted() || matches!(state, State::Failed { .. }) { break }
         }
+        self.stop(&state);
         state
     }
 
-    pub fn start(&mut self) -> State<'a> {
-        let first = self.programs.next().expect("Didn't find any programs");
-        self.start_heat(first)
+    /// Saves the checkpoint and active program, and records the phase
+    pub(crate) fn save(&mut self, state: &State<'a>) {
+        self.checkpoint(state);
+        self.record_phase(state);
+        self.publish(state);
     }
 
-    pub fn start_heat(&self, program: &'a Program) -> State<'a> {
-        info!("Starting heat for program: {:?}", &program);
-        for board in self.payload {
-            // #88 turn off heaters on all the boards, so we start in a known state
-            board.write_heater_mode(HeaterMode::OFF);
+    pub(crate) fn stop(&mut self, state: &State<'a>) {
+        let reason = match state {
+            State::Done => String::from("completed"),
+            State::Failed { message } => message.clone(),
+            _ => String::from("stopped"),
+        };
+        let reason = match self.board {
+            Some(board) => format!("{} track {}", board, reason),
+            None => reason,
+        };
+        self.record(None, None, RunnerEventKind::Stopped { reason });
+        if let Some(active) = &self.active {
+            active.write(&ActiveProgram::idle(self.now()));
         }
-        let board = &self.payload[program.heat_board as u8];
-        let end_time = Utc::now() + program.heat_time;
-        board.write_heater_duty((program.heat_duty * 255.0) as u16);
-        let target_sensor = TargetSensor::from(program.temp_sensor.clone());
-        board.write_target_sensor(target_sensor);
-        match program.thermostat {
-            Some(temp) => {
-                board.write_target_temp(temp);
-                board.write_heater_mode(HeaterMode::PID);
+    }
+
+    fn take_commands(&self) -> Vec<ControlRequest> {
+        self.control.as_ref().map(|control| control.take(self.now())).unwrap_or_default()
+    }
+
+    /// Whether a command is for the track this controller is running. Commands for a board are
+    /// checked against the running program when not running in a track.
+    fn is_for(&self, request: &ControlRequest) -> bool {
+        request.board.is_none() || self.board.is_none() || request.board == self.board
+    }
+
+    /// Applies a command to the state, auditing the outcome. Returns a new state if it changes.
+    pub fn command(&mut self, state: &State<'a>, request: &ControlRequest) -> Option<State<'a>> {
+        let program = state.program();
+        let result = match (program, request.board) {
+            (Some(program), Some(board)) if program.heat_board != board =>
+                Err(format!("not running a program on the {} board", board)),
+            (Some(program), _) => self.apply(state, program, request).map_err(String::from),
+            (None, _) => Err(String::from("no program is running")),
+        };
+        let outcome = match (&result, self.board) {
+            (Ok(_), Some(board)) => format!("applied to the {} track", board),
+            (Ok(_), None) => String::from("applied"),
+            (Err(reason), _) => format!("ignored, {}", reason),
+        };
+        self.report(request, program, state.program_step().map(|(_, step)| step), outcome);
+        result.ok().flatten()
+    }
+
+    /// Logs, audits and records the outcome of a command
+    fn report(&mut self, request: &ControlRequest, program: Option<&'a Program>, step: Option<usize>, outcome: String) {
+        info!("Command {} from {} {}", request.command, request.source, outcome);
+        if let Some(control) = &self.control {
+            control.audit(request, &outcome, self.now());
+        }
+        let (command, source) = (request.command.to_string(), request.source.clone());
+        self.record(program, step, RunnerEventKind::Command { command, source, outcome });
+    }
+
+    fn apply(&mut self, state: &State<'a>, program: &'a Program, request: &ControlRequest)
+             -> Result<Option<State<'a>>, &'static str> {
+        let now = self.now();
+        match request.command {
+            Command::Pause => {
+                if self.paused.is_some() {
+                    return Err("already paused");
+                }
+                info!("Pausing with the heater off: {}", program);
+                self.heater_off(program);
+                self.paused = Some(now);
+                Ok(None)
             }
-            None => {
-                board.write_heater_mode(HeaterMode::PWM);
+            Command::Resume => {
+                let paused = self.paused.take().ok_or("not paused")?;
+                info!("Resuming after pausing for {}s: {}", (now - paused).num_seconds(), program);
+                let (program, step) = match state.program_step() {
+                    Some(program_step) => program_step,
+                    // waiting to start, so check the start time again
+                    None => return Ok(Some(self.schedule_program(program))),
+                };
+                let failed = self.failed;
+                let state = self.resume_step(program, step, paused - self.step_start, self.aborted);
+                if failed {
+                    // still cooling without a timeout after failing with the wait policy
+                    self.failed = true;
+                    self.step_deadline = None;
+                }
+                Ok(Some(state))
+            }
+            Command::Skip => {
+                info!("Skipping to the next program: {}", program);
+                self.paused = None;
+                Ok(Some(self.finish_program(program)))
+            }
+            Command::Abort => {
+                warn!("Switching off heaters and stopping programs: {}", program);
+                self.paused = None;
+                for board in self.payload {
+                    if self.board.is_none() || self.board == Some(board.id) {
+                        board.write_heater_mode(HeaterMode::OFF);
+                        self.record_write(program, board.into(), "heater_mode", &HeaterMode::OFF);
+                    }
+                }
+                self.reset_control();
+                Ok(Some(State::Failed { message: format!("Aborted by {}", request.source) }))
             }
         }
-        State::Heating { program, end_time }
     }
 
-    pub fn start_cool(&self, program: &'a Program) -> State<'a> {
-        info!("Starting cool for program: {:?}", &program);
-        let board = &self.payload[program.heat_board as u8];
-        board.write_heater_mode(HeaterMode::OFF);
-        State::Cooling { program }
+    /// Events for the board of the track, or every event if not running in a track
+    pub(crate) fn accepts(&self, event: &Event) -> bool {
+        match (self.board, event) {
+            (Some(track), Event::TemperatureReading { board, .. }) => *board == track,
+            _ => true,
+        }
     }
 
-    pub fn next_program_or_done(&mut self) -> State<'a> {
-        if let Some(program) = self.programs.next() {
-            self.start_heat(program)
-        } else {
-            State::Done
+    pub fn start(&mut self) -> State<'a> {
+        if self.is_ended(self.now()) {
+            info!("End time has passed, not starting programs");
+            return State::Done;
+        }
+        let resume = std::mem::replace(&mut self.resume, Resume::Start);
+        let paused = matches!(resume, Resume::Program { paused: true, .. } | Resume::Step { paused: true, .. });
+        let state = match resume {
+            Resume::Start => {
+                let first = self.next_program().expect("Didn't find any programs");
+                self.schedule_program(first)
+            }
+            Resume::Program { index, .. } => match self.skip_programs(index) {
+                Some(program) => self.schedule_program(program),
+                None => State::Done,
+            },
+            Resume::Step { index, step, elapsed, aborted, .. } => match self.skip_programs(index) {
+                Some(program) => self.resume_step(program, step, elapsed, aborted),
+                None => State::Done,
+            },
+        };
+        if let (true, Some(program)) = (paused, state.program()) {
+            // paused by a command before the restart, so it waits for a resume command
+            info!("Staying paused with the heater off: {}", program);
+            self.heater_off(program);
+            self.paused = Some(self.now());
         }
+        state
     }
 
-    pub fn is_aborted(&self) -> bool {
-        ABORT.load(Relaxed)
+    fn next_program(&mut self) -> Option<&'a Program> {
+        let program = self.programs.next()?;
+        self.program_count += 1;
+        self.retries = 0;
+        Some(program)
     }
-}
 
-impl<'a> Drop for PayloadController<'a> {
-    fn drop(&mut self) {
-        info!("Disabling payload heaters");
+    /// The program at this position in the sequence, skipping those before it
+    fn skip_programs(&mut self, index: usize) -> Option<&'a Program> {
+        for _ in 0..index {
+            self.next_program()?;
+        }
+        self.next_program()
+    }
+
+    /// Starts the program if it can start now, otherwise switches the heaters off to wait for
+    /// its start time or window, or skips it if its window has passed
+    pub fn schedule_program(&mut self, program: &'a Program) -> State<'a> {
+        let now = self.now();
+        match program.next_start(now) {
+            Some(start_time) if start_time <= now => self.start_program(program),
+            Some(start_time) => {
+                info!("Waiting until {} to start: {}", start_time.to_rfc3339(), program);
+                self.record(Some(program), None, RunnerEventKind::Scheduled { start_time: start_time.to_rfc3339() });
+                self.load_program(program);
+                self.step_start = now;
+                self.step_deadline = None;
+                State::Scheduled { program, start_time }
+            }
+            None => {
+                info!("Skipping program, it can't start before not_after: {}", program);
+                let reason = String::from("can't start before not_after");
+                self.record(Some(program), None, RunnerEventKind::Skipped { reason });
+                self.next_program_or_done()
+            }
+        }
+    }
+
+    pub fn start_program(&mut self, program: &'a Program) -> State<'a> {
+        info!("Starting program: {:?}", &program);
+        self.record(Some(program), None, RunnerEventKind::ProgramStarted);
+        self.load_program(program);
+        self.start_step(program, 0)
+    }
+
+    fn load_program(&mut self, program: &'a Program) {
         for board in self.payload {
+            if matches!(self.board, Some(track) if track != board.id) {
+                // the other boards are running their own tracks
+                continue;
+            }
+            // #88 turn off heaters on all the boards, so we start in a known state
             board.write_heater_mode(HeaterMode::OFF);
+            self.record_write(program, board.into(), "heater_mode", &HeaterMode::OFF);
         }
+        self.reset_control();
+        self.steps = Rc::new(program.steps());
+        self.aborted = false;
+        self.failed = false;
     }
-}
 
+    /// Starts a program part way through a step, with the time already spent on it taken off
+    /// its duration or timeout. Steps which leave the heater as it is set it up again from the
+    /// heat step before them. Setpoint profiles start again from the current temperature.
+    pub fn resume_step(&mut self, program: &'a Program, step: usize, elapsed: Duration, aborted: bool) -> State<'a> {
+        info!("Resuming program at step {} after {}s: {:?}", step + 1, elapsed.num_seconds(), &program);
+        self.record(Some(program), Some(step), RunnerEventKind::ProgramResumed { elapsed_secs: elapsed.num_seconds() });
+        self.load_program(program);
+        self.aborted = aborted;
+        let steps = Rc::clone(&self.steps);
+        let keeps_heater = |s: &Step| !matches!(s.action, Action::Heat(_) | Action::Cool { .. });
+        if matches!(steps.get(step), Some(s) if keeps_heater(s)) {
+            let last = steps[..step].iter().rev().find(|s| !keeps_heater(s));
+            if let Some(last) = last {
+                if let Action::Heat(heat) = &last.action {
+                    self.configure_heater(program, heat, last.sensor(program));
+                }
+            }
+        }
+        let state = self.start_step(program, step);
+        self.step_start -= elapsed;
+        self.step_deadline = self.step_deadline.map(|deadline| deadline - elapsed);
+        state.after(elapsed)
+    }
 
-pub struct PayloadEvents<'a> {
-    payload: &'a Payload,
-    buffer: Vec<Event<'a>>,
-    phantom: PhantomData<&'a Event<'a>>,
-}
+    pub fn start_step(&mut self, program: &'a Program, index: usize) -> State<'a> {
+        let steps = Rc::clone(&self.steps);
+        let step = match steps.get(index) {
+            Some(step) => step,
+            None => return self.finish_program(program),
+        };
+        info!("Starting step {} of {} ({}): {}", index + 1, steps.len(), step, program);
+        self.stability = None;
+        self.steady_state = step.steady_state.as_ref()
+            .map(|steady| SteadyStateDetector::new(steady, &step.steady_state_sensors(program)));
+        self.step_start = self.now();
+        self.step_deadline = step.timeout(program)
+            .filter(|_| !self.failed)
+            .map(|timeout| self.step_start + timeout);
+        match &step.action {
+            Action::Heat(heat) if !self.has_power(program, heat) => {
+                info!("Waiting for the power budget to start heating: {}", program);
+                self.heater_off(program);
+                State::WaitingForPower { program, step: index }
+            }
+            Action::Heat(heat) => self.start_heat(program, index, heat, step.sensor(program)),
+            &Action::Hold { tolerance, window, timeout } => {
+                self.stability = Some(StabilityDetector::new(tolerance, window));
+                State::Holding { program, step: index, end_time: timeout.map(|t| self.now() + t) }
+            }
+            &Action::Wait { duration } =>
+                State::Waiting { program, step: index, end_time: self.now() + duration },
+            Action::WaitTemp { .. } => State::WaitingForTemp { program, step: index },
+            Action::Cool { .. } => self.start_cool(program, index),
+        }
+    }
 
-impl<'a> PayloadEvents<'a> {
-    pub fn new(payload: &'a Payload) -> PayloadEvents<'a> {
-        PayloadEvents { payload, buffer: vec![], phantom: PhantomData }
+    /// Starts the next step, or finishes the program after the last step or cooling after an abort
+    pub fn next_step(&mut self, program: &'a Program, step: usize) -> State<'a> {
+        if self.aborted {
+            return self.finish_program(program);
+        }
+        self.start_step(program, step + 1)
     }
-}
 
-impl<'a> Iterator for PayloadEvents<'a> {
-    type Item = Event<'a>;
+    /// Skips to the next cool step, finishing the program once it has cooled
+    pub fn abort(&mut self, program: &'a Program, step: usize, reason: &str) -> State<'a> {
+        self.record(Some(program), Some(step), RunnerEventKind::Aborted { reason: String::from(reason) });
+        self.aborted = true;
+        let cool = self.steps.iter().enumerate().skip(step + 1).find(|(_, s)| s.is_cool());
+        match cool.map(|(index, _)| index) {
+            Some(index) => self.start_step(program, index),
+            None => self.finish_program(program),
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.buffer.is_empty() {
-            self.buffer.push(Event::Time); // always put something in the buffer
-            for board in self.payload {
-                if let Some(reading) = read_board(board, board.into()) {
-                    self.buffer.push(reading);
+    /// Handles a failed step with the program `on_failure` policy
+    pub fn fail(&mut self, program: &'a Program, step: usize, reason: &str) -> State<'a> {
+        warn!("Step {} failed, {}: {}", step + 1, reason, program);
+        let policy = format!("{:?}", program.on_failure);
+        self.record(Some(program), Some(step), RunnerEventKind::Failed { reason: String::from(reason), policy });
+        match program.on_failure {
+            FailurePolicy::Retry(retries) if self.retries < retries => {
+                self.retries += 1;
+                warn!("Retrying program ({} of {}): {}", self.retries, retries, program);
+                self.start_program(program)
+            }
+            FailurePolicy::Skip | FailurePolicy::Retry(_) => {
+                warn!("Skipping to the next program: {}", program);
+                self.finish_program(program)
+            }
+            FailurePolicy::Abort => {
+                warn!("Switching off heaters and stopping programs: {}", program);
+                for board in self.payload {
+                    if self.board.is_none() || self.board == Some(board.id) {
+                        board.write_heater_mode(HeaterMode::OFF);
+                        self.record_write(program, board.into(), "heater_mode", &HeaterMode::OFF);
+                    }
+                }
+                self.reset_control();
+                State::Failed { message: format!("Step {} of {} failed, {}", step + 1, program, reason) }
+            }
+            FailurePolicy::Wait => {
+                warn!("Switching off heater and waiting to cool without a timeout: {}", program);
+                self.failed = true;
+                if self.steps[step].is_cool() {
+                    self.aborted = true;
+                    self.start_step(program, step)
+                } else {
+                    self.abort(program, step, "failed with the wait policy")
                 }
             }
         }
-        self.buffer.pop()
     }
-}
 
-fn read_board<'a>(board: &Board, heat_board: BoardId) -> Option<Event<'a>> {
-    let sensor = board.get_target_sensor().ok()?;
-    let reading = board.read_target_sensor_temp().ok()?;
-    Some(Event::TemperatureReading {
-        board: heat_board,
-        temp_sensor: sensor.id,
-        temp: reading.display_value,
-    })
-}
+    fn finish_program(&mut self, program: &'a Program) -> State<'a> {
+        info!("Finished program: {}", program);
+        self.heater_off(program);
+        self.record(Some(program), None, RunnerEventKind::ProgramFinished);
+        State::FinishedProgram
+    }
 
-pub fn run(payload: &Payload, programs: &Programs) {
-    loop {
-        let mut events = PayloadEvents::new(payload);
-        let program_list = &mut programs.iter();
-        let mut controller = PayloadController::new(payload, program_list);
-        controller.run(&mut events, Duration::seconds(1));
-        if !programs.run_loop || controller.is_aborted() { break; }
+    pub fn start_heat(&mut self, program: &'a Program, step: usize, heat: &HeatStep, sensor: &str) -> State<'a> {
+        let end_time = heat.duration.map(|duration| self.now() + duration);
+        if !self.configure_heater(program, heat, sensor) {
+            warn!("Power budget rejected the heater, waiting for power: {}", program);
+            self.heater_off(program);
+            return State::WaitingForPower { program, step };
+        }
+        if end_time.is_none() && self.profile.is_none() && self.steady_state.is_none() {
+            // nothing to wait for, so leave the heater on for the following steps
+            return self.next_step(program, step);
+        }
+        State::Heating { program, step, end_time }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use chrono::Duration;
+    /// False if the heat step could exceed the power budget, with the power the other heaters
+    /// are using
+    fn has_power(&self, program: &Program, heat: &HeatStep) -> bool {
+        let budget = match &self.payload[program.heat_board as u8].budget {
+            Some(budget) => budget,
+            None => return true,
+        };
+        // host-side PID and the firmware thermostat can use full power
+        let (mode, duty) = if heat.pid.is_some() {
+            (HeaterMode::PWM, PWM_DUTY_MAX)
+        } else if heat.thermostat.is_some() || heat.setpoint_profile().is_some() {
+            (HeaterMode::PID, PWM_DUTY_MAX)
+        } else {
+            (HeaterMode::PWM, (heat.duty * 255.0) as u16)
+        };
+        budget.allows(program.heat_board, mode, duty)
+    }
 
-    use crate::board::BoardId;
-    use crate::payload::Payload;
+    /// Switches the heater on with the heat step settings, controlling on the sensor. Returns
+    /// false if the power budget rejected it.
+    fn configure_heater(&mut self, program: &'a Program, heat: &HeatStep, sensor: &str) -> bool {
+        let board = &self.payload[program.heat_board as u8];
+        if let Some(deadman) = &self.deadman {
+            if deadman.disarm(board.bus.id) {
+                info!("Disarmed the deadman left by a manual heater command: {}", program);
+            }
+        }
+        self.reset_control();
+        self.control_sensor = Some(sensor.to_string());
+        let selector: SensorSelector = sensor.parse()
+            .unwrap_or_else(|err| panic!("Invalid temp_sensor for {}: {}", program, err));
+        let start_temp = board.read_temp(&selector).ok();
+        self.profile = heat.setpoint_profile()
+            .map(|segments| SetpointProfile::new(segments, start_temp, self.now()));
+        let setpoint = self.profile.as_ref().map(|p| p.setpoint());
+        let target_sensor = selector.target_sensor();
+        let pid = heat.pid.clone().or_else(|| {
+            // fall back to host-side control if the firmware can't see the sensor
+            let temp = setpoint.or(heat.thermostat).filter(|_| target_sensor.is_none())?;
+            info!("Firmware can't control on {}, using host-side PID", selector);
+            Some(PidConfig::firmware_equivalent(temp))
+        });
+        if let Some(pid) = pid {
+            // host-side PID can use any sensor, and sets the duty on each reading
+            let mut pid = PidController::new(pid);
+            if let Some(setpoint) = setpoint {
+                pid.set_setpoint(setpoint);
+            }
+            board.write_heater_duty(pid.duty());
+            self.record_duty(program, pid.duty());
+            self.pid = Some(pid);
+            self.write_heater_mode(program, HeaterMode::PWM)
+        } else {
+            board.write_heater_duty((heat.duty * 255.0) as u16);
+            self.record_duty(program, (heat.duty * 255.0) as u16);
+            if let Some(target_sensor) = target_sensor {
+                board.write_target_sensor(target_sensor);
+                self.record_write(program, program.heat_board, "target_sensor", &format!("{:?}", target_sensor));
+            }
+            // a setpoint profile without host-side PID uses the firmware thermostat
+            self.target_temp = setpoint.or(heat.thermostat);
+            match self.target_temp {
+                Some(temp) => {
+                    board.write_target_temp(temp);
+                    self.record_write(program, program.heat_board, "target_temp", &temp);
+                    self.write_heater_mode(program, HeaterMode::PID)
+                }
+                None => {
+                    if let Some(power) = heat.power {
+                        // duty starts from the heat duty, and is corrected as power is measured
+                        self.power = Some(PowerController::new(power, heat.duty, POWER_INTERVAL));
+                    }
+                    self.write_heater_mode(program, HeaterMode::PWM)
+                }
+            }
+        }
+    }
 
-    use crate::programs::Program;
-    use crate::programs::runner::{Event, PayloadController, PayloadEvents, State};
+    /// Switches the heater on, recording the write unless the power budget rejected it
+    fn write_heater_mode(&mut self, program: &'a Program, mode: HeaterMode) -> bool {
+        let board = &self.payload[program.heat_board as u8];
+        match board.write_heater_mode(mode) {
+            BudgetDecision::Rejected => return false,
+            BudgetDecision::Derated(duty) => self.record_write(program, program.heat_board, "heater_duty", &duty),
+            BudgetDecision::Allowed => {}
+        }
+        self.record_write(program, program.heat_board, "heater_mode", &mode);
+        true
+    }
 
-    const TH1: &str = "TH1";
-    const J7: &str = "J7";
+    pub fn start_cool(&mut self, program: &'a Program, step: usize) -> State<'a> {
+        info!("Starting cool for program: {:?}", &program);
+        self.heater_off(program);
+        State::Cooling { program, step }
+    }
 
-    #[test]
-    fn test_programs() {
-        let _ = env_logger::try_init();
-        let programs: Vec<Program> = vec![
-            Program {
-                id: 0,
-                name: String::from("Top"),
-                heat_time: Duration::milliseconds(5),
-                temp_sensor: String::from("TH1"),
-                temp_abort: 80.0,
-                thermostat: None,
-                cool_temp: 40.0,
-                heat_board: BoardId::Top,
-                heat_duty: 1.0,
-            },
-            Program {
-                id: 1,
-                name: String::from("Bottom"),
-                heat_time: Duration::milliseconds(3),
-                temp_sensor: String::from("J7"),
-                temp_abort: 100.0,
-                thermostat: Some(80.0),
-                cool_temp: 30.0,
-                heat_board: BoardId::Bottom,
-                heat_duty: 1.0,
-            },
-        ];
+    fn heater_off(&mut self, program: &'a Program) {
+        let board = &self.payload[program.heat_board as u8];
+        board.write_heater_mode(HeaterMode::OFF);
+        self.record_write(program, program.heat_board, "heater_mode", &HeaterMode::OFF);
+        self.reset_control();
+    }
 
-        let events: Vec<Event> = vec![
-            Event::Time,
-            Event::TemperatureReading { board: BoardId::Top, temp: 55.0, temp_sensor: TH1 },
-            Event::TemperatureReading { board: BoardId::Top, temp: 105.0, temp_sensor: TH1 },
-            Event::Time,
-            Event::Time,
-            Event::TemperatureReading { board: BoardId::Top, temp: 60.0, temp_sensor: TH1 },
-            Event::TemperatureReading { board: BoardId::Bottom, temp: 80.0, temp_sensor: J7 },
-            Event::Time,
-            Event::TemperatureReading { board: BoardId::Top, temp: 35.0, temp_sensor: TH1 },
-            Event::TemperatureReading { board: BoardId::Bottom, temp: 120.0, temp_sensor: J7 },
+    fn reset_control(&mut self) {
+        self.control_sensor = None;
+        self.pid = None;
+        self.power = None;
+        self.profile = None;
+        self.target_temp = None;
+        self.duty_recorded = None;
+    }
+
+    /// Aborts the program if a reading exceeds the program or step abort temperature
+    fn check_abort(&mut self, program: &'a Program, step: usize, event: &Event) -> Option<State<'a>> {
+        let (temp_sensor, temp) = match *event {
+            Event::TemperatureReading { board, temp_sensor, temp } if board == program.heat_board =>
+                (temp_sensor, temp),
+            _ => return None,
+        };
+        let steps = Rc::clone(&self.steps);
+        let step_abort = steps[step].temp_abort.map(|limit| (steps[step].abort_sensor(program), limit));
+        let limits = std::iter::once((program.abort_sensor(), program.temp_abort)).chain(step_abort);
+        for (sensor, limit) in limits {
+            if temp_sensor == sensor && temp > limit {
+                info!("Abort temp reached on {} ({} > {}): {}", temp_sensor, temp, limit, program);
+                let reason = format!("{} {:.2}°C above temp_abort {:.1}°C", temp_sensor, temp, limit);
+                return Some(self.abort(program, step, &reason));
+            }
+        }
+        None
+    }
+
+    /// Ends the step once every steady-state sensor has settled, recording the equilibrium
+    /// values in the run summary
+    fn check_steady_state(&mut self, program: &'a Program, step: usize, phase: &str, event: &Event) -> Option<State<'a>> {
+        let (temp_sensor, temp) = match *event {
+            Event::TemperatureReading { board, temp_sensor, temp } if board == program.heat_board =>
+                (temp_sensor, temp),
+            _ => return None,
+        };
+        let now = self.now();
+        let equilibria = self.steady_state.as_mut()?.update(temp_sensor, temp, now)?;
+        let values: Vec<String> = equilibria.iter()
+            .map(|e| format!("{} {:.2}°C at {:.3}°C/min", e.sensor, e.temp, e.rate))
+            .collect();
+        info!("Steady state reached ({}): {}", values.join(", "), program);
+        self.record(Some(program), Some(step), RunnerEventKind::SteadyState { sensors: equilibria.clone() });
+        if let Some(summary) = &self.summary {
+            summary.add_steady_state(SteadyStateRecord {
+                iteration: self.iteration,
+                program_index: self.program_count - 1,
+                program_id: program.id,
+                program_name: program.name.clone(),
+                step: step + 1,
+                phase: String::from(phase),
+                time: now.to_rfc3339(),
+                sensors: equilibria,
+            });
+        }
+        Some(self.next_step(program, step))
+    }
+
+    /// Keeps control of a heater left on by an earlier heat step, and checks abort temperatures
+    fn keep_heating(&mut self, program: &'a Program, step: usize, event: &Event) -> Option<State<'a>> {
+        if let Some(state) = self.update_power(program, step) {
+            return Some(state);
+        }
+        if let Some(state) = self.check_abort(program, step, event) {
+            return Some(state);
+        }
+        if let Event::TemperatureReading { board, temp_sensor, temp } = *event {
+            if board == program.heat_board && self.control_sensor.as_deref() == Some(temp_sensor) {
+                self.update_profile(program, temp);
+                return self.update_pid(program, step, temp);
+            }
+        }
+        None
+    }
+
+    /// Advances the setpoint profile, if the program has one, updating the PID or firmware
+    /// setpoint. Returns true once the profile has completed.
+    pub fn update_profile(&mut self, program: &'a Program, temp: f32) -> bool {
+        let now = self.now();
+        let profile = match self.profile.as_mut() {
+            Some(profile) => profile,
+            None => return false,
+        };
+        let setpoint = match profile.update(temp, now) {
+            Some(setpoint) => setpoint,
+            None => return true,
+        };
+        debug!("Setpoint profile: {}", profile);
+        if let Some(pid) = self.pid.as_mut() {
+            pid.set_setpoint(setpoint);
+        } else if !matches!(self.target_temp, Some(t) if (t - setpoint).abs() < SETPOINT_RESOLUTION) {
+            let board = &self.payload[program.heat_board as u8];
+            board.write_target_temp(setpoint);
+            self.record_write(program, program.heat_board, "target_temp", &setpoint);
+            self.target_temp = Some(setpoint);
+        }
+        false
+    }
+
+    /// Updates the host-side PID with a new reading, if the program uses one and it's due.
+    /// Returns the failed state if the power budget rejects the new duty.
+    pub fn update_pid(&mut self, program: &'a Program, step: usize, temp: f32) -> Option<State<'a>> {
+        let now = self.now();
+        let pid = self.pid.as_mut()?;
+        if !pid.is_due(now) {
+            return None;
+        }
+        pid.update(temp, now);
+        debug!("PID {}°C vs setpoint {}°C, setting duty: {}", temp, pid.setpoint(), pid.duty());
+        let duty = pid.duty();
+        self.write_duty(program, step, duty)
+    }
+
+    /// Corrects the duty cycle for the measured heater power, if the program uses constant power.
+    /// Returns the failed state if the power budget rejects the new duty.
+    pub fn update_power(&mut self, program: &'a Program, step: usize) -> Option<State<'a>> {
+        let now = self.now();
+        let power = self.power.as_mut()?;
+        if !power.is_due(now) {
+            return None;
+        }
+        let board = &self.payload[program.heat_board as u8];
+        match board.read_heater_power() {
+            Ok(measured) => {
+                power.update(measured, now);
+                info!("Heater power commanded: {:.2} W, achieved: {:.2} W, setting duty: {}",
+                    power.target(), measured, power.duty());
+                let duty = power.duty();
+                self.write_duty(program, step, duty)
+            }
+            Err(e) => {
+                debug!("Failed to read heater power: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Writes a duty correction while heating. The board switches the heater off if the power
+    /// budget rejects it, which fails the step rather than carrying on with the heater off.
+    fn write_duty(&mut self, program: &'a Program, step: usize, duty: u16) -> Option<State<'a>> {
+        let board = &self.payload[program.heat_board as u8];
+        match board.write_heater_duty(duty) {
+            BudgetDecision::Rejected => {
+                self.record_write(program, program.heat_board, "heater_mode", &HeaterMode::OFF);
+                return Some(self.fail(program, step, &format!("power budget rejected heater duty {}", duty)));
+            }
+            BudgetDecision::Derated(derated) => self.record_duty(program, derated),
+            BudgetDecision::Allowed => self.record_duty(program, duty),
+        }
+        None
+    }
+
+    /// Records the heater duty if it has changed, at most once every `DUTY_RECORD_INTERVAL`
+    fn record_duty(&mut self, program: &'a Program, duty: u16) {
+        let now = self.now();
+        if matches!(self.duty_recorded, Some((recorded, time)) if recorded == duty || now - time < DUTY_RECORD_INTERVAL) {
+            return;
+        }
+        self.record_write(program, program.heat_board, "heater_duty", &duty);
+        self.duty_recorded = Some((duty, now));
+    }
+
+    /// Saves progress through the programs when the step changes, and periodically for the
+    /// elapsed time, if checkpoints are enabled
+    fn checkpoint(&mut self, state: &State<'a>) {
+        // a scheduled program is saved at its first step, to be scheduled again when resumed
+        let program_step = match *state {
+            State::Scheduled { program, .. } => Some((program, 0)),
+            _ => state.program_step(),
+        };
+        let (checkpoints, (program, step)) = match (&self.checkpoints, program_step) {
+            (Some(checkpoints), Some(program_step)) => (checkpoints, program_step),
+            _ => return,
+        };
+        let now = self.now();
+        let index = self.program_count - 1;
+        let paused = self.paused.is_some();
+        if matches!(self.checkpointed, Some((i, s, p, time))
+            if i == index && s == step && p == paused && now - time < CHECKPOINT_INTERVAL) {
+            return;
+        }
+        checkpoints.write(&Checkpoint {
+            program_index: index,
+            program_id: program.id,
+            program_name: program.name.clone(),
+            step,
+            phase: String::from(state.phase()),
+            elapsed_secs: (self.paused.unwrap_or(now) - self.step_start).num_seconds(),
+            aborted: self.aborted,
+            paused,
+            iteration: self.iteration,
+            time: now.to_rfc3339(),
+        });
+        self.checkpointed = Some((index, step, paused, now));
+    }
+
+    fn record(&self, program: Option<&Program>, step: Option<usize>, kind: RunnerEventKind) {
+        if let Some(event_log) = &self.event_log {
+            event_log.record(program, step, kind, self.now());
+        }
+    }
+
+    /// Records a setting written to a heater in the event log
+    fn record_write(&self, program: &Program, board: BoardId, setting: &str, value: &dyn Display) {
+        let (setting, value) = (String::from(setting), value.to_string());
+        self.record(Some(program), None, RunnerEventKind::HeaterWrite { board, setting, value });
+    }
+
+    /// Records the phase when a step starts or the phase changes
+    fn record_phase(&mut self, state: &State<'a>) {
+        let (program, step) = match state.program_step() {
+            Some(program_step) => program_step,
+            None => return,
+        };
+        let current = (self.program_count, step, state.phase());
+        if self.recorded != Some(current) {
+            self.record(Some(program), Some(step), RunnerEventKind::Phase { phase: String::from(state.phase()) });
+            self.recorded = Some(current);
+        }
+    }
+
+    /// Saves the active program when the phase changes, and regularly so it isn't seen as stale
+    fn publish(&mut self, state: &State<'a>) {
+        let active = match &self.active {
+            Some(active) => active,
+            None => return,
+        };
+        let program = match *state {
+            State::Scheduled { program, .. } => Some(program),
+            _ => state.program_step().map(|(program, _)| program),
+        };
+        let phase = match program {
+            Some(_) if self.paused.is_some() => "paused",
+            Some(_) => state.phase(),
+            None => "idle",
+        };
+        let now = self.now();
+        if matches!(self.published, Some((i, p, time)) if i == self.program_count && p == phase && now - time < CHECKPOINT_INTERVAL) {
+            return;
+        }
+        active.write(&ActiveProgram {
+            program_id: program.map(|p| p.id),
+            program_name: program.map(|p| p.name.clone()),
+            phase: String::from(phase),
+            time: now.to_rfc3339(),
+        });
+        self.published = Some((self.program_count, phase, now));
+    }
+
+    /// Skips to cooling once the end time is reached, finishing the program after cooling
+    fn check_end_time(&mut self, program: &'a Program, step: usize, now: DateTime<Utc>) -> Option<State<'a>> {
+        if !self.is_ended(now) || self.aborted {
+            return None;
+        }
+        info!("End time reached, finishing after cooling: {}", program);
+        if self.steps[step].is_cool() {
+            self.record(Some(program), Some(step), RunnerEventKind::Aborted { reason: String::from("end time reached") });
+            self.aborted = true;
+            None
+        } else {
+            Some(self.abort(program, step, "end time reached"))
+        }
+    }
+
+    pub fn next_program_or_done(&mut self) -> State<'a> {
+        if self.is_ended(self.now()) {
+            info!("End time reached, not starting any more programs");
+            return State::Done;
+        }
+        if let Some(program) = self.next_program() {
+            self.schedule_program(program)
+        } else {
+            State::Done
+        }
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        guard::is_aborted()
+    }
+}
+
+impl<'a> Drop for PayloadController<'a> {
+    fn drop(&mut self) {
+        info!("Disabling payload heaters");
+        for board in self.payload {
+            if self.board.is_none() || self.board == Some(board.id) {
+                board.write_heater_mode(HeaterMode::OFF);
+            }
+        }
+    }
+}
+
+/// Runs the track of programs for each board at the same time, each controller with its own
+/// state, and with the events for its board. A track that fails is stopped while the others
+/// keep running, and the first failure is returned once every track is done or has failed,
+/// or a signal stops them. Sleeps on the clock of the first controller between events.
+pub fn run_tracks<'a>(controllers: &mut [PayloadController<'a>], events: &mut dyn Iterator<Item = Event<'a>>,
+                      duration: Duration) -> State<'a> {
+    let mut states: Vec<State<'a>> = vec![];
+    for controller in controllers.iter_mut() {
+        let state = controller.start();
+        controller.save(&state);
+        if matches!(state, State::Failed { .. }) {
+            controller.stop(&state);
+        }
+        states.push(state);
+    }
+    for event in events {
+        if states.iter().all(State::is_finished) { break }
+        let requests = controllers.first().map(|c| c.take_commands()).unwrap_or_default();
+        for (controller, state) in controllers.iter_mut().zip(states.iter_mut()) {
+            let finished = state.is_finished();
+            for request in &requests {
+                if !controller.is_for(request) {
+                    continue;
+                }
+                if let Some(new_state) = controller.command(state, request) {
+                    *state = new_state
+                }
+            }
+            if !state.is_finished() && controller.paused.is_none() && controller.accepts(&event) {
+                debug!("{} <- {:?}", state, &event);
+                if let Some(new_state) = state.next(controller, event.clone()) {
+                    *state = new_state
+                }
+            }
+            controller.save(state);
+            if !finished && matches!(state, State::Failed { .. }) {
+                // only this track's heater is off, the others carry on
+                controller.stop(state);
+            }
+        }
+        let unclaimed: Vec<&ControlRequest> = requests.iter()
+            .filter(|request| !controllers.iter().any(|controller| controller.is_for(request)))
+            .collect();
+        if let Some(controller) = controllers.first_mut() {
+            for request in unclaimed {
+                let board = request.board.map(|board| board.to_string()).unwrap_or_default();
+                controller.report(request, None, None, format!("ignored, no track for the {} board", board));
+            }
+            controller.clock.sleep(duration);
+        }
+        if guard::is_aborted() { break }
+    }
+    for (controller, state) in controllers.iter_mut().zip(&states) {
+        if !matches!(state, State::Failed { .. }) {
+            controller.stop(state);
+        }
+    }
+    let failed = states.iter().position(|state| matches!(state, State::Failed { .. }));
+    let running = states.iter().position(|state| *state != State::Done);
+    match failed.or(running) {
+        Some(index) => states.swap_remove(index),
+        None => State::Done,
+    }
+}
+
+
+pub struct PayloadEvents<'a> {
+    payload: &'a Payload,
+    sensors: Vec<&'a str>,
+    limits: Vec<&'a str>,
+    buffer: Vec<Event<'a>>,
+    phantom: PhantomData<&'a Event<'a>>,
+}
+
+impl<'a> PayloadEvents<'a> {
+    pub fn new(payload: &'a Payload) -> PayloadEvents<'a> {
+        PayloadEvents { payload, sensors: vec![], limits: vec![], buffer: vec![], phantom: PhantomData }
+    }
+
+    /// Also read these sensors on each board, in addition to the heater target sensor
+    pub fn watching<I>(mut self, sensors: I) -> PayloadEvents<'a>
+        where I: IntoIterator<Item=&'a str> {
+        for sensor in sensors {
+            if !self.sensors.contains(&sensor) {
+                self.sensors.push(sensor);
+            }
+        }
+        self
+    }
+
+    /// Sensors read to check a limit, which are only reported if every sensor in an aggregate
+    /// can be read
+    pub fn limiting<I>(mut self, sensors: I) -> PayloadEvents<'a>
+        where I: IntoIterator<Item=&'a str> {
+        for sensor in sensors {
+            if !self.limits.contains(&sensor) {
+                self.limits.push(sensor);
+            }
+        }
+        self
+    }
+}
+
+/// Reads a watched sensor or aggregate, failing if any sensor in it fails when it's checked
+/// against a limit, such as `temp_abort`
+pub(crate) fn read_watched(board: &Board, sensor_id: &str, limits: &[&str]) -> Option<f32> {
+    let selector = sensor_id.parse::<SensorSelector>().ok()?;
+    let reading = if limits.contains(&sensor_id) {
+        board.read_limit_temp(&selector)
+    } else {
+        board.read_temp(&selector)
+    };
+    reading.ok()
+}
+
+impl<'a> Iterator for PayloadEvents<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            self.buffer.push(Event::Time); // always put something in the buffer
+            for board in self.payload {
+                let target = read_board(board, board.into());
+                for &sensor_id in &self.sensors {
+                    if matches!(target, Some(Event::TemperatureReading { temp_sensor, .. })
+                        if temp_sensor.eq_ignore_ascii_case(sensor_id)) {
+                        continue;
+                    }
+                    if let Some(temp) = read_watched(board, sensor_id, &self.limits) {
+                        self.buffer.push(Event::TemperatureReading {
+                            board: board.into(),
+                            temp_sensor: sensor_id,
+                            temp,
+                        });
+                    }
+                }
+                if let Some(reading) = target {
+                    self.buffer.push(reading);
+                }
+            }
+        }
+        self.buffer.pop()
+    }
+}
+
+fn read_board<'a>(board: &Board, heat_board: BoardId) -> Option<Event<'a>> {
+    let sensor = board.get_target_sensor().ok()?;
+    let reading = board.read_target_sensor_temp().ok()?;
+    Some(Event::TemperatureReading {
+        board: heat_board,
+        temp_sensor: sensor.id,
+        temp: reading.display_value,
+    })
+}
+
+pub fn run(payload: &Payload, programs: &Programs) {
+    run_from(payload, programs, &RunFiles::default(), Rc::new(SystemClock))
+}
+
+/// Files shared with other processes while running the programs, any of which can be left out
+#[derive(Debug, Clone, Default)]
+pub struct RunFiles {
+    pub checkpoints: Option<Checkpoints>,
+    pub summary: Option<SummaryFile>,
+    pub event_log: Option<EventLog>,
+    pub active: Option<ActiveProgramFile>,
+    pub control: Option<ControlChannel>,
+    pub deadman: Option<Deadman>,
+}
+
+impl RunFiles {
+    pub fn from_config(config: &Config) -> Self {
+        RunFiles {
+            checkpoints: Some(Checkpoints::from_config(config)),
+            summary: Some(SummaryFile::from_config(config)),
+            event_log: EventLog::from_config(config),
+            active: Some(ActiveProgramFile::from_config(config)),
+            control: Some(ControlChannel::from_config(config)),
+            deadman: Some(Deadman::from_config(config)),
+        }
+    }
+}
+
+/// Runs the programs, saving checkpoints so they can be resumed according to the resume policy
+/// after a reboot or power cycle. The checkpoint is kept if stopped by a signal, and removed
+/// once the programs complete or a failure stops them. The run summary is started again
+/// unless resuming. Events are recorded in the event log, if there is one, the running program
+/// is shared in the active program file, commands are taken from the control channel, and
+/// deadlines left by manual heater commands are disarmed when a program takes the heater.
+pub fn run_resumable(payload: &Payload, programs: &Programs, files: &RunFiles) {
+    run_from(payload, programs, files, Rc::new(SystemClock))
+}
+
+/// Runs the programs on the clock, which every track shares
+fn run_from(payload: &Payload, programs: &Programs, files: &RunFiles, clock: Rc<dyn Clock>) {
+    let RunFiles { checkpoints, summary, event_log, active, control, deadman } = files;
+    // one track running every program, or a track for each board with `parallel = true`
+    let tracks: Vec<(Option<BoardId>, Vec<&Program>)> = if programs.parallel {
+        programs.tracks().into_iter().map(|(board, track)| (Some(board), track)).collect()
+    } else {
+        vec![(None, programs.sequence())]
+    };
+    let track_checkpoints: Vec<Option<Checkpoints>> = tracks.iter()
+        .map(|(board, _)| checkpoints.as_ref().map(|checkpoints| match board {
+            Some(board) => checkpoints.for_board(*board),
+            None => checkpoints.clone(),
+        }))
+        .collect();
+    let mut resumes: Vec<Resume> = track_checkpoints.iter()
+        .map(|checkpoints| match checkpoints {
+            Some(checkpoints) => checkpoints.resume(programs, clock.now()),
+            None => Resume::Start,
+        })
+        .collect();
+    if let (Some(summary), true) = (summary, resumes.iter().all(|resume| *resume == Resume::Start)) {
+        summary.start(clock.now());
+    }
+    if tracks.iter().all(|(_, track)| track.is_empty()) {
+        warn!("No programs to run, every program has repeat = 0");
+        return;
+    }
+    loop {
+        let iteration = resumes.iter().map(Resume::iteration).min().unwrap_or(0);
+        let mut events = PayloadEvents::new(payload)
+            .watching(programs.iter().flat_map(|p| p.sensors()))
+            .limiting(programs.iter().flat_map(|p| p.abort_sensors()));
+        let mut program_lists: Vec<_> = tracks.iter().map(|(_, track)| track.iter().copied()).collect();
+        let mut controllers = vec![];
+        let track_resumes = track_checkpoints.iter().zip(resumes);
+        for ((program_list, (board, _)), (checkpoints, resume)) in program_lists.iter_mut().zip(&tracks).zip(track_resumes) {
+            let mut controller = PayloadController::new(payload, program_list)
+                .until(programs.end_time)
+                .with_clock(Rc::clone(&clock));
+            if let Some(board) = board {
+                controller = controller.for_board(*board);
+            }
+            if let Some(checkpoints) = checkpoints {
+                controller = controller.with_checkpoints(checkpoints.clone(), resume);
+            }
+            if let Some(summary) = summary {
+                controller = controller.with_summary(summary.clone());
+            }
+            if let Some(event_log) = event_log {
+                controller = controller.with_event_log(event_log.clone());
+            }
+            if let Some(active) = active {
+                let active = match board {
+                    Some(board) => active.for_board(*board),
+                    None => active.clone(),
+                };
+                controller = controller.with_active_program(active);
+            }
+            if let Some(control) = control {
+                controller = controller.with_control(control.clone());
+            }
+            if let Some(deadman) = deadman {
+                controller = controller.with_deadman(deadman.clone());
+            }
+            controllers.push(controller);
+        }
+        let state = if programs.parallel {
+            run_tracks(&mut controllers, &mut events, Duration::seconds(1))
+        } else {
+            controllers[0].run(&mut events, Duration::seconds(1))
+        };
+        if guard::is_aborted() { break; }
+        if let State::Failed { message } = &state {
+            warn!("Stopped running programs: {}", message);
+        }
+        let completed = programs.run_loop && matches!(programs.max_iterations, Some(max) if iteration + 1 >= max);
+        if completed {
+            info!("Completed {} runs through the programs", iteration + 1);
+        }
+        if !programs.run_loop || completed || controllers[0].is_ended(clock.now())
+            || matches!(state, State::Failed { .. }) {
+            if matches!(state, State::Done | State::Failed { .. }) {
+                track_checkpoints.iter().flatten().for_each(Checkpoints::clear);
+            }
+            break;
+        }
+        resumes = tracks.iter().map(|_| Resume::Program { index: 0, iteration: iteration + 1, paused: false }).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::fmt::{Display, Formatter};
+    use std::rc::Rc;
+
+    use assert_approx_eq::assert_approx_eq;
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::ReadResult;
+    use crate::board::{ALL_SENSORS, Board, BoardId, BoardVersion, V_CURR_AVG, V_HIGH_AVG, V_LOW_AVG};
+    use crate::clock::{Clock, SimulatedClock};
+    use crate::control::pid::PidConfig;
+    use crate::deadman::Deadman;
+    use crate::payload::Payload;
+
+    use crate::programs::{FailurePolicy, Program};
+    use crate::programs::checkpoint::{Checkpoints, Resume, ResumePolicy};
+    use crate::programs::control::{Command, ControlChannel};
+    use crate::programs::event_log::EventLog;
+    use crate::programs::profile::Segment;
+    use crate::programs::runner::{Event, PayloadController, PayloadEvents, run_tracks, State};
+    use crate::programs::step::{Action, Equilibrium, HeatStep, Step, SteadyState};
+    use crate::programs::summary::SummaryFile;
+    use crate::reading::{ReadableSensor, SensorReading};
+
+    const TH1: &str = "TH1";
+    const J7: &str = "J7";
+
+    /// A heater voltage sensor whose reading the test can change
+    struct VoltageSensor {
+        id: &'static str,
+        volts: Rc<Cell<f32>>,
+    }
+
+    impl Display for VoltageSensor {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.id)
+        }
+    }
+
+    impl ReadableSensor for VoltageSensor {
+        fn read(&self) -> ReadResult<SensorReading<f32>> {
+            Ok(SensorReading::new(0, self.volts.get()))
+        }
+    }
+
+    /// Heats the top board on TH1 without a heat time or cool temp, for tests to override the
+    /// fields they use, so new program settings don't need adding to every test
+    fn base_program(name: &str) -> Program {
+        Program {
+            id: 0,
+            name: String::from(name),
+            heat_time: None,
+            temp_sensor: String::from("TH1"),
+            temp_abort: 90.0,
+            abort_sensor: None,
+            thermostat: None,
+            pid: None,
+            ramp_rate: None,
+            profile: vec![],
+            cool_temp: None,
+            heat_board: BoardId::Top,
+            heat_duty: 1.0,
+            heat_power: None,
+            steps: vec![],
+            heat_timeout: None,
+            cool_timeout: None,
+            on_failure: FailurePolicy::Skip,
+            repeat: 1,
+            start_at: None,
+            not_before: None,
+            not_after: None,
+            daily_window: None,
+        }
+    }
+
+    #[test]
+    fn test_programs() {
+        let _ = env_logger::try_init();
+        let programs: Vec<Program> = vec![
+            Program {
+                heat_time: Some(Duration::minutes(50)),
+                temp_abort: 80.0,
+                cool_temp: Some(40.0),
+                ..base_program("Top")
+            },
+            Program {
+                id: 1,
+                heat_time: Some(Duration::minutes(30)),
+                temp_sensor: String::from("J7"),
+                temp_abort: 100.0,
+                thermostat: Some(80.0),
+                cool_temp: Some(30.0),
+                heat_board: BoardId::Bottom,
+                ..base_program("Bottom")
+            },
+        ];
+
+        let events: Vec<Event> = vec![
+            Event::Time,
+            Event::TemperatureReading { board: BoardId::Top, temp: 55.0, temp_sensor: TH1 },
+            Event::TemperatureReading { board: BoardId::Top, temp: 105.0, temp_sensor: TH1 },
+            Event::Time,
+            Event::Time,
+            Event::TemperatureReading { board: BoardId::Top, temp: 60.0, temp_sensor: TH1 },
+            Event::TemperatureReading { board: BoardId::Bottom, temp: 80.0, temp_sensor: J7 },
+            Event::Time,
+            Event::TemperatureReading { board: BoardId::Top, temp: 35.0, temp_sensor: TH1 },
+            Event::TemperatureReading { board: BoardId::Bottom, temp: 120.0, temp_sensor: J7 },
             Event::TemperatureReading { board: BoardId::Bottom, temp: 100.0, temp_sensor: J7 },
             Event::Time,
             Event::Time,
@@ -331,12 +1705,894 @@ mod tests {
 
         let payload = Payload::create();
         let program_list = &mut programs.iter();
-        let mut controller = PayloadController::new(&payload, program_list);
+        let start = Utc::now();
+        let clock = Rc::new(SimulatedClock::new(start));
+        let mut controller = PayloadController::new(&payload, program_list)
+            .with_clock(clock.clone());
         let final_state = controller.run(
             &mut events.into_iter(),
-            Duration::milliseconds(1),
+            Duration::minutes(10),
         );
         assert_eq!(State::Done, final_state);
+        // both heat times have passed without waiting for them
+        assert!(clock.now() - start >= Duration::minutes(50 + 30));
+    }
+
+    #[test]
+    fn test_program_with_host_pid() {
+        let _ = env_logger::try_init();
+        let programs: Vec<Program> = vec![
+            Program {
+                heat_time: Some(Duration::seconds(60)),
+                temp_sensor: String::from("U7"),
+                temp_abort: 80.0,
+                pid: Some(PidConfig::new(60.0, 0.05, 0.0, 0.0)),
+                cool_temp: Some(40.0),
+                ..base_program("Top PID")
+            },
+        ];
+
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        let state = controller.start();
+        assert_eq!(State::Heating { program: &programs[0], step: 0, end_time: None }, state);
+        assert_eq!(0, controller.pid.as_ref().unwrap().duty());
+
+        // readings from other sensors don't update the controller
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 50.0, temp_sensor: TH1 };
+        assert_eq!(None, state.next(&mut controller, event));
+        assert_eq!(0, controller.pid.as_ref().unwrap().duty());
+
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 50.0, temp_sensor: "U7" };
+        assert_eq!(None, state.next(&mut controller, event));
+        assert_eq!(128, controller.pid.as_ref().unwrap().duty());
+
+        // abort temp switches to cooling and drops the controller
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 85.0, temp_sensor: "U7" };
+        let state = state.next(&mut controller, event).unwrap();
+        assert_eq!(State::Cooling { program: &programs[0], step: 1 }, state);
+        assert!(controller.pid.is_none());
+    }
+
+    #[test]
+    fn test_record_duty_corrections() {
+        let _ = env_logger::try_init();
+        let programs = [Program {
+            heat_time: Some(Duration::minutes(10)),
+            temp_sensor: String::from("U7"),
+            pid: Some(PidConfig::new(60.0, 0.05, 0.0, 0.0)),
+            ..base_program("Top PID")
+        }];
+        let dir = tempfile::tempdir().unwrap();
+        let event_log = EventLog::new(dir.path());
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let clock = Rc::new(SimulatedClock::new(Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap()));
+        let mut controller = PayloadController::new(&payload, program_list)
+            .with_event_log(event_log.clone())
+            .with_clock(clock.clone());
+        let state = controller.start();
+        let reading = |temp| Event::TemperatureReading { board: BoardId::Top, temp, temp_sensor: "U7" };
+
+        // a new duty straight after the last recorded one is left out of the event log
+        assert_eq!(None, state.next(&mut controller, reading(50.0)));
+        assert_eq!(128, controller.pid.as_ref().unwrap().duty());
+        // recorded once a minute has passed, then changes are left out for another minute
+        clock.sleep(Duration::seconds(60));
+        assert_eq!(None, state.next(&mut controller, reading(50.0)));
+        clock.sleep(Duration::seconds(5));
+        assert_eq!(None, state.next(&mut controller, reading(55.0)));
+        assert_eq!(64, controller.pid.as_ref().unwrap().duty());
+
+        let events = event_log.events(100);
+        let duties: Vec<&str> = events.iter()
+            .filter(|e| e["setting"] == "heater_duty")
+            .filter_map(|e| e["value"].as_str())
+            .collect();
+        assert_eq!(["0", "128"], duties.as_slice());
+    }
+
+    #[test]
+    fn test_program_with_setpoint_profile() {
+        let _ = env_logger::try_init();
+        let programs: Vec<Program> = vec![
+            Program {
+                heat_time: Some(Duration::seconds(60)),
+                temp_abort: 80.0,
+                profile: vec![
+                    Segment { target: 50.0, ramp_rate: None, soak: Duration::zero(), tolerance: Some(1.0) },
+                    Segment { target: 60.0, ramp_rate: None, soak: Duration::zero(), tolerance: Some(1.0) },
+                ],
+                cool_temp: Some(40.0),
+                ..base_program("Top profile")
+            },
+        ];
+
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        let state = controller.start();
+        assert_eq!(Some(50.0), controller.target_temp);
+
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 45.0, temp_sensor: TH1 };
+        assert_eq!(None, state.next(&mut controller, event));
+        assert_eq!(Some(50.0), controller.target_temp);
+
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 49.5, temp_sensor: TH1 };
+        assert_eq!(None, state.next(&mut controller, event));
+        assert_eq!(Some(60.0), controller.target_temp);
+
+        // last segment completes once within tolerance, and heating finishes
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 59.5, temp_sensor: TH1 };
+        let state = state.next(&mut controller, event).unwrap();
+        assert_eq!(State::Cooling { program: &programs[0], step: 1 }, state);
+    }
+
+    #[test]
+    fn test_program_with_aggregate_sensors() {
+        let _ = env_logger::try_init();
+        let programs: Vec<Program> = vec![
+            Program {
+                heat_time: Some(Duration::seconds(60)),
+                temp_sensor: String::from("mean(TH1, TH2)"),
+                temp_abort: 80.0,
+                abort_sensor: Some(String::from("max(TH1, TH2, TH3, U7)")),
+                thermostat: Some(60.0),
+                cool_temp: Some(40.0),
+                ..base_program("Top hottest")
+            },
+        ];
+
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        let state = controller.start();
+        assert_eq!(State::Heating { program: &programs[0], step: 0, end_time: None }, state);
+        // firmware can't control on an aggregate, so falls back to host-side PID
+        assert!(controller.pid.is_some());
+
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 50.0, temp_sensor: "mean(TH1, TH2)" };
+        assert_eq!(None, state.next(&mut controller, event));
+        assert!(controller.pid.as_ref().unwrap().duty() > 0);
+
+        // control sensor is below the abort temp, but the abort aggregate isn't
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 79.0, temp_sensor: "mean(TH1, TH2)" };
+        assert_eq!(None, state.next(&mut controller, event));
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 85.0, temp_sensor: "max(TH1, TH2, TH3, U7)" };
+        let state = state.next(&mut controller, event).unwrap();
+        assert_eq!(State::Cooling { program: &programs[0], step: 1 }, state);
+    }
+
+    #[test]
+    fn test_program_with_constant_power() {
+        let _ = env_logger::try_init();
+        let programs: Vec<Program> = vec![
+            Program {
+                heat_time: Some(Duration::seconds(60)),
+                temp_abort: 80.0,
+                cool_temp: Some(40.0),
+                heat_duty: 0.2,
+                heat_power: Some(2.5),
+                ..base_program("Top 2.5 W")
+            },
+        ];
+
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        let state = controller.start();
+        assert_eq!(State::Heating { program: &programs[0], step: 0, end_time: None }, state);
+        let power = controller.power.as_ref().unwrap();
+        assert_eq!(2.5, power.target());
+        assert_eq!(51, power.duty());
+
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 85.0, temp_sensor: TH1 };
+        let state = state.next(&mut controller, event).unwrap();
+        assert_eq!(State::Cooling { program: &programs[0], step: 1 }, state);
+        assert!(controller.power.is_none());
+    }
+
+    #[test]
+    fn test_constant_power_corrects_duty() {
+        let _ = env_logger::try_init();
+        let programs = [Program {
+            heat_time: Some(Duration::minutes(5)),
+            temp_abort: 80.0,
+            cool_temp: Some(40.0),
+            heat_duty: 0.5,
+            heat_power: Some(2.5),
+            ..base_program("Top 2.5 W")
+        }];
+        // the heater voltage is v_high - v_low, and the current (v_low - v_curr) / 0.05 Ω
+        let (v_high, v_low, v_curr) = (Rc::new(Cell::new(7.0)), Rc::new(Cell::new(2.0)), Rc::new(Cell::new(1.95)));
+        let mut top = Board::new(BoardId::Top, BoardVersion::V2_2);
+        for (sensor, volts) in [(V_HIGH_AVG, &v_high), (V_LOW_AVG, &v_low), (V_CURR_AVG, &v_curr)] {
+            let index = ALL_SENSORS.iter().position(|s| s.id == sensor.id).unwrap();
+            top.sensors[index] = Box::new(VoltageSensor { id: sensor.id, volts: Rc::clone(volts) });
+        }
+        let payload = Payload::from_boards(vec![top, Board::new(BoardId::Bottom, BoardVersion::V2_2)]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let event_log = EventLog::new(dir.path());
+        let clock = Rc::new(SimulatedClock::new(Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap()));
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list)
+            .with_event_log(event_log.clone())
+            .with_clock(clock.clone());
+        let mut state = controller.start();
+
+        // 5 W at half duty, so the duty is halved
+        assert_eq!(None, state.next(&mut controller, Event::Time));
+        let power = controller.power.as_ref().unwrap();
+        assert_approx_eq!(5.0, power.achieved().unwrap(), 0.01);
+        assert_eq!(64, power.duty());
+
+        // the supply sags to 4 V across the heater
+        v_high.set(6.0);
+        clock.sleep(Duration::minutes(1));
+        assert_eq!(None, state.next(&mut controller, Event::Time));
+        let power = controller.power.as_ref().unwrap();
+        assert_approx_eq!(4.0, power.achieved().unwrap(), 0.01);
+        assert_eq!(40, power.duty());
+
+        // then the heater resistance drops, drawing 2 A, and the duty is halved at most per update
+        v_curr.set(1.9);
+        clock.sleep(Duration::minutes(1));
+        assert_eq!(None, state.next(&mut controller, Event::Time));
+        let power = controller.power.as_ref().unwrap();
+        assert_approx_eq!(8.0, power.achieved().unwrap(), 0.01);
+        assert_eq!(20, power.duty());
+
+        clock.sleep(Duration::minutes(3));
+        state = state.next(&mut controller, Event::Time).unwrap();
+        assert_eq!(State::Cooling { program: &programs[0], step: 1 }, state);
+        let duties: Vec<String> = event_log.events(100).iter()
+            .filter(|e| e["setting"] == "heater_duty")
+            .filter_map(|e| e["value"].as_str().map(String::from))
+            .collect();
+        // the first correction isn't recorded, as it's within a minute of the starting duty
+        assert_eq!(vec!["127", "40", "20"], duties);
+    }
+
+    #[test]
+    fn test_program_with_steps() {
+        let _ = env_logger::try_init();
+        let heat = HeatStep {
+            duration: None,
+            duty: 1.0,
+            power: None,
+            thermostat: Some(60.0),
+            pid: None,
+            ramp_rate: None,
+            profile: vec![],
+        };
+        let hold = Step {
+            temp_abort: Some(70.0),
+            abort_sensor: Some(String::from("U7")),
+            ..Step::new(Action::Hold { tolerance: 0.5, window: Duration::zero(), timeout: None })
+        };
+        let wait_temp = Step {
+            temp_sensor: Some(String::from("J7")),
+            ..Step::new(Action::WaitTemp { above: None, below: Some(50.0) })
+        };
+        let programs: Vec<Program> = vec![
+            Program {
+                steps: vec![
+                    Step::new(Action::Heat(heat)),
+                    hold,
+                    wait_temp,
+                    Step::new(Action::Cool { temp: 40.0 }),
+                    Step::new(Action::Wait { duration: Duration::zero() }),
+                ],
+                ..base_program("Top steps")
+            },
+        ];
+        assert_eq!(Ok(()), programs[0].validate());
+
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        // heat step without a duration leaves the heater on for the following steps
+        let state = controller.start();
+        assert_eq!(State::Holding { program: &programs[0], step: 1, end_time: None }, state);
+        assert_eq!(Some(60.0), controller.target_temp);
+
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 55.0, temp_sensor: TH1 };
+        let state = state.next(&mut controller, event).unwrap();
+        assert_eq!(State::WaitingForTemp { program: &programs[0], step: 2 }, state);
+
+        // waits on the step sensor, not the program sensor
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 45.0, temp_sensor: TH1 };
+        assert_eq!(None, state.next(&mut controller, event));
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 45.0, temp_sensor: J7 };
+        let state = state.next(&mut controller, event).unwrap();
+        assert_eq!(State::Cooling { program: &programs[0], step: 3 }, state);
+        assert_eq!(None, controller.target_temp);
+
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 39.0, temp_sensor: TH1 };
+        let state = state.next(&mut controller, event).unwrap();
+        assert!(matches!(state, State::Waiting { step: 4, .. }));
+        let state = state.next(&mut controller, Event::Time).unwrap();
+        assert_eq!(State::FinishedProgram, state);
+        assert_eq!(Some(State::Done), state.next(&mut controller, Event::Time));
+
+        // the hold step abort skips to cooling, and the program finishes after cooling
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        let state = controller.start();
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 75.0, temp_sensor: "U7" };
+        let state = state.next(&mut controller, event).unwrap();
+        assert_eq!(State::Cooling { program: &programs[0], step: 3 }, state);
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 35.0, temp_sensor: TH1 };
+        let state = state.next(&mut controller, event).unwrap();
+        assert_eq!(State::FinishedProgram, state);
+    }
+
+    #[test]
+    fn test_resume_step() {
+        let _ = env_logger::try_init();
+        let heat = HeatStep {
+            duration: None,
+            duty: 1.0,
+            power: None,
+            thermostat: Some(60.0),
+            pid: None,
+            ramp_rate: None,
+            profile: vec![],
+        };
+        let program = |name: &str| Program {
+            steps: vec![
+                Step::new(Action::Heat(heat.clone())),
+                Step::new(Action::Wait { duration: Duration::minutes(10) }),
+                Step::new(Action::Cool { temp: 40.0 }),
+            ],
+            ..base_program(name)
+        };
+        let programs = [program("First"), program("Second")];
+
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoints = Checkpoints::new(dir.path().join("runner.json"), ResumePolicy::Resume, None);
+        let resume = Resume::Step { index: 1, step: 1, elapsed: Duration::minutes(4), aborted: false, iteration: 3, paused: false };
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let clock = Rc::new(SimulatedClock::new(Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap()));
+        let mut controller = PayloadController::new(&payload, program_list)
+            .with_checkpoints(checkpoints.clone(), resume)
+            .with_clock(clock.clone());
+        let state = controller.start();
+        // the wait continues for the rest of its time, with the heater on from the heat step
+        let end_time = match state {
+            State::Waiting { program, step: 1, end_time } if program.name == "Second" => end_time,
+            _ => panic!("Should resume waiting in the second program: {}", state),
+        };
+        assert_eq!(Duration::minutes(6), end_time - clock.now());
+        assert_eq!(Some(60.0), controller.target_temp);
+
+        controller.checkpoint(&state);
+        let checkpoint = checkpoints.read().unwrap();
+        assert_eq!((1, 1, "waiting", 240, 3), (checkpoint.program_index, checkpoint.step,
+            checkpoint.phase.as_str(), checkpoint.elapsed_secs, checkpoint.iteration));
+
+        let state = state.next(&mut controller, Event::Time);
+        assert_eq!(None, state);
+    }
+
+    #[test]
+    fn test_cool_timeout_failure_policies() {
+        let _ = env_logger::try_init();
+        let program = |on_failure| Program {
+            steps: vec![
+                Step::new(Action::Wait { duration: Duration::minutes(10) }),
+                Step::new(Action::Cool { temp: 40.0 }),
+            ],
+            cool_timeout: Some(Duration::zero()),
+            on_failure,
+            ..base_program("Top never cools")
+        };
+        let payload = Payload::create();
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 60.0, temp_sensor: TH1 };
+
+        let programs = [program(FailurePolicy::Skip)];
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        controller.start();
+        let state = controller.start_step(&programs[0], 1);
+        assert_eq!(Some(State::FinishedProgram), state.next(&mut controller, event.clone()));
+
+        let programs = [program(FailurePolicy::Retry(1))];
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        controller.start();
+        let state = controller.start_step(&programs[0], 1).next(&mut controller, event.clone()).unwrap();
+        assert!(matches!(state, State::Waiting { step: 0, .. }));
+        let state = controller.start_step(&programs[0], 1).next(&mut controller, event.clone()).unwrap();
+        assert_eq!(State::FinishedProgram, state);
+
+        let programs = [program(FailurePolicy::Abort)];
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        controller.start();
+        let state = controller.start_step(&programs[0], 1);
+        match state.next(&mut controller, event.clone()) {
+            Some(State::Failed { message }) => assert!(message.ends_with("failed, cooling timed out")),
+            state => panic!("Expected the abort policy to fail: {:?}", state),
+        }
+
+        // keeps cooling without the timeout, then finishes the program
+        let programs = [program(FailurePolicy::Wait)];
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list);
+        controller.start();
+        let state = controller.start_step(&programs[0], 1);
+        let state = state.next(&mut controller, event.clone()).unwrap();
+        assert_eq!(State::Cooling { program: &programs[0], step: 1 }, state);
+        assert_eq!(None, controller.step_deadline);
+        assert_eq!(None, state.next(&mut controller, event));
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 35.0, temp_sensor: TH1 };
+        assert_eq!(Some(State::FinishedProgram), state.next(&mut controller, event));
+    }
+
+    #[test]
+    fn test_event_log() {
+        let _ = env_logger::try_init();
+        let programs = [Program {
+            id: 3,
+            steps: vec![
+                Step::new(Action::Wait { duration: Duration::minutes(10) }),
+                Step::new(Action::Cool { temp: 40.0 }),
+            ],
+            cool_timeout: Some(Duration::zero()),
+            ..base_program("Top never cools")
+        }];
+        let dir = tempfile::tempdir().unwrap();
+        let event_log = EventLog::new(dir.path());
+        let payload = Payload::create();
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 60.0, temp_sensor: TH1 };
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list).with_event_log(event_log.clone());
+        let state = controller.start();
+        controller.record_phase(&state);
+        let state = controller.start_step(&programs[0], 1);
+        controller.record_phase(&state);
+        assert_eq!(Some(State::FinishedProgram), state.next(&mut controller, event));
+
+        let events = event_log.events(100);
+        let kinds: Vec<&str> = events.iter().filter_map(|e| e["event"].as_str()).collect();
+        assert_eq!(Some(&"program_started"), kinds.first());
+        assert!(kinds.contains(&"heater_write"));
+        let phases: Vec<&str> = events.iter().filter_map(|e| e["phase"].as_str()).collect();
+        assert_eq!(["waiting", "cooling"], phases.as_slice());
+        let failed = events.iter().find(|e| e["event"] == "failed").unwrap();
+        assert_eq!(3, failed["program_id"]);
+        assert_eq!(2, failed["step"]);
+        assert_eq!("Skip", failed["policy"]);
+        assert!(failed["reason"].as_str().unwrap().contains("timed out"));
+    }
+
+    #[test]
+    fn test_disarms_manual_deadman() {
+        let _ = env_logger::try_init();
+        let programs = [Program {
+            heat_time: Some(Duration::minutes(10)),
+            ..base_program("Top after manual heater")
+        }];
+        let dir = tempfile::tempdir().unwrap();
+        let deadman = Deadman::new(dir.path());
+        let payload = Payload::create();
+        let top = payload[BoardId::Top as u8].bus.id;
+        let bottom = payload[BoardId::Bottom as u8].bus.id;
+        let deadline = Utc::now() + Duration::minutes(5);
+        // left running by uts-cli heater or uts-web on both boards
+        deadman.arm(top, deadline);
+        deadman.arm(bottom, deadline);
+
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list).with_deadman(deadman.clone());
+        let state = controller.start();
+        assert!(matches!(state, State::Heating { .. }));
+        // the program's heater is no longer switched off at the deadline, the other still is
+        let armed: Vec<u8> = deadman.read().keys().copied().collect();
+        assert_eq!(vec![bottom], armed);
+    }
+
+    #[test]
+    fn test_parallel_tracks() {
+        let _ = env_logger::try_init();
+        let program = |id, heat_board, cool_temp| Program {
+            id,
+            heat_time: Some(Duration::minutes(30)),
+            cool_temp: Some(cool_temp),
+            heat_board,
+            ..base_program(&format!("{} heat", heat_board))
+        };
+        let top = [program(0, BoardId::Top, 40.0)];
+        let bottom = [program(1, BoardId::Bottom, 30.0)];
+        let events = [
+            Event::Time,
+            Event::Time,
+            Event::Time,
+            Event::Time,
+            // each track only sees readings from its board
+            Event::TemperatureReading { board: BoardId::Bottom, temp: 35.0, temp_sensor: TH1 },
+            Event::TemperatureReading { board: BoardId::Top, temp: 35.0, temp_sensor: TH1 },
+            Event::Time,
+            Event::TemperatureReading { board: BoardId::Bottom, temp: 25.0, temp_sensor: TH1 },
+            Event::Time,
+            Event::Time,
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let event_log = EventLog::new(dir.path());
+        let payload = Payload::create();
+        let (top_list, bottom_list) = (&mut top.iter(), &mut bottom.iter());
+        let clock: Rc<dyn Clock> = Rc::new(SimulatedClock::new(Utc::now()));
+        let mut controllers = [
+            PayloadController::new(&payload, top_list).for_board(BoardId::Top)
+                .with_event_log(event_log.clone()).with_clock(Rc::clone(&clock)),
+            PayloadController::new(&payload, bottom_list).for_board(BoardId::Bottom)
+                .with_event_log(event_log.clone()).with_clock(Rc::clone(&clock)),
+        ];
+        let state = run_tracks(&mut controllers, &mut IntoIterator::into_iter(events), Duration::minutes(10));
+        assert_eq!(State::Done, state);
+
+        let events = event_log.events(100);
+        let phases: Vec<(u64, &str)> = events.iter()
+            .filter_map(|e| Some((e["program_id"].as_u64()?, e["phase"].as_str()?)))
+            .collect();
+        assert_eq!([(0, "heating"), (1, "heating"), (0, "cooling"), (1, "cooling")], phases.as_slice());
+        let finished: Vec<u64> = events.iter()
+            .filter(|e| e["event"] == "program_finished")
+            .filter_map(|e| e["program_id"].as_u64())
+            .collect();
+        // the top program has cooled at 35°C, while the bottom program keeps cooling
+        assert_eq!([0, 1], finished.as_slice());
+    }
+
+    #[test]
+    fn test_failed_track() {
+        let _ = env_logger::try_init();
+        let top = [Program {
+            heat_time: Some(Duration::minutes(10)),
+            cool_temp: Some(20.0),
+            cool_timeout: Some(Duration::zero()),
+            on_failure: FailurePolicy::Abort,
+            ..base_program("top never cools")
+        }];
+        let bottom = [Program {
+            id: 1,
+            heat_time: Some(Duration::minutes(30)),
+            cool_temp: Some(30.0),
+            heat_board: BoardId::Bottom,
+            ..base_program("bottom heat")
+        }];
+        let events = [
+            Event::Time,
+            Event::Time,
+            // the top track fails here, while the bottom keeps heating
+            Event::Time,
+            Event::Time,
+            Event::TemperatureReading { board: BoardId::Bottom, temp: 25.0, temp_sensor: TH1 },
+            Event::Time,
+            Event::Time,
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let event_log = EventLog::new(dir.path());
+        let payload = Payload::create();
+        let (top_list, bottom_list) = (&mut top.iter(), &mut bottom.iter());
+        let clock: Rc<dyn Clock> = Rc::new(SimulatedClock::new(Utc::now()));
+        let mut controllers = [
+            PayloadController::new(&payload, top_list).for_board(BoardId::Top)
+                .with_event_log(event_log.clone()).with_clock(Rc::clone(&clock)),
+            PayloadController::new(&payload, bottom_list).for_board(BoardId::Bottom)
+                .with_event_log(event_log.clone()).with_clock(Rc::clone(&clock)),
+        ];
+        let state = run_tracks(&mut controllers, &mut IntoIterator::into_iter(events), Duration::minutes(10));
+        let failure = format!("Step 2 of {} failed, cooling timed out", top[0]);
+        match state {
+            State::Failed { message } => assert_eq!(failure, message),
+            state => panic!("Expected the top track to fail, got {}", state),
+        }
+
+        let events = event_log.events(100);
+        let offs: Vec<&str> = events.iter()
+            .filter(|e| e["event"] == "heater_write" && e["value"] == "OFF" && e["program_id"] == 0)
+            .filter_map(|e| e["board"].as_str())
+            .collect();
+        // the abort policy only switches off the heater of the failed track
+        assert!(offs.contains(&"top") && !offs.contains(&"bottom"));
+        let phases: Vec<(u64, &str)> = events.iter()
+            .filter_map(|e| Some((e["program_id"].as_u64()?, e["phase"].as_str()?)))
+            .collect();
+        assert_eq!([(0, "heating"), (1, "heating"), (0, "cooling"), (1, "cooling")], phases.as_slice());
+        let stopped: Vec<&str> = events.iter()
+            .filter(|e| e["event"] == "stopped")
+            .filter_map(|e| e["reason"].as_str())
+            .collect();
+        assert_eq!([format!("top track {}", failure).as_str(), "bottom track completed"], stopped.as_slice());
+    }
+
+    #[test]
+    fn test_control_commands() {
+        let _ = env_logger::try_init();
+        let programs = [Program {
+            steps: vec![
+                Step::new(Action::Wait { duration: Duration::minutes(10) }),
+                Step::new(Action::Cool { temp: 40.0 }),
+            ],
+            ..base_program("Top wait")
+        }];
+        let dir = tempfile::tempdir().unwrap();
+        let control = ControlChannel::new(dir.path());
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let clock = Rc::new(SimulatedClock::new(Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap()));
+        let mut controller = PayloadController::new(&payload, program_list)
+            .with_control(control.clone())
+            .with_clock(clock.clone());
+        let mut state = controller.start();
+        fn send<'a>(controller: &mut PayloadController<'a>, state: &State<'a>, command: Command) -> Option<State<'a>> {
+            send_to(controller, state, command, None)
+        }
+        fn send_to<'a>(controller: &mut PayloadController<'a>, state: &State<'a>, command: Command,
+                       board: Option<BoardId>) -> Option<State<'a>> {
+            controller.control.as_ref().unwrap().send(command, board, "test", controller.now());
+            let requests = controller.take_commands();
+            assert_eq!(1, requests.len());
+            controller.command(state, &requests[0])
+        }
+
+        // the step is paused 5 minutes in, so it has 5 minutes left however long it's paused
+        clock.sleep(Duration::minutes(5));
+        assert_eq!(None, send(&mut controller, &state, Command::Pause));
+        assert!(controller.paused.is_some());
+        assert_eq!(None, send(&mut controller, &state, Command::Pause));
+        clock.sleep(Duration::hours(1));
+        state = send(&mut controller, &state, Command::Resume).unwrap();
+        let remaining = match state {
+            State::Waiting { end_time, .. } => end_time - clock.now(),
+            _ => panic!("Expected waiting: {}", state),
+        };
+        assert_eq!(Duration::minutes(5), remaining);
+        assert_eq!(None, send(&mut controller, &state, Command::Resume));
+        // the program heats the top board
+        assert_eq!(None, send_to(&mut controller, &state, Command::Skip, Some(BoardId::Bottom)));
+        state = send_to(&mut controller, &state, Command::Skip, Some(BoardId::Top)).unwrap();
+        assert_eq!(State::FinishedProgram, state);
+        assert_eq!(None, send(&mut controller, &state, Command::Abort));
+
+        let outcomes: Vec<String> = control.audit_log(100).into_iter()
+            .filter(|audit| audit.outcome != "requested")
+            .map(|audit| audit.outcome)
+            .collect();
+        assert_eq!(["applied", "ignored, already paused", "applied", "ignored, not paused",
+                       "ignored, not running a program on the bottom board", "applied",
+                       "ignored, no program is running"], outcomes.as_slice());
+    }
+
+    #[test]
+    fn test_abort_one_track() {
+        let _ = env_logger::try_init();
+        let program = |id, heat_board| Program {
+            id,
+            heat_time: Some(Duration::minutes(30)),
+            cool_temp: Some(30.0),
+            heat_board,
+            ..base_program(&format!("{} heat", heat_board))
+        };
+        let top = [program(0, BoardId::Top)];
+        let bottom = [program(1, BoardId::Bottom)];
+        let events = [
+            Event::Time,
+            Event::Time,
+            Event::Time,
+            Event::Time,
+            Event::TemperatureReading { board: BoardId::Bottom, temp: 25.0, temp_sensor: TH1 },
+            Event::Time,
+            Event::Time,
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let control = ControlChannel::new(dir.path());
+        let event_log = EventLog::new(dir.path());
+        let payload = Payload::create();
+        let (top_list, bottom_list) = (&mut top.iter(), &mut bottom.iter());
+        let clock: Rc<dyn Clock> = Rc::new(SimulatedClock::new(Utc::now()));
+        let mut controllers = [
+            PayloadController::new(&payload, top_list).for_board(BoardId::Top).with_control(control.clone())
+                .with_event_log(event_log.clone()).with_clock(Rc::clone(&clock)),
+            PayloadController::new(&payload, bottom_list).for_board(BoardId::Bottom).with_control(control.clone())
+                .with_event_log(event_log.clone()).with_clock(Rc::clone(&clock)),
+        ];
+        control.send(Command::Abort, Some(BoardId::Top), "test", clock.now());
+        let state = run_tracks(&mut controllers, &mut IntoIterator::into_iter(events), Duration::minutes(10));
+        match state {
+            State::Failed { message } => assert_eq!("Aborted by test", message),
+            state => panic!("Expected the top track to be aborted, got {}", state),
+        }
+
+        let events = event_log.events(100);
+        let offs: Vec<&str> = events.iter()
+            .filter(|e| e["event"] == "heater_write" && e["value"] == "OFF" && e["program_id"] == 0)
+            .filter_map(|e| e["board"].as_str())
+            .collect();
+        assert!(offs.contains(&"top") && !offs.contains(&"bottom"));
+        // the bottom track heats for its full time and cools, after the top track was aborted
+        let phases: Vec<(u64, &str)> = events.iter()
+            .filter_map(|e| Some((e["program_id"].as_u64()?, e["phase"].as_str()?)))
+            .collect();
+        assert_eq!([(0, "heating"), (1, "heating"), (1, "cooling")], phases.as_slice());
+        let finished: Vec<u64> = events.iter()
+            .filter(|e| e["event"] == "program_finished")
+            .filter_map(|e| e["program_id"].as_u64())
+            .collect();
+        assert_eq!([1], finished.as_slice());
+    }
+
+    #[test]
+    fn test_resume_paused() {
+        let _ = env_logger::try_init();
+        let programs = [Program {
+            heat_time: Some(Duration::minutes(10)),
+            ..base_program("Top paused")
+        }];
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoints = Checkpoints::new(dir.path().join("runner.json"), ResumePolicy::Resume, None);
+        let control = ControlChannel::new(dir.path());
+        let resume = Resume::Step { index: 0, step: 0, elapsed: Duration::minutes(4), aborted: false, iteration: 0, paused: true };
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let clock = Rc::new(SimulatedClock::new(Utc::now()));
+        let mut controller = PayloadController::new(&payload, program_list)
+            .with_checkpoints(checkpoints.clone(), resume)
+            .with_control(control.clone())
+            .with_clock(clock.clone());
+        let state = controller.start();
+        assert_eq!(State::Heating { program: &programs[0], step: 0, end_time: None }, state);
+        assert!(controller.paused.is_some());
+
+        // still paused after an hour, with the heat time frozen
+        clock.sleep(Duration::hours(1));
+        controller.save(&state);
+        let checkpoint = checkpoints.read().unwrap();
+        assert_eq!((true, 240), (checkpoint.paused, checkpoint.elapsed_secs));
+
+        control.send(Command::Resume, None, "test", clock.now());
+        let requests = controller.take_commands();
+        let end_time = match controller.command(&state, &requests[0]) {
+            Some(State::Heating { end_time: Some(end_time), .. }) => end_time,
+            state => panic!("Expected heating: {:?}", state),
+        };
+        assert_eq!(Duration::minutes(6), end_time - clock.now());
+        controller.save(&state);
+        assert!(!checkpoints.read().unwrap().paused);
+    }
+
+    #[test]
+    fn test_command_for_board_without_track() {
+        let _ = env_logger::try_init();
+        let programs = [Program {
+            heat_time: Some(Duration::minutes(30)),
+            ..base_program("top heat")
+        }];
+        let dir = tempfile::tempdir().unwrap();
+        let control = ControlChannel::new(dir.path());
+        let payload = Payload::create();
+        let program_list = &mut programs.iter();
+        let clock: Rc<dyn Clock> = Rc::new(SimulatedClock::new(Utc::now()));
+        let mut controllers = [
+            PayloadController::new(&payload, program_list).for_board(BoardId::Top)
+                .with_control(control.clone()).with_clock(Rc::clone(&clock)),
+        ];
+        control.send(Command::Skip, Some(BoardId::Bottom), "test", clock.now());
+        run_tracks(&mut controllers, &mut IntoIterator::into_iter([Event::Time]), Duration::minutes(10));
+
+        let outcomes: Vec<String> = control.audit_log(100).into_iter()
+            .filter(|audit| audit.outcome != "requested")
+            .map(|audit| audit.outcome)
+            .collect();
+        assert_eq!(["ignored, no track for the bottom board"], outcomes.as_slice());
+    }
+
+    #[test]
+    fn test_end_time() {
+        let _ = env_logger::try_init();
+        let program = |name: &str| Program {
+            steps: vec![
+                Step::new(Action::Wait { duration: Duration::minutes(10) }),
+                Step::new(Action::Cool { temp: 40.0 }),
+            ],
+            ..base_program(name)
+        };
+        let payload = Payload::create();
+        let programs = [program("First"), program("Second")];
+        let program_list = &mut programs.iter();
+        let clock = Rc::new(SimulatedClock::new(Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap()));
+        let mut controller = PayloadController::new(&payload, program_list)
+            .until(Some(clock.now() + Duration::minutes(5)))
+            .with_clock(clock.clone());
+        let state = controller.start();
+        assert!(matches!(state, State::Waiting { step: 0, .. }));
+
+        // skips to cooling, then doesn't start the second program
+        clock.sleep(Duration::minutes(5));
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 60.0, temp_sensor: TH1 };
+        let state = state.next(&mut controller, event).unwrap();
+        assert_eq!(State::Cooling { program: &programs[0], step: 1 }, state);
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 35.0, temp_sensor: TH1 };
+        assert_eq!(Some(State::FinishedProgram), state.next(&mut controller, event));
+        assert_eq!(State::Done, controller.next_program_or_done());
+    }
+
+    #[test]
+    fn test_steady_state() {
+        let _ = env_logger::try_init();
+        let heat = HeatStep {
+            duration: None,
+            duty: 0.5,
+            power: None,
+            thermostat: None,
+            pid: None,
+            ramp_rate: None,
+            profile: vec![],
+        };
+        // a single reading is steady over an empty window
+        let steady_state = SteadyState { max_rate: 0.1, window: Duration::zero(), sensors: vec![String::from("J7")] };
+        let program = Program {
+            id: 3,
+            steps: vec![
+                Step { steady_state: Some(steady_state), ..Step::new(Action::Heat(heat)) },
+                Step::new(Action::Cool { temp: 40.0 }),
+            ],
+            ..base_program("Top equilibrium")
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let summary = SummaryFile::new(dir.path().join("summary.json"));
+        let payload = Payload::create();
+        let programs = [program];
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list).with_summary(summary.clone());
+        // keeps heating without a duration until steady
+        let state = controller.start();
+        assert!(matches!(state, State::Heating { step: 0, end_time: None, .. }));
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 55.0, temp_sensor: TH1 };
+        assert_eq!(None, state.next(&mut controller, event));
+        let event = Event::TemperatureReading { board: BoardId::Top, temp: 52.5, temp_sensor: J7 };
+        assert_eq!(Some(State::Cooling { program: &programs[0], step: 1 }), state.next(&mut controller, event));
+
+        let record = &summary.read().unwrap().steady_states[0];
+        assert_eq!((0, 3, 1, "heating"), (record.program_index, record.program_id, record.step, record.phase.as_str()));
+        assert_eq!(vec![Equilibrium { sensor: String::from("J7"), temp: 52.5, rate: 0.0 }], record.sensors);
+    }
+
+    #[test]
+    fn test_scheduled_programs() {
+        let _ = env_logger::try_init();
+        let program = |name: &str| Program {
+            steps: vec![Step::new(Action::Wait { duration: Duration::minutes(10) })],
+            ..base_program(name)
+        };
+        let payload = Payload::create();
+        let clock = Rc::new(SimulatedClock::new(Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap()));
+        let start = clock.now();
+        let programs = [
+            Program { not_before: Some(start + Duration::hours(1)), ..program("Later") },
+            Program { not_after: Some(start + Duration::minutes(30)), ..program("Missed") },
+            program("Now"),
+        ];
+        let program_list = &mut programs.iter();
+        let mut controller = PayloadController::new(&payload, program_list).with_clock(clock.clone());
+        let state = controller.start();
+        match state {
+            State::Scheduled { program, start_time } => assert_eq!((&programs[0], start + Duration::hours(1)), (program, start_time)),
+            _ => panic!("Expected the first program to be scheduled: {}", state),
+        }
+        assert_eq!(None, state.next(&mut controller, Event::Time));
+
+        // starts once the start time is reached, and skips programs which can't start in time
+        clock.sleep(Duration::hours(1));
+        let state = state.next(&mut controller, Event::Time).unwrap();
+        assert!(matches!(state, State::Waiting { step: 0, .. }));
+        let state = controller.next_program_or_done();
+        assert!(matches!(state, State::Waiting { program, .. } if program.name == "Now"));
     }
 
     #[test]
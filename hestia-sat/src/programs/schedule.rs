@@ -0,0 +1,86 @@
+use std::fmt::{Display, Formatter};
+
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+
+/// Time of day a program may start in, every day, configured as
+/// `daily_window = { start = "22:00", end = "04:00" }` in UTC. Windows with an end before the
+/// start run over midnight. The window only controls when the program starts, so a program
+/// started near the end keeps heating after it closes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+pub struct DailyWindow {
+    #[serde(deserialize_with = "deserialize_time_of_day")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time_of_day")]
+    pub end: NaiveTime,
+}
+
+fn deserialize_time_of_day<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+    where D: Deserializer<'de> {
+    let value = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&value, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(&value, "%H:%M"))
+        .map_err(|err| D::Error::custom(format!("invalid time of day {}, expected HH:MM: {}", value, err)))
+}
+
+impl DailyWindow {
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        let time = time.time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// How long the window is open for each day
+    pub fn length(&self) -> Duration {
+        let length = self.end.signed_duration_since(self.start);
+        if length > Duration::zero() { length } else { length + Duration::days(1) }
+    }
+
+    /// The time itself if it's within the window, otherwise when the window next opens
+    pub fn next_open(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        if self.contains(time) {
+            return time;
+        }
+        let open = Utc.from_utc_datetime(&time.date_naive().and_time(self.start));
+        if open > time { open } else { open + Duration::days(1) }
+    }
+}
+
+impl Display for DailyWindow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{} UTC", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::programs::schedule::DailyWindow;
+
+    #[test]
+    fn test_daily_window() {
+        let at = |h, m| Utc.with_ymd_and_hms(2023, 9, 1, h, m, 0).unwrap();
+        let day: DailyWindow = toml::from_str("start = \"09:00\"\nend = \"17:30\"").unwrap();
+        assert!(day.contains(at(12, 0)));
+        assert!(!day.contains(at(17, 30)));
+        assert_eq!(at(12, 0), day.next_open(at(12, 0)));
+        assert_eq!(at(9, 0), day.next_open(at(3, 0)));
+        assert_eq!(Utc.with_ymd_and_hms(2023, 9, 2, 9, 0, 0).unwrap(), day.next_open(at(18, 0)));
+
+        let night: DailyWindow = toml::from_str("start = \"22:00\"\nend = \"04:00:00\"").unwrap();
+        assert!(night.contains(at(23, 0)));
+        assert!(night.contains(at(1, 0)));
+        assert!(!night.contains(at(12, 0)));
+        assert_eq!(at(22, 0), night.next_open(at(12, 0)));
+        assert_eq!("22:00-04:00 UTC", night.to_string());
+        assert_eq!(Duration::hours(6), night.length());
+        assert_eq!(Duration::minutes(510), day.length());
+
+        assert!(toml::from_str::<DailyWindow>("start = \"25:00\"\nend = \"04:00\"").is_err());
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+use chrono::{DateTime, Duration, Utc};
+use duration_str::{deserialize_duration_chrono, deserialize_option_duration_chrono};
+use serde::{Deserialize, Serialize};
+
+use crate::control::pid::PidConfig;
+use crate::programs::Program;
+use crate::programs::profile::Segment;
+
+fn default_heat_duty() -> f32 { 1.0 }
+
+fn default_tolerance() -> f32 { 0.5 }
+
+fn default_window() -> Duration { Duration::minutes(1) }
+
+/// Heater settings for a heat step, which are the same as the heat settings of a
+/// `[[programs]]` entry without steps
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct HeatStep {
+    /// Heat for this long, or until the profile completes. Without either, the step ends
+    /// as soon as the heater is on, leaving it on for the following steps.
+    #[serde(default, deserialize_with = "deserialize_option_duration_chrono")]
+    pub duration: Option<Duration>,
+    #[serde(default = "default_heat_duty")]
+    pub duty: f32,
+    /// Constant heater power in W, adjusting duty for the measured power instead of `duty`
+    pub power: Option<f32>,
+    pub thermostat: Option<f32>,
+    /// Host-side PID control on the step sensor, used instead of the firmware `thermostat`
+    pub pid: Option<PidConfig>,
+    /// Limits the rate of change of the thermostat or PID setpoint, in °C/min
+    pub ramp_rate: Option<f32>,
+    /// Ramp/soak segments for the setpoint
+    #[serde(default)]
+    pub profile: Vec<Segment>,
+}
+
+impl HeatStep {
+    /// Setpoint segments from `profile`, or a single ramp to the thermostat or PID setpoint
+    /// held for the rest of the step. None if the setpoint is fixed.
+    pub fn setpoint_profile(&self) -> Option<Vec<Segment>> {
+        if !self.profile.is_empty() {
+            return Some(self.profile.clone());
+        }
+        let ramp_rate = self.ramp_rate?;
+        let target = self.pid.as_ref().map(|pid| pid.setpoint).or(self.thermostat)?;
+        let soak = self.duration.unwrap_or_else(Duration::zero);
+        Some(vec![Segment { target, ramp_rate: Some(ramp_rate), soak, tolerance: None }])
+    }
+
+    /// Checks for settings which conflict, or would be ignored and leave the heater at `duty`
+    pub fn validate(&self) -> Result<(), String> {
+        if self.thermostat.is_some() && self.pid.is_some() {
+            return Err(String::from("should set only one of thermostat or pid"));
+        }
+        if self.ramp_rate.is_some() {
+            if !self.profile.is_empty() {
+                return Err(String::from("should set ramp_rate on the profile segments, not with a profile"));
+            }
+            if self.thermostat.is_none() && self.pid.is_none() {
+                return Err(String::from("should set thermostat or pid for ramp_rate to ramp to"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a step does, configured with `type` in `[[programs.steps]]`
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    /// Switch the heater on with PWM, thermostat, host PID or constant power
+    Heat(HeatStep),
+    /// Leave the heater as it is until the temperature is stable
+    Hold {
+        /// Largest change in temperature over the window, in °C
+        #[serde(default = "default_tolerance")]
+        tolerance: f32,
+        #[serde(default = "default_window", deserialize_with = "deserialize_duration_chrono")]
+        window: Duration,
+        /// Continue to the next step if not stable by then
+        #[serde(default, deserialize_with = "deserialize_option_duration_chrono")]
+        timeout: Option<Duration>,
+    },
+    /// Leave the heater as it is for a time
+    Wait {
+        #[serde(deserialize_with = "deserialize_duration_chrono")]
+        duration: Duration,
+    },
+    /// Leave the heater as it is until the temperature is above or below a limit
+    WaitTemp {
+        above: Option<f32>,
+        below: Option<f32>,
+    },
+    /// Switch the heater off and wait until the temperature is at or below `temp`
+    Cool {
+        temp: f32,
+    },
+}
+
+/// Ends a heat or cool step once the temperature has settled, configured as
+/// `[programs.steps.steady_state]`
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct SteadyState {
+    /// Largest rate of change over the window, in °C/min
+    pub max_rate: f32,
+    #[serde(default = "default_window", deserialize_with = "deserialize_duration_chrono")]
+    pub window: Duration,
+    /// Sensors or aggregates which must all be steady, if different to the step sensor
+    #[serde(default)]
+    pub sensors: Vec<String>,
+}
+
+/// One step of a program. Sensors default to the program `temp_sensor`.
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct Step {
+    #[serde(flatten)]
+    pub action: Action,
+    /// Sensor or aggregate to control on or check, if different to the program `temp_sensor`
+    pub temp_sensor: Option<String>,
+    /// Skips to the next cool step if exceeded during this step, as well as the program `temp_abort`
+    pub temp_abort: Option<f32>,
+    /// Sensor or aggregate checked against the step `temp_abort`, if different to the step sensor
+    pub abort_sensor: Option<String>,
+    /// Ends a heat or cool step early once the temperature is steady
+    pub steady_state: Option<SteadyState>,
+}
+
+impl Step {
+    pub fn new(action: Action) -> Self {
+        Step { action, temp_sensor: None, temp_abort: None, abort_sensor: None, steady_state: None }
+    }
+
+    /// The sensor or aggregate to control on or check
+    pub fn sensor<'a>(&'a self, program: &'a Program) -> &'a str {
+        self.temp_sensor.as_deref().unwrap_or(&program.temp_sensor)
+    }
+
+    /// The sensor or aggregate checked against the step `temp_abort`
+    pub fn abort_sensor<'a>(&'a self, program: &'a Program) -> &'a str {
+        self.abort_sensor.as_deref().unwrap_or_else(|| self.sensor(program))
+    }
+
+    /// Sensors or aggregates checked for a steady state, if the step has one
+    pub fn steady_state_sensors<'a>(&'a self, program: &'a Program) -> Vec<&'a str> {
+        match &self.steady_state {
+            Some(steady) if steady.sensors.is_empty() => vec![self.sensor(program)],
+            Some(steady) => steady.sensors.iter().map(|s| s.as_str()).collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn is_cool(&self) -> bool {
+        matches!(self.action, Action::Cool { .. })
+    }
+
+    /// Time after which the step fails, from the program `heat_timeout` or `cool_timeout`.
+    /// None for steps which always end after a time.
+    pub fn timeout(&self, program: &Program) -> Option<Duration> {
+        match &self.action {
+            Action::Heat(heat) if heat.duration.is_none() => program.heat_timeout,
+            Action::Hold { timeout: None, .. } | Action::WaitTemp { .. } => program.heat_timeout,
+            Action::Cool { .. } => program.cool_timeout,
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match &self.action {
+            Action::Heat(heat) => heat.validate().map_err(|err| format!("heat step {}", err))
+                .and_then(|_| self.validate_steady_state()),
+            Action::Hold { tolerance, .. } if *tolerance <= 0.0 =>
+                Err(format!("hold tolerance should be positive: {}", tolerance)),
+            Action::WaitTemp { above, below } if above.is_some() == below.is_some() =>
+                Err(String::from("wait_temp step should set one of above or below")),
+            _ => self.validate_steady_state(),
+        }
+    }
+
+    fn validate_steady_state(&self) -> Result<(), String> {
+        match &self.steady_state {
+            Some(_) if !matches!(self.action, Action::Heat(_) | Action::Cool { .. }) =>
+                Err(String::from("steady_state is only used by heat and cool steps")),
+            Some(steady) if steady.max_rate <= 0.0 =>
+                Err(format!("steady_state max_rate should be positive: {}", steady.max_rate)),
+            Some(steady) if steady.window <= Duration::zero() =>
+                Err(String::from("steady_state window should be positive")),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.action.fmt(f)?;
+        if let Some(steady) = &self.steady_state {
+            write!(f, " or until steady within {:.2}°C/min over {}s", steady.max_rate, steady.window.num_seconds())?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Heat(heat) => {
+                write!(f, "heat")?;
+                if let Some(power) = heat.power {
+                    write!(f, " {:.2} W", power)?;
+                } else if let Some(pid) = &heat.pid {
+                    write!(f, " PID {:.1}°C", pid.setpoint)?;
+                } else if let Some(thermostat) = heat.thermostat {
+                    write!(f, " thermostat {:.1}°C", thermostat)?;
+                } else if heat.profile.is_empty() {
+                    write!(f, " duty {:.2}", heat.duty)?;
+                }
+                if !heat.profile.is_empty() {
+                    let segments: Vec<String> = heat.profile.iter().map(|s| s.to_string()).collect();
+                    write!(f, " profile [{}]", segments.join(", "))?;
+                }
+                if let Some(duration) = heat.duration {
+                    write!(f, " for {}s", duration.num_seconds())?;
+                }
+                Ok(())
+            }
+            Action::Hold { tolerance, window, .. } =>
+                write!(f, "hold until stable ±{:.1}°C over {}s", tolerance, window.num_seconds()),
+            Action::Wait { duration } => write!(f, "wait {}s", duration.num_seconds()),
+            Action::WaitTemp { above: Some(temp), .. } => write!(f, "wait until above {:.1}°C", temp),
+            Action::WaitTemp { below, .. } => write!(f, "wait until below {:.1}°C", below.unwrap_or(f32::NAN)),
+            Action::Cool { temp } => write!(f, "cool to {:.1}°C", temp),
+        }
+    }
+}
+
+/// Detects when readings have stayed within a tolerance for a window of time
+#[derive(Debug, Clone)]
+pub struct StabilityDetector {
+    tolerance: f32,
+    window: Duration,
+    readings: VecDeque<(DateTime<Utc>, f32)>,
+}
+
+impl StabilityDetector {
+    pub fn new(tolerance: f32, window: Duration) -> Self {
+        StabilityDetector { tolerance, window, readings: VecDeque::new() }
+    }
+
+    /// Adds a reading, returning true once the readings cover the window and stay within tolerance
+    pub fn update(&mut self, temp: f32, now: DateTime<Utc>) -> bool {
+        self.readings.push_back((now, temp));
+        // keep one reading at or before the start of the window, so we know it's covered
+        while self.readings.len() > 1 && now - self.readings[1].0 >= self.window {
+            self.readings.pop_front();
+        }
+        let covered = now - self.readings[0].0 >= self.window;
+        let (min, max) = self.readings.iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), (_, t)| (min.min(*t), max.max(*t)));
+        covered && max - min <= self.tolerance
+    }
+}
+
+/// Mean temperature and rate of change of a sensor over a steady-state window
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Equilibrium {
+    pub sensor: String,
+    pub temp: f32,
+    /// Rate of change in °C/min
+    pub rate: f32,
+}
+
+/// Timestamped temperature readings
+type Readings = VecDeque<(DateTime<Utc>, f32)>;
+
+/// Detects when the rate of change of each sensor, from a least-squares fit over a window of
+/// readings, has stayed within a limit
+#[derive(Debug, Clone)]
+pub struct SteadyStateDetector {
+    max_rate: f32,
+    window: Duration,
+    /// Readings covering the window for each sensor
+    sensors: Vec<(String, Readings)>,
+}
+
+impl SteadyStateDetector {
+    pub fn new(steady: &SteadyState, sensors: &[&str]) -> Self {
+        let sensors = sensors.iter().map(|s| (s.to_string(), VecDeque::new())).collect();
+        SteadyStateDetector { max_rate: steady.max_rate, window: steady.window, sensors }
+    }
+
+    /// Adds a reading, returning the equilibrium of every sensor once each covers the window
+    /// with a rate of change within the limit
+    pub fn update(&mut self, sensor: &str, temp: f32, now: DateTime<Utc>) -> Option<Vec<Equilibrium>> {
+        let readings = &mut self.sensors.iter_mut().find(|(s, _)| s == sensor)?.1;
+        readings.push_back((now, temp));
+        // keep one reading at or before the start of the window, so we know it's covered
+        while readings.len() > 1 && now - readings[1].0 >= self.window {
+            readings.pop_front();
+        }
+        self.sensors.iter()
+            .map(|(sensor, readings)| {
+                let (first, _) = *readings.front()?;
+                let last = readings.back()?.0;
+                if last - first < self.window {
+                    return None;
+                }
+                let rate = rate_of_change(readings);
+                let temp = readings.iter().map(|(_, t)| t).sum::<f32>() / readings.len() as f32;
+                Some(Equilibrium { sensor: sensor.clone(), temp, rate }).filter(|_| rate.abs() <= self.max_rate)
+            })
+            .collect()
+    }
+}
+
+/// Slope of a least-squares fit to the readings, in °C/min
+fn rate_of_change(readings: &Readings) -> f32 {
+    let start = readings[0].0;
+    let points: Vec<(f32, f32)> = readings.iter()
+        .map(|(time, temp)| ((*time - start).num_milliseconds() as f32 / 60_000.0, *temp))
+        .collect();
+    let n = points.len() as f32;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f32>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f32>() / n;
+    let covariance: f32 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance: f32 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if variance > 0.0 { covariance / variance } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::programs::profile::Segment;
+    use crate::programs::step::{Action, HeatStep, StabilityDetector, Step, SteadyState, SteadyStateDetector};
+
+    #[test]
+    fn test_stability() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut detector = StabilityDetector::new(0.5, Duration::seconds(60));
+        let at = |secs| start + Duration::seconds(secs);
+        assert!(!detector.update(50.0, at(0)));
+        assert!(!detector.update(55.0, at(30)));
+        // stable since 30s, but not for a full window yet
+        assert!(!detector.update(55.2, at(60)));
+        assert!(!detector.update(55.1, at(89)));
+        assert!(detector.update(55.3, at(90)));
+        // a jump resets it
+        assert!(!detector.update(57.0, at(100)));
+    }
+
+    #[test]
+    fn test_steady_state() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let at = |secs| start + Duration::seconds(secs);
+        let steady = SteadyState { max_rate: 0.1, window: Duration::minutes(5), sensors: vec![] };
+        let mut detector = SteadyStateDetector::new(&steady, &["TH1", "TH2"]);
+        // TH1 settles at 0.05°C/min, TH2 rises at 0.5°C/min then jumps and settles
+        for secs in (0..=300).step_by(30) {
+            assert_eq!(None, detector.update("TH1", 50.0 + secs as f32 / 1200.0, at(secs)));
+            assert_eq!(None, detector.update("TH2", 40.0 + secs as f32 / 120.0, at(secs)));
+        }
+        assert_eq!(None, detector.update("U7", 30.0, at(330)));
+        for secs in (330..600).step_by(30) {
+            assert_eq!(None, detector.update("TH2", 45.0, at(secs)));
+        }
+        let equilibria = detector.update("TH2", 45.0, at(630)).unwrap();
+        assert_eq!(2, equilibria.len());
+        assert_eq!("TH1", equilibria[0].sensor);
+        assert!((equilibria[0].rate - 0.05).abs() < 0.001);
+        assert!((equilibria[0].temp - 50.125).abs() < 0.001);
+        assert_eq!(45.0, equilibria[1].temp);
+        assert_eq!(0.0, equilibria[1].rate);
+    }
+
+    #[test]
+    fn test_parse_steps() {
+        #[derive(serde::Deserialize)]
+        struct Steps {
+            steps: Vec<Step>,
+        }
+        let steps: Steps = toml::from_str(r#"
+            [[steps]]
+            type = "heat"
+            thermostat = 60
+            temp_abort = 70.0
+
+            [[steps]]
+            type = "hold"
+            tolerance = 0.2
+            window = "2m"
+            temp_sensor = "max(TH1, TH2)"
+
+            [[steps]]
+            type = "wait"
+            duration = "30s"
+
+            [[steps]]
+            type = "wait_temp"
+            below = 50.0
+
+            [[steps]]
+            type = "cool"
+            temp = 40.0
+        "#).unwrap();
+        let steps = steps.steps;
+        assert_eq!(5, steps.len());
+        assert!(matches!(&steps[0].action, Action::Heat(heat) if heat.thermostat == Some(60.0) && heat.duty == 1.0));
+        assert_eq!(Some(70.0), steps[0].temp_abort);
+        assert_eq!(Action::Hold { tolerance: 0.2, window: Duration::minutes(2), timeout: None }, steps[1].action);
+        assert_eq!(Some(String::from("max(TH1, TH2)")), steps[1].temp_sensor);
+        assert_eq!(Action::Wait { duration: Duration::seconds(30) }, steps[2].action);
+        assert_eq!(Action::WaitTemp { above: None, below: Some(50.0) }, steps[3].action);
+        assert!(steps[4].is_cool());
+        assert!(steps.iter().all(|s| s.validate().is_ok()));
+    }
+
+    #[test]
+    fn test_validate() {
+        let step = Step::new(Action::WaitTemp { above: Some(50.0), below: Some(40.0) });
+        assert!(step.validate().is_err());
+        let step = Step::new(Action::Hold { tolerance: 0.0, window: Duration::minutes(1), timeout: None });
+        assert!(step.validate().is_err());
+        let steady = SteadyState { max_rate: 0.1, window: Duration::minutes(5), sensors: vec![] };
+        let step = Step { steady_state: Some(steady.clone()), ..Step::new(Action::Wait { duration: Duration::minutes(1) }) };
+        assert!(step.validate().is_err());
+        let step = Step { steady_state: Some(steady), ..Step::new(Action::Cool { temp: 40.0 }) };
+        assert!(step.validate().is_ok());
+
+        let heat = HeatStep {
+            duration: None,
+            duty: 1.0,
+            power: None,
+            thermostat: None,
+            pid: None,
+            ramp_rate: Some(2.0),
+            profile: vec![],
+        };
+        assert_eq!(Err(String::from("heat step should set thermostat or pid for ramp_rate to ramp to")),
+                   Step::new(Action::Heat(heat.clone())).validate());
+        let profile = vec![Segment { target: 60.0, ramp_rate: None, soak: Duration::minutes(5), tolerance: None }];
+        assert!(Step::new(Action::Heat(HeatStep { thermostat: Some(60.0), profile, ..heat.clone() })).validate().is_err());
+        assert!(Step::new(Action::Heat(HeatStep { thermostat: Some(60.0), ..heat })).validate().is_ok());
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::payload::Config;
+use crate::programs::step::Equilibrium;
+
+const SUMMARY_FILE: &str = "summary.json";
+
+/// Results of a run through the programs
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub started: String,
+    pub steady_states: Vec<SteadyStateRecord>,
+}
+
+/// Equilibrium values detected at the end of a heat or cool step
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SteadyStateRecord {
+    /// Number of completed runs through the programs with `loop = true`
+    pub iteration: u32,
+    /// Position of the program in `Programs::sequence()`, or its board's track with `parallel = true`
+    pub program_index: usize,
+    pub program_id: u8,
+    pub program_name: String,
+    /// Step number, starting from 1
+    pub step: usize,
+    pub phase: String,
+    pub time: String,
+    pub sensors: Vec<Equilibrium>,
+}
+
+/// Saves the summary of the current run to UTS_STATE_PATH, updating it as results come in
+#[derive(Debug, Clone)]
+pub struct SummaryFile {
+    path: PathBuf,
+}
+
+impl SummaryFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        SummaryFile { path: path.as_ref().to_path_buf() }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(Path::new(&config.state_path).join(SUMMARY_FILE))
+    }
+
+    /// None if there's no summary or it can't be read
+    pub fn read(&self) -> Option<RunSummary> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read summary file {:?}: {}", self.path, e);
+                return None;
+            }
+        };
+        serde_json::from_str(&contents)
+            .map_err(|e| warn!("Invalid summary file {:?}: {}", self.path, e))
+            .ok()
+    }
+
+    /// Replaces the summary of the last run with an empty one
+    pub fn start(&self, now: DateTime<Utc>) {
+        self.write(&RunSummary { started: now.to_rfc3339(), steady_states: vec![] });
+    }
+
+    pub fn add_steady_state(&self, record: SteadyStateRecord) {
+        let mut summary = self.read().unwrap_or_else(|| RunSummary { started: record.time.clone(), ..Default::default() });
+        summary.steady_states.push(record);
+        self.write(&summary);
+    }
+
+    fn write(&self, summary: &RunSummary) {
+        let result = self.path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                // write then rename, so a power cycle never leaves a partial file
+                let tmp = self.path.with_extension("tmp");
+                fs::write(&tmp, serde_json::to_string_pretty(summary)?)?;
+                fs::rename(&tmp, &self.path)
+            });
+        if let Err(e) = result {
+            warn!("Failed to write summary file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::programs::step::Equilibrium;
+    use crate::programs::summary::{SteadyStateRecord, SummaryFile};
+
+    #[test]
+    fn test_steady_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let summary = SummaryFile::new(dir.path().join("state/summary.json"));
+        let record = SteadyStateRecord {
+            iteration: 0,
+            program_index: 1,
+            program_id: 2,
+            program_name: String::from("Top soak"),
+            step: 1,
+            phase: String::from("heating"),
+            time: Utc.with_ymd_and_hms(2023, 9, 1, 1, 0, 0).unwrap().to_rfc3339(),
+            sensors: vec![Equilibrium { sensor: String::from("TH1"), temp: 60.2, rate: 0.01 }],
+        };
+        assert_eq!(None, summary.read());
+        summary.add_steady_state(record.clone());
+        summary.add_steady_state(record.clone());
+        assert_eq!(2, summary.read().unwrap().steady_states.len());
+
+        summary.start(Utc.with_ymd_and_hms(2023, 9, 2, 0, 0, 0).unwrap());
+        let run = summary.read().unwrap();
+        assert_eq!("2023-09-02T00:00:00+00:00", run.started);
+        assert!(run.steady_states.is_empty());
+    }
+}
@@ -0,0 +1,510 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use chrono::Utc;
+
+use crate::payload::Config;
+use crate::programs::{Program, Programs};
+use crate::programs::plan::{format_duration, profile_time};
+use crate::programs::step::{Action, HeatStep, Step};
+use crate::selector::SensorSelector;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The program can't run, or won't run as intended
+    Error,
+    /// The program will run, but may not finish or may be stopped by the safety supervisor
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in a program file, with the line it's on where known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Line number, starting from 1
+    pub line: Option<usize>,
+    pub program: Option<String>,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        write!(f, "{}: ", self.severity)?;
+        if let Some(program) = &self.program {
+            write!(f, "{}: ", program)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Limits programs are checked against, from the config
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationLimits {
+    /// Safety supervisor limit, which switches heaters off before a higher abort temp is reached
+    pub max_temp: f32,
+    /// Expected ambient temperature, which cooling can't go below
+    pub ambient_temp: f32,
+    /// I2C buses of the configured boards
+    pub boards: Vec<u8>,
+}
+
+impl From<&Config> for ValidationLimits {
+    fn from(config: &Config) -> Self {
+        ValidationLimits {
+            max_temp: config.safety_max_temp,
+            ambient_temp: config.ambient_temp,
+            boards: config.i2c_bus.clone(),
+        }
+    }
+}
+
+/// Result of validating a program file: the programs if it could be parsed, and every problem found
+#[derive(Debug)]
+pub struct Validation {
+    pub programs: Option<Programs>,
+    pub problems: Vec<Problem>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.programs.is_some() && !self.problems.iter().any(|p| p.severity == Severity::Error)
+    }
+}
+
+/// Checks the contents of a program file without touching the hardware
+pub fn validate(source: &str, limits: &ValidationLimits) -> Validation {
+    let programs: Programs = match toml::from_str(source) {
+        Ok(programs) => programs,
+        Err(err) => {
+            let line = err.span().map(|span| line_of_offset(source, span.start));
+            let problem = Problem {
+                severity: Severity::Error,
+                line,
+                program: None,
+                message: err.message().to_string(),
+            };
+            return Validation { programs: None, problems: vec![problem] };
+        }
+    };
+    let lines: Vec<&str> = source.lines().collect();
+    let tables = find_tables(&lines, "programs", 0..lines.len());
+    let mut problems = vec![];
+    for (index, program) in programs.iter().enumerate() {
+        // programs written as inline tables can't be located, so are reported without lines
+        let table = tables.get(index).cloned().filter(|_| tables.len() == programs.programs.len());
+        let mut checker = Checker { lines: &lines, program, table, limits, problems: &mut problems };
+        checker.check();
+    }
+    check_sequence(&programs, &lines, &mut problems);
+    problems.sort_by_key(|p| (p.line, p.severity));
+    Validation { programs: Some(programs), problems }
+}
+
+fn line_of_offset(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+/// Checks the top-level settings which control the order programs run in
+fn check_sequence(programs: &Programs, lines: &[&str], problems: &mut Vec<Problem>) {
+    // top-level keys come before the first table
+    let top_level = 0..lines.iter().position(|line| line.trim_start().starts_with('[')).unwrap_or(lines.len());
+    if let Err(message) = programs.validate_sequence() {
+        let line = find_key(lines, "sequence", top_level.clone())
+            .or_else(|| find_tables(lines, "groups", 0..lines.len()).first().map(|range| range.start))
+            .map(|i| i + 1);
+        problems.push(Problem { severity: Severity::Error, line, program: None, message });
+    }
+    if programs.max_iterations.is_some() && !programs.run_loop {
+        let line = find_key(lines, "max_iterations", top_level).map(|i| i + 1);
+        let message = String::from("max_iterations has no effect without loop = true");
+        problems.push(Problem { severity: Severity::Warning, line, program: None, message });
+    }
+}
+
+/// Index of the first `key = ...` line within the range
+fn find_key(lines: &[&str], key: &str, within: Range<usize>) -> Option<usize> {
+    within.into_iter().find(|i| {
+        let line = lines[*i].trim_start();
+        matches!(line.strip_prefix(key), Some(rest) if rest.trim_start().starts_with('='))
+    })
+}
+
+/// Line ranges of each `[[name]]` table within the range, including its sub-tables
+fn find_tables(lines: &[&str], name: &str, within: Range<usize>) -> Vec<Range<usize>> {
+    let header = format!("[[{}]]", name);
+    let prefix = format!("{}.", name);
+    let is_end = |line: &str| line.starts_with('[') && !line.trim_start_matches('[').starts_with(&prefix);
+    within.clone()
+        .filter(|i| lines[*i].trim() == header)
+        .map(|start| {
+            let end = (start + 1..within.end).find(|i| is_end(lines[*i].trim())).unwrap_or(within.end);
+            start..end
+        })
+        .collect()
+}
+
+struct Checker<'a> {
+    lines: &'a [&'a str],
+    program: &'a Program,
+    table: Option<Range<usize>>,
+    limits: &'a ValidationLimits,
+    problems: &'a mut Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    fn check(&mut self) {
+        let program = self.program;
+        if !self.limits.boards.contains(&(program.heat_board as u8)) {
+            self.report(Severity::Error, Some("heat_board"), None,
+                        format!("heat_board {:?} isn't configured in UTS_I2C_BUS {:?}", program.heat_board, self.limits.boards));
+        }
+        if let Err(err) = program.validate_settings() {
+            self.report(Severity::Error, None, None, err);
+        }
+        self.check_sensor("temp_sensor", &program.temp_sensor, None);
+        if let Some(sensor) = &program.abort_sensor {
+            self.check_sensor("abort_sensor", sensor, None);
+        }
+        self.check_abort(program.temp_abort, None);
+        if let Some(not_after) = program.not_after.filter(|t| *t <= Utc::now()) {
+            self.report(Severity::Warning, Some("not_after"), None,
+                        format!("not_after {} has passed, so the program will be skipped", not_after.to_rfc3339()));
+        }
+        if program.steps.is_empty() {
+            // heat settings are on the program itself
+            if let Some(Step { action: Action::Heat(heat), .. }) = program.steps().first() {
+                self.check_heat(heat, None);
+            }
+            if let Some(temp) = program.cool_temp {
+                self.check_cool("cool_temp", temp, None);
+            }
+        }
+        for (index, step) in program.steps.iter().enumerate() {
+            self.check_step(step, index);
+        }
+    }
+
+    fn check_step(&mut self, step: &Step, index: usize) {
+        let at = Some(index);
+        if let Err(err) = step.validate() {
+            self.report(Severity::Error, None, at, err);
+        }
+        if let Some(sensor) = &step.temp_sensor {
+            self.check_sensor("temp_sensor", sensor, at);
+        }
+        if let Some(sensor) = &step.abort_sensor {
+            self.check_sensor("abort_sensor", sensor, at);
+        }
+        if let Some(temp_abort) = step.temp_abort {
+            self.check_abort(temp_abort, at);
+        }
+        for sensor in step.steady_state.iter().flat_map(|steady| &steady.sensors) {
+            self.check_sensor("sensors", sensor, at);
+        }
+        match &step.action {
+            Action::Heat(heat) => self.check_heat(heat, at),
+            Action::Cool { temp } => self.check_cool("temp", *temp, at),
+            Action::WaitTemp { below: Some(temp), .. } => self.check_cool("below", *temp, at),
+            _ => {}
+        }
+    }
+
+    fn check_heat(&mut self, heat: &HeatStep, step: Option<usize>) {
+        // settings on the program itself have a heat_ prefix
+        let (duty_key, power_key) = if step.is_some() { ("duty", "power") } else { ("heat_duty", "heat_power") };
+        if !(0.0..=1.0).contains(&heat.duty) {
+            self.report(Severity::Error, Some(duty_key), step,
+                        format!("{} {} should be between 0.0 and 1.0", duty_key, heat.duty));
+        }
+        if let Some(power) = heat.power.filter(|p| *p <= 0.0) {
+            self.report(Severity::Error, Some(power_key), step,
+                        format!("{} {} W should be positive", power_key, power));
+        }
+        let temp_abort = step.and_then(|i| self.program.steps[i].temp_abort)
+            .map_or(self.program.temp_abort, |t| t.min(self.program.temp_abort));
+        let mut setpoints = vec![];
+        if let Some(thermostat) = heat.thermostat {
+            setpoints.push(("thermostat", thermostat));
+        }
+        if let Some(pid) = &heat.pid {
+            setpoints.push(("setpoint", pid.setpoint));
+        }
+        setpoints.extend(heat.profile.iter().map(|s| ("target", s.target)));
+        for (key, setpoint) in setpoints.into_iter().filter(|(_, t)| *t >= temp_abort) {
+            self.report(Severity::Error, Some(key), step,
+                        format!("{} {:.1}°C should be below temp_abort {:.1}°C", key, setpoint, temp_abort));
+        }
+        let duration_key = if step.is_some() { "duration" } else { "heat_time" };
+        if let (Some(duration), false) = (heat.duration, heat.profile.is_empty()) {
+            let profile = profile_time(&heat.profile, self.limits.ambient_temp);
+            if duration < profile {
+                self.report(Severity::Warning, Some(duration_key), step,
+                            format!("{} {} is shorter than the {} the profile takes from the expected ambient \
+                                     {:.1}°C, so heating will stop before the profile completes",
+                                    duration_key, format_duration(duration), format_duration(profile),
+                                    self.limits.ambient_temp));
+            }
+        }
+    }
+
+    fn check_abort(&mut self, temp_abort: f32, step: Option<usize>) {
+        if temp_abort > self.limits.max_temp {
+            self.report(Severity::Warning, Some("temp_abort"), step,
+                        format!("temp_abort {:.1}°C is above UTS_SAFETY_MAX_TEMP {:.1}°C, so the safety supervisor \
+                                 will switch off the heater first", temp_abort, self.limits.max_temp));
+        }
+    }
+
+    fn check_cool(&mut self, key: &str, temp: f32, step: Option<usize>) {
+        if temp <= self.limits.ambient_temp {
+            self.report(Severity::Warning, Some(key), step,
+                        format!("{} {:.1}°C isn't above the expected ambient {:.1}°C (UTS_AMBIENT_TEMP), \
+                                 so may never be reached", key, temp, self.limits.ambient_temp));
+        }
+    }
+
+    fn check_sensor(&mut self, key: &str, sensor: &str, step: Option<usize>) {
+        if let Err(err) = sensor.parse::<SensorSelector>() {
+            self.report(Severity::Error, Some(key), step, format!("unknown sensor in {}: {}", key, err));
+        }
+    }
+
+    /// Reports a problem on the line of the key in the program or step, or its header if no key
+    fn report(&mut self, severity: Severity, key: Option<&str>, step: Option<usize>, message: String) {
+        let line = match key {
+            Some(key) => self.key_line(key, step),
+            None => self.range(step).map(|range| range.start + 1),
+        };
+        let message = match step {
+            Some(index) => format!("step {}: {}", index + 1, message),
+            None => message,
+        };
+        let program = Some(self.program.name.clone());
+        self.problems.push(Problem { severity, line, program, message });
+    }
+
+    /// Lines of the program table, or of one of its steps
+    fn range(&self, step: Option<usize>) -> Option<Range<usize>> {
+        let table = self.table.clone()?;
+        match step {
+            Some(index) => find_tables(self.lines, "programs.steps", table).get(index).cloned(),
+            None => Some(table),
+        }
+    }
+
+    /// Line of the first `key = ...` in the program or step, falling back to its header
+    fn key_line(&self, key: &str, step: Option<usize>) -> Option<usize> {
+        let range = self.range(step)?;
+        let found = find_key(self.lines, key, range.clone());
+        Some(found.unwrap_or(range.start) + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::programs::validate::{Severity, validate, ValidationLimits};
+
+    fn limits() -> ValidationLimits {
+        ValidationLimits { max_temp: 105.0, ambient_temp: 25.0, boards: vec![1, 2] }
+    }
+
+    #[test]
+    fn test_valid_programs() {
+        let source = std::fs::read_to_string("programs/example.toml").unwrap();
+        let validation = validate(&source, &limits());
+        assert!(validation.is_valid(), "{:?}", validation.problems);
+        assert!(validation.problems.is_empty());
+    }
+
+    #[test]
+    fn test_problems_with_lines() {
+        let source = r#"
+[[programs]]
+name = "Bad legacy"
+heat_board = "Top"
+heat_time = "3m"
+heat_duty = 1.5
+temp_sensor = "TH9"
+temp_abort = 110.0
+thermostat = 120.0
+cool_temp = 20.0
+
+[[programs]]
+name = "Bad steps"
+heat_board = "Bottom"
+temp_sensor = "TH1"
+temp_abort = 90.0
+
+[[programs.steps]]
+type = "heat"
+duration = "5m"
+
+[programs.steps.pid]
+setpoint = 95.0
+kp = 0.05
+ki = 0.0005
+kd = 0.0
+
+[[programs.steps]]
+type = "wait_temp"
+above = 50.0
+below = 40.0
+"#;
+        let validation = validate(source, &ValidationLimits { boards: vec![2], ..limits() });
+        assert!(!validation.is_valid());
+        let problems: Vec<(Option<usize>, Severity, &str)> = validation.problems.iter()
+            .map(|p| (p.line, p.severity, p.message.split(' ').next().unwrap()))
+            .collect();
+        assert_eq!(vec![
+            (Some(4), Severity::Error, "heat_board"),
+            (Some(6), Severity::Error, "heat_duty"),
+            (Some(7), Severity::Error, "unknown"),
+            (Some(8), Severity::Warning, "temp_abort"),
+            (Some(9), Severity::Error, "thermostat"),
+            (Some(10), Severity::Warning, "cool_temp"),
+            (Some(23), Severity::Error, "step"),
+            (Some(28), Severity::Error, "step"),
+        ], problems);
+        assert_eq!("line 23: error: Bad steps: step 1: setpoint 95.0°C should be below temp_abort 90.0°C",
+                   validation.problems[6].to_string());
+    }
+
+    #[test]
+    fn test_heat_time_shorter_than_profile() {
+        let source = r#"
+[[programs]]
+name = "Short ramp"
+heat_board = "Top"
+heat_time = "30m"
+temp_sensor = "TH1"
+temp_abort = 90.0
+cool_temp = 40.0
+
+[[programs.profile]]
+target = 65.0
+ramp_rate = 2.0
+soak = "15m"
+"#;
+        let validation = validate(source, &limits());
+        assert!(validation.is_valid());
+        assert_eq!(1, validation.problems.len());
+        assert_eq!(Some(5), validation.problems[0].line);
+        assert_eq!("line 5: warning: Short ramp: heat_time 30m00s is shorter than the 35m00s the profile takes \
+                    from the expected ambient 25.0°C, so heating will stop before the profile completes",
+                   validation.problems[0].to_string());
+    }
+
+    #[test]
+    fn test_thermostat_and_pid() {
+        let source = r#"
+[[programs]]
+name = "Legacy both"
+heat_board = "Top"
+heat_time = "3m"
+temp_sensor = "TH1"
+temp_abort = 90.0
+thermostat = 60.0
+pid = { setpoint = 60.0, kp = 0.05, ki = 0.0005, kd = 0.0 }
+cool_temp = 40.0
+
+[[programs]]
+name = "Step both"
+heat_board = "Top"
+temp_sensor = "TH1"
+temp_abort = 90.0
+
+[[programs.steps]]
+type = "heat"
+thermostat = 60.0
+pid = { setpoint = 60.0, kp = 0.05, ki = 0.0005, kd = 0.0 }
+"#;
+        let validation = validate(source, &limits());
+        assert!(!validation.is_valid());
+        let problems: Vec<String> = validation.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(vec![
+            "line 2: error: Legacy both: should set only one of thermostat or pid",
+            "line 18: error: Step both: step 1: heat step should set only one of thermostat or pid",
+        ], problems);
+    }
+
+    #[test]
+    fn test_ramp_rate_without_setpoint() {
+        let source = r#"
+[[programs]]
+name = "Legacy ramp"
+heat_board = "Top"
+heat_time = "30m"
+temp_sensor = "TH1"
+temp_abort = 90.0
+ramp_rate = 2.0
+cool_temp = 40.0
+
+[[programs]]
+name = "Step ramp"
+heat_board = "Top"
+temp_sensor = "TH1"
+temp_abort = 90.0
+
+[[programs.steps]]
+type = "heat"
+ramp_rate = 2.0
+"#;
+        let validation = validate(source, &limits());
+        assert!(!validation.is_valid());
+        let problems: Vec<String> = validation.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(vec![
+            "line 2: error: Legacy ramp: should set thermostat or pid for ramp_rate to ramp to",
+            "line 17: error: Step ramp: step 1: heat step should set thermostat or pid for ramp_rate to ramp to",
+        ], problems);
+    }
+
+    #[test]
+    fn test_sequence_problems() {
+        let source = r#"
+max_iterations = 3
+sequence = ["Hot", "Cycle"]
+
+[[groups]]
+name = "Cycle"
+programs = ["Hot", "Cold", "Cycle"]
+
+[[programs]]
+name = "Hot"
+heat_board = "Top"
+heat_time = "3m"
+temp_sensor = "TH1"
+temp_abort = 90.0
+cool_temp = 40.0
+"#;
+        let validation = validate(source, &limits());
+        assert!(!validation.is_valid());
+        let problems: Vec<(Option<usize>, Severity, &str)> = validation.problems.iter()
+            .map(|p| (p.line, p.severity, p.message.as_str()))
+            .collect();
+        assert_eq!(vec![
+            (Some(2), Severity::Warning, "max_iterations has no effect without loop = true"),
+            (Some(3), Severity::Error, "group \"Cycle\" has unknown program or group \"Cold\""),
+        ], problems);
+    }
+
+    #[test]
+    fn test_invalid_toml() {
+        let source = "[[programs]]\nname = \"Bad board\"\nheat_board = \"Middle\"\n";
+        let validation = validate(source, &limits());
+        assert!(validation.programs.is_none());
+        assert_eq!(1, validation.problems.len());
+        assert_eq!(Some(3), validation.problems[0].line);
+    }
+}
@@ -0,0 +1,383 @@
+use std::fmt::{Display, Formatter};
+use std::iter::zip;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+use std::thread::JoinHandle;
+
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+
+use crate::board::{ALL_SENSORS, BoardData, BoardDataProvider, BoardId};
+use crate::deadman::Deadman;
+use crate::diagnostics::{Baselines, FlagMonitor, Health, HeaterMeasurement, HeaterMonitor};
+use crate::heater::HeaterMode;
+use crate::payload::{Config, Payload};
+use crate::ReadError;
+use crate::sensors::SensorId;
+
+/// Limits enforced by the heater safety supervisor, independent of the firmware max temp
+#[derive(Debug, Clone, PartialEq)]
+pub struct SafetyLimits {
+    /// Highest temperature allowed on any sensor of any board
+    pub max_temp: f32,
+
+    /// Longest time a board can go without a successful reading
+    pub stale_timeout: Duration,
+
+    /// Number of consecutive failed reads from a board before heaters are switched off
+    pub max_failures: u32,
+}
+
+impl From<&Config> for SafetyLimits {
+    fn from(config: &Config) -> Self {
+        SafetyLimits {
+            max_temp: config.safety_max_temp,
+            stale_timeout: Duration::seconds(config.safety_stale_timeout as i64),
+            max_failures: config.safety_max_failures,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SafetyViolation {
+    OverTemperature {
+        sensor: SensorId,
+        temp: f32,
+        limit: f32,
+    },
+    StaleData {
+        last_reading: DateTime<Utc>,
+    },
+    ReadFailures {
+        count: u32,
+    },
+    FlagError {
+        flags: String,
+    },
+}
+
+impl Display for SafetyViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SafetyViolation::OverTemperature { sensor, temp, limit } =>
+                write!(f, "{} over temperature ({:.2} > {:.2})", sensor, temp, limit),
+            SafetyViolation::StaleData { last_reading } =>
+                write!(f, "no data since {}", last_reading.format("%T")),
+            SafetyViolation::ReadFailures { count } =>
+                write!(f, "{} consecutive read failures", count),
+            SafetyViolation::FlagError { flags } =>
+                write!(f, "board reported {}", flags),
+        }
+    }
+}
+
+/// Tracks readings from one board, and checks them against the safety limits
+#[derive(Debug, Clone)]
+pub struct BoardMonitor {
+    board: BoardId,
+    failures: u32,
+    last_reading: DateTime<Utc>,
+}
+
+impl BoardMonitor {
+    pub fn new(board: BoardId, now: DateTime<Utc>) -> Self {
+        BoardMonitor { board, failures: 0, last_reading: now }
+    }
+
+    pub fn check(&mut self, data: Option<&BoardData>, limits: &SafetyLimits,
+                 now: DateTime<Utc>) -> Option<SafetyViolation> {
+        let data = match data {
+            Some(data) if !matches!(data.heater_mode, Err(ReadError::I2CError(_))) => data,
+            _ => {
+                self.failures += 1;
+                return self.check_failures(limits, now);
+            }
+        };
+        self.failures = 0;
+        self.last_reading = now;
+
+        for (sensor, reading) in zip(ALL_SENSORS, &data.sensors) {
+            if let Ok(reading) = reading {
+                if sensor.is_temperature() && reading.display_value > limits.max_temp {
+                    return Some(SafetyViolation::OverTemperature {
+                        sensor: sensor.id,
+                        temp: reading.display_value,
+                        limit: limits.max_temp,
+                    });
+                }
+            }
+        }
+        match &data.flags {
+            Ok(flags) if flags.display_value.is_error() => Some(SafetyViolation::FlagError {
+                flags: flags.display_value.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    fn check_failures(&self, limits: &SafetyLimits, now: DateTime<Utc>) -> Option<SafetyViolation> {
+        if now - self.last_reading > limits.stale_timeout {
+            Some(SafetyViolation::StaleData { last_reading: self.last_reading })
+        } else if self.failures >= limits.max_failures {
+            Some(SafetyViolation::ReadFailures { count: self.failures })
+        } else {
+            None
+        }
+    }
+}
+
+/// Watches every board and forces all heaters off when any safety limit is breached.
+/// Also diagnoses heater faults, which are reported but don't switch the heaters off.
+pub struct Supervisor {
+    limits: SafetyLimits,
+    monitors: Vec<BoardMonitor>,
+    heaters: Vec<HeaterMonitor>,
+    flags: Vec<FlagMonitor>,
+    health: Health,
+    deadman: Option<Deadman>,
+    violations: Vec<(BoardId, SafetyViolation)>,
+}
+
+impl Supervisor {
+    pub fn new(limits: SafetyLimits, payload: &Payload) -> Self {
+        let now = Utc::now();
+        let monitors = payload.iter().map(|b| BoardMonitor::new(b.id, now)).collect();
+        let heaters = payload.iter().map(|b| HeaterMonitor::new(b.id)).collect();
+        let flags = payload.iter().map(|b| FlagMonitor::new(b.id)).collect();
+        Supervisor { limits, monitors, heaters, flags, health: Health::default(), deadman: None, violations: vec![] }
+    }
+
+    /// Switches off heaters left running by manual commands once their deadline passes
+    pub fn with_deadman(self, deadman: Deadman) -> Self {
+        Supervisor { deadman: Some(deadman), ..self }
+    }
+
+    fn check_deadman(&self, payload: &Payload, now: DateTime<Utc>) {
+        let deadman = match &self.deadman {
+            Some(deadman) => deadman,
+            None => return,
+        };
+        for (bus, _) in deadman.expired(now) {
+            let board = match payload.iter().find(|b| b.bus.id == bus) {
+                Some(board) => board,
+                None => continue, // another process may be supervising this board
+            };
+            // another process may have got there first, or the heater been switched on again
+            if let Some(deadline) = deadman.claim_expired(bus, now) {
+                warn!("Deadman timeout expired on {} board, switching off heater", board.id);
+                board.write_heater_mode(HeaterMode::OFF);
+                deadman.record(bus, deadline, now);
+            }
+        }
+    }
+
+    /// Keeps each board's heater resistance baseline across restarts
+    pub fn with_baselines(self, baselines: Baselines) -> Self {
+        let heaters = self.heaters.into_iter()
+            .map(|heater| heater.with_baselines(baselines.clone()))
+            .collect();
+        Supervisor { heaters, ..self }
+    }
+
+    /// Publishes heater diagnosis to a shared Health
+    pub fn with_health(self, health: Health) -> Self {
+        Supervisor { health, ..self }
+    }
+
+    /// Reads all boards and switches off their heaters if any limit is breached.
+    /// Returns the violations found, which are only logged when they first occur.
+    pub fn check(&mut self, payload: &Payload) -> Vec<(BoardId, SafetyViolation)> {
+        let now = Utc::now();
+        self.check_deadman(payload, now);
+        let mut violations = vec![];
+        let mut heater_events = vec![];
+        let mut flag_events = vec![];
+        let boards = zip(zip(payload, &mut self.monitors), zip(&mut self.heaters, &mut self.flags));
+        for ((board, monitor), (heater, flags)) in boards {
+            let data = board.read_data();
+            if let Some(Ok(reading)) = data.as_ref().map(|d| &d.flags) {
+                flag_events.append(&mut flags.update(reading.display_value, now));
+            }
+            if let Some(violation) = monitor.check(data.as_ref(), &self.limits, now) {
+                violations.push((monitor.board, violation));
+            }
+            if let Some(measurement) = data.and_then(|d| HeaterMeasurement::from_data(board.version, &d)) {
+                heater_events.append(&mut heater.update(&measurement, now));
+            }
+        }
+        self.health.update(&self.heaters, heater_events);
+        self.health.update_flags(&self.flags, flag_events);
+
+        for (board, violation) in &violations {
+            if !self.violations.iter().any(|(b, v)| b == board && same_kind(v, violation)) {
+                error!("Safety limit breached on {} board: {}, switching off heaters", board, violation);
+            }
+        }
+        if !violations.is_empty() {
+            for board in payload {
+                if !matches!(board.read_heater_mode(), Ok(mode) if mode.display_value == HeaterMode::OFF) {
+                    warn!("Switching off heater on {} board", board.id);
+                    board.write_heater_mode(HeaterMode::OFF);
+                }
+            }
+        } else if !self.violations.is_empty() {
+            info!("Safety limits no longer breached");
+        }
+        self.violations = violations.clone();
+        violations
+    }
+}
+
+fn same_kind(a: &SafetyViolation, b: &SafetyViolation) -> bool {
+    std::mem::discriminant(a) == std::mem::discriminant(b)
+}
+
+/// Runs a Supervisor on a background thread until dropped
+pub struct SupervisorThread {
+    stop: Arc<AtomicBool>,
+    health: Health,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SupervisorThread {
+    /// Starts the supervisor with its own connection to the payload. Returns None if
+    /// the supervisor is disabled with UTS_SAFETY_ENABLE=false.
+    pub fn spawn(config: &Config) -> Option<SupervisorThread> {
+        if !config.safety_enable {
+            warn!("Heater safety supervisor is disabled");
+            return None;
+        }
+        let config = config.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let health = Health::default();
+        let thread_health = health.clone();
+        let handle = thread::Builder::new()
+            .name(String::from("safety"))
+            .spawn(move || {
+                let payload = Payload::from_config(&config);
+                let limits = SafetyLimits::from(&config);
+                info!("Heater safety supervisor started: {:?}", limits);
+                let mut supervisor = Supervisor::new(limits, &payload)
+                    .with_health(thread_health)
+                    .with_deadman(Deadman::from_config(&config))
+                    .with_baselines(Baselines::from_config(&config));
+                let interval = std::time::Duration::from_secs(config.safety_interval as u64);
+                while !thread_stop.load(Relaxed) {
+                    supervisor.check(&payload);
+                    sleep_unless_stopped(interval, &thread_stop);
+                }
+            })
+            .expect("Failed to start safety supervisor thread");
+        Some(SupervisorThread { stop, health, handle: Some(handle) })
+    }
+
+    /// Latest heater diagnosis from the supervisor
+    pub fn health(&self) -> Health {
+        self.health.clone()
+    }
+}
+
+fn sleep_unless_stopped(duration: std::time::Duration, stop: &AtomicBool) {
+    let step = std::time::Duration::from_millis(100);
+    let mut remaining = duration;
+    while !stop.load(Relaxed) && remaining > std::time::Duration::ZERO {
+        let sleep = remaining.min(step);
+        thread::sleep(sleep);
+        remaining -= sleep;
+    }
+}
+
+impl Drop for SupervisorThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::board::{BoardData, BoardFlags, BoardId, SENSOR_COUNT};
+    use crate::heater::HeaterMode;
+    use crate::ReadError;
+    use crate::reading::SensorReading;
+    use crate::safety::{BoardMonitor, SafetyLimits, SafetyViolation};
+
+    fn limits() -> SafetyLimits {
+        SafetyLimits { max_temp: 100.0, stale_timeout: Duration::seconds(30), max_failures: 3 }
+    }
+
+    fn board_data(temps: &[f32], flags: u16) -> BoardData {
+        let sensors: [_; SENSOR_COUNT] = std::array::from_fn(|i| match temps.get(i) {
+            Some(&temp) => Ok(SensorReading::new(0, temp)),
+            None => Ok(SensorReading::new(0, 5.0)), // voltages etc.
+        });
+        BoardData {
+            sensors,
+            heater_mode: Ok(SensorReading::new(2, HeaterMode::PWM)),
+            target_temp: Err(ReadError::None),
+            target_sensor: Err(ReadError::None),
+            heater_duty: Ok(SensorReading::new(255, 255)),
+            max_temp: Err(ReadError::None),
+            flags: Ok(SensorReading::new(flags, BoardFlags::from(flags))),
+        }
+    }
+
+    #[test]
+    fn test_within_limits() {
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut monitor = BoardMonitor::new(BoardId::Top, now);
+        let data = board_data(&[60.0, 70.0, 99.0], 0b01);
+        assert_eq!(None, monitor.check(Some(&data), &limits(), now));
+    }
+
+    #[test]
+    fn test_over_temperature_on_any_sensor() {
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut monitor = BoardMonitor::new(BoardId::Top, now);
+        let mut temps = [25.0; 17];
+        temps[6] = 101.0; // U7
+        let data = board_data(&temps, 0b01);
+        assert_eq!(Some(SafetyViolation::OverTemperature { sensor: "U7", temp: 101.0, limit: 100.0 }),
+                   monitor.check(Some(&data), &limits(), now));
+    }
+
+    #[test]
+    fn test_flag_error() {
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut monitor = BoardMonitor::new(BoardId::Top, now);
+        let data = board_data(&[60.0], 0b11);
+        assert_eq!(Some(SafetyViolation::FlagError { flags: String::from("ERR_MAX_TEMP") }),
+                   monitor.check(Some(&data), &limits(), now));
+    }
+
+    #[test]
+    fn test_read_failure_streak() {
+        let now = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut monitor = BoardMonitor::new(BoardId::Top, now);
+        assert_eq!(None, monitor.check(None, &limits(), now));
+        assert_eq!(None, monitor.check(None, &limits(), now));
+        assert_eq!(Some(SafetyViolation::ReadFailures { count: 3 }),
+                   monitor.check(None, &limits(), now));
+
+        // a successful read resets the streak
+        let data = board_data(&[60.0], 0b01);
+        assert_eq!(None, monitor.check(Some(&data), &limits(), now));
+        assert_eq!(None, monitor.check(None, &limits(), now));
+    }
+
+    #[test]
+    fn test_stale_data() {
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let mut monitor = BoardMonitor::new(BoardId::Top, start);
+        let now = start + Duration::seconds(31);
+        assert_eq!(Some(SafetyViolation::StaleData { last_reading: start }),
+                   monitor.check(None, &limits(), now));
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use crate::board::ALL_SENSORS;
+use crate::heater::TargetSensor;
+use crate::sensors::SensorId;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Aggregate {
+    Max,
+    Min,
+    Mean,
+}
+
+impl Aggregate {
+    fn apply(&self, values: &[f32]) -> f32 {
+        match self {
+            Aggregate::Max => values.iter().copied().fold(f32::MIN, f32::max),
+            Aggregate::Min => values.iter().copied().fold(f32::MAX, f32::min),
+            Aggregate::Mean => values.iter().sum::<f32>() / values.len() as f32,
+        }
+    }
+}
+
+impl Display for Aggregate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Aggregate::Max => f.write_str("max"),
+            Aggregate::Min => f.write_str("min"),
+            Aggregate::Mean => f.write_str("mean"),
+        }
+    }
+}
+
+/// A temperature to control or check against: a single sensor such as `U7`, or an
+/// aggregate over several sensors such as `max(TH1, TH2, TH3)`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SensorSelector {
+    Single(SensorId),
+    Aggregate(Aggregate, Vec<SensorId>),
+}
+
+impl SensorSelector {
+    /// Sensors which need to be read to evaluate this selector
+    pub fn sensors(&self) -> &[SensorId] {
+        match self {
+            SensorSelector::Single(id) => std::slice::from_ref(id),
+            SensorSelector::Aggregate(_, ids) => ids,
+        }
+    }
+
+    /// The firmware target sensor, if the MSP430 thermostat can control on this selector
+    pub fn target_sensor(&self) -> Option<TargetSensor> {
+        match self {
+            SensorSelector::Single(id) => TargetSensor::from_id(id),
+            SensorSelector::Aggregate(..) => None,
+        }
+    }
+
+    /// Combines readings of `sensors()`, skipping any which failed. None if they all failed.
+    /// Only for control, see `combine_all` for limits.
+    pub fn combine(&self, readings: &[Option<f32>]) -> Option<f32> {
+        let values: Vec<f32> = readings.iter().flatten().copied().collect();
+        if values.is_empty() {
+            return None;
+        }
+        match self {
+            SensorSelector::Single(_) => Some(values[0]),
+            SensorSelector::Aggregate(aggregate, _) => Some(aggregate.apply(&values)),
+        }
+    }
+
+    /// Combines readings of `sensors()`, or None if any failed, so a limit isn't checked against
+    /// the cooler sensors left when the hottest fails
+    pub fn combine_all(&self, readings: &[Option<f32>]) -> Option<f32> {
+        if readings.iter().any(Option::is_none) {
+            return None;
+        }
+        self.combine(readings)
+    }
+}
+
+fn parse_sensor_id(id: &str) -> Result<SensorId, String> {
+    ALL_SENSORS.iter()
+        .filter(|s| s.is_temperature())
+        .find(|s| s.id.eq_ignore_ascii_case(id.trim()))
+        .map(|s| s.id)
+        .ok_or_else(|| format!("Unknown temperature sensor: {}", id.trim()))
+}
+
+impl FromStr for SensorSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, args) = match (s.find('('), s.strip_suffix(')')) {
+            (Some(open), Some(inner)) => (&s[..open], &inner[open + 1..]),
+            (None, None) => return parse_sensor_id(s).map(SensorSelector::Single),
+            _ => return Err(format!("Invalid sensor selector: {}", s)),
+        };
+        let aggregate = match name.trim().to_lowercase().as_str() {
+            "max" => Aggregate::Max,
+            "min" => Aggregate::Min,
+            "mean" | "avg" => Aggregate::Mean,
+            other => return Err(format!("Unknown sensor aggregate: {}", other)),
+        };
+        let ids = args.split(',')
+            .map(parse_sensor_id)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SensorSelector::Aggregate(aggregate, ids))
+    }
+}
+
+impl Display for SensorSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SensorSelector::Single(id) => f.write_str(id),
+            SensorSelector::Aggregate(aggregate, ids) => write!(f, "{}({})", aggregate, ids.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selector::{Aggregate, SensorSelector};
+
+    #[test]
+    fn test_parse_single() {
+        assert_eq!(Ok(SensorSelector::Single("TH1")), "TH1".parse());
+        assert_eq!(Ok(SensorSelector::Single("U7")), " u7 ".parse());
+        assert!("TH9".parse::<SensorSelector>().is_err());
+        assert!("v_high".parse::<SensorSelector>().is_err(), "not a temperature sensor");
+    }
+
+    #[test]
+    fn test_parse_aggregate() {
+        assert_eq!(Ok(SensorSelector::Aggregate(Aggregate::Max, vec!["TH1", "TH2", "TH3"])),
+                   "max(TH1, TH2, th3)".parse());
+        assert_eq!(Ok(SensorSelector::Aggregate(Aggregate::Mean, vec!["U4", "U7"])),
+                   "mean(U4,U7)".parse());
+        assert!("sum(TH1, TH2)".parse::<SensorSelector>().is_err());
+        assert!("max(TH1, TH2".parse::<SensorSelector>().is_err());
+        assert!("max()".parse::<SensorSelector>().is_err());
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let selector: SensorSelector = "MAX(th1,TH2)".parse().unwrap();
+        assert_eq!("max(TH1, TH2)", selector.to_string());
+        assert_eq!(Ok(selector.clone()), selector.to_string().parse());
+    }
+
+    #[test]
+    fn test_target_sensor() {
+        assert!("J7".parse::<SensorSelector>().unwrap().target_sensor().is_some());
+        assert!("U7".parse::<SensorSelector>().unwrap().target_sensor().is_none());
+        assert!("max(TH1)".parse::<SensorSelector>().unwrap().target_sensor().is_none());
+    }
+
+    #[test]
+    fn test_combine() {
+        let max: SensorSelector = "max(TH1, TH2, TH3)".parse().unwrap();
+        assert_eq!(Some(60.0), max.combine(&[Some(50.0), None, Some(60.0)]));
+        assert_eq!(None, max.combine(&[None, None, None]));
+        let mean: SensorSelector = "mean(TH1, TH2)".parse().unwrap();
+        assert_eq!(Some(55.0), mean.combine(&[Some(50.0), Some(60.0)]));
+        let single: SensorSelector = "TH1".parse().unwrap();
+        assert_eq!(Some(42.0), single.combine(&[Some(42.0)]));
+    }
+
+    #[test]
+    fn test_combine_all() {
+        let max: SensorSelector = "max(TH1, TH2, TH3)".parse().unwrap();
+        assert_eq!(Some(60.0), max.combine_all(&[Some(50.0), Some(40.0), Some(60.0)]));
+        // the hottest sensor failing doesn't leave the limit checked on the cooler ones
+        assert_eq!(None, max.combine_all(&[Some(50.0), Some(40.0), None]));
+    }
+}
@@ -2,7 +2,9 @@ use std::fmt;
 use std::fmt::Formatter;
 use strum_macros::Display;
 
+use crate::device::ads7828::ADS7828_ADC_RESOLUTION;
 use crate::device::i2c::*;
+use crate::device::{max31725, msp430};
 use crate::{ReadError, ReadResult};
 
 pub(crate) const MSP430_ADC_RESOLUTION: u16 = 1 << 12;
@@ -52,6 +54,23 @@ impl Sensor {
         Sensor { id, iface, addr: I2cAddr(addr), label, pos_x, pos_y }
     }
 
+    /// true for temperature sensors, false for heater voltage and current
+    pub const fn is_temperature(&self) -> bool {
+        matches!(self.iface,
+            SensorInterface::MSP430 | SensorInterface::ADS7828 | SensorInterface::MAX31725)
+    }
+
+    /// Converts a raw value, e.g. from the raw CSV log, as the sensor does when it's read
+    pub fn convert_raw(&self, raw_value: u16) -> ReadResult<f32> {
+        match self.iface {
+            SensorInterface::MSP430 => adc_val_to_temp(raw_value, MSP430_ADC_RESOLUTION),
+            SensorInterface::MSP430Voltage => Ok(msp430::adc_val_to_voltage(raw_value)),
+            SensorInterface::MSP430Current => Ok(msp430::adc_val_to_current(raw_value)),
+            SensorInterface::ADS7828 => adc_val_to_temp(raw_value, ADS7828_ADC_RESOLUTION),
+            SensorInterface::MAX31725 => Ok(max31725::raw_to_temp(raw_value)),
+        }
+    }
+
     /// mounted sensors have no position and a location of "Mounted"
     pub const fn mounted(id: SensorId, iface: SensorInterface,
                          addr: u8) -> Sensor {
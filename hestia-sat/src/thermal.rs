@@ -0,0 +1,266 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use duration_str::deserialize_duration_chrono;
+use serde::Deserialize;
+
+use crate::board::BoardId;
+use crate::csv::TIMESTAMP_FORMAT;
+use crate::payload::Config;
+
+fn default_gain() -> f32 { 5.0 }
+
+fn default_time_constant() -> Duration { Duration::minutes(10) }
+
+/// Longest gap between log readings used for fitting, so restarts aren't treated as one interval
+const MAX_FIT_INTERVAL: Duration = Duration::minutes(1);
+
+/// First-order thermal model of a board, where the temperature approaches
+/// `ambient + gain * power` exponentially with the time constant
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub struct ThermalModel {
+    /// Steady-state temperature rise above ambient per W of heater power, in °C/W
+    #[serde(default = "default_gain")]
+    pub gain: f32,
+
+    /// Time to reach 63% of the steady-state temperature rise
+    #[serde(default = "default_time_constant", deserialize_with = "deserialize_duration_chrono")]
+    pub time_constant: Duration,
+}
+
+impl Default for ThermalModel {
+    fn default() -> Self {
+        ThermalModel { gain: default_gain(), time_constant: default_time_constant() }
+    }
+}
+
+/// A logged temperature and heater power, for fitting a model
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ThermalSample {
+    pub time: DateTime<Utc>,
+    pub temp: f32,
+    pub power: f32,
+}
+
+impl ThermalModel {
+    fn time_constant_secs(&self) -> f32 {
+        self.time_constant.num_milliseconds() as f32 / 1000.0
+    }
+
+    /// Temperature the board settles at with constant heater power
+    pub fn steady_state(&self, power: f32, ambient: f32) -> f32 {
+        ambient + self.gain * power
+    }
+
+    /// Temperature after a time at constant heater power
+    pub fn temp_after(&self, temp: f32, power: f32, ambient: f32, elapsed: Duration) -> f32 {
+        let steady_state = self.steady_state(power, ambient);
+        let secs = elapsed.num_milliseconds() as f32 / 1000.0;
+        steady_state + (temp - steady_state) * (-secs / self.time_constant_secs()).exp()
+    }
+
+    /// Heater power needed to hold a temperature
+    pub fn holding_power(&self, temp: f32, ambient: f32) -> f32 {
+        ((temp - ambient) / self.gain).max(0.0)
+    }
+
+    /// Heater power to reach a temperature after a time, limited to between zero and the max,
+    /// as an ideal controller would apply
+    pub fn power_to_reach(&self, temp: f32, target: f32, ambient: f32, elapsed: Duration, max_power: f32) -> f32 {
+        let secs = elapsed.num_milliseconds() as f32 / 1000.0;
+        let decay = (-secs / self.time_constant_secs()).exp();
+        let steady_state = (target - temp * decay) / (1.0 - decay);
+        self.holding_power(steady_state, ambient).min(max_power)
+    }
+
+    /// Least-squares fit of the rate of change of temperature to the heater power and the
+    /// temperature above ambient. None if the samples don't have enough variation in power and
+    /// temperature, or don't fit a stable model.
+    pub fn fit(samples: &[ThermalSample], ambient: f32) -> Option<ThermalModel> {
+        // dT/dt = a * power - b * (temp - ambient), where b = 1 / time constant and a = gain * b
+        let (mut pp, mut pt, mut tt, mut py, mut ty) = (0.0f64, 0.0f64, 0.0f64, 0.0f64, 0.0f64);
+        for pair in samples.windows(2) {
+            let (start, end) = (pair[0], pair[1]);
+            let interval = end.time - start.time;
+            if interval <= Duration::zero() || interval > MAX_FIT_INTERVAL {
+                continue;
+            }
+            let secs = interval.num_milliseconds() as f64 / 1000.0;
+            let rate = (end.temp - start.temp) as f64 / secs;
+            let power = start.power as f64;
+            let rise = -((start.temp + end.temp) as f64 / 2.0 - ambient as f64);
+            pp += power * power;
+            pt += power * rise;
+            tt += rise * rise;
+            py += power * rate;
+            ty += rise * rate;
+        }
+        let det = pp * tt - pt * pt;
+        if det.abs() < f64::EPSILON {
+            return None;
+        }
+        let a = (py * tt - ty * pt) / det;
+        let b = (ty * pp - py * pt) / det;
+        if a <= 0.0 || b <= 0.0 {
+            return None;
+        }
+        Some(ThermalModel {
+            gain: (a / b) as f32,
+            time_constant: Duration::milliseconds((1000.0 / b) as i64),
+        })
+    }
+}
+
+/// Thermal models for each board, configured as `[top]` and `[bottom]` tables
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize)]
+pub struct ThermalModels {
+    #[serde(default)]
+    pub top: ThermalModel,
+    #[serde(default)]
+    pub bottom: ThermalModel,
+}
+
+impl ThermalModels {
+    /// Models from UTS_THERMAL_MODEL_FILE, or the defaults if it isn't set
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        match &config.thermal_model_file {
+            Some(filename) => Self::load_from_file(filename),
+            None => Ok(ThermalModels::default()),
+        }
+    }
+
+    pub fn load_from_file(filename: &str) -> Result<Self, String> {
+        let str = fs::read_to_string(filename)
+            .map_err(|err| format!("Thermal model file should be readable {}: {}", filename, err))?;
+        toml::from_str(&str)
+            .map_err(|err| format!("Thermal model file should contain valid TOML {}: {}", filename, err))
+    }
+
+    pub fn get(&self, board: BoardId) -> &ThermalModel {
+        match board {
+            BoardId::Top => &self.top,
+            BoardId::Bottom => &self.bottom,
+        }
+    }
+
+    pub fn set(&mut self, board: BoardId, model: ThermalModel) {
+        match board {
+            BoardId::Top => self.top = model,
+            BoardId::Bottom => self.bottom = model,
+        }
+    }
+
+    /// Formats the models as a TOML file for UTS_THERMAL_MODEL_FILE
+    pub fn to_toml(&self) -> String {
+        [("top", &self.top), ("bottom", &self.bottom)].iter()
+            .map(|(board, model)| format!("[{}]\ngain = {:.3}\ntime_constant = \"{}s\"\n",
+                                          board, model.gain, model.time_constant.num_seconds()))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// Reads the temperature of a sensor and the heater power for one board from a CSV log
+/// written by uts-log, skipping rows with missing values
+pub fn read_log_samples<P: AsRef<Path>>(path: P, bus: u8, sensor: &str) -> io::Result<Vec<ThermalSample>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let reader = BufReader::new(fs::File::open(path)?);
+    let mut lines = reader.lines();
+    let headers: Vec<String> = match lines.next() {
+        Some(line) => line?.trim_end().split(',').map(String::from).collect(),
+        None => return Ok(vec![]),
+    };
+    let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name))
+        .ok_or_else(|| invalid(format!("Log file should have a {} column", name)));
+    let (time_col, board_col, temp_col, power_col) =
+        (column("UTC")?, column("board")?, column(sensor)?, column("heater_power")?);
+    let bus = bus.to_string();
+    let mut samples = vec![];
+    for line in lines {
+        let line = line?;
+        let values: Vec<&str> = line.trim_end().split(',').collect();
+        if values.get(board_col) != Some(&bus.as_str()) {
+            continue;
+        }
+        let time = values.get(time_col)
+            .and_then(|v| NaiveDateTime::parse_from_str(v, TIMESTAMP_FORMAT).ok());
+        let temp = values.get(temp_col).and_then(|v| v.parse().ok());
+        let power = values.get(power_col).and_then(|v| v.parse().ok());
+        if let (Some(time), Some(temp), Some(power)) = (time, temp, power) {
+            samples.push(ThermalSample { time: Utc.from_utc_datetime(&time), temp, power });
+        }
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use assert_approx_eq::assert_approx_eq;
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::thermal::{read_log_samples, ThermalModel, ThermalModels, ThermalSample};
+
+    #[test]
+    fn test_temp_after() {
+        let model = ThermalModel { gain: 4.0, time_constant: Duration::minutes(10) };
+        assert_eq!(65.0, model.steady_state(10.0, 25.0));
+        assert_approx_eq!(25.0 + 40.0 * (1.0 - (-1.0f32).exp()),
+            model.temp_after(25.0, 10.0, 25.0, Duration::minutes(10)), 0.01);
+        assert_approx_eq!(10.0, model.holding_power(65.0, 25.0));
+        assert_eq!(0.0, model.holding_power(20.0, 25.0));
+        let power = model.power_to_reach(40.0, 40.5, 25.0, Duration::seconds(10), 15.0);
+        assert_approx_eq!(40.5, model.temp_after(40.0, power, 25.0, Duration::seconds(10)), 0.01);
+        assert_eq!(15.0, model.power_to_reach(25.0, 60.0, 25.0, Duration::seconds(10), 15.0));
+    }
+
+    #[test]
+    fn test_fit() {
+        let model = ThermalModel { gain: 4.0, time_constant: Duration::minutes(10) };
+        let start = Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 0).unwrap();
+        let interval = Duration::seconds(5);
+        let mut samples = vec![];
+        let mut temp = 25.0;
+        for i in 0..720 {
+            // heat for half an hour, then cool
+            let power = if i < 360 { 10.0 } else { 0.0 };
+            samples.push(ThermalSample { time: start + interval * i, temp, power });
+            temp = model.temp_after(temp, power, 25.0, interval);
+        }
+        let fitted = ThermalModel::fit(&samples, 25.0).unwrap();
+        assert_approx_eq!(4.0, fitted.gain, 0.05);
+        assert_approx_eq!(600.0, fitted.time_constant.num_seconds() as f32, 10.0);
+
+        // no heating, so nothing to fit
+        let flat: Vec<ThermalSample> = samples.iter().map(|s| ThermalSample { power: 0.0, temp: 25.0, ..*s }).collect();
+        assert_eq!(None, ThermalModel::fit(&flat, 25.0));
+    }
+
+    #[test]
+    fn test_models_file() {
+        let models: ThermalModels = toml::from_str("[top]\ngain = 3.5\ntime_constant = \"8m\"\n").unwrap();
+        assert_eq!(ThermalModel { gain: 3.5, time_constant: Duration::minutes(8) }, models.top);
+        assert_eq!(ThermalModel::default(), models.bottom);
+        let saved: ThermalModels = toml::from_str(&models.to_toml()).unwrap();
+        assert_eq!(models, saved);
+    }
+
+    #[test]
+    fn test_read_log_samples() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "UTC,board,TH1,heater_power\r\n\
+            2023-09-01 00:00:00.000000,1,25.00,0.00\r\n\
+            2023-09-01 00:00:00.000000,2,30.00,1.00\r\n\
+            2023-09-01 00:00:05.000000,1,26.00,\r\n\
+            2023-09-01 00:00:10.000000,1,27.00,10.00\r\n").unwrap();
+        let samples = read_log_samples(file.path(), 1, "TH1").unwrap();
+        assert_eq!(2, samples.len());
+        assert_eq!(27.0, samples[1].temp);
+        assert_eq!(Utc.with_ymd_and_hms(2023, 9, 1, 0, 0, 10).unwrap(), samples[1].time);
+        assert!(read_log_samples(file.path(), 1, "U7").is_err());
+    }
+}
@@ -18,16 +18,18 @@ pub fn zip_logs(config: &Config) {
         info!("Creating download directory at {}", download_path.display());
         create_dir_all(&download_path).expect(&*format!("Could not create download directory {}", download_path.display()));
     }
-    let path = format!("{}/*.csv", path);
-    for file in glob(&path).expect("Glob pattern failed").flatten() {
-        zip_file(file, &download_path);
+    // CSV files and the runner event logs, but not the predictions from uts-replay
+    for pattern in ["*.csv", "uts-events-*.jsonl"] {
+        let pattern = format!("{}/{}", path, pattern);
+        for file in glob(&pattern).expect("Glob pattern failed").flatten() {
+            zip_file(file, &download_path);
+        }
     }
 }
 
 fn zip_file(in_file: PathBuf, download_path: &PathBuf) {
     let mut out_file = download_path.clone();
-    out_file.push(in_file.file_name().unwrap());
-    out_file.set_extension("csv.gz");
+    out_file.push(format!("{}.gz", in_file.file_name().unwrap().to_string_lossy()));
     if out_file.exists() && mtime(&out_file) >= mtime(&in_file) {
         // ignore files we've already compressed
         return